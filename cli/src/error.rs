@@ -0,0 +1,17 @@
+use perps_client::error::ClientError;
+use solana_sdk::signer::SignerError;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CliError {
+    #[error("Encountered a connection error")]
+    ConnectionError(#[from] solana_client::client_error::ClientError),
+    #[error(transparent)]
+    ClientError(#[from] ClientError),
+    #[error("Failed to sign the transaction")]
+    SigningError(#[from] SignerError),
+    #[error("The account {0} has invalid data")]
+    InvalidAccountData(String),
+    #[error("{0}")]
+    InvalidArgument(String),
+}
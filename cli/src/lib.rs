@@ -0,0 +1,711 @@
+use audaces_protocol::{
+    instruction::{
+        add_budget, add_instance, add_page, close_position, create_market, increase_position,
+        migrate_instance, migrate_page, open_position, transfer_position, transfer_user_account,
+        withdraw_budget, InstanceContext, MarketContext, PositionInfo,
+    },
+    positions_book::{
+        memory::{LEGACY_SLOT_SIZE, MAX_PAGES, SLOT_SIZE, TAG_SIZE},
+        page::Page,
+    },
+    processor::FIDA_BNB,
+    quote::get_mark_price,
+    state::{
+        instance::{
+            get_page_capacity, get_page_infos_offset, parse_instance, PageInfo, INSTANCE_VERSION,
+        },
+        user_account::{OpenPosition, UserAccountState},
+        PositionType,
+    },
+};
+use error::CliError;
+use perps_client::{
+    get_close_slippage, get_discount_account, get_funding_instructions, get_market,
+    get_open_slippage, get_quote_context, get_user_account_filters, UserAccount,
+    DEFAULT_OPEN_POSITIONS_CAPACITY,
+};
+use serde_json::{json, Value};
+use solana_client::{
+    rpc_client::RpcClient,
+    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+};
+use solana_program::{
+    instruction::Instruction, program_pack::Pack, pubkey::Pubkey, system_instruction,
+};
+use solana_sdk::{
+    signature::{Keypair, Signature, Signer},
+    transaction::Transaction,
+};
+use spl_associated_token_account::{create_associated_token_account, get_associated_token_address};
+use std::str::FromStr;
+
+pub mod error;
+
+const MARKET_STATE_SPACE: usize = 5_000; // Same as the JS library, leaves room for the instance addresses
+
+pub struct Context {
+    pub program_id: Pubkey,
+    pub fee_payer: Keypair,
+    pub connection: RpcClient,
+    pub max_slippage_bps: u64,
+}
+
+impl Context {
+    pub fn create_market(
+        &self,
+        market_symbol: String,
+        quote_mint: Pubkey,
+        oracle: Pubkey,
+        coin_decimals: u8,
+        initial_v_pc_amount: u64,
+    ) -> Result<Value, CliError> {
+        let market = Keypair::new();
+        let (market_signer_account, signer_nonce) =
+            Pubkey::find_program_address(&[&market.pubkey().to_bytes()], &self.program_id);
+        let market_vault = get_associated_token_address(&market_signer_account, &quote_mint);
+        let quote_decimals =
+            spl_token::state::Mint::unpack(&self.connection.get_account_data(&quote_mint)?)
+                .map_err(|_| CliError::InvalidAccountData(quote_mint.to_string()))?
+                .decimals;
+
+        let ctx = MarketContext {
+            audaces_protocol_program_id: self.program_id,
+            signer_nonce,
+            market_signer_account,
+            oracle_account: oracle,
+            market_account: market.pubkey(),
+            admin_account: self.fee_payer.pubkey(),
+            market_vault,
+            bonfida_bnb: Pubkey::from_str(FIDA_BNB).unwrap(),
+            instances: vec![],
+        };
+        let lamports = self
+            .connection
+            .get_minimum_balance_for_rent_exemption(MARKET_STATE_SPACE)?;
+        let instructions = vec![
+            system_instruction::create_account(
+                &self.fee_payer.pubkey(),
+                &market.pubkey(),
+                lamports,
+                MARKET_STATE_SPACE as u64,
+                &self.program_id,
+            ),
+            create_associated_token_account(
+                &self.fee_payer.pubkey(),
+                &market_signer_account,
+                &quote_mint,
+            ),
+            create_market(
+                &ctx,
+                market_symbol,
+                initial_v_pc_amount,
+                coin_decimals,
+                quote_decimals,
+            ),
+        ];
+        let signature = self.send_transaction(instructions, &[&market])?;
+        Ok(json!({
+            "signature": signature.to_string(),
+            "market": market.pubkey().to_string(),
+            "market_signer": market_signer_account.to_string(),
+            "market_vault": market_vault.to_string(),
+        }))
+    }
+
+    pub fn add_instance(
+        &self,
+        market: Pubkey,
+        number_of_pages: usize,
+        page_slots: usize,
+    ) -> Result<Value, CliError> {
+        if number_of_pages == 0 || number_of_pages > MAX_PAGES {
+            return Err(CliError::InvalidArgument(format!(
+                "An instance must have between 1 and {} pages",
+                MAX_PAGES
+            )));
+        }
+        let (ctx, _) = get_market(self.program_id, market, &self.connection)?;
+        let mut memory_pages = Vec::with_capacity(number_of_pages);
+        for _ in 0..number_of_pages {
+            memory_pages.push(self.create_page(page_slots)?);
+        }
+
+        let instance = Keypair::new();
+        let space = get_page_infos_offset(INSTANCE_VERSION) + MAX_PAGES * PageInfo::LEN;
+        let lamports = self
+            .connection
+            .get_minimum_balance_for_rent_exemption(space)?;
+        let instructions = vec![
+            system_instruction::create_account(
+                &self.fee_payer.pubkey(),
+                &instance.pubkey(),
+                lamports,
+                space as u64,
+                &self.program_id,
+            ),
+            add_instance(&ctx, instance.pubkey(), &memory_pages),
+        ];
+        let signature = self.send_transaction(instructions, &[&instance])?;
+        Ok(json!({
+            "signature": signature.to_string(),
+            "instance_index": ctx.instances.len(),
+            "instance": instance.pubkey().to_string(),
+            "memory_pages": memory_pages.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+        }))
+    }
+
+    pub fn add_page(
+        &self,
+        market: Pubkey,
+        instance_index: u8,
+        page_slots: usize,
+    ) -> Result<Value, CliError> {
+        let (ctx, _) = get_market(self.program_id, market, &self.connection)?;
+        let instance = self.get_instance_context(&ctx, instance_index)?;
+        let instance_data = self
+            .connection
+            .get_account_data(&instance.instance_account)?;
+        let capacity = get_page_capacity(&instance_data)
+            .map_err(|_| CliError::InvalidAccountData(instance.instance_account.to_string()))?;
+        if instance.memory_pages.len() >= MAX_PAGES {
+            return Err(CliError::InvalidArgument(String::from(
+                "The instance has no room for an additional page",
+            )));
+        }
+        if instance.memory_pages.len() >= capacity {
+            return Err(CliError::InvalidArgument(String::from(
+                "The instance account is full, it has to be migrated with migrate-instance",
+            )));
+        }
+        let page = self.create_page(page_slots)?;
+        let signature = self.send_transaction(vec![add_page(&ctx, instance_index, page)], &[])?;
+        Ok(json!({
+            "signature": signature.to_string(),
+            "page": page.to_string(),
+        }))
+    }
+
+    /// Copies a page of the legacy layout into a new page with the same number of slots
+    pub fn migrate_page(
+        &self,
+        market: Pubkey,
+        instance_index: u8,
+        page_index: usize,
+    ) -> Result<Value, CliError> {
+        let (ctx, _) = get_market(self.program_id, market, &self.connection)?;
+        let instance = self.get_instance_context(&ctx, instance_index)?;
+        let legacy_page = *instance
+            .memory_pages
+            .get(page_index)
+            .ok_or_else(|| CliError::InvalidArgument(String::from("Invalid page index")))?;
+        let legacy_page_data = self.connection.get_account_data(&legacy_page)?;
+        let page_slots = (legacy_page_data.len() - TAG_SIZE) / LEGACY_SLOT_SIZE;
+        let page = self.create_page(page_slots)?;
+        let instruction = migrate_page(
+            &ctx,
+            instance_index,
+            page_index as u32,
+            page,
+            self.fee_payer.pubkey(),
+        );
+        let signature = self.send_transaction(vec![instruction], &[])?;
+        Ok(json!({
+            "signature": signature.to_string(),
+            "legacy_page": legacy_page.to_string(),
+            "page": page.to_string(),
+        }))
+    }
+
+    /// Copies an instance into a new account which can reference the maximum number of pages
+    pub fn migrate_instance(&self, market: Pubkey, instance_index: u8) -> Result<Value, CliError> {
+        let (ctx, _) = get_market(self.program_id, market, &self.connection)?;
+        let previous_instance = self.get_instance_context(&ctx, instance_index)?;
+        let instance = Keypair::new();
+        let space = get_page_infos_offset(INSTANCE_VERSION) + MAX_PAGES * PageInfo::LEN;
+        let lamports = self
+            .connection
+            .get_minimum_balance_for_rent_exemption(space)?;
+        let instructions = vec![
+            system_instruction::create_account(
+                &self.fee_payer.pubkey(),
+                &instance.pubkey(),
+                lamports,
+                space as u64,
+                &self.program_id,
+            ),
+            migrate_instance(
+                &ctx,
+                instance_index,
+                instance.pubkey(),
+                self.fee_payer.pubkey(),
+            ),
+        ];
+        let signature = self.send_transaction(instructions, &[&instance])?;
+        Ok(json!({
+            "signature": signature.to_string(),
+            "previous_instance": previous_instance.instance_account.to_string(),
+            "instance": instance.pubkey().to_string(),
+        }))
+    }
+
+    pub fn create_user_account(&self, market: Pubkey) -> Result<Value, CliError> {
+        let (ctx, quote_mint) = get_market(self.program_id, market, &self.connection)?;
+        let user_account = Keypair::new();
+        let space = UserAccountState::LEN + OpenPosition::LEN * DEFAULT_OPEN_POSITIONS_CAPACITY;
+        let lamports = self
+            .connection
+            .get_minimum_balance_for_rent_exemption(space)?;
+        let instructions = vec![
+            system_instruction::create_account(
+                &self.fee_payer.pubkey(),
+                &user_account.pubkey(),
+                lamports,
+                space as u64,
+                &self.program_id,
+            ),
+            add_budget(
+                &ctx,
+                0,
+                self.fee_payer.pubkey(),
+                get_associated_token_address(&self.fee_payer.pubkey(), &quote_mint),
+                user_account.pubkey(),
+            ),
+        ];
+        let signature = self.send_transaction(instructions, &[&user_account])?;
+        Ok(json!({
+            "signature": signature.to_string(),
+            "user_account": user_account.pubkey().to_string(),
+        }))
+    }
+
+    pub fn deposit(
+        &self,
+        market: Pubkey,
+        user_account: Pubkey,
+        amount: u64,
+    ) -> Result<Value, CliError> {
+        let (ctx, quote_mint) = get_market(self.program_id, market, &self.connection)?;
+        let instruction = add_budget(
+            &ctx,
+            amount,
+            self.fee_payer.pubkey(),
+            get_associated_token_address(&self.fee_payer.pubkey(), &quote_mint),
+            user_account,
+        );
+        let signature = self.send_transaction(vec![instruction], &[])?;
+        Ok(json!({ "signature": signature.to_string() }))
+    }
+
+    pub fn withdraw(
+        &self,
+        market: Pubkey,
+        user_account: Pubkey,
+        amount: u64,
+    ) -> Result<Value, CliError> {
+        let (ctx, quote_mint) = get_market(self.program_id, market, &self.connection)?;
+        let user_account = self.get_user_account(user_account)?;
+        let (market_state, _, _) = get_quote_context(&self.connection, &ctx)?;
+        let mut instructions = get_funding_instructions(&ctx, &market_state, &user_account);
+        instructions.push(withdraw_budget(
+            &ctx,
+            amount,
+            get_associated_token_address(&self.fee_payer.pubkey(), &quote_mint),
+            self.fee_payer.pubkey(),
+            user_account.address,
+        ));
+        let signature = self.send_transaction(instructions, &[])?;
+        Ok(json!({ "signature": signature.to_string() }))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn open_position(
+        &self,
+        market: Pubkey,
+        user_account: Pubkey,
+        instance_index: u8,
+        side: PositionType,
+        collateral: u64,
+        leverage: u64, // FP32
+        referrer_account: Option<Pubkey>,
+    ) -> Result<Value, CliError> {
+        let (ctx, _) = get_market(self.program_id, market, &self.connection)?;
+        self.get_instance_context(&ctx, instance_index)?;
+        let user_account = self.get_user_account(user_account)?;
+        let (market_state, market_vault_balance, oracle_price) =
+            get_quote_context(&self.connection, &ctx)?;
+        let (predicted_entry_price, maximum_slippage_margin) = get_open_slippage(
+            &market_state,
+            market_vault_balance,
+            oracle_price,
+            side,
+            collateral,
+            leverage,
+            self.max_slippage_bps,
+        )?;
+        let mut instructions = get_funding_instructions(&ctx, &market_state, &user_account);
+        let discount_account = get_discount_account(&self.connection, &self.fee_payer.pubkey())?;
+        instructions.push(open_position(
+            &ctx,
+            &PositionInfo {
+                user_account: user_account.address,
+                user_account_owner: self.fee_payer.pubkey(),
+                instance_index,
+                side,
+            },
+            collateral,
+            leverage,
+            predicted_entry_price,
+            maximum_slippage_margin,
+            discount_account.as_ref(),
+            referrer_account,
+        ));
+        let signature = self.send_transaction(instructions, &[])?;
+        Ok(json!({ "signature": signature.to_string() }))
+    }
+
+    pub fn increase_position(
+        &self,
+        market: Pubkey,
+        user_account: Pubkey,
+        position_index: u16,
+        add_collateral: u64,
+        leverage: u64, // FP32
+        referrer_account: Option<Pubkey>,
+    ) -> Result<Value, CliError> {
+        let (ctx, _) = get_market(self.program_id, market, &self.connection)?;
+        let user_account = self.get_user_account(user_account)?;
+        let position = get_position(&user_account, position_index)?;
+        let (market_state, market_vault_balance, oracle_price) =
+            get_quote_context(&self.connection, &ctx)?;
+        let (predicted_entry_price, maximum_slippage_margin) = get_open_slippage(
+            &market_state,
+            market_vault_balance,
+            oracle_price,
+            position.side,
+            add_collateral,
+            leverage,
+            self.max_slippage_bps,
+        )?;
+        let mut instructions = get_funding_instructions(&ctx, &market_state, &user_account);
+        let discount_account = get_discount_account(&self.connection, &self.fee_payer.pubkey())?;
+        instructions.push(increase_position(
+            &ctx,
+            add_collateral,
+            leverage,
+            position.instance_index,
+            position_index,
+            self.fee_payer.pubkey(),
+            user_account.address,
+            predicted_entry_price,
+            maximum_slippage_margin,
+            discount_account.as_ref(),
+            referrer_account,
+        ));
+        let signature = self.send_transaction(instructions, &[])?;
+        Ok(json!({ "signature": signature.to_string() }))
+    }
+
+    /// Closes the whole position when the closing amounts are not specified.
+    pub fn close_position(
+        &self,
+        market: Pubkey,
+        user_account: Pubkey,
+        position_index: u16,
+        closing_collateral: Option<u64>,
+        closing_v_coin: Option<u64>,
+        referrer_account: Option<Pubkey>,
+    ) -> Result<Value, CliError> {
+        let (ctx, _) = get_market(self.program_id, market, &self.connection)?;
+        let user_account = self.get_user_account(user_account)?;
+        let position = get_position(&user_account, position_index)?;
+        let closing_collateral = closing_collateral.unwrap_or(position.collateral);
+        let closing_v_coin = closing_v_coin.unwrap_or(position.v_coin_amount);
+        let (market_state, _, oracle_price) = get_quote_context(&self.connection, &ctx)?;
+        let (predicted_entry_price, maximum_slippage_margin) = get_close_slippage(
+            &market_state,
+            oracle_price,
+            position,
+            closing_collateral,
+            closing_v_coin,
+            self.max_slippage_bps,
+        )?;
+        let mut instructions = get_funding_instructions(&ctx, &market_state, &user_account);
+        let discount_account = get_discount_account(&self.connection, &self.fee_payer.pubkey())?;
+        instructions.push(close_position(
+            &ctx,
+            &PositionInfo {
+                user_account: user_account.address,
+                user_account_owner: self.fee_payer.pubkey(),
+                instance_index: position.instance_index,
+                side: position.side,
+            },
+            closing_collateral,
+            closing_v_coin,
+            position_index,
+            predicted_entry_price,
+            maximum_slippage_margin,
+            discount_account.as_ref(),
+            referrer_account,
+        ));
+        let signature = self.send_transaction(instructions, &[])?;
+        Ok(json!({ "signature": signature.to_string() }))
+    }
+
+    pub fn transfer_position(
+        &self,
+        market: Pubkey,
+        position_index: u16,
+        source_user_account: Pubkey,
+        destination_user_account: Pubkey,
+        destination_owner: &Keypair,
+    ) -> Result<Value, CliError> {
+        let (ctx, _) = get_market(self.program_id, market, &self.connection)?;
+        let instruction = transfer_position(
+            &ctx,
+            position_index,
+            source_user_account,
+            self.fee_payer.pubkey(),
+            destination_user_account,
+            destination_owner.pubkey(),
+        );
+        let signature = self.send_transaction(vec![instruction], &[destination_owner])?;
+        Ok(json!({ "signature": signature.to_string() }))
+    }
+
+    pub fn transfer_user_account(
+        &self,
+        market: Pubkey,
+        user_account: Pubkey,
+        new_owner: Pubkey,
+    ) -> Result<Value, CliError> {
+        let (ctx, _) = get_market(self.program_id, market, &self.connection)?;
+        let instruction =
+            transfer_user_account(&ctx, user_account, self.fee_payer.pubkey(), new_owner);
+        let signature = self.send_transaction(vec![instruction], &[])?;
+        Ok(json!({ "signature": signature.to_string() }))
+    }
+
+    pub fn show_market(&self, market: Pubkey) -> Result<Value, CliError> {
+        let (ctx, quote_mint) = get_market(self.program_id, market, &self.connection)?;
+        let (market_state, vault_balance, oracle_price) =
+            get_quote_context(&self.connection, &ctx)?;
+        let symbol_length = market_state
+            .market_symbol
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or_else(|| market_state.market_symbol.len());
+
+        Ok(json!({
+            "address": market.to_string(),
+            "symbol": String::from_utf8_lossy(&market_state.market_symbol[..symbol_length]),
+            "version": market_state.version,
+            "admin": ctx.admin_account.to_string(),
+            "oracle": ctx.oracle_account.to_string(),
+            "market_signer": ctx.market_signer_account.to_string(),
+            "market_vault": ctx.market_vault.to_string(),
+            "quote_mint": quote_mint.to_string(),
+            "coin_decimals": market_state.coin_decimals,
+            "quote_decimals": market_state.quote_decimals,
+            "vault_balance": vault_balance,
+            "insurance_fund": market_state.get_insurance_fund(vault_balance),
+            "total_collateral": market_state.total_collateral,
+            "total_user_balances": market_state.total_user_balances,
+            "total_fee_balance": market_state.total_fee_balance,
+            "rebalancing_funds": market_state.rebalancing_funds,
+            "rebalanced_v_coin": market_state.rebalanced_v_coin,
+            "v_coin_amount": market_state.v_coin_amount,
+            "v_pc_amount": market_state.v_pc_amount,
+            "open_shorts_v_coin": market_state.open_shorts_v_coin,
+            "open_longs_v_coin": market_state.open_longs_v_coin,
+            "open_shorts_v_pc": market_state.open_shorts_v_pc,
+            "open_longs_v_pc": market_state.open_longs_v_pc,
+            "mark_price": fp32_to_f64(get_mark_price(&market_state)),
+            "oracle_price": fp32_to_f64(oracle_price),
+            "last_funding_timestamp": market_state.last_funding_timestamp,
+            "last_recording_timestamp": market_state.last_recording_timestamp,
+            "funding_samples_count": market_state.funding_samples_count,
+            "funding_samples_sum": market_state.funding_samples_sum,
+            "funding_history_offset": market_state.funding_history_offset,
+            "funding_history": market_state.funding_history.to_vec(),
+            "funding_balancing_factors": market_state.funding_balancing_factors.to_vec(),
+            "instances": ctx
+                .instances
+                .iter()
+                .map(|i| i.instance_account.to_string())
+                .collect::<Vec<_>>(),
+        }))
+    }
+
+    pub fn show_instance(&self, market: Pubkey, instance_index: u8) -> Result<Value, CliError> {
+        let (ctx, _) = get_market(self.program_id, market, &self.connection)?;
+        let instance_address = self
+            .get_instance_context(&ctx, instance_index)?
+            .instance_account;
+        let (instance, page_infos) =
+            parse_instance(&self.connection.get_account_data(&instance_address)?)
+                .map_err(|_| CliError::InvalidAccountData(instance_address.to_string()))?;
+        Ok(json!({
+            "address": instance_address.to_string(),
+            "version": instance.version,
+            "shorts_pointer": instance.shorts_pointer,
+            "longs_pointer": instance.longs_pointer,
+            "garbage_pointer": instance.garbage_pointer,
+            "number_of_pages": instance.number_of_pages,
+            "pages": page_infos.iter().map(page_info_to_json).collect::<Vec<_>>(),
+        }))
+    }
+
+    pub fn show_page(
+        &self,
+        market: Pubkey,
+        instance_index: u8,
+        page_index: usize,
+    ) -> Result<Value, CliError> {
+        let (ctx, _) = get_market(self.program_id, market, &self.connection)?;
+        let instance_address = self
+            .get_instance_context(&ctx, instance_index)?
+            .instance_account;
+        let (_, page_infos) = parse_instance(&self.connection.get_account_data(&instance_address)?)
+            .map_err(|_| CliError::InvalidAccountData(instance_address.to_string()))?;
+        let page_info = page_infos
+            .get(page_index)
+            .ok_or_else(|| CliError::InvalidArgument(String::from("Invalid page index")))?;
+        let page_address = Pubkey::new(&page_info.address);
+        let mut page_data = self.connection.get_account_data(&page_address)?;
+        let page = Page::new_from_slice_unchecked(&mut page_data, page_info)
+            .map_err(|_| CliError::InvalidAccountData(page_address.to_string()))?;
+        let free_slots = page
+            .get_nb_free_slots()
+            .map_err(|_| CliError::InvalidAccountData(page_address.to_string()))?;
+        let used_slots = (page.uninitialized_memory as u64) - free_slots;
+
+        let mut result = page_info_to_json(page_info);
+        result["size"] = json!(TAG_SIZE + (page.page_size as usize) * SLOT_SIZE);
+        result["number_of_slots"] = json!(page.page_size);
+        result["free_slots"] = json!(free_slots);
+        result["used_slots"] = json!(used_slots);
+        result["full_ratio"] = json!((used_slots as f64) / (page.page_size as f64));
+        Ok(result)
+    }
+
+    pub fn show_user_account(&self, user_account: Pubkey) -> Result<Value, CliError> {
+        Ok(user_account_to_json(&self.get_user_account(user_account)?))
+    }
+
+    pub fn show_user_accounts(&self, market: Pubkey, owner: Pubkey) -> Result<Value, CliError> {
+        let config = RpcProgramAccountsConfig {
+            filters: Some(get_user_account_filters(&owner, &market)),
+            account_config: RpcAccountInfoConfig::default(),
+            with_context: None,
+        };
+        let accounts = self
+            .connection
+            .get_program_accounts_with_config(&self.program_id, config)?
+            .into_iter()
+            .filter_map(|(k, a)| UserAccount::parse(k, &a.data).ok())
+            .map(|u| user_account_to_json(&u))
+            .collect::<Vec<_>>();
+        Ok(Value::Array(accounts))
+    }
+
+    fn create_page(&self, page_slots: usize) -> Result<Pubkey, CliError> {
+        let page = Keypair::new();
+        let space = TAG_SIZE + page_slots * SLOT_SIZE;
+        let lamports = self
+            .connection
+            .get_minimum_balance_for_rent_exemption(space)?;
+        let instruction = system_instruction::create_account(
+            &self.fee_payer.pubkey(),
+            &page.pubkey(),
+            lamports,
+            space as u64,
+            &self.program_id,
+        );
+        self.send_transaction(vec![instruction], &[&page])?;
+        Ok(page.pubkey())
+    }
+
+    fn get_instance_context<'a>(
+        &self,
+        ctx: &'a MarketContext,
+        instance_index: u8,
+    ) -> Result<&'a InstanceContext, CliError> {
+        ctx.instances
+            .get(instance_index as usize)
+            .ok_or_else(|| CliError::InvalidArgument(String::from("Invalid instance index")))
+    }
+
+    fn get_user_account(&self, user_account: Pubkey) -> Result<UserAccount, CliError> {
+        let data = self.connection.get_account_data(&user_account)?;
+        UserAccount::parse(user_account, &data)
+            .map_err(|_| CliError::InvalidAccountData(user_account.to_string()))
+    }
+
+    fn send_transaction(
+        &self,
+        instructions: Vec<Instruction>,
+        signers: &[&Keypair],
+    ) -> Result<Signature, CliError> {
+        let mut all_signers = vec![&self.fee_payer];
+        all_signers.extend_from_slice(signers);
+        let (recent_blockhash, _) = self.connection.get_recent_blockhash()?;
+        let mut transaction =
+            Transaction::new_with_payer(&instructions, Some(&self.fee_payer.pubkey()));
+        transaction.try_sign(&all_signers, recent_blockhash)?;
+        Ok(self
+            .connection
+            .send_and_confirm_transaction_with_spinner(&transaction)?)
+    }
+}
+
+fn get_position(
+    user_account: &UserAccount,
+    position_index: u16,
+) -> Result<&OpenPosition, CliError> {
+    user_account
+        .positions
+        .get(position_index as usize)
+        .ok_or_else(|| CliError::InvalidArgument(String::from("Invalid position index")))
+}
+
+fn fp32_to_f64(n: u64) -> f64 {
+    (n as f64) / ((1u64 << 32) as f64)
+}
+
+fn page_info_to_json(page_info: &PageInfo) -> Value {
+    json!({
+        "address": Pubkey::new(&page_info.address).to_string(),
+        "uninitialized_memory_index": page_info.unitialized_memory_index,
+        "free_slot_list_hd": page_info.free_slot_list_hd,
+    })
+}
+
+fn user_account_to_json(user_account: &UserAccount) -> Value {
+    let header = &user_account.header;
+    json!({
+        "address": user_account.address.to_string(),
+        "version": header.version,
+        "owner": Pubkey::new(&header.owner).to_string(),
+        "active": header.active,
+        "market": Pubkey::new(&header.market).to_string(),
+        "balance": header.balance,
+        "last_funding_offset": header.last_funding_offset,
+        "number_of_open_positions": header.number_of_open_positions,
+        "positions": user_account.positions.iter().enumerate().map(|(i, p)| json!({
+            "position_index": i,
+            "instance_index": p.instance_index,
+            "side": match p.side {
+                PositionType::Long => "long",
+                PositionType::Short => "short",
+            },
+            "collateral": p.collateral,
+            "v_coin_amount": p.v_coin_amount,
+            "v_pc_amount": p.v_pc_amount,
+            "entry_price": (p.v_pc_amount as f64) / (p.v_coin_amount as f64),
+            "liquidation_index": p.liquidation_index,
+            "liquidation_price": fp32_to_f64(p.liquidation_index),
+            "slot_number": p.slot_number,
+            "last_funding_offset": p.last_funding_offset,
+        })).collect::<Vec<_>>(),
+    })
+}
@@ -0,0 +1,649 @@
+use audaces_protocol::state::PositionType;
+use clap::{value_t_or_exit, App, AppSettings, Arg, ArgMatches, SubCommand};
+use perps_cli::Context;
+use solana_clap_utils::{
+    fee_payer::{fee_payer_arg, FEE_PAYER_ARG},
+    input_parsers::{keypair_of, pubkey_of},
+    input_validators::{is_keypair, is_pubkey},
+};
+use solana_client::rpc_client::RpcClient;
+
+fn market_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("market")
+        .short("m")
+        .long("market")
+        .help("The pubkey of the Audaces Protocol market to interact with")
+        .takes_value(true)
+        .validator(is_pubkey)
+        .required(true)
+}
+
+fn user_account_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("user_account")
+        .long("user-account")
+        .help("The pubkey of the user account, owned by the fee payer")
+        .takes_value(true)
+        .validator(is_pubkey)
+        .required(true)
+}
+
+fn amount_arg<'a, 'b>(name: &'a str, long: &'a str, help: &'a str, required: bool) -> Arg<'a, 'b> {
+    Arg::with_name(name)
+        .long(long)
+        .help(help)
+        .takes_value(true)
+        .required(required)
+        .validator(|s| {
+            s.parse::<u64>()
+                .map(|_| ())
+                .map_err(|_| String::from("The amount must be an integer"))
+        })
+}
+
+fn index_arg<'a, 'b>(name: &'a str, long: &'a str, help: &'a str) -> Arg<'a, 'b> {
+    Arg::with_name(name)
+        .long(long)
+        .help(help)
+        .takes_value(true)
+        .required(true)
+        .validator(|s| {
+            s.parse::<u16>()
+                .map(|_| ())
+                .map_err(|_| String::from("The index must be an integer"))
+        })
+}
+
+fn leverage_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("leverage")
+        .long("leverage")
+        .help("The leverage of the position, for instance 2.5")
+        .takes_value(true)
+        .required(true)
+        .validator(|s| {
+            s.parse::<f64>()
+                .map(|_| ())
+                .map_err(|_| String::from("The leverage must be a number"))
+        })
+}
+
+fn referrer_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("referrer")
+        .long("referrer")
+        .help("The quote token account of the referrer")
+        .takes_value(true)
+        .validator(is_pubkey)
+}
+
+fn parse_leverage(matches: &ArgMatches) -> u64 {
+    let leverage = value_t_or_exit!(matches.value_of("leverage"), f64);
+    (leverage * ((1u64 << 32) as f64)) as u64
+}
+
+fn parse_side(matches: &ArgMatches) -> PositionType {
+    match matches.value_of("side").unwrap() {
+        "long" => PositionType::Long,
+        _ => PositionType::Short,
+    }
+}
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("perps-cli")
+        .version("0.1")
+        .author("Audaces Protocol")
+        .about("Command line interface for the Audaces Protocol")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("create-market")
+                .about("Create a new market, the fee payer becomes the market admin")
+                .arg(
+                    Arg::with_name("symbol")
+                        .long("symbol")
+                        .help("The market symbol, for instance BTC/USD")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("quote_mint")
+                        .long("quote-mint")
+                        .help("The mint of the quote token")
+                        .takes_value(true)
+                        .validator(is_pubkey)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("oracle")
+                        .long("oracle")
+                        .help("The oracle price account")
+                        .takes_value(true)
+                        .validator(is_pubkey)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("coin_decimals")
+                        .long("coin-decimals")
+                        .help("The number of decimals of the virtual coin")
+                        .takes_value(true)
+                        .default_value("6"),
+                )
+                .arg(amount_arg(
+                    "initial_v_pc_amount",
+                    "initial-v-pc-amount",
+                    "The initial amount of virtual quote in the vAMM",
+                    true,
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("add-instance")
+                .about("Add an instance to a market (market admin only)")
+                .arg(market_arg())
+                .arg(
+                    Arg::with_name("pages")
+                        .long("pages")
+                        .help("The number of memory pages to allocate")
+                        .takes_value(true)
+                        .default_value("1"),
+                )
+                .arg(
+                    Arg::with_name("page_slots")
+                        .long("page-slots")
+                        .help("The number of slots in each memory page")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("add-page")
+                .about("Add a memory page to an instance (market admin only)")
+                .arg(market_arg())
+                .arg(index_arg(
+                    "instance_index",
+                    "instance-index",
+                    "The index of the instance",
+                ))
+                .arg(
+                    Arg::with_name("page_slots")
+                        .long("page-slots")
+                        .help("The number of slots in the memory page")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate-page")
+                .about(
+                    "Copy a memory page of the legacy layout into a new page (market admin only)",
+                )
+                .arg(market_arg())
+                .arg(index_arg(
+                    "instance_index",
+                    "instance-index",
+                    "The index of the instance",
+                ))
+                .arg(index_arg(
+                    "page_index",
+                    "page-index",
+                    "The index of the page in the instance",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate-instance")
+                .about(
+                    "Copy an instance into a new account which can reference more pages (market admin only)",
+                )
+                .arg(market_arg())
+                .arg(index_arg(
+                    "instance_index",
+                    "instance-index",
+                    "The index of the instance",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("create-user-account")
+                .about("Create a user account owned by the fee payer")
+                .arg(market_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("deposit")
+                .about("Deposit quote tokens from the fee payer's associated token account")
+                .arg(market_arg())
+                .arg(user_account_arg())
+                .arg(amount_arg(
+                    "amount",
+                    "amount",
+                    "The amount to deposit, with precision",
+                    true,
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("withdraw")
+                .about("Withdraw quote tokens to the fee payer's associated token account")
+                .arg(market_arg())
+                .arg(user_account_arg())
+                .arg(amount_arg(
+                    "amount",
+                    "amount",
+                    "The amount to withdraw, with precision",
+                    true,
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("open-position")
+                .about("Open a position")
+                .arg(market_arg())
+                .arg(user_account_arg())
+                .arg(index_arg(
+                    "instance_index",
+                    "instance-index",
+                    "The index of the instance",
+                ))
+                .arg(
+                    Arg::with_name("side")
+                        .long("side")
+                        .takes_value(true)
+                        .possible_values(&["long", "short"])
+                        .required(true),
+                )
+                .arg(amount_arg(
+                    "collateral",
+                    "collateral",
+                    "The collateral of the position, with precision",
+                    true,
+                ))
+                .arg(leverage_arg())
+                .arg(referrer_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("increase-position")
+                .about("Increase a position")
+                .arg(market_arg())
+                .arg(user_account_arg())
+                .arg(index_arg(
+                    "position_index",
+                    "position-index",
+                    "The index of the position",
+                ))
+                .arg(amount_arg(
+                    "collateral",
+                    "collateral",
+                    "The collateral to add, with precision",
+                    true,
+                ))
+                .arg(leverage_arg())
+                .arg(referrer_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("close-position")
+                .about("Close a position, entirely unless the closing amounts are given")
+                .arg(market_arg())
+                .arg(user_account_arg())
+                .arg(index_arg(
+                    "position_index",
+                    "position-index",
+                    "The index of the position",
+                ))
+                .arg(amount_arg(
+                    "collateral",
+                    "collateral",
+                    "The collateral to remove, with precision",
+                    false,
+                ))
+                .arg(amount_arg(
+                    "v_coin",
+                    "v-coin",
+                    "The virtual coin amount to close, with precision",
+                    false,
+                ))
+                .arg(referrer_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("transfer-position")
+                .about("Transfer a position to another user account")
+                .arg(market_arg())
+                .arg(index_arg(
+                    "position_index",
+                    "position-index",
+                    "The index of the position",
+                ))
+                .arg(
+                    Arg::with_name("source")
+                        .long("source")
+                        .help("The source user account, owned by the fee payer")
+                        .takes_value(true)
+                        .validator(is_pubkey)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("destination")
+                        .long("destination")
+                        .help("The destination user account")
+                        .takes_value(true)
+                        .validator(is_pubkey)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("destination_owner")
+                        .long("destination-owner")
+                        .help("The keypair owning the destination user account")
+                        .takes_value(true)
+                        .validator(is_keypair)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("transfer-user-account")
+                .about("Transfer the ownership of a user account")
+                .arg(market_arg())
+                .arg(user_account_arg())
+                .arg(
+                    Arg::with_name("new_owner")
+                        .long("new-owner")
+                        .takes_value(true)
+                        .validator(is_pubkey)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("show-market")
+                .about("Print the market state as JSON")
+                .arg(market_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("show-instance")
+                .about("Print an instance as JSON")
+                .arg(market_arg())
+                .arg(index_arg(
+                    "instance_index",
+                    "instance-index",
+                    "The index of the instance",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("show-page")
+                .about("Print the memory usage of a page as JSON")
+                .arg(market_arg())
+                .arg(index_arg(
+                    "instance_index",
+                    "instance-index",
+                    "The index of the instance",
+                ))
+                .arg(index_arg(
+                    "page_index",
+                    "page-index",
+                    "The index of the page in the instance",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("show-user-account")
+                .about("Print a user account and its positions as JSON")
+                .arg(user_account_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("show-user-accounts")
+                .about("Print all the user accounts of an owner on a market as JSON")
+                .arg(market_arg())
+                .arg(
+                    Arg::with_name("owner")
+                        .long("owner")
+                        .takes_value(true)
+                        .validator(is_pubkey)
+                        .required(true),
+                ),
+        )
+        .arg(
+            Arg::with_name("url")
+                .short("u")
+                .long("url")
+                .help("A Solana RPC endpoint url")
+                .takes_value(true),
+        )
+        .arg(fee_payer_arg())
+        .arg(
+            Arg::with_name("program_id")
+                .short("p")
+                .long("program-id")
+                .help("The pubkey of the Audaces Protocol program")
+                .takes_value(true)
+                .validator(is_pubkey)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("slippage")
+                .long("slippage")
+                .help("The slippage tolerance on the quoted fill price, in basis points")
+                .takes_value(true)
+                .default_value("100"),
+        )
+}
+
+fn main() {
+    let matches = app().get_matches();
+    let endpoint = matches
+        .value_of("url")
+        .unwrap_or("https://solana-api.projectserum.com");
+    let context = Context {
+        program_id: pubkey_of(&matches, "program_id").unwrap(),
+        fee_payer: keypair_of(&matches, FEE_PAYER_ARG.name).unwrap(),
+        connection: RpcClient::new(String::from(endpoint)),
+        max_slippage_bps: value_t_or_exit!(matches.value_of("slippage"), u64),
+    };
+
+    let (subcommand, m) = matches.subcommand();
+    let m = m.unwrap();
+    let market = || pubkey_of(m, "market").unwrap();
+    let user_account = || pubkey_of(m, "user_account").unwrap();
+    let result = match subcommand {
+        "create-market" => context.create_market(
+            m.value_of("symbol").unwrap().to_owned(),
+            pubkey_of(m, "quote_mint").unwrap(),
+            pubkey_of(m, "oracle").unwrap(),
+            value_t_or_exit!(m.value_of("coin_decimals"), u8),
+            value_t_or_exit!(m.value_of("initial_v_pc_amount"), u64),
+        ),
+        "add-instance" => context.add_instance(
+            market(),
+            value_t_or_exit!(m.value_of("pages"), usize),
+            value_t_or_exit!(m.value_of("page_slots"), usize),
+        ),
+        "add-page" => context.add_page(
+            market(),
+            value_t_or_exit!(m.value_of("instance_index"), u8),
+            value_t_or_exit!(m.value_of("page_slots"), usize),
+        ),
+        "migrate-page" => context.migrate_page(
+            market(),
+            value_t_or_exit!(m.value_of("instance_index"), u8),
+            value_t_or_exit!(m.value_of("page_index"), usize),
+        ),
+        "migrate-instance" => {
+            context.migrate_instance(market(), value_t_or_exit!(m.value_of("instance_index"), u8))
+        }
+        "create-user-account" => context.create_user_account(market()),
+        "deposit" => context.deposit(
+            market(),
+            user_account(),
+            value_t_or_exit!(m.value_of("amount"), u64),
+        ),
+        "withdraw" => context.withdraw(
+            market(),
+            user_account(),
+            value_t_or_exit!(m.value_of("amount"), u64),
+        ),
+        "open-position" => context.open_position(
+            market(),
+            user_account(),
+            value_t_or_exit!(m.value_of("instance_index"), u8),
+            parse_side(m),
+            value_t_or_exit!(m.value_of("collateral"), u64),
+            parse_leverage(m),
+            pubkey_of(m, "referrer"),
+        ),
+        "increase-position" => context.increase_position(
+            market(),
+            user_account(),
+            value_t_or_exit!(m.value_of("position_index"), u16),
+            value_t_or_exit!(m.value_of("collateral"), u64),
+            parse_leverage(m),
+            pubkey_of(m, "referrer"),
+        ),
+        "close-position" => context.close_position(
+            market(),
+            user_account(),
+            value_t_or_exit!(m.value_of("position_index"), u16),
+            m.value_of("collateral").map(|s| s.parse().unwrap()),
+            m.value_of("v_coin").map(|s| s.parse().unwrap()),
+            pubkey_of(m, "referrer"),
+        ),
+        "transfer-position" => context.transfer_position(
+            market(),
+            value_t_or_exit!(m.value_of("position_index"), u16),
+            pubkey_of(m, "source").unwrap(),
+            pubkey_of(m, "destination").unwrap(),
+            &keypair_of(m, "destination_owner").unwrap(),
+        ),
+        "transfer-user-account" => context.transfer_user_account(
+            market(),
+            user_account(),
+            pubkey_of(m, "new_owner").unwrap(),
+        ),
+        "show-market" => context.show_market(market()),
+        "show-instance" => {
+            context.show_instance(market(), value_t_or_exit!(m.value_of("instance_index"), u8))
+        }
+        "show-page" => context.show_page(
+            market(),
+            value_t_or_exit!(m.value_of("instance_index"), u8),
+            value_t_or_exit!(m.value_of("page_index"), usize),
+        ),
+        "show-user-account" => context.show_user_account(user_account()),
+        "show-user-accounts" => {
+            context.show_user_accounts(market(), pubkey_of(m, "owner").unwrap())
+        }
+        _ => panic!("Invalid subcommand"),
+    };
+
+    match result {
+        Ok(output) => println!("{}", serde_json::to_string_pretty(&output).unwrap()),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_program::pubkey::Pubkey;
+
+    fn get_matches(args: &[&str]) -> clap::Result<ArgMatches<'static>> {
+        let program_id = Pubkey::new_unique().to_string();
+        let mut all_args = vec!["perps-cli", "--program-id", program_id.as_str()];
+        all_args.extend_from_slice(args);
+        app().get_matches_from_safe(all_args)
+    }
+
+    #[test]
+    fn test_open_position_args() {
+        let market = Pubkey::new_unique().to_string();
+        let user_account = Pubkey::new_unique().to_string();
+        let args = [
+            "open-position",
+            "--market",
+            market.as_str(),
+            "--user-account",
+            user_account.as_str(),
+            "--instance-index",
+            "1",
+            "--side",
+            "short",
+            "--collateral",
+            "1000000",
+            "--leverage",
+            "2.5",
+        ];
+        let matches = get_matches(&args).unwrap();
+        assert_eq!(matches.value_of("slippage"), Some("100"));
+        let (subcommand, m) = matches.subcommand();
+        let m = m.unwrap();
+        assert_eq!(subcommand, "open-position");
+        assert_eq!(pubkey_of(m, "market"), Some(market.parse().unwrap()));
+        assert_eq!(parse_side(m), PositionType::Short);
+        assert_eq!(parse_leverage(m), 5 << 31);
+        assert_eq!(pubkey_of(m, "referrer"), None);
+
+        // The side, amounts and leverage are validated
+        let mut invalid_args = args;
+        invalid_args[8] = "both";
+        assert!(get_matches(&invalid_args).is_err());
+        let mut invalid_args = args;
+        invalid_args[10] = "1.5";
+        assert!(get_matches(&invalid_args).is_err());
+        let mut invalid_args = args;
+        invalid_args[12] = "high";
+        assert!(get_matches(&invalid_args).is_err());
+    }
+
+    #[test]
+    fn test_close_position_args() {
+        let market = Pubkey::new_unique().to_string();
+        let user_account = Pubkey::new_unique().to_string();
+        let args = [
+            "close-position",
+            "--market",
+            market.as_str(),
+            "--user-account",
+            user_account.as_str(),
+            "--position-index",
+            "3",
+        ];
+        // The closing amounts are optional and default to the whole position
+        let matches = get_matches(&args).unwrap();
+        let m = matches.subcommand_matches("close-position").unwrap();
+        assert_eq!(m.value_of("position_index"), Some("3"));
+        assert_eq!(m.value_of("collateral"), None);
+        assert_eq!(m.value_of("v_coin"), None);
+
+        let mut partial_args = args.to_vec();
+        partial_args.extend_from_slice(&["--collateral", "500", "--v-coin", "42"]);
+        let matches = get_matches(&partial_args).unwrap();
+        let m = matches.subcommand_matches("close-position").unwrap();
+        assert_eq!(m.value_of("collateral"), Some("500"));
+        assert_eq!(m.value_of("v_coin"), Some("42"));
+
+        // The market and the position index are required, and must be valid
+        assert!(get_matches(&args[..5]).is_err());
+        let mut invalid_args = args;
+        invalid_args[2] = "market";
+        assert!(get_matches(&invalid_args).is_err());
+        let mut invalid_args = args;
+        invalid_args[6] = "-1";
+        assert!(get_matches(&invalid_args).is_err());
+    }
+
+    #[test]
+    fn test_show_user_accounts_args() {
+        let market = Pubkey::new_unique().to_string();
+        let owner = Pubkey::new_unique().to_string();
+        let matches = get_matches(&[
+            "show-user-accounts",
+            "--market",
+            market.as_str(),
+            "--owner",
+            owner.as_str(),
+        ])
+        .unwrap();
+        let m = matches.subcommand_matches("show-user-accounts").unwrap();
+        assert_eq!(pubkey_of(m, "owner"), Some(owner.parse().unwrap()));
+        assert!(get_matches(&["show-user-accounts", "--market", market.as_str()]).is_err());
+
+        // The program id is required
+        assert!(app()
+            .get_matches_from_safe(vec![
+                "perps-cli",
+                "show-market",
+                "--market",
+                market.as_str()
+            ])
+            .is_err());
+    }
+}
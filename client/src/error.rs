@@ -0,0 +1,28 @@
+use audaces_protocol::error::PerpError;
+use solana_program::pubkey::Pubkey;
+use solana_sdk::signer::SignerError;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("Encountered a connection error")]
+    ConnectionError(#[from] solana_client::client_error::ClientError),
+    #[error("The parsed market state is invalid")]
+    InvalidMarketState,
+    #[error("The parsed user account is invalid")]
+    InvalidUserAccount,
+    #[error("The account {0} was not found")]
+    AccountNotFound(Pubkey),
+    #[error("The position index is invalid")]
+    InvalidPositionIndex,
+    #[error("The trade is rejected: {0}")]
+    TradeRejected(#[from] PerpError),
+    #[error("The price impact of the trade exceeds the maximum slippage")]
+    PriceImpactTooLarge,
+    #[error("A transaction needs at least one signer to pay for the fees")]
+    MissingSigner,
+    #[error("Failed to sign the transaction")]
+    SigningError(#[from] SignerError),
+    #[error("The blocking RPC task failed")]
+    TaskError(#[from] tokio::task::JoinError),
+}
@@ -0,0 +1,897 @@
+//! Async client for the Audaces perpetuals protocol.
+//!
+//! This is the Rust counterpart of `js/src/high_level.ts`: a `PerpsClient` is bound to a single
+//! market and resolves every account required by an instruction (instance pages, label accounts,
+//! oracle, discount and referrer accounts) on its own.
+use audaces_protocol::{
+    instruction::{
+        add_budget, close_position, extract_funding, increase_position, open_position,
+        select_pages, withdraw_budget, DiscountAccount, InstanceContext, MarketContext,
+        PositionInfo,
+    },
+    positions_book::{memory::Memory, page::Page, positions_book_tree::PositionsBook},
+    processor::{FIDA_BNB, FIDA_MINT},
+    quote::{get_mark_price, quote_close_position, quote_open_position, OrderSize},
+    state::{
+        instance::parse_instance,
+        market::MarketState,
+        user_account::{OpenPosition, UserAccountState},
+        PositionType, StateObject,
+    },
+    utils::get_oracle_price,
+};
+use error::ClientError;
+use solana_client::{
+    rpc_client::RpcClient,
+    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+    rpc_filter::{Memcmp, MemcmpEncodedBytes, RpcFilterType},
+};
+use solana_program::{
+    instruction::Instruction, program_pack::Pack, pubkey::Pubkey, system_instruction,
+};
+use solana_sdk::{
+    signature::{Keypair, Signature, Signer},
+    transaction::Transaction,
+};
+use spl_associated_token_account::get_associated_token_address;
+use std::{
+    str::FromStr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::task;
+
+pub mod error;
+
+/// Number of positions a user account created by the client can hold.
+pub const DEFAULT_OPEN_POSITIONS_CAPACITY: usize = 128;
+/// Default slippage tolerance on the fill price, including the price impact, in basis points.
+pub const DEFAULT_MAX_SLIPPAGE_BPS: u64 = 100;
+
+/// Offset of the owner in the user account data, after the state tag and the version.
+pub const USER_ACCOUNT_OWNER_OFFSET: usize = 2;
+/// Offset of the market in the user account data, after the owner and the active flag.
+pub const USER_ACCOUNT_MARKET_OFFSET: usize = 35;
+
+pub struct UserAccount {
+    pub address: Pubkey,
+    pub header: UserAccountState,
+    pub positions: Vec<OpenPosition>,
+}
+
+impl UserAccount {
+    pub fn parse(address: Pubkey, data: &[u8]) -> Result<Self, ClientError> {
+        if data.len() < UserAccountState::LEN {
+            return Err(ClientError::InvalidUserAccount);
+        }
+        let header = UserAccountState::unpack_from_slice(&data[..UserAccountState::LEN])
+            .map_err(|_| ClientError::InvalidUserAccount)?;
+        let positions = data[UserAccountState::LEN..]
+            .chunks_exact(OpenPosition::LEN)
+            .take(header.number_of_open_positions as usize)
+            .map(|s| {
+                OpenPosition::unpack_from_slice(s).map_err(|_| ClientError::InvalidUserAccount)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if positions.len() != header.number_of_open_positions as usize {
+            return Err(ClientError::InvalidUserAccount);
+        }
+        Ok(Self {
+            address,
+            header,
+            positions,
+        })
+    }
+
+    /// Returns the indices of the instances on which funding has to be extracted before the account can trade.
+    pub fn pending_funding_instances(&self, market_state: &MarketState) -> Vec<u8> {
+        let mut instances = self
+            .positions
+            .iter()
+            .filter(|p| p.last_funding_offset != market_state.funding_history_offset)
+            .map(|p| p.instance_index)
+            .collect::<Vec<_>>();
+        instances.sort_unstable();
+        instances.dedup();
+        instances
+    }
+
+    fn get_position(&self, position_index: u16) -> Result<&OpenPosition, ClientError> {
+        self.positions
+            .get(position_index as usize)
+            .ok_or(ClientError::InvalidPositionIndex)
+    }
+}
+
+pub struct PerpsClient {
+    connection: Arc<RpcClient>,
+    market: MarketContext,
+    quote_mint: Pubkey,
+    max_slippage_bps: u64,
+}
+
+impl PerpsClient {
+    pub async fn load(
+        endpoint: String,
+        program_id: Pubkey,
+        market: Pubkey,
+    ) -> Result<Self, ClientError> {
+        let connection = Arc::new(RpcClient::new(endpoint));
+        let c = Arc::clone(&connection);
+        let (market, quote_mint) =
+            task::spawn_blocking(move || get_market(program_id, market, &c)).await??;
+        Ok(Self {
+            connection,
+            market,
+            quote_mint,
+            max_slippage_bps: DEFAULT_MAX_SLIPPAGE_BPS,
+        })
+    }
+
+    pub fn with_max_slippage(mut self, max_slippage_bps: u64) -> Self {
+        self.max_slippage_bps = max_slippage_bps;
+        self
+    }
+
+    pub fn market(&self) -> &MarketContext {
+        &self.market
+    }
+
+    pub fn quote_mint(&self) -> Pubkey {
+        self.quote_mint
+    }
+
+    pub fn connection(&self) -> &RpcClient {
+        &self.connection
+    }
+
+    /// Reloads the market context, to pick up newly added instances and memory pages.
+    pub async fn refresh_market(&mut self) -> Result<(), ClientError> {
+        let program_id = self.market.audaces_protocol_program_id;
+        let market = self.market.market_account;
+        let (market, quote_mint) = self.run(move |c| get_market(program_id, market, c)).await?;
+        self.market = market;
+        self.quote_mint = quote_mint;
+        Ok(())
+    }
+
+    pub async fn get_market_state(&self) -> Result<MarketState, ClientError> {
+        let market = self.market.market_account;
+        let data = self.run(move |c| Ok(c.get_account_data(&market)?)).await?;
+        MarketState::unpack_from_slice(&data).map_err(|_| ClientError::InvalidMarketState)
+    }
+
+    /// Returns the current oracle price as fixed point 32.
+    pub async fn get_oracle_price(&self) -> Result<u64, ClientError> {
+        let market_state = self.get_market_state().await?;
+        let oracle = self.market.oracle_account;
+        let data = self.run(move |c| Ok(c.get_account_data(&oracle)?)).await?;
+        get_oracle_price(
+            &data,
+            market_state.coin_decimals,
+            market_state.quote_decimals,
+        )
+        .map_err(|_| ClientError::InvalidMarketState)
+    }
+
+    pub async fn get_user_account(&self, address: Pubkey) -> Result<UserAccount, ClientError> {
+        let account = self
+            .run(move |c| {
+                c.get_account_with_commitment(&address, c.commitment())?
+                    .value
+                    .ok_or(ClientError::AccountNotFound(address))
+            })
+            .await?;
+        let user_account = UserAccount::parse(address, &account.data)?;
+        if Pubkey::new(&user_account.header.market) != self.market.market_account {
+            return Err(ClientError::InvalidUserAccount);
+        }
+        Ok(user_account)
+    }
+
+    /// Returns all the user accounts of an owner on the client's market.
+    pub async fn get_user_accounts(&self, owner: Pubkey) -> Result<Vec<UserAccount>, ClientError> {
+        let program_id = self.market.audaces_protocol_program_id;
+        let config = RpcProgramAccountsConfig {
+            filters: Some(get_user_account_filters(
+                &owner,
+                &self.market.market_account,
+            )),
+            account_config: RpcAccountInfoConfig::default(),
+            with_context: None,
+        };
+        let accounts = self
+            .run(move |c| Ok(c.get_program_accounts_with_config(&program_id, config)?))
+            .await?;
+        accounts
+            .into_iter()
+            .map(|(k, a)| UserAccount::parse(k, &a.data))
+            .collect()
+    }
+
+    /// Creates a new user account for the owner on the client's market, and returns its address.
+    pub async fn create_user_account(
+        &self,
+        owner: &Keypair,
+    ) -> Result<(Pubkey, Signature), ClientError> {
+        let user_account = Keypair::new();
+        let space = UserAccountState::LEN + OpenPosition::LEN * DEFAULT_OPEN_POSITIONS_CAPACITY;
+        let lamports = self
+            .run(move |c| Ok(c.get_minimum_balance_for_rent_exemption(space)?))
+            .await?;
+        let instructions = vec![
+            system_instruction::create_account(
+                &owner.pubkey(),
+                &user_account.pubkey(),
+                lamports,
+                space as u64,
+                &self.market.audaces_protocol_program_id,
+            ),
+            add_budget(
+                &self.market,
+                0,
+                owner.pubkey(),
+                self.get_quote_account(&owner.pubkey()),
+                user_account.pubkey(),
+            ),
+        ];
+        let signature = self
+            .send_transaction(instructions, &[owner, &user_account])
+            .await?;
+        Ok((user_account.pubkey(), signature))
+    }
+
+    /// Transfers quote tokens from the owner's associated token account to the user account.
+    pub async fn add_budget(
+        &self,
+        owner: &Keypair,
+        user_account: Pubkey,
+        amount: u64,
+    ) -> Result<Signature, ClientError> {
+        let instruction = add_budget(
+            &self.market,
+            amount,
+            owner.pubkey(),
+            self.get_quote_account(&owner.pubkey()),
+            user_account,
+        );
+        self.send_transaction(vec![instruction], &[owner]).await
+    }
+
+    /// Transfers quote tokens from the user account to the owner's associated token account.
+    pub async fn withdraw_budget(
+        &self,
+        owner: &Keypair,
+        user_account: Pubkey,
+        amount: u64,
+    ) -> Result<Signature, ClientError> {
+        let user_account = self.get_user_account(user_account).await?;
+        let mut instructions = self.get_funding_instructions(&user_account).await?;
+        instructions.push(withdraw_budget(
+            &self.market,
+            amount,
+            self.get_quote_account(&owner.pubkey()),
+            owner.pubkey(),
+            user_account.address,
+        ));
+        self.send_transaction(instructions, &[owner]).await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn open_position(
+        &self,
+        owner: &Keypair,
+        user_account: Pubkey,
+        instance_index: u8,
+        side: PositionType,
+        collateral: u64,
+        leverage: u64, // FP32
+        referrer_account: Option<Pubkey>,
+    ) -> Result<Signature, ClientError> {
+        let user_account = self.get_user_account(user_account).await?;
+        let (market_state, market_vault_balance, oracle_price) = self.get_quote_context().await?;
+        let (predicted_entry_price, maximum_slippage_margin) = get_open_slippage(
+            &market_state,
+            market_vault_balance,
+            oracle_price,
+            side,
+            collateral,
+            leverage,
+            self.max_slippage_bps,
+        )?;
+        let mut instructions = get_funding_instructions(&self.market, &market_state, &user_account);
+        let discount_account = self.get_discount_account(&owner.pubkey()).await?;
+        instructions.push(open_position(
+            &self.market,
+            &PositionInfo {
+                user_account: user_account.address,
+                user_account_owner: owner.pubkey(),
+                instance_index,
+                side,
+            },
+            collateral,
+            leverage,
+            predicted_entry_price,
+            maximum_slippage_margin,
+            discount_account.as_ref(),
+            referrer_account,
+        ));
+        self.send_transaction(instructions, &[owner]).await
+    }
+
+    pub async fn increase_position(
+        &self,
+        owner: &Keypair,
+        user_account: Pubkey,
+        position_index: u16,
+        add_collateral: u64,
+        leverage: u64, // FP32
+        referrer_account: Option<Pubkey>,
+    ) -> Result<Signature, ClientError> {
+        let user_account = self.get_user_account(user_account).await?;
+        let position = user_account.get_position(position_index)?;
+        let (market_state, market_vault_balance, oracle_price) = self.get_quote_context().await?;
+        // The increase trades like a new position of the added size
+        let (predicted_entry_price, maximum_slippage_margin) = get_open_slippage(
+            &market_state,
+            market_vault_balance,
+            oracle_price,
+            position.side,
+            add_collateral,
+            leverage,
+            self.max_slippage_bps,
+        )?;
+        let mut instructions = get_funding_instructions(&self.market, &market_state, &user_account);
+        let discount_account = self.get_discount_account(&owner.pubkey()).await?;
+        instructions.push(increase_position(
+            &self.market,
+            add_collateral,
+            leverage,
+            position.instance_index,
+            position_index,
+            owner.pubkey(),
+            user_account.address,
+            predicted_entry_price,
+            maximum_slippage_margin,
+            discount_account.as_ref(),
+            referrer_account,
+        ));
+        self.send_transaction(instructions, &[owner]).await
+    }
+
+    /// Closes part of a position. Passing the position's full collateral and v_coin amount closes it entirely.
+    pub async fn close_position(
+        &self,
+        owner: &Keypair,
+        user_account: Pubkey,
+        position_index: u16,
+        closing_collateral: u64,
+        closing_v_coin: u64,
+        referrer_account: Option<Pubkey>,
+    ) -> Result<Signature, ClientError> {
+        let user_account = self.get_user_account(user_account).await?;
+        let position = user_account.get_position(position_index)?;
+        let (market_state, _, oracle_price) = self.get_quote_context().await?;
+        let (predicted_entry_price, maximum_slippage_margin) = get_close_slippage(
+            &market_state,
+            oracle_price,
+            position,
+            closing_collateral,
+            closing_v_coin,
+            self.max_slippage_bps,
+        )?;
+        let mut instructions = get_funding_instructions(&self.market, &market_state, &user_account);
+        let discount_account = self.get_discount_account(&owner.pubkey()).await?;
+        let mut instruction = close_position(
+            &self.market,
+            &PositionInfo {
+                user_account: user_account.address,
+                user_account_owner: owner.pubkey(),
+                instance_index: position.instance_index,
+                side: position.side,
+            },
+            closing_collateral,
+            closing_v_coin,
+            position_index,
+            predicted_entry_price,
+            maximum_slippage_margin,
+            discount_account.as_ref(),
+            referrer_account,
+        );
+        // A partial close reinserts the position elsewhere in the tree and needs every page
+        if closing_collateral == position.collateral && closing_v_coin == position.v_coin_amount {
+            let page_path = self.get_page_path(position).await?;
+            instruction = select_pages(
+                instruction,
+                &self.market.instances[position.instance_index as usize],
+                &page_path,
+            );
+        }
+        instructions.push(instruction);
+        self.send_transaction(instructions, &[owner]).await
+    }
+
+    /// Extracts pending funding for all the instances on which the user account has positions.
+    /// Returns `None` when there is no funding to extract.
+    pub async fn extract_funding(
+        &self,
+        fee_payer: &Keypair,
+        user_account: Pubkey,
+    ) -> Result<Option<Signature>, ClientError> {
+        let user_account = self.get_user_account(user_account).await?;
+        let instructions = self.get_funding_instructions(&user_account).await?;
+        if instructions.is_empty() {
+            return Ok(None);
+        }
+        self.send_transaction(instructions, &[fee_payer])
+            .await
+            .map(Some)
+    }
+
+    /// Signs and sends a transaction, the first signer pays for the fees. At least one signer is required.
+    pub async fn send_transaction(
+        &self,
+        instructions: Vec<Instruction>,
+        signers: &[&Keypair],
+    ) -> Result<Signature, ClientError> {
+        let fee_payer = signers.first().ok_or(ClientError::MissingSigner)?.pubkey();
+        let (recent_blockhash, _) = self.run(|c| Ok(c.get_recent_blockhash()?)).await?;
+        let mut transaction = Transaction::new_with_payer(&instructions, Some(&fee_payer));
+        transaction.try_sign(&signers.to_vec(), recent_blockhash)?;
+        self.run(move |c| Ok(c.send_and_confirm_transaction(&transaction)?))
+            .await
+    }
+
+    async fn get_funding_instructions(
+        &self,
+        user_account: &UserAccount,
+    ) -> Result<Vec<Instruction>, ClientError> {
+        let market_state = self.get_market_state().await?;
+        Ok(get_funding_instructions(
+            &self.market,
+            &market_state,
+            user_account,
+        ))
+    }
+
+    // Memory pages holding the path to the leaf of a position, computed from a copy of its instance
+    async fn get_page_path(&self, position: &OpenPosition) -> Result<Vec<u32>, ClientError> {
+        let instance_account =
+            self.market.instances[position.instance_index as usize].instance_account;
+        let (liquidation_index, side) = (position.liquidation_index, position.side);
+        self.run(move |c| {
+            let instance_data = c.get_account_data(&instance_account)?;
+            let (instance, page_infos) =
+                parse_instance(&instance_data).map_err(|_| ClientError::InvalidMarketState)?;
+            let mut page_datas = page_infos
+                .iter()
+                .map(|p| Ok(c.get_account_data(&Pubkey::new(&p.address))?))
+                .collect::<Result<Vec<_>, ClientError>>()?;
+            let pages = page_datas
+                .iter_mut()
+                .zip(page_infos.iter())
+                .map(|(d, p)| Page::new_from_slice_unchecked(d, p))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| ClientError::InvalidMarketState)?;
+            let book = PositionsBook::new(
+                instance.shorts_pointer,
+                instance.longs_pointer,
+                Memory::new(pages, instance.garbage_pointer),
+            );
+            book.get_page_path(liquidation_index, side)
+                .map_err(|_| ClientError::InvalidMarketState)
+        })
+        .await
+    }
+
+    async fn get_discount_account(
+        &self,
+        owner: &Pubkey,
+    ) -> Result<Option<DiscountAccount>, ClientError> {
+        let owner = *owner;
+        self.run(move |c| get_discount_account(c, &owner)).await
+    }
+
+    fn get_quote_account(&self, owner: &Pubkey) -> Pubkey {
+        get_associated_token_address(owner, &self.quote_mint)
+    }
+
+    async fn get_quote_context(&self) -> Result<(MarketState, u64, u64), ClientError> {
+        let market = self.market.clone();
+        self.run(move |c| get_quote_context(c, &market)).await
+    }
+
+    async fn run<F, T>(&self, f: F) -> Result<T, ClientError>
+    where
+        F: FnOnce(&RpcClient) -> Result<T, ClientError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let connection = Arc::clone(&self.connection);
+        task::spawn_blocking(move || f(&connection)).await?
+    }
+}
+
+/// Filters the program accounts down to the user accounts of an owner on a market.
+pub fn get_user_account_filters(owner: &Pubkey, market: &Pubkey) -> Vec<RpcFilterType> {
+    vec![
+        RpcFilterType::Memcmp(Memcmp {
+            offset: 0,
+            bytes: MemcmpEncodedBytes::Binary(
+                bs58::encode(vec![StateObject::UserAccount as u8]).into_string(),
+            ),
+            encoding: None,
+        }),
+        RpcFilterType::Memcmp(Memcmp {
+            offset: USER_ACCOUNT_OWNER_OFFSET,
+            bytes: MemcmpEncodedBytes::Binary(owner.to_string()),
+            encoding: None,
+        }),
+        RpcFilterType::Memcmp(Memcmp {
+            offset: USER_ACCOUNT_MARKET_OFFSET,
+            bytes: MemcmpEncodedBytes::Binary(market.to_string()),
+            encoding: None,
+        }),
+    ]
+}
+
+/// Instructions extracting the pending funding of a user account, which have to run before it can trade.
+pub fn get_funding_instructions(
+    market: &MarketContext,
+    market_state: &MarketState,
+    user_account: &UserAccount,
+) -> Vec<Instruction> {
+    user_account
+        .pending_funding_instances(market_state)
+        .into_iter()
+        .map(|i| extract_funding(market, i, user_account.address, None))
+        .collect()
+}
+
+/// The discount account is the owner's FIDA associated token account, if it exists.
+pub fn get_discount_account(
+    connection: &RpcClient,
+    owner: &Pubkey,
+) -> Result<Option<DiscountAccount>, ClientError> {
+    let address = get_associated_token_address(owner, &Pubkey::from_str(FIDA_MINT).unwrap());
+    let account = connection
+        .get_account_with_commitment(&address, connection.commitment())?
+        .value;
+    Ok(account.map(|_| DiscountAccount {
+        owner: *owner,
+        address,
+    }))
+}
+
+/// Returns the market state, market vault balance and oracle price which trades are quoted against.
+pub fn get_quote_context(
+    connection: &RpcClient,
+    market: &MarketContext,
+) -> Result<(MarketState, u64, u64), ClientError> {
+    let market_state =
+        MarketState::unpack_from_slice(&connection.get_account_data(&market.market_account)?)
+            .map_err(|_| ClientError::InvalidMarketState)?;
+    let market_vault_balance =
+        spl_token::state::Account::unpack(&connection.get_account_data(&market.market_vault)?)
+            .map_err(|_| ClientError::InvalidMarketState)?
+            .amount;
+    let oracle_price = get_oracle_price(
+        &connection.get_account_data(&market.oracle_account)?,
+        market_state.coin_decimals,
+        market_state.quote_decimals,
+    )
+    .map_err(|_| ClientError::InvalidMarketState)?;
+    Ok((market_state, market_vault_balance, oracle_price))
+}
+
+/// Quotes the opening of a position, or the increase of one by the added collateral, and returns
+/// the predicted entry price and the slippage margin to pass to the instruction.
+pub fn get_open_slippage(
+    market_state: &MarketState,
+    market_vault_balance: u64,
+    oracle_price: u64,
+    side: PositionType,
+    collateral: u64,
+    leverage: u64, // FP32
+    max_slippage_bps: u64,
+) -> Result<(u64, u64), ClientError> {
+    let quote = quote_open_position(
+        market_state,
+        market_vault_balance,
+        oracle_price,
+        side,
+        collateral,
+        OrderSize::Leverage(leverage),
+        0,
+    )?;
+    get_slippage(market_state, quote.fill_price, max_slippage_bps)
+}
+
+/// Quotes the closing of a position and returns the predicted entry price and the slippage margin
+/// to pass to the instruction.
+pub fn get_close_slippage(
+    market_state: &MarketState,
+    oracle_price: u64,
+    position: &OpenPosition,
+    closing_collateral: u64,
+    closing_v_coin: u64,
+    max_slippage_bps: u64,
+) -> Result<(u64, u64), ClientError> {
+    let current_timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let quote = quote_close_position(
+        market_state,
+        oracle_price,
+        current_timestamp,
+        position,
+        closing_collateral,
+        closing_v_coin,
+        0,
+    )?;
+    get_slippage(market_state, quote.fill_price, max_slippage_bps)
+}
+
+// The slippage protection checks the mark price before the trade against the predicted one. The
+// tolerance applies to the quoted fill price, so the price impact of the trade is deducted from
+// the margin left to the mark price.
+fn get_slippage(
+    market_state: &MarketState,
+    fill_price: u64,
+    max_slippage_bps: u64,
+) -> Result<(u64, u64), ClientError> {
+    let mark_price = get_mark_price(market_state);
+    let tolerance = ((fill_price as u128 * max_slippage_bps as u128) / 10_000) as u64;
+    let price_impact = (fill_price as i64 - mark_price as i64).abs() as u64;
+    let margin = tolerance
+        .checked_sub(price_impact)
+        .ok_or(ClientError::PriceImpactTooLarge)?;
+    Ok((mark_price, margin))
+}
+
+pub fn get_market(
+    program_id: Pubkey,
+    market_key: Pubkey,
+    connection: &RpcClient,
+) -> Result<(MarketContext, Pubkey), ClientError> {
+    load_market(program_id, market_key, |k| connection.get_account_data(k))
+}
+
+/// Builds the market context and returns it along with the quote mint, reading the accounts through
+/// `get_account_data`. This lets other RPC layers, like the cranker's, share the same parsing.
+pub fn load_market<F>(
+    program_id: Pubkey,
+    market_key: Pubkey,
+    get_account_data: F,
+) -> Result<(MarketContext, Pubkey), ClientError>
+where
+    F: Fn(&Pubkey) -> Result<Vec<u8>, solana_client::client_error::ClientError>,
+{
+    let market_data = get_account_data(&market_key)?;
+    let market_state = MarketState::unpack_from_slice(&market_data)
+        .map_err(|_| ClientError::InvalidMarketState)?;
+    let instance_addresses = market_data
+        .get(MarketState::LEN..MarketState::LEN + (market_state.number_of_instances as usize) * 32)
+        .ok_or(ClientError::InvalidMarketState)?
+        .chunks(32)
+        .map(Pubkey::new)
+        .collect::<Vec<_>>();
+
+    let instances = instance_addresses
+        .into_iter()
+        .map(|a| {
+            let instance_data = get_account_data(&a)?;
+            let (_, page_infos) =
+                parse_instance(&instance_data).map_err(|_| ClientError::InvalidMarketState)?;
+            let memory_pages = page_infos.iter().map(|p| Pubkey::new(&p.address)).collect();
+
+            Ok(InstanceContext {
+                instance_account: a,
+                memory_pages,
+            })
+        })
+        .collect::<Result<Vec<_>, ClientError>>()?;
+
+    let market_signer_account = Pubkey::create_program_address(
+        &[&market_key.to_bytes(), &[market_state.signer_nonce]],
+        &program_id,
+    )
+    .map_err(|_| ClientError::InvalidMarketState)?;
+
+    let vault = Pubkey::new(&market_state.vault_address);
+    let vault_data = get_account_data(&vault)?;
+    let quote_mint = spl_token::state::Account::unpack(&vault_data)
+        .map_err(|_| ClientError::InvalidMarketState)?
+        .mint;
+
+    let ctx = MarketContext {
+        audaces_protocol_program_id: program_id,
+        signer_nonce: market_state.signer_nonce,
+        market_signer_account,
+        oracle_account: Pubkey::new(&market_state.oracle_address),
+        market_account: market_key,
+        admin_account: Pubkey::new(&market_state.admin_address),
+        market_vault: vault,
+        bonfida_bnb: Pubkey::from_str(FIDA_BNB).unwrap(),
+        instances,
+    };
+
+    Ok((ctx, quote_mint))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_position(instance_index: u8, last_funding_offset: u8) -> OpenPosition {
+        OpenPosition {
+            last_funding_offset,
+            instance_index,
+            side: PositionType::Long,
+            liquidation_index: 0,
+            collateral: 1_000_000,
+            slot_number: 0,
+            v_coin_amount: 1_000_000,
+            v_pc_amount: 5_000_000,
+        }
+    }
+
+    fn get_market_state() -> MarketState {
+        MarketState {
+            version: 0,
+            signer_nonce: 0,
+            market_symbol: [0; 32],
+            oracle_address: [0; 32],
+            admin_address: [0; 32],
+            vault_address: [0; 32],
+            quote_decimals: 6,
+            coin_decimals: 6,
+            total_collateral: 0,
+            total_user_balances: 0,
+            total_fee_balance: 0,
+            rebalancing_funds: 0,
+            rebalanced_v_coin: 0,
+            v_coin_amount: 1_000_000,
+            v_pc_amount: 1_000_000,
+            open_shorts_v_coin: 0,
+            open_longs_v_coin: 0,
+            open_shorts_v_pc: 0,
+            open_longs_v_pc: 0,
+            last_funding_timestamp: 0,
+            last_recording_timestamp: 0,
+            funding_samples_count: 0,
+            funding_samples_sum: 0,
+            funding_history_offset: 0,
+            funding_history: [0; 16],
+            funding_balancing_factors: [0; 16],
+            number_of_instances: 0,
+        }
+    }
+
+    #[test]
+    fn test_parse_user_account() {
+        let positions = vec![get_position(0, 2), get_position(1, 3), get_position(0, 3)];
+        let header = UserAccountState {
+            version: 0,
+            owner: Pubkey::new_unique().to_bytes(),
+            active: true,
+            market: Pubkey::new_unique().to_bytes(),
+            balance: 42,
+            last_funding_offset: 2,
+            number_of_open_positions: positions.len() as u32,
+        };
+        let mut data =
+            vec![0; UserAccountState::LEN + OpenPosition::LEN * DEFAULT_OPEN_POSITIONS_CAPACITY];
+        header.pack_into_slice(&mut data);
+        for (i, p) in positions.iter().enumerate() {
+            let offset = UserAccountState::LEN + i * OpenPosition::LEN;
+            p.pack_into_slice(&mut data[offset..offset + OpenPosition::LEN]);
+        }
+
+        let user_account = UserAccount::parse(Pubkey::new_unique(), &data).unwrap();
+        assert_eq!(user_account.header.balance, 42);
+        assert_eq!(user_account.positions.len(), 3);
+        assert_eq!(user_account.positions[1].instance_index, 1);
+
+        let mut market_state = get_market_state();
+        market_state.funding_history_offset = 3;
+        market_state.number_of_instances = 2;
+        assert_eq!(
+            user_account.pending_funding_instances(&market_state),
+            vec![0]
+        );
+
+        assert!(
+            UserAccount::parse(Pubkey::new_unique(), &data[..UserAccountState::LEN + 1]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_get_user_account_filters() {
+        let owner = Pubkey::new_unique();
+        let market = Pubkey::new_unique();
+        let header = UserAccountState {
+            version: 0,
+            owner: owner.to_bytes(),
+            active: true,
+            market: market.to_bytes(),
+            balance: 0,
+            last_funding_offset: 0,
+            number_of_open_positions: 0,
+        };
+        let mut data = vec![0; UserAccountState::LEN];
+        header.pack_into_slice(&mut data);
+
+        let filters = get_user_account_filters(&owner, &market);
+        assert_eq!(filters.len(), 3);
+        let matches = |filter: &RpcFilterType, data: &[u8]| match filter {
+            RpcFilterType::Memcmp(Memcmp {
+                offset,
+                bytes: MemcmpEncodedBytes::Binary(bytes),
+                ..
+            }) => {
+                let bytes = bs58::decode(bytes).into_vec().unwrap();
+                data.get(*offset..*offset + bytes.len()) == Some(&bytes[..])
+            }
+            _ => false,
+        };
+        // The tag, owner and market filters all match the packed header
+        assert!(filters.iter().all(|f| matches(f, &data)));
+
+        // Another owner, market or state object is filtered out
+        for (offset, filter_index) in &[
+            (0, 0),
+            (USER_ACCOUNT_OWNER_OFFSET, 1),
+            (USER_ACCOUNT_MARKET_OFFSET, 2),
+        ] {
+            let mut other_data = data.clone();
+            other_data[*offset] ^= 1;
+            assert!(!matches(&filters[*filter_index], &other_data));
+        }
+    }
+
+    #[test]
+    fn test_get_slippage() {
+        // Mark price of 1
+        let market_state = get_market_state();
+        let mark_price = 1 << 32;
+
+        // The price impact is taken out of the 1% tolerance on the fill price
+        let fill_price = mark_price + mark_price / 200;
+        let (predicted_price, margin) = get_slippage(&market_state, fill_price, 100).unwrap();
+        assert_eq!(predicted_price, mark_price);
+        assert_eq!(margin, fill_price / 100 - mark_price / 200);
+
+        let fill_price = mark_price - mark_price / 50;
+        assert!(matches!(
+            get_slippage(&market_state, fill_price, 100),
+            Err(ClientError::PriceImpactTooLarge)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_send_transaction_without_signer() {
+        let market = MarketContext {
+            audaces_protocol_program_id: Pubkey::new_unique(),
+            signer_nonce: 0,
+            market_signer_account: Pubkey::new_unique(),
+            oracle_account: Pubkey::new_unique(),
+            market_account: Pubkey::new_unique(),
+            admin_account: Pubkey::new_unique(),
+            market_vault: Pubkey::new_unique(),
+            bonfida_bnb: Pubkey::new_unique(),
+            instances: vec![],
+        };
+        // The error is returned before reaching the endpoint
+        let client = PerpsClient {
+            connection: Arc::new(RpcClient::new("http://127.0.0.1:1".to_string())),
+            market,
+            quote_mint: Pubkey::new_unique(),
+            max_slippage_bps: DEFAULT_MAX_SLIPPAGE_BPS,
+        };
+        assert!(matches!(
+            client.send_transaction(vec![], &[]).await,
+            Err(ClientError::MissingSigner)
+        ));
+    }
+}
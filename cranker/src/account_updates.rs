@@ -0,0 +1,63 @@
+use std::{thread, time::Duration};
+
+use solana_account_decoder::UiAccountEncoding;
+use solana_client::{pubsub_client::PubsubClient, rpc_config::RpcAccountInfoConfig};
+use solana_program::pubkey::Pubkey;
+use solana_sdk::account::Account;
+use tokio::sync::mpsc::UnboundedSender;
+
+const RECONNECT_DELAY: u64 = 1_000;
+
+pub type AccountUpdate = (Pubkey, Vec<u8>);
+
+/// A feed of account updates. The default implementation relies on websocket subscriptions,
+/// but any other feed (geyser plugin, test harness, ...) can be plugged into the cranker.
+pub trait AccountUpdateSource: Send + Sync {
+    /// Forwards every new state of the account to the sender, until the receiving end is dropped.
+    fn subscribe(&self, account: Pubkey, sender: UnboundedSender<AccountUpdate>);
+}
+
+pub struct WebsocketSource {
+    url: String,
+}
+
+impl WebsocketSource {
+    pub fn new(url: String) -> Self {
+        Self { url }
+    }
+}
+
+impl AccountUpdateSource for WebsocketSource {
+    fn subscribe(&self, account: Pubkey, sender: UnboundedSender<AccountUpdate>) {
+        let url = self.url.clone();
+        thread::spawn(move || loop {
+            let config = RpcAccountInfoConfig {
+                encoding: Some(UiAccountEncoding::Base64),
+                data_slice: None,
+                commitment: None,
+            };
+            let (mut subscription, receiver) =
+                match PubsubClient::account_subscribe(&url, &account, Some(config)) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        println!("Failed to subscribe to {:?} with {:?}", account, e);
+                        thread::sleep(Duration::from_millis(RECONNECT_DELAY));
+                        continue;
+                    }
+                };
+            // The receiver fails once the websocket connection is lost
+            while let Ok(response) = receiver.recv() {
+                let update: Option<Account> = response.value.decode();
+                if let Some(a) = update {
+                    if sender.send((account, a.data)).is_err() {
+                        subscription.shutdown().ok();
+                        return;
+                    }
+                }
+            }
+            println!("Lost the subscription to {:?}, reconnecting", account);
+            subscription.shutdown().ok();
+            thread::sleep(Duration::from_millis(RECONNECT_DELAY));
+        });
+    }
+}
@@ -0,0 +1,381 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind},
+    rpc_client::RpcClient,
+    rpc_config::RpcProgramAccountsConfig,
+    rpc_config::RpcSendTransactionConfig,
+    rpc_request::RpcError,
+};
+use solana_program::{hash::Hash, pubkey::Pubkey};
+use solana_sdk::{account::Account, signature::Signature, transaction::Transaction};
+use solana_transaction_status::{EncodedConfirmedTransaction, UiTransactionEncoding};
+
+use crate::metrics;
+
+// Weight of the last call in the moving averages of latency and error rate
+const HEALTH_DECAY: f64 = 0.2;
+// An endpoint failing every call ranks as if it was this many times slower
+const ERROR_PENALTY: f64 = 10.0;
+// Failed calls are recorded as taking at least this long (in milliseconds), since an endpoint
+// refusing connections fails faster than a healthy one answers
+const MIN_FAILED_CALL_LATENCY: f64 = 1_000.0;
+// Time after which half of the recorded latency and error rate of an unused endpoint are forgotten
+const HEALTH_HALF_LIFE: Duration = Duration::from_secs(60);
+
+/// The subset of the RPC interface used by the cranking services. It is implemented by `RpcClient`
+/// in production and can be backed by an in-process bank for testing.
+pub trait RpcBackend: Send + Sync {
+    fn get_account(&self, key: &Pubkey) -> Result<Account, ClientError>;
+
+    fn get_multiple_accounts(&self, keys: &[Pubkey]) -> Result<Vec<Option<Account>>, ClientError>;
+
+    fn get_program_accounts_with_config(
+        &self,
+        program_id: &Pubkey,
+        config: RpcProgramAccountsConfig,
+    ) -> Result<Vec<(Pubkey, Account)>, ClientError>;
+
+    fn get_recent_blockhash(&self) -> Result<Hash, ClientError>;
+
+    fn get_lamports_per_signature(&self) -> Result<u64, ClientError>;
+
+    fn get_minimum_balance_for_rent_exemption(&self, data_len: usize) -> Result<u64, ClientError>;
+
+    fn send_transaction_with_config(
+        &self,
+        transaction: &Transaction,
+        config: RpcSendTransactionConfig,
+    ) -> Result<Signature, ClientError>;
+
+    fn send_and_confirm_transaction(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<Signature, ClientError>;
+
+    /// Waits for the transaction to be confirmed and returns it with its metadata
+    fn get_confirmed_transaction(
+        &self,
+        signature: &Signature,
+    ) -> Result<EncodedConfirmedTransaction, ClientError>;
+
+    fn get_account_data(&self, key: &Pubkey) -> Result<Vec<u8>, ClientError> {
+        self.get_account(key).map(|a| a.data)
+    }
+
+    fn get_balance(&self, key: &Pubkey) -> Result<u64, ClientError> {
+        self.get_account(key).map(|a| a.lamports)
+    }
+}
+
+impl RpcBackend for RpcClient {
+    fn get_account(&self, key: &Pubkey) -> Result<Account, ClientError> {
+        RpcClient::get_account(self, key)
+    }
+
+    fn get_multiple_accounts(&self, keys: &[Pubkey]) -> Result<Vec<Option<Account>>, ClientError> {
+        RpcClient::get_multiple_accounts(self, keys)
+    }
+
+    fn get_program_accounts_with_config(
+        &self,
+        program_id: &Pubkey,
+        config: RpcProgramAccountsConfig,
+    ) -> Result<Vec<(Pubkey, Account)>, ClientError> {
+        RpcClient::get_program_accounts_with_config(self, program_id, config)
+    }
+
+    fn get_recent_blockhash(&self) -> Result<Hash, ClientError> {
+        RpcClient::get_recent_blockhash(self).map(|(h, _)| h)
+    }
+
+    fn get_lamports_per_signature(&self) -> Result<u64, ClientError> {
+        RpcClient::get_recent_blockhash(self).map(|(_, f)| f.lamports_per_signature)
+    }
+
+    fn get_minimum_balance_for_rent_exemption(&self, data_len: usize) -> Result<u64, ClientError> {
+        RpcClient::get_minimum_balance_for_rent_exemption(self, data_len)
+    }
+
+    fn send_transaction_with_config(
+        &self,
+        transaction: &Transaction,
+        config: RpcSendTransactionConfig,
+    ) -> Result<Signature, ClientError> {
+        RpcClient::send_transaction_with_config(self, transaction, config)
+    }
+
+    fn send_and_confirm_transaction(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<Signature, ClientError> {
+        RpcClient::send_and_confirm_transaction(self, transaction)
+    }
+
+    fn get_confirmed_transaction(
+        &self,
+        signature: &Signature,
+    ) -> Result<EncodedConfirmedTransaction, ClientError> {
+        self.poll_for_signature(signature)?;
+        RpcClient::get_confirmed_transaction(self, signature, UiTransactionEncoding::Json)
+    }
+
+    fn get_account_data(&self, key: &Pubkey) -> Result<Vec<u8>, ClientError> {
+        RpcClient::get_account_data(self, key)
+    }
+
+    fn get_balance(&self, key: &Pubkey) -> Result<u64, ClientError> {
+        RpcClient::get_balance(self, key)
+    }
+}
+
+// Moving averages of the latency (in milliseconds) and of the error rate of an endpoint
+#[derive(Default)]
+struct EndpointHealth {
+    latency: f64,
+    error_rate: f64,
+    last_call: Option<Instant>,
+}
+
+impl EndpointHealth {
+    // Share of the averages which is remembered. They decay while the endpoint is not called, so
+    // that an endpoint demoted after failures or slow calls is eventually tried again.
+    fn retention(&self, half_life: Duration) -> f64 {
+        self.last_call.map_or(1.0, |t| {
+            0.5f64.powf(t.elapsed().as_secs_f64() / half_life.as_secs_f64())
+        })
+    }
+
+    fn score(&self, half_life: Duration) -> f64 {
+        let retention = self.retention(half_life);
+        retention * self.latency * (1.0 + ERROR_PENALTY * retention * self.error_rate)
+    }
+
+    fn record(&mut self, latency: f64, error: f64, half_life: Duration) {
+        let retention = self.retention(half_life);
+        self.latency *= retention;
+        self.error_rate *= retention;
+        self.latency += HEALTH_DECAY * (latency - self.latency);
+        self.error_rate += HEALTH_DECAY * (error - self.error_rate);
+        self.last_call = Some(Instant::now());
+    }
+}
+
+struct Endpoint<C> {
+    label: String,
+    client: C,
+    health: Mutex<EndpointHealth>,
+}
+
+impl<C> Endpoint<C> {
+    fn call<T, F>(&self, f: F, half_life: Duration) -> Result<T, ClientError>
+    where
+        F: Fn(&C) -> Result<T, ClientError>,
+    {
+        let start = Instant::now();
+        let res = f(&self.client);
+        let failed = res.as_ref().err().map_or(false, is_endpoint_error);
+        self.record(start.elapsed(), failed, half_life);
+        res
+    }
+
+    fn record(&self, latency: Duration, failed: bool, half_life: Duration) {
+        let mut health = self.health.lock().unwrap();
+        let mut latency = latency.as_secs_f64() * 1000.0;
+        let error = if failed {
+            latency = latency.max(MIN_FAILED_CALL_LATENCY);
+            1.0
+        } else {
+            0.0
+        };
+        health.record(latency, error, half_life);
+        metrics::RPC_LATENCY
+            .with_label_values(&[&self.label])
+            .set(health.latency);
+        metrics::RPC_ERROR_RATE
+            .with_label_values(&[&self.label])
+            .set(health.error_rate);
+    }
+}
+
+// Errors caused by the endpoint rather than by the request, which other endpoints may not have
+fn is_endpoint_error(e: &ClientError) -> bool {
+    matches!(
+        e.kind,
+        ClientErrorKind::Io(_)
+            | ClientErrorKind::Reqwest(_)
+            | ClientErrorKind::SerdeJson(_)
+            | ClientErrorKind::RpcError(RpcError::RpcRequestError(_))
+    )
+}
+
+/// Only keeps the host of the endpoint, urls often contain api keys
+pub fn endpoint_label(url: &str) -> String {
+    let host = url.split("://").last().unwrap_or(url);
+    host.split(|c| c == '/' || c == '?')
+        .next()
+        .unwrap_or(host)
+        .to_owned()
+}
+
+/// Routes every request to the healthiest of several RPC endpoints, ranked by latency and
+/// error rate, and fails over to the next ones when an endpoint is unreachable.
+/// Transactions can also be broadcast to all endpoints.
+pub struct MultiEndpointClient<C = RpcClient> {
+    endpoints: Vec<Endpoint<C>>,
+    broadcast: bool,
+    health_half_life: Duration,
+}
+
+impl MultiEndpointClient {
+    pub fn new(urls: Vec<String>, broadcast: bool) -> Self {
+        let clients = urls
+            .into_iter()
+            .map(|url| (endpoint_label(&url), RpcClient::new(url)))
+            .collect();
+        Self::from_clients(clients, broadcast)
+    }
+}
+
+impl<C: RpcBackend> MultiEndpointClient<C> {
+    /// Builds the client from labelled endpoints
+    pub fn from_clients(clients: Vec<(String, C)>, broadcast: bool) -> Self {
+        assert!(!clients.is_empty(), "At least one RPC endpoint is required");
+        let endpoints = clients
+            .into_iter()
+            .map(|(label, client)| Endpoint {
+                label,
+                client,
+                health: Mutex::new(EndpointHealth::default()),
+            })
+            .collect();
+        Self {
+            endpoints,
+            broadcast,
+            health_half_life: HEALTH_HALF_LIFE,
+        }
+    }
+
+    /// Sets the time after which half of the recorded health of an unused endpoint is forgotten
+    pub fn with_health_half_life(mut self, half_life: Duration) -> Self {
+        self.health_half_life = half_life;
+        self
+    }
+
+    /// Labels of the endpoints sorted from the healthiest
+    pub fn ranking(&self) -> Vec<&str> {
+        self.ranked()
+            .into_iter()
+            .map(|e| e.label.as_str())
+            .collect()
+    }
+
+    // Endpoints sorted from the healthiest
+    fn ranked(&self) -> Vec<&Endpoint<C>> {
+        let mut ranked = self
+            .endpoints
+            .iter()
+            .map(|e| (e.health.lock().unwrap().score(self.health_half_life), e))
+            .collect::<Vec<_>>();
+        ranked.sort_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap());
+        ranked.into_iter().map(|(_, e)| e).collect()
+    }
+
+    fn call<T, F>(&self, f: F) -> Result<T, ClientError>
+    where
+        F: Fn(&C) -> Result<T, ClientError>,
+    {
+        let mut last_error = None;
+        for e in self.ranked() {
+            match e.call(&f, self.health_half_life) {
+                Err(err) if is_endpoint_error(&err) => {
+                    println!("Endpoint {} failed with {:?}", e.label, err);
+                    last_error = Some(err);
+                }
+                res => return res,
+            }
+        }
+        Err(last_error.unwrap())
+    }
+
+    // Sends the transaction through all endpoints but the healthiest, ignoring failures
+    fn broadcast_to_others(&self, transaction: &Transaction, config: RpcSendTransactionConfig) {
+        for e in self.ranked().into_iter().skip(1) {
+            if let Err(err) = e.call(
+                |c| c.send_transaction_with_config(transaction, config),
+                self.health_half_life,
+            ) {
+                println!("Broadcast through {} failed with {:?}", e.label, err);
+            }
+        }
+    }
+}
+
+impl<C: RpcBackend> RpcBackend for MultiEndpointClient<C> {
+    fn get_account(&self, key: &Pubkey) -> Result<Account, ClientError> {
+        self.call(|c| c.get_account(key))
+    }
+
+    fn get_multiple_accounts(&self, keys: &[Pubkey]) -> Result<Vec<Option<Account>>, ClientError> {
+        self.call(|c| c.get_multiple_accounts(keys))
+    }
+
+    fn get_program_accounts_with_config(
+        &self,
+        program_id: &Pubkey,
+        config: RpcProgramAccountsConfig,
+    ) -> Result<Vec<(Pubkey, Account)>, ClientError> {
+        self.call(|c| c.get_program_accounts_with_config(program_id, config.clone()))
+    }
+
+    fn get_recent_blockhash(&self) -> Result<Hash, ClientError> {
+        self.call(|c| RpcBackend::get_recent_blockhash(c))
+    }
+
+    fn get_lamports_per_signature(&self) -> Result<u64, ClientError> {
+        self.call(|c| c.get_lamports_per_signature())
+    }
+
+    fn get_minimum_balance_for_rent_exemption(&self, data_len: usize) -> Result<u64, ClientError> {
+        self.call(|c| RpcBackend::get_minimum_balance_for_rent_exemption(c, data_len))
+    }
+
+    fn send_transaction_with_config(
+        &self,
+        transaction: &Transaction,
+        config: RpcSendTransactionConfig,
+    ) -> Result<Signature, ClientError> {
+        if self.broadcast {
+            self.broadcast_to_others(transaction, config);
+        }
+        self.call(|c| c.send_transaction_with_config(transaction, config))
+    }
+
+    fn send_and_confirm_transaction(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<Signature, ClientError> {
+        if self.broadcast {
+            self.broadcast_to_others(transaction, RpcSendTransactionConfig::default());
+        }
+        self.call(|c| c.send_and_confirm_transaction(transaction))
+    }
+
+    fn get_confirmed_transaction(
+        &self,
+        signature: &Signature,
+    ) -> Result<EncodedConfirmedTransaction, ClientError> {
+        self.call(|c| RpcBackend::get_confirmed_transaction(c, signature))
+    }
+
+    fn get_account_data(&self, key: &Pubkey) -> Result<Vec<u8>, ClientError> {
+        self.call(|c| c.get_account_data(key))
+    }
+
+    fn get_balance(&self, key: &Pubkey) -> Result<u64, ClientError> {
+        self.call(|c| c.get_balance(key))
+    }
+}
@@ -0,0 +1,117 @@
+use std::{convert::TryInto, path::Path};
+
+use solana_program::pubkey::Pubkey;
+
+use crate::error::CrankError;
+
+const POSITIONS_TREE: &[u8] = b"positions";
+
+/// Local store of the progress of the sweeping services, which lets a restarted cranker skip the
+/// user accounts it already processed. Cloning the store shares the underlying database.
+#[derive(Clone)]
+pub struct CheckpointStore {
+    db: sled::Db,
+}
+
+impl CheckpointStore {
+    pub fn open(path: &Path) -> Result<Self, CrankError> {
+        let db = sled::open(path).map_err(store_error)?;
+        Ok(Self { db })
+    }
+
+    /// A store which is deleted when dropped
+    pub fn temporary() -> Result<Self, CrankError> {
+        let db = sled::Config::new()
+            .temporary(true)
+            .open()
+            .map_err(store_error)?;
+        Ok(Self { db })
+    }
+
+    pub fn sweep(&self, service: &str, market: &Pubkey) -> Result<Sweep, CrankError> {
+        let name = format!("{}/{}", service, market);
+        Ok(Sweep {
+            positions: self.db.open_tree(POSITIONS_TREE).map_err(store_error)?,
+            accounts: self.db.open_tree(name.as_bytes()).map_err(store_error)?,
+            name,
+        })
+    }
+}
+
+/// Position of a sweep over the user accounts of a market. A sweep goes through epochs, the
+/// accounts processed in the current epoch are skipped until the next one.
+pub struct Sweep {
+    name: String,
+    positions: sled::Tree,
+    // Epoch in which each account was last processed
+    accounts: sled::Tree,
+}
+
+impl Sweep {
+    /// Epoch of the last cycle, and whether this cycle went through all the accounts
+    pub fn position(&self) -> Result<Option<(u64, bool)>, CrankError> {
+        let position = self
+            .positions
+            .get(self.name.as_bytes())
+            .map_err(store_error)?;
+        Ok(position.and_then(|p| {
+            let epoch = u64::from_le_bytes(p.get(..8)?.try_into().ok()?);
+            Some((epoch, *p.get(8)? != 0))
+        }))
+    }
+
+    /// Starts a cycle, forgetting the accounts processed in other epochs. Returns the number of
+    /// accounts which are already processed.
+    pub fn begin(&self, epoch: u64) -> Result<usize, CrankError> {
+        if self.position()?.map(|(e, _)| e) != Some(epoch) {
+            self.accounts.clear().map_err(store_error)?;
+        }
+        self.set_position(epoch, false)?;
+        Ok(self.accounts.len())
+    }
+
+    pub fn complete(&self) -> Result<(), CrankError> {
+        if let Some((epoch, _)) = self.position()? {
+            self.set_position(epoch, true)?;
+        }
+        self.positions.flush().map_err(store_error)?;
+        self.accounts.flush().map_err(store_error)?;
+        Ok(())
+    }
+
+    pub fn is_processed(&self, account: &Pubkey) -> Result<bool, CrankError> {
+        let epoch = match self.position()? {
+            Some((e, _)) => e,
+            None => return Ok(false),
+        };
+        let processed_epoch = self
+            .accounts
+            .get(account.to_bytes())
+            .map_err(store_error)?
+            .and_then(|e| Some(u64::from_le_bytes(e.as_ref().try_into().ok()?)));
+        Ok(processed_epoch == Some(epoch))
+    }
+
+    pub fn mark_processed(&self, account: &Pubkey) -> Result<(), CrankError> {
+        let (epoch, _) = self
+            .position()?
+            .ok_or_else(|| CrankError::CheckpointError(String::from("The sweep has not begun")))?;
+        self.accounts
+            .insert(account.to_bytes(), &epoch.to_le_bytes())
+            .map_err(store_error)?;
+        Ok(())
+    }
+
+    fn set_position(&self, epoch: u64, completed: bool) -> Result<(), CrankError> {
+        let mut position = epoch.to_le_bytes().to_vec();
+        position.push(completed as u8);
+        self.positions
+            .insert(self.name.as_bytes(), position)
+            .map_err(store_error)?;
+        Ok(())
+    }
+}
+
+fn store_error(e: sled::Error) -> CrankError {
+    CrankError::CheckpointError(e.to_string())
+}
@@ -0,0 +1,185 @@
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use serde::Deserialize;
+use solana_program::pubkey::Pubkey;
+use solana_sdk::signature::read_keypair_file;
+
+use crate::{
+    backend::{MultiEndpointClient, RpcBackend},
+    checkpoint::CheckpointStore,
+    error::CrankError,
+    provisioning::Provisioning,
+    rebalancing::Rebalancing,
+    swarm::Swarm,
+    utils::{compute_websocket_url, RetryPolicy},
+    Context, Periods, Service,
+};
+
+const DEFAULT_ENDPOINT: &str = "https://solana-api.projectserum.com";
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    #[serde(default = "default_endpoint")]
+    pub url: String,
+    /// Other RPC endpoints, requests are routed to the healthiest one
+    #[serde(default)]
+    pub additional_urls: Vec<String>,
+    /// Sends the transactions through all endpoints
+    #[serde(default)]
+    pub broadcast: bool,
+    /// Defaults to the websocket endpoint of the RPC node
+    pub ws_url: Option<String>,
+    pub program_id: String,
+    /// Path to the fee payer keypair file
+    pub fee_payer: String,
+    pub metrics_address: Option<SocketAddr>,
+    pub num_threads: Option<usize>,
+    /// Default swarm settings for the swarm-aware services, can be overriden per market
+    #[serde(default)]
+    pub swarm: Swarm,
+    /// Retry settings of the RPC operations, shared by all markets
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    /// Price of SOL in quote token, transactions are only skipped when they are no-ops if unset
+    pub sol_price: Option<f64>,
+    /// Directory of the store in which the sweeps are checkpointed, shared by all markets
+    pub checkpoint_path: Option<PathBuf>,
+    pub markets: Vec<MarketConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MarketConfig {
+    pub address: String,
+    pub services: Vec<ServiceName>,
+    #[serde(default)]
+    pub periods: Periods,
+    pub swarm: Option<Swarm>,
+    /// Settings of the page provisioning service
+    #[serde(default)]
+    pub provisioning: Provisioning,
+    /// Settings of the rebalancing service
+    pub rebalancing: Option<RebalancingConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RebalancingConfig {
+    /// User account holding the rebalancing positions
+    pub user_account: String,
+    #[serde(flatten)]
+    pub settings: Rebalancing,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ServiceName {
+    Liquidate,
+    Funding,
+    FundingExtraction,
+    LiquidationCleanup,
+    GarbageCollect,
+    AutoDeleverage,
+    PageProvisioning,
+    Rebalance,
+    Defragment,
+}
+
+fn default_endpoint() -> String {
+    String::from(DEFAULT_ENDPOINT)
+}
+
+impl Config {
+    /// Parses a TOML or YAML configuration file, depending on its extension.
+    pub fn load(path: &Path) -> Result<Self, CrankError> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| CrankError::InvalidConfig(e.to_string()))?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&contents)
+                .map_err(|e| CrankError::InvalidConfig(e.to_string())),
+            _ => toml::from_str(&contents).map_err(|e| CrankError::InvalidConfig(e.to_string())),
+        }
+    }
+
+    /// Builds the services to run. All markets share the same fee payer and RPC connection.
+    pub fn into_services(self) -> Result<Vec<(Arc<Context>, Service)>, CrankError> {
+        let program_id = parse_pubkey(&self.program_id)?;
+        let fee_payer = Arc::new(
+            read_keypair_file(&self.fee_payer)
+                .map_err(|e| CrankError::InvalidConfig(e.to_string()))?,
+        );
+        let websocket_url = self
+            .ws_url
+            .unwrap_or_else(|| compute_websocket_url(&self.url));
+        let mut urls = vec![self.url];
+        urls.extend(self.additional_urls);
+        let connection: Arc<dyn RpcBackend> =
+            Arc::new(MultiEndpointClient::new(urls, self.broadcast));
+        let num_threads = self.num_threads.unwrap_or_else(num_cpus::get);
+        let checkpoints = self
+            .checkpoint_path
+            .as_deref()
+            .map(CheckpointStore::open)
+            .transpose()?;
+        let mut services = vec![];
+        for m in self.markets {
+            let swarm = m.swarm.unwrap_or(self.swarm);
+            if swarm.check().is_err() {
+                return Err(CrankError::InvalidConfig(format!(
+                    "Invalid swarm settings for market {}",
+                    m.address
+                )));
+            }
+            m.provisioning.check()?;
+            let ctx = Arc::new(Context {
+                program_id,
+                market: parse_pubkey(&m.address)?,
+                fee_payer: Arc::clone(&fee_payer),
+                connection: Arc::clone(&connection),
+                websocket_url: websocket_url.clone(),
+                num_threads,
+                periods: m.periods,
+                retry: self.retry,
+                sol_price: self.sol_price,
+                checkpoints: checkpoints.clone(),
+            });
+            for s in m.services {
+                let service = match s {
+                    ServiceName::Liquidate => Service::Liquidate,
+                    ServiceName::Funding => Service::Funding,
+                    ServiceName::FundingExtraction => Service::FundingExtraction { swarm },
+                    ServiceName::LiquidationCleanup => Service::LiquidationCleanup { swarm },
+                    ServiceName::GarbageCollect => Service::GarbageCollect,
+                    ServiceName::AutoDeleverage => Service::AutoDeleverage,
+                    ServiceName::PageProvisioning => Service::PageProvisioning {
+                        provisioning: m.provisioning,
+                    },
+                    ServiceName::Rebalance => {
+                        let r = m.rebalancing.as_ref().ok_or_else(|| {
+                            CrankError::InvalidConfig(format!(
+                                "Missing rebalancing settings for market {}",
+                                m.address
+                            ))
+                        })?;
+                        r.settings.check()?;
+                        Service::Rebalance {
+                            user_account: parse_pubkey(&r.user_account)?,
+                            rebalancing: r.settings,
+                        }
+                    }
+                    ServiceName::Defragment => Service::Defragment,
+                };
+                services.push((Arc::clone(&ctx), service));
+            }
+        }
+        Ok(services)
+    }
+}
+
+fn parse_pubkey(s: &str) -> Result<Pubkey, CrankError> {
+    Pubkey::from_str(s).map_err(|_| CrankError::InvalidConfig(format!("Invalid pubkey {}", s)))
+}
@@ -1,8 +1,33 @@
+use solana_program::{program_error::ProgramError, pubkey::Pubkey};
 use thiserror::Error;
 #[derive(Clone, Debug, Eq, Error, PartialEq)]
 pub enum CrankError {
     #[error("Encountered a connection error")]
     ConnectionError,
     #[error("The parsed market state is invalid")]
-    InvalidMarketState
-}
\ No newline at end of file
+    InvalidMarketState,
+    #[error("The configuration file is invalid: {0}")]
+    InvalidConfig(String),
+    #[error("The parsed instance state is invalid")]
+    InvalidInstanceState,
+    #[error("The account {0} could not be found")]
+    AccountNotFound(Pubkey),
+    #[error("The operation failed with a fatal error: {0}")]
+    FatalError(String),
+    #[error("The operation failed after {0} attempts")]
+    RetriesExhausted(u32),
+    #[error("The parsed user account state is invalid")]
+    InvalidUserAccountState,
+    #[error("The swarm size, node id or redundancy is invalid")]
+    InvalidSwarm,
+    #[error("The fee payer is not the market admin")]
+    NotMarketAdmin,
+    #[error("The checkpoint store failed: {0}")]
+    CheckpointError(String)
+}
+
+impl From<ProgramError> for CrankError {
+    fn from(_: ProgramError) -> Self {
+        CrankError::InvalidMarketState
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use solana_program::pubkey::Pubkey;
+use tokio::{
+    signal,
+    sync::watch::{self, Receiver, Sender},
+    time::Interval,
+};
+
+// A service is not ready anymore when it has not cranked successfully for this many periods
+const STALE_PERIODS: u32 = 3;
+
+struct ServiceHealth {
+    period: Duration,
+    last_success: Option<SystemTime>,
+}
+
+lazy_static! {
+    static ref SHUTDOWN: (Sender<bool>, Receiver<bool>) = watch::channel(false);
+    static ref SERVICES: Mutex<BTreeMap<(String, String), ServiceHealth>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// Declares a service for the readiness check, with its period in milliseconds
+pub fn register(service: &str, market: &Pubkey, period: u64) {
+    SERVICES.lock().unwrap().insert(
+        (service.to_owned(), market.to_string()),
+        ServiceHealth {
+            period: Duration::from_millis(period),
+            last_success: None,
+        },
+    );
+}
+
+pub fn record_success(service: &str, market: &Pubkey) {
+    if let Some(h) = SERVICES
+        .lock()
+        .unwrap()
+        .get_mut(&(service.to_owned(), market.to_string()))
+    {
+        h.last_success = Some(SystemTime::now());
+    }
+}
+
+/// The cranker is ready when it is not shutting down and every service cranked successfully
+/// within its last few periods. The report lists the last success of every service.
+pub fn report() -> (bool, String) {
+    let now = SystemTime::now();
+    let mut ready = !is_shutting_down();
+    let mut entries = vec![];
+    for ((service, market), h) in SERVICES.lock().unwrap().iter() {
+        let fresh = h.last_success.map_or(false, |t| {
+            now.duration_since(t).unwrap_or_default() <= h.period * STALE_PERIODS
+        });
+        ready &= fresh;
+        let last_success = h
+            .last_success
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or_else(|| String::from("null"), |d| d.as_secs().to_string());
+        entries.push(format!(
+            r#"{{"service":"{}","market":"{}","last_success":{},"ready":{}}}"#,
+            service, market, last_success, fresh
+        ));
+    }
+    let body = format!(
+        r#"{{"ready":{},"shutting_down":{},"services":[{}]}}"#,
+        ready,
+        is_shutting_down(),
+        entries.join(",")
+    );
+    (ready, body)
+}
+
+/// Stops the services once their current iteration is done
+pub fn shutdown() {
+    SHUTDOWN.0.send(true).ok();
+}
+
+pub fn is_shutting_down() -> bool {
+    *SHUTDOWN.1.borrow()
+}
+
+pub async fn wait_for_shutdown() {
+    let mut receiver = SHUTDOWN.1.clone();
+    while !*receiver.borrow() {
+        if receiver.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Waits for the next tick of a service loop, returns false when the cranker is shutting down
+pub async fn tick(ticker: &mut Interval) -> bool {
+    tokio::select! {
+        _ = ticker.tick() => !is_shutting_down(),
+        _ = wait_for_shutdown() => false,
+    }
+}
+
+/// Resolves on SIGTERM or SIGINT
+pub async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to listen to SIGTERM");
+        tokio::select! {
+            _ = terminate.recv() => {},
+            _ = signal::ctrl_c() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    signal::ctrl_c().await.ok();
+}
@@ -1,344 +1,930 @@
+use account_updates::{AccountUpdateSource, WebsocketSource};
 use audaces_protocol::{
     instruction::{
-        close_position, collect_garbage, crank_funding, crank_liquidation, extract_funding,
-        InstanceContext, MarketContext, PositionInfo,
+        add_page, auto_deleverage, close_position, collect_garbage, crank_bounded_liquidation,
+        crank_funding, defragment_page, extract_funding, purge_liquidated_positions, rebalance,
+        MarketContext, PositionInfo,
     },
-    processor::FIDA_BNB,
+    positions_book::memory::{SLOT_SIZE, TAG_SIZE},
+    processor::ALLOCATION_FEE,
     state::{
-        instance::Instance, instance::PageInfo, market::MarketState, user_account::OpenPosition,
-        user_account::UserAccountState, StateObject,
+        instance::{get_pending_liquidation, parse_instance},
+        liquidation_receipts::LiquidationReceiptsState,
+        market::MarketState,
+        user_account::OpenPosition,
+        StateObject,
     },
+    utils::{compute_deleveraging_score, get_oracle_price},
 };
+use backend::RpcBackend;
+use checkpoint::{CheckpointStore, Sweep};
 use error::CrankError;
 use futures::{
+    future::{self, try_join_all},
     stream::{self, Iter},
     StreamExt,
 };
+use perps_client::load_market;
+use profitability::{
+    estimate_garbage_collection_reward, estimate_liquidation_reward, get_transaction_cost,
+    is_profitable,
+};
+use provisioning::{
+    get_defragmentation_page, get_instance_full_ratio, get_page_capacity, Provisioning,
+};
+use rebalancing::{decide, get_unrealized_pnl, Rebalancing, RebalancingAction};
+use serde::Deserialize;
 use solana_client::{
-    rpc_client::RpcClient,
     rpc_config::RpcAccountInfoConfig,
     rpc_config::{RpcProgramAccountsConfig, RpcSendTransactionConfig},
     rpc_filter::{self, Memcmp, RpcFilterType},
 };
-use solana_program::{program_pack::Pack, pubkey::Pubkey};
+use solana_program::{
+    instruction::Instruction, program_pack::Pack, pubkey::Pubkey,
+    system_instruction::create_account,
+};
 use solana_sdk::{
     account::Account,
-    signature::{Keypair, Signer},
+    packet::PACKET_DATA_SIZE,
+    signature::{Keypair, Signature, Signer},
     transaction::Transaction,
 };
 use spl_associated_token_account::get_associated_token_address;
 use std::{
     borrow::Borrow,
-    str::FromStr,
+    collections::HashMap,
+    net::SocketAddr,
     sync::Arc,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant},
     vec::IntoIter,
 };
+use swarm::Swarm;
 use tokio::{
     runtime::Runtime,
-    sync::Mutex,
-    task::{self, JoinError},
-    time::interval,
+    sync::{mpsc::unbounded_channel, Mutex},
+    task,
+    time::{interval, sleep},
 };
 
-use crate::utils::{invalid_signature_filter, no_op_filter};
+use crate::utils::{get_token_balance_change, no_op_filter, parse_user_account, RetryPolicy};
 
+pub mod account_updates;
+pub mod backend;
+pub mod checkpoint;
+pub mod config;
 pub mod error;
-
-mod utils;
+pub mod health;
+pub mod metrics;
+pub mod profitability;
+pub mod provisioning;
+pub mod rebalancing;
+pub mod swarm;
+pub mod utils;
 
 pub struct Context {
     pub program_id: Pubkey,
     pub market: Pubkey,
-    pub fee_payer: Keypair,
-    pub endpoint: String,
+    pub fee_payer: Arc<Keypair>,
+    pub connection: Arc<dyn RpcBackend>,
+    pub websocket_url: String,
     pub num_threads: usize,
+    pub periods: Periods,
+    pub retry: RetryPolicy,
+    /// Price of SOL in quote token, used to skip the transactions which cost more than their reward
+    pub sol_price: Option<f64>,
+    /// Progress of the funding extraction and liquidation cleanup sweeps, kept across restarts
+    pub checkpoints: Option<CheckpointStore>,
 }
 
-const LIQUIDATION_PERIOD: u64 = 1_000;
+const LIQUIDATION_PERIOD: u64 = 30_000;
 const FUNDING_PERIOD: u64 = 1_000;
 const FUNDING_EXTRACTION_PERIOD: u64 = 1_800_000;
 const LIQUIDATION_CLEANUP_PERIOD: u64 = 1_800_000;
 const GARBAGE_COLLECTION_PERIOD: u64 = 10_000;
 const GARBAGE_COLLECT_MAX_ITERATIONS: u64 = 500;
+// Nodes of the positions book processed by a liquidation transaction, larger liquidations are
+// resumed by the following transactions so that they stay within the compute limit
+const LIQUIDATION_NODE_BUDGET: u16 = 48;
+const AUTO_DELEVERAGE_PERIOD: u64 = 10_000;
+const PAGE_PROVISIONING_PERIOD: u64 = 60_000;
+const REBALANCING_PERIOD: u64 = 60_000;
+const DEFRAGMENTATION_PERIOD: u64 = 60_000;
+// Nodes moved by a defragmentation transaction, the following transactions resume the page
+const DEFRAGMENTATION_MAX_MOVES: u16 = 32;
+// Ratio of used slots of a page below which its nodes are moved to the other pages
+const DEFRAGMENTATION_MAX_USED_RATIO: f64 = 0.25;
+// Time given to the services to finish their current iteration on shutdown
+const DRAIN_TIMEOUT: u64 = 30_000;
+// Delay before restarting a service which panicked
+const RESTART_DELAY: u64 = 1_000;
+const MAX_MULTIPLE_ACCOUNTS: usize = 100;
+// Upper estimate of the compute units used by an extract funding instruction
+const FUNDING_EXTRACTION_COMPUTE_UNITS: u64 = 25_000;
+const MAX_TRANSACTION_COMPUTE_UNITS: u64 = 200_000;
 
-impl Context {
-    pub fn crank_liquidation(self) {
-        let connection = RpcClient::new(self.endpoint.clone());
-        let (market_ctx, quote_mint) =
-            get_market(self.program_id, self.market, &connection).unwrap();
-        println!("Market quote mint {:?}", quote_mint);
+/// Periods of the cranking services, in milliseconds
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default)]
+pub struct Periods {
+    /// Liquidations are driven by account updates, this is the period at which all accounts are refetched
+    pub liquidation: u64,
+    pub funding: u64,
+    pub funding_extraction: u64,
+    pub liquidation_cleanup: u64,
+    pub garbage_collection: u64,
+    pub auto_deleverage: u64,
+    pub page_provisioning: u64,
+    pub rebalancing: u64,
+    pub defragmentation: u64,
+}
 
-        let endpoint = Arc::new(self.endpoint.clone());
-        let market = Arc::new(market_ctx);
+impl Default for Periods {
+    fn default() -> Self {
+        Self {
+            liquidation: LIQUIDATION_PERIOD,
+            funding: FUNDING_PERIOD,
+            funding_extraction: FUNDING_EXTRACTION_PERIOD,
+            liquidation_cleanup: LIQUIDATION_CLEANUP_PERIOD,
+            garbage_collection: GARBAGE_COLLECTION_PERIOD,
+            auto_deleverage: AUTO_DELEVERAGE_PERIOD,
+            page_provisioning: PAGE_PROVISIONING_PERIOD,
+            rebalancing: REBALANCING_PERIOD,
+            defragmentation: DEFRAGMENTATION_PERIOD,
+        }
+    }
+}
 
-        let target_token_account = Arc::new(get_associated_token_address(
-            &self.fee_payer.pubkey(),
-            &quote_mint,
-        ));
-        let fee_payer = Arc::new(self.fee_payer);
+#[derive(Clone, Copy, Debug)]
+pub enum Service {
+    Liquidate,
+    Funding,
+    FundingExtraction {
+        swarm: Swarm,
+    },
+    LiquidationCleanup {
+        swarm: Swarm,
+    },
+    GarbageCollect,
+    AutoDeleverage,
+    PageProvisioning {
+        provisioning: Provisioning,
+    },
+    Rebalance {
+        user_account: Pubkey,
+        rebalancing: Rebalancing,
+    },
+    Defragment,
+}
 
-        let rt = Runtime::new().unwrap();
+impl Service {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Service::Liquidate => "liquidation",
+            Service::Funding => "funding",
+            Service::FundingExtraction { .. } => "funding_extraction",
+            Service::LiquidationCleanup { .. } => "liquidation_cleanup",
+            Service::GarbageCollect => "garbage_collection",
+            Service::AutoDeleverage => "auto_deleverage",
+            Service::PageProvisioning { .. } => "page_provisioning",
+            Service::Rebalance { .. } => "rebalancing",
+            Service::Defragment => "defragmentation",
+        }
+    }
+}
 
-        let mut tasks = Vec::with_capacity(market.instances.len());
+impl Periods {
+    pub fn of(&self, service: &Service) -> u64 {
+        match service {
+            Service::Liquidate => self.liquidation,
+            Service::Funding => self.funding,
+            Service::FundingExtraction { .. } => self.funding_extraction,
+            Service::LiquidationCleanup { .. } => self.liquidation_cleanup,
+            Service::GarbageCollect => self.garbage_collection,
+            Service::AutoDeleverage => self.auto_deleverage,
+            Service::PageProvisioning { .. } => self.page_provisioning,
+            Service::Rebalance { .. } => self.rebalancing,
+            Service::Defragment => self.defragmentation,
+        }
+    }
+}
 
-        println!("Found {} instances", market.instances.len());
+/// Runs all the services on a single runtime, until one of them fails or the process receives
+/// SIGTERM or SIGINT. On shutdown, the services finish their current iteration within a timeout.
+pub fn run(
+    services: Vec<(Arc<Context>, Service)>,
+    metrics_address: Option<SocketAddr>,
+) -> Result<(), CrankError> {
+    let rt = Runtime::new().unwrap();
+    let guard = rt.enter();
+    task::spawn(async {
+        health::wait_for_signal().await;
+        println!("Received a termination signal, draining the services");
+        health::shutdown();
+    });
+    for (ctx, service) in &services {
+        health::register(service.name(), &ctx.market, ctx.periods.of(service));
+    }
+    if let Some(address) = metrics_address {
+        task::spawn(metrics::serve(address));
+        let mut sampled_markets = vec![];
+        for (ctx, _) in &services {
+            if sampled_markets.contains(&ctx.market) {
+                continue;
+            }
+            sampled_markets.push(ctx.market);
+            task::spawn(metrics::sample(
+                Arc::clone(&ctx.connection),
+                ctx.market,
+                ctx.fee_payer.pubkey(),
+            ));
+        }
+    }
+    let tasks = services
+        .into_iter()
+        .map(|(ctx, service)| supervise(ctx, service));
+    // Stops at the first service which fails with a fatal error
+    let res = rt.block_on(async {
+        tokio::select! {
+            r = try_join_all(tasks) => r.map(|_| ()),
+            _ = async {
+                health::wait_for_shutdown().await;
+                sleep(Duration::from_millis(DRAIN_TIMEOUT)).await
+            } => {
+                println!("The services did not drain within {}s", DRAIN_TIMEOUT / 1000);
+                Ok(())
+            }
+        }
+    });
+    drop(guard);
+    rt.shutdown_timeout(Duration::from_millis(DRAIN_TIMEOUT));
+    res
+}
 
-        for i in 0..market.instances.len() {
-            let t = run_liquidation(
-                Arc::clone(&endpoint),
-                Arc::clone(&market),
-                i,
-                Arc::clone(&target_token_account),
-                Arc::clone(&fee_payer),
-            );
-            tasks.push(t);
+// Restarts the service when it panics, a single bad account must not stop the cranker
+async fn supervise(ctx: Arc<Context>, service: Service) -> Result<(), CrankError> {
+    loop {
+        match task::spawn(Arc::clone(&ctx).run_service(service)).await {
+            Ok(r) => return r,
+            Err(e) => {
+                println!(
+                    "{:?} for market {:?} panicked with {:?}, restarting",
+                    service, ctx.market, e
+                );
+                metrics::SERVICE_RESTARTS
+                    .with_label_values(&[service.name(), &ctx.market.to_string()])
+                    .inc();
+            }
         }
+        if health::is_shutting_down() {
+            return Ok(());
+        }
+        sleep(Duration::from_millis(RESTART_DELAY)).await;
+    }
+}
 
-        for t in tasks {
-            rt.block_on(t).unwrap();
+impl Context {
+    pub async fn run_service(self: Arc<Self>, service: Service) -> Result<(), CrankError> {
+        println!("Starting {:?} for market {:?}", service, self.market);
+        match service {
+            Service::Liquidate => {
+                let source = WebsocketSource::new(self.websocket_url.clone());
+                self.crank_liquidation(source).await
+            }
+            Service::Funding => self.crank_funding().await,
+            Service::FundingExtraction { swarm } => self.crank_funding_extraction(swarm).await,
+            Service::LiquidationCleanup { swarm } => self.crank_liquidation_cleanup(swarm).await,
+            Service::GarbageCollect => self.garbage_collect().await,
+            Service::AutoDeleverage => self.auto_deleverage().await,
+            Service::PageProvisioning { provisioning } => self.provision_pages(provisioning).await,
+            Service::Rebalance {
+                user_account,
+                rebalancing,
+            } => self.rebalance(user_account, rebalancing).await,
+            Service::Defragment => self.defragment().await,
         }
     }
 
-    pub fn crank_funding(self) {
-        let connection = RpcClient::new(self.endpoint.clone());
-        let (market_ctx, _) = get_market(self.program_id, self.market, &connection).unwrap();
-        let market = Arc::new(market_ctx);
-        let fee_payer = Arc::new(self.fee_payer);
+    // Liquidations are only sent when the oracle price crosses the liquidation index of a live position
+    pub async fn crank_liquidation<S: AccountUpdateSource>(
+        self: Arc<Self>,
+        source: S,
+    ) -> Result<(), CrankError> {
+        let (_, quote_mint) = get_market(self.program_id, self.market, &*self.connection)?;
+        println!("Market quote mint {:?}", quote_mint);
 
-        let rt = Runtime::new().unwrap();
-        let _guard = rt.enter();
+        let target_token_account =
+            get_associated_token_address(&self.fee_payer.pubkey(), &quote_mint);
 
-        let instruction = crank_funding(&market);
-        let t = task::spawn(async move {
-            let mut ticker = interval(Duration::from_millis(FUNDING_PERIOD));
-            loop {
-                ticker.tick().await;
-                let transaction =
-                    Transaction::new_with_payer(&[instruction.clone()], Some(&fee_payer.pubkey()));
-                let sig = utils::retry(
-                    transaction,
-                    |t| {
-                        let mut tr = t.clone();
-                        let (recent_blockhash, _) = connection.get_recent_blockhash()?;
-                        tr.partial_sign::<Vec<&Keypair>>(
-                            &vec![fee_payer.borrow()],
-                            recent_blockhash,
-                        );
-                        connection.send_and_confirm_transaction(&tr)
-                    },
-                    no_op_filter,
-                )
-                .await;
-                println!("Sent funding transaction {:?}", sig);
-            }
-        });
+        watch_liquidations(self, target_token_account, source).await
+    }
+
+    pub async fn crank_funding(self: Arc<Self>) -> Result<(), CrankError> {
+        let (market, _) = get_market(self.program_id, self.market, &*self.connection)?;
 
-        rt.block_on(t).unwrap();
+        let mut ticker = interval(Duration::from_millis(self.periods.funding));
+        while health::tick(&mut ticker).await {
+            match crank_funding_iteration(&self, &market).await {
+                Ok(_) => health::record_success("funding", &self.market),
+                Err(e) => println!("Funding crank failed with {:?}", e),
+            }
+        }
+        Ok(())
     }
-    pub fn crank_funding_extraction(self, swarm_size: u16, node_id: u8) {
-        let s = Arc::new(self);
-        let rt = Runtime::new().unwrap();
-        let _guard = rt.enter();
-        let t = async move {
-            let mut ticker = interval(Duration::from_millis(FUNDING_EXTRACTION_PERIOD));
-            loop {
-                ticker.tick().await;
-                let start_time = SystemTime::now();
-                crank_funding_extraction_iteration(&s, swarm_size, node_id).await;
-                let end_time = SystemTime::now();
-                println!(
-                    "Finished funding extraction cycle in {:?}s within a funding period of {:?}s",
-                    end_time.duration_since(start_time).unwrap().as_secs_f64(),
-                    FUNDING_PERIOD / 1000
-                )
+
+    pub async fn crank_funding_extraction(self: Arc<Self>, swarm: Swarm) -> Result<(), CrankError> {
+        let mut ticker = interval(Duration::from_millis(self.periods.funding_extraction));
+        while health::tick(&mut ticker).await {
+            let start_time = Instant::now();
+            if let Err(e) = crank_funding_extraction_iteration(&self, &swarm).await {
+                println!("Funding extraction cycle failed with {:?}", e);
+                continue;
             }
-        };
-        rt.block_on(t);
+            let cycle_time = start_time.elapsed().as_secs_f64();
+            metrics::CYCLE_DURATION
+                .with_label_values(&["funding_extraction", &self.market.to_string()])
+                .set(cycle_time);
+            health::record_success("funding_extraction", &self.market);
+            println!(
+                "Finished funding extraction cycle in {:?}s within a funding period of {:?}s",
+                cycle_time,
+                self.periods.funding / 1000
+            )
+        }
+        Ok(())
     }
 
-    pub fn crank_liquidation_cleanup(self, swarm_size: u16, node_id: u8) {
-        let s = Arc::new(self);
-        let rt = Runtime::new().unwrap();
-        let _guard = rt.enter();
-        let t = async move {
-            let mut ticker = interval(Duration::from_millis(LIQUIDATION_CLEANUP_PERIOD));
-            loop {
-                ticker.tick().await;
-                let start_time = SystemTime::now();
-                crank_liquidation_cleanup_iteration(&s, swarm_size, node_id).await;
-                let end_time = SystemTime::now();
-                println!(
-                    "Finished liquidation cleanup cycle in {:?}s within a liquidation cleanup period of {:?}s",
-                    end_time.duration_since(start_time).unwrap().as_secs_f64(),
-                    LIQUIDATION_CLEANUP_PERIOD / 1000
-                )
+    pub async fn crank_liquidation_cleanup(
+        self: Arc<Self>,
+        swarm: Swarm,
+    ) -> Result<(), CrankError> {
+        let mut ticker = interval(Duration::from_millis(self.periods.liquidation_cleanup));
+        while health::tick(&mut ticker).await {
+            let start_time = Instant::now();
+            if let Err(e) = crank_liquidation_cleanup_iteration(&self, &swarm).await {
+                println!("Liquidation cleanup cycle failed with {:?}", e);
+                continue;
             }
-        };
-        rt.block_on(t);
-    }
-
-    pub fn garbage_collect(self) {
-        let s = Arc::new(self);
-        let rt = Runtime::new().unwrap();
-        let _guard = rt.enter();
-        let connection = RpcClient::new(String::clone(&s.endpoint));
-        let (market, quote_mint) = get_market(s.program_id, s.market, &connection).unwrap();
-        let target_token_account = Arc::new(get_associated_token_address(
-            &s.fee_payer.pubkey(),
-            &quote_mint,
-        ));
+            let cycle_time = start_time.elapsed().as_secs_f64();
+            metrics::CYCLE_DURATION
+                .with_label_values(&["liquidation_cleanup", &self.market.to_string()])
+                .set(cycle_time);
+            health::record_success("liquidation_cleanup", &self.market);
+            println!(
+                "Finished liquidation cleanup cycle in {:?}s within a liquidation cleanup period of {:?}s",
+                cycle_time,
+                self.periods.liquidation_cleanup / 1000
+            )
+        }
+        Ok(())
+    }
+
+    pub async fn garbage_collect(self: Arc<Self>) -> Result<(), CrankError> {
+        let (market, quote_mint) = get_market(self.program_id, self.market, &*self.connection)?;
+        let target_token_account =
+            get_associated_token_address(&self.fee_payer.pubkey(), &quote_mint);
         let market = Arc::new(market);
-        let t = task::spawn(async move {
-            let mut ticker = interval(Duration::from_millis(GARBAGE_COLLECTION_PERIOD));
-            loop {
-                ticker.tick().await;
-                crank_garbage_collection(&s, &market, &target_token_account).await;
+        let mut ticker = interval(Duration::from_millis(self.periods.garbage_collection));
+        while health::tick(&mut ticker).await {
+            match crank_garbage_collection(&self, &market, &target_token_account).await {
+                Ok(_) => health::record_success("garbage_collection", &self.market),
+                Err(e) => println!("Garbage collection failed with {:?}", e),
+            }
+        }
+        Ok(())
+    }
+
+    // The fee payer must be the market admin
+    pub async fn auto_deleverage(self: Arc<Self>) -> Result<(), CrankError> {
+        let mut ticker = interval(Duration::from_millis(self.periods.auto_deleverage));
+        while health::tick(&mut ticker).await {
+            match crank_auto_deleverage_iteration(&self).await {
+                Ok(_) => health::record_success("auto_deleverage", &self.market),
+                Err(e) => println!("Auto-deleveraging failed with {:?}", e),
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn defragment(self: Arc<Self>) -> Result<(), CrankError> {
+        let mut ticker = interval(Duration::from_millis(self.periods.defragmentation));
+        while health::tick(&mut ticker).await {
+            match crank_defragmentation_iteration(&self).await {
+                Ok(_) => health::record_success("defragmentation", &self.market),
+                Err(e) => println!("Defragmentation failed with {:?}", e),
+            }
+        }
+        Ok(())
+    }
+
+    // The fee payer must be the market admin, unless running dry
+    pub async fn provision_pages(
+        self: Arc<Self>,
+        provisioning: Provisioning,
+    ) -> Result<(), CrankError> {
+        provisioning.check()?;
+        let (market, _) = get_market(self.program_id, self.market, &*self.connection)?;
+        if !provisioning.dry_run && market.admin_account != self.fee_payer.pubkey() {
+            return Err(CrankError::NotMarketAdmin);
+        }
+        let mut ticker = interval(Duration::from_millis(self.periods.page_provisioning));
+        while health::tick(&mut ticker).await {
+            match crank_page_provisioning_iteration(&self, &provisioning).await {
+                Ok(_) => health::record_success("page_provisioning", &self.market),
+                Err(e) => println!("Page provisioning failed with {:?}", e),
+            }
+        }
+        Ok(())
+    }
+
+    // The fee payer must be the market admin and the owner of the rebalancing user account
+    pub async fn rebalance(
+        self: Arc<Self>,
+        user_account: Pubkey,
+        rebalancing: Rebalancing,
+    ) -> Result<(), CrankError> {
+        rebalancing.check()?;
+        let (market, _) = get_market(self.program_id, self.market, &*self.connection)?;
+        if market.admin_account != self.fee_payer.pubkey() {
+            return Err(CrankError::NotMarketAdmin);
+        }
+        if rebalancing.instance_index as usize >= market.instances.len() {
+            return Err(CrankError::InvalidConfig(format!(
+                "The market has no instance {}",
+                rebalancing.instance_index
+            )));
+        }
+        let user_account_data = self
+            .connection
+            .get_account_data(&user_account)
+            .map_err(|_| CrankError::ConnectionError)?;
+        let (header, _) = parse_user_account(&user_account_data)?;
+        if Pubkey::new(&header.owner) != self.fee_payer.pubkey() {
+            return Err(CrankError::InvalidConfig(String::from(
+                "The fee payer must own the rebalancing user account",
+            )));
+        }
+        let mut ticker = interval(Duration::from_millis(self.periods.rebalancing));
+        while health::tick(&mut ticker).await {
+            match crank_rebalancing_iteration(&self, &user_account, &rebalancing).await {
+                Ok(_) => health::record_success("rebalancing", &self.market),
+                Err(e) => println!("Rebalancing failed with {:?}", e),
             }
-        });
-        rt.block_on(t).unwrap();
+        }
+        Ok(())
     }
 }
 
 pub fn get_market(
     program_id: Pubkey,
     market_key: Pubkey,
-    connection: &RpcClient,
+    connection: &dyn RpcBackend,
 ) -> Result<(MarketContext, Pubkey), CrankError> {
-    let market_data = connection
-        .get_account_data(&market_key)
-        .map_err(|_| CrankError::ConnectionError)?;
+    load_market(program_id, market_key, |k| connection.get_account_data(k)).map_err(|e| match e {
+        perps_client::error::ClientError::ConnectionError(_) => CrankError::ConnectionError,
+        _ => CrankError::InvalidMarketState,
+    })
+}
+
+// Rebuilds the positions books locally from account updates and liquidates instances
+// whose books have a position at or beyond the current oracle price.
+async fn watch_liquidations<S: AccountUpdateSource>(
+    ctx: Arc<Context>,
+    target_token_account: Pubkey,
+    source: S,
+) -> Result<(), CrankError> {
+    let (mut market, mut market_state) = refresh_market(&ctx).await?;
+    println!("Found {} instances", market.instances.len());
+    let (sender, mut receiver) = unbounded_channel();
+    let mut accounts: HashMap<Pubkey, Vec<u8>> = HashMap::new();
+    let mut subscribed = vec![];
+    // Instances with a liquidation in flight, which are skipped until their account is updated
+    let mut pending = vec![];
+    // The market and its accounts are periodically refetched in case instances or pages were
+    // added or migrated, or subscription updates were missed
+    let mut ticker = interval(Duration::from_millis(ctx.periods.liquidation));
+    let mut is_first_tick = true;
+    loop {
+        let mut updates = tokio::select! {
+            _ = ticker.tick() => {
+                if !is_first_tick {
+                    match refresh_market(&ctx).await {
+                        Ok((m, s)) => {
+                            market = m;
+                            market_state = s;
+                        }
+                        Err(e) => {
+                            println!("Failed to refetch the market with {:?}", e);
+                            continue;
+                        }
+                    }
+                }
+                is_first_tick = false;
+                pending = vec![false; market.instances.len()];
+                let mut market_accounts = vec![market.oracle_account];
+                for i in &market.instances {
+                    market_accounts.push(i.instance_account);
+                    market_accounts.extend_from_slice(&i.memory_pages);
+                }
+                for k in market_accounts {
+                    if !subscribed.contains(&k) {
+                        source.subscribe(k, sender.clone());
+                        subscribed.push(k);
+                    }
+                }
+                match fetch_accounts(&ctx, subscribed.clone()).await {
+                    Ok(a) => {
+                        health::record_success("liquidation", &ctx.market);
+                        a
+                    }
+                    Err(e) => {
+                        println!("Failed to refetch the market accounts with {:?}", e);
+                        continue;
+                    }
+                }
+            }
+            Some(update) = receiver.recv() => vec![update],
+            _ = health::wait_for_shutdown() => return Ok(()),
+        };
+        while !updates.is_empty() {
+            let mut new_pages = vec![];
+            for (key, data) in updates {
+                if let Some(i) = market
+                    .instances
+                    .iter()
+                    .position(|i| i.instance_account == key)
+                {
+                    pending[i] = false;
+                    // Subscribe to the pages added to the instance
+                    if let Ok((_, page_infos)) = parse_instance(&data) {
+                        for p in page_infos {
+                            let page_key = Pubkey::new(&p.address);
+                            if !subscribed.contains(&page_key) {
+                                source.subscribe(page_key, sender.clone());
+                                subscribed.push(page_key);
+                                new_pages.push(page_key);
+                            }
+                        }
+                    }
+                }
+                accounts.insert(key, data);
+            }
+            updates = if new_pages.is_empty() {
+                vec![]
+            } else {
+                // The new pages are fetched again on the next resync if this fails
+                fetch_accounts(&ctx, new_pages).await.unwrap_or_default()
+            };
+        }
+        liquidate_crossed_instances(
+            &ctx,
+            &market,
+            &market_state,
+            &accounts,
+            &target_token_account,
+            &mut pending,
+        )
+        .await;
+    }
+}
+
+// Loads the market context, with the current instances and pages, and the market state
+async fn refresh_market(ctx: &Context) -> Result<(MarketContext, MarketState), CrankError> {
+    let (market, _) = utils::retry(
+        &ctx.retry,
+        &*ctx.connection,
+        |c| get_market(ctx.program_id, ctx.market, *c),
+        |r| r,
+    )
+    .await?;
+    let market_data = utils::retry(
+        &ctx.retry,
+        &ctx.connection,
+        |c| c.get_account_data(&ctx.market),
+        |r| r,
+    )
+    .await?;
     let market_state =
         MarketState::unpack_from_slice(&market_data).map_err(|_| CrankError::InvalidMarketState)?;
-    let instance_addresses = market_data
-        [MarketState::LEN..MarketState::LEN + (market_state.number_of_instances as usize) * 32]
-        .chunks(32)
-        .into_iter()
-        .map(|s| Pubkey::new(s));
-
-    let instances = instance_addresses
-        .map(|a| {
-            let instance_data = connection
-                .get_account_data(&a)
-                .map_err(|_| CrankError::ConnectionError)?;
-            let instance = Instance::unpack_from_slice(&instance_data)
-                .map_err(|_| CrankError::InvalidMarketState)?;
-            let memory_pages = instance_data[Instance::LEN..]
-                .chunks(PageInfo::LEN)
-                .map(|s| {
-                    Ok(Pubkey::new(
-                        &PageInfo::unpack_from_slice(s)
-                            .map_err(|_| CrankError::InvalidMarketState)?
-                            .address,
-                    ))
-                })
-                .take(instance.number_of_pages as usize)
-                .collect::<Result<Vec<_>, _>>()?;
-
-            Ok(InstanceContext {
-                instance_account: a,
-                memory_pages,
-            })
-        })
-        .collect::<Result<Vec<_>, _>>()?;
+    Ok((market, market_state))
+}
 
-    let market_signer_account = Pubkey::create_program_address(
-        &[&market_key.to_bytes(), &[market_state.signer_nonce]],
-        &program_id,
+/// Fetches the market accounts and liquidates the instances which can be liquidated, until their
+/// liquidations are complete
+pub async fn crank_liquidation_iteration(ctx: &Arc<Context>) -> Result<(), CrankError> {
+    let (market, quote_mint) = utils::retry(
+        &ctx.retry,
+        &*ctx.connection,
+        |c| get_market(ctx.program_id, ctx.market, *c),
+        |r| r,
     )
-    .map_err(|_| CrankError::InvalidMarketState)?;
+    .await?;
+    let target_token_account = get_associated_token_address(&ctx.fee_payer.pubkey(), &quote_mint);
+    let market_data = utils::retry(
+        &ctx.retry,
+        &ctx.connection,
+        |c| c.get_account_data(&market.market_account),
+        |r| r,
+    )
+    .await?;
+    let market_state =
+        MarketState::unpack_from_slice(&market_data).map_err(|_| CrankError::InvalidMarketState)?;
+    let mut keys = vec![market.oracle_account];
+    for i in &market.instances {
+        keys.push(i.instance_account);
+        keys.extend(&i.memory_pages);
+    }
+    let mut accounts = fetch_accounts(ctx, keys.clone())
+        .await?
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+    // The liquidations which exceed the node budget of a transaction are resumed until they complete
+    loop {
+        let mut pending = vec![false; market.instances.len()];
+        liquidate_crossed_instances(
+            ctx,
+            &market,
+            &market_state,
+            &accounts,
+            &target_token_account,
+            &mut pending,
+        )
+        .await;
+        if !pending.iter().any(|p| *p) {
+            return Ok(());
+        }
+        accounts = fetch_accounts(ctx, keys.clone())
+            .await?
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+    }
+}
 
-    let token_account = connection
-        .get_token_account(&Pubkey::new(&market_state.vault_address))
-        .map_err(|_| CrankError::ConnectionError)?
-        .unwrap();
-
-    let ctx = MarketContext {
-        audaces_protocol_program_id: program_id,
-        signer_nonce: market_state.signer_nonce,
-        market_signer_account,
-        oracle_account: Pubkey::new(&market_state.oracle_address),
-        market_account: market_key,
-        admin_account: Pubkey::new(&market_state.admin_address),
-        market_vault: Pubkey::new(&market_state.vault_address),
-        bonfida_bnb: Pubkey::from_str(FIDA_BNB).unwrap(),
-        instances,
+// Liquidates the instances whose positions books are crossed by the oracle price, and flags them as pending
+async fn liquidate_crossed_instances(
+    ctx: &Arc<Context>,
+    market: &MarketContext,
+    market_state: &MarketState,
+    accounts: &HashMap<Pubkey, Vec<u8>>,
+    target_token_account: &Pubkey,
+    pending: &mut [bool],
+) {
+    let liquidation_index = match accounts
+        .get(&market.oracle_account)
+        .map(|d| get_oracle_price(d, market_state.coin_decimals, market_state.quote_decimals))
+    {
+        Some(Ok(p)) => p,
+        Some(Err(e)) => {
+            println!("Failed to parse the oracle price with {:?}", e);
+            return;
+        }
+        None => return,
     };
+    let transaction_cost = match get_transaction_cost(ctx, market_state.quote_decimals).await {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Failed to fetch the transaction fees with {:?}", e);
+            return;
+        }
+    };
+    for (instance_index, instance) in market.instances.iter().enumerate() {
+        if pending[instance_index] {
+            continue;
+        }
+        let instance_data = match accounts.get(&instance.instance_account) {
+            Some(d) => d,
+            None => continue,
+        };
+        let is_liquidation_pending = match get_pending_liquidation(instance_data) {
+            Ok(p) => !p.is_empty(),
+            Err(e) => {
+                println!("Failed to parse instance {:?} with {:?}", instance_index, e);
+                continue;
+            }
+        };
+        // The instance is locked until its pending liquidation completes, which is resumed
+        // without a reward whatever the transaction cost
+        let expected_reward = if is_liquidation_pending {
+            0
+        } else {
+            let expected_reward =
+                match estimate_liquidation_reward(instance_data, accounts, liquidation_index) {
+                    Ok(0) => continue,
+                    Ok(r) => r,
+                    Err(e) => {
+                        println!(
+                            "Failed to rebuild the positions book of instance {:?} with {:?}",
+                            instance_index, e
+                        );
+                        continue;
+                    }
+                };
+            if !is_profitable(
+                "liquidation",
+                &market.market_account.to_string(),
+                &instance_index.to_string(),
+                expected_reward,
+                transaction_cost,
+            ) {
+                continue;
+            }
+            expected_reward
+        };
+        pending[instance_index] = match send_liquidation(
+            ctx,
+            market,
+            instance_index,
+            target_token_account,
+            expected_reward,
+        )
+        .await
+        {
+            Ok(sig) => sig != Signature::default(),
+            Err(e) => {
+                println!(
+                    "Failed to liquidate instance {:?} with {:?}",
+                    instance_index, e
+                );
+                false
+            }
+        };
+    }
+}
 
-    Ok((ctx, Pubkey::from_str(&token_account.mint).unwrap()))
+async fn fetch_accounts(
+    ctx: &Context,
+    keys: Vec<Pubkey>,
+) -> Result<Vec<(Pubkey, Vec<u8>)>, CrankError> {
+    let mut res = Vec::with_capacity(keys.len());
+    for chunk in keys.chunks(MAX_MULTIPLE_ACCOUNTS) {
+        let accounts = utils::retry(
+            &ctx.retry,
+            chunk,
+            |k| ctx.connection.get_multiple_accounts(k),
+            |r| r,
+        )
+        .await?;
+        for (k, a) in chunk.iter().zip(accounts) {
+            if let Some(a) = a {
+                res.push((*k, a.data));
+            }
+        }
+    }
+    Ok(res)
 }
 
-async fn run_liquidation(
-    endpoint: Arc<String>,
-    market: Arc<MarketContext>,
+async fn send_liquidation(
+    ctx: &Arc<Context>,
+    market: &MarketContext,
     instance_index: usize,
-    target_token_account: Arc<Pubkey>,
-    fee_payer: Arc<Keypair>,
-) -> Result<(), JoinError> {
-    task::spawn(async move {
-        let connection = RpcClient::new(String::clone(&endpoint));
-        let liquidation_instruction = crank_liquidation(
-            &market,
-            instance_index as u8,
-            *target_token_account.borrow(),
-        );
-        println!("Starting liquidation task");
-        let mut ticker = interval(Duration::from_millis(LIQUIDATION_PERIOD));
-        loop {
-            ticker.tick().await;
-            println!("Liquidation tick");
-            let transaction = Transaction::new_with_payer(
-                &[liquidation_instruction.clone()],
-                Some(&fee_payer.pubkey()),
-            );
-            let sig = utils::retry(
-                transaction,
-                |t| {
-                    let (recent_blockhash, _) = connection.get_recent_blockhash()?;
-                    let mut tr = t.clone();
-                    tr.partial_sign::<Vec<&Keypair>>(&vec![fee_payer.borrow()], recent_blockhash);
-                    connection.send_transaction_with_config(
-                        &tr,
-                        RpcSendTransactionConfig {
-                            skip_preflight: false,
-                            preflight_commitment: None,
-                            ..RpcSendTransactionConfig::default()
-                        },
-                    )
+    target_token_account: &Pubkey,
+    expected_reward: u64,
+) -> Result<Signature, CrankError> {
+    let market_label = market.market_account.to_string();
+    let instance_label = instance_index.to_string();
+    let liquidation_instruction = crank_bounded_liquidation(
+        market,
+        instance_index as u8,
+        LIQUIDATION_NODE_BUDGET,
+        *target_token_account,
+    );
+    let transaction =
+        Transaction::new_with_payer(&[liquidation_instruction], Some(&ctx.fee_payer.pubkey()));
+    let target_index = get_account_index(&transaction, target_token_account);
+    let sig = utils::retry(
+        &ctx.retry,
+        transaction,
+        |t| {
+            let recent_blockhash = ctx.connection.get_recent_blockhash()?;
+            let mut tr = t.clone();
+            tr.partial_sign::<Vec<&Keypair>>(&vec![ctx.fee_payer.borrow()], recent_blockhash);
+            ctx.connection.send_transaction_with_config(
+                &tr,
+                RpcSendTransactionConfig {
+                    skip_preflight: false,
+                    preflight_commitment: None,
+                    ..RpcSendTransactionConfig::default()
                 },
-                no_op_filter,
             )
-            .await;
-            println!(
-                "Sent liquidation transaction for instance {:?} with signature {:?}",
-                instance_index, sig
-            );
-        }
-    })
-    .await
+        },
+        |r| {
+            metrics::record_transaction(
+                "liquidation",
+                &market_label,
+                &instance_label,
+                no_op_filter(r),
+            )
+        },
+    )
+    .await?;
+    println!(
+        "Sent liquidation transaction for instance {:?} with signature {:?}",
+        instance_index, sig
+    );
+    if sig != Signature::default() && expected_reward != 0 {
+        track_reward(
+            Arc::clone(&ctx.connection),
+            sig,
+            target_index,
+            expected_reward,
+            "liquidation",
+            market_label,
+            instance_label,
+        );
+    }
+    Ok(sig)
 }
 
-async fn crank_garbage_collection(
+pub async fn crank_funding_iteration(
     ctx: &Arc<Context>,
-    market: &Arc<MarketContext>,
-    target_token_account: &Arc<Pubkey>,
-) {
-    let connection = RpcClient::new(String::clone(&ctx.endpoint));
-    for i in 0..(market.instances.len() as u8) {
+    market: &MarketContext,
+) -> Result<(), CrankError> {
+    let transaction =
+        Transaction::new_with_payer(&[crank_funding(market)], Some(&ctx.fee_payer.pubkey()));
+    let market_label = ctx.market.to_string();
+    let sig = utils::retry(
+        &ctx.retry,
+        transaction,
+        |t| {
+            let mut tr = t.clone();
+            let recent_blockhash = ctx.connection.get_recent_blockhash()?;
+            tr.partial_sign::<Vec<&Keypair>>(&vec![ctx.fee_payer.borrow()], recent_blockhash);
+            ctx.connection.send_and_confirm_transaction(&tr)
+        },
+        |r| metrics::record_transaction("funding", &market_label, "", no_op_filter(r)),
+    )
+    .await?;
+    println!("Sent funding transaction {:?}", sig);
+    Ok(())
+}
+
+pub async fn crank_garbage_collection(
+    ctx: &Arc<Context>,
+    market: &MarketContext,
+    target_token_account: &Pubkey,
+) -> Result<(), CrankError> {
+    let connection = &ctx.connection;
+    let market_data = utils::retry(
+        &ctx.retry,
+        connection,
+        |c| c.get_account_data(&market.market_account),
+        |r| r,
+    )
+    .await?;
+    let market_state =
+        MarketState::unpack_from_slice(&market_data).map_err(|_| CrankError::InvalidMarketState)?;
+    let transaction_cost = get_transaction_cost(ctx, market_state.quote_decimals).await?;
+    let mut keys = vec![];
+    for i in &market.instances {
+        keys.push(i.instance_account);
+        keys.extend(&i.memory_pages);
+    }
+    let accounts = fetch_accounts(ctx, keys)
+        .await?
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+    for (i, instance) in market.instances.iter().enumerate() {
+        let market_label = ctx.market.to_string();
+        let instance_label = i.to_string();
+        let expected_reward = match accounts
+            .get(&instance.instance_account)
+            .ok_or(CrankError::AccountNotFound(instance.instance_account))
+            .and_then(|d| {
+                estimate_garbage_collection_reward(d, &accounts, GARBAGE_COLLECT_MAX_ITERATIONS)
+            }) {
+            Ok(r) => r,
+            Err(e) => {
+                println!(
+                    "Failed to estimate the garbage collection reward of instance {:?} with {:?}",
+                    i, e
+                );
+                continue;
+            }
+        };
+        if !is_profitable(
+            "garbage_collection",
+            &market_label,
+            &instance_label,
+            expected_reward,
+            transaction_cost,
+        ) {
+            continue;
+        }
         let instruction = collect_garbage(
             &market,
-            i,
+            i as u8,
             GARBAGE_COLLECT_MAX_ITERATIONS,
-            **target_token_account,
+            *target_token_account,
         );
         let transaction =
             Transaction::new_with_payer(&[instruction], Some(&ctx.fee_payer.pubkey()));
+        let target_index = get_account_index(&transaction, target_token_account);
         let sig = utils::retry(
+            &ctx.retry,
             transaction,
             |t| {
                 let mut tr = t.clone();
-                let (recent_blockhash, _) = connection.get_recent_blockhash()?;
-                tr.partial_sign(&[&ctx.fee_payer], recent_blockhash);
+                let recent_blockhash = connection.get_recent_blockhash()?;
+                tr.partial_sign(&[ctx.fee_payer.as_ref()], recent_blockhash);
                 connection.send_transaction_with_config(
                     &tr,
                     RpcSendTransactionConfig {
@@ -348,53 +934,95 @@ async fn crank_garbage_collection(
                     },
                 )
             },
-            no_op_filter,
+            |r| {
+                metrics::record_transaction(
+                    "garbage_collection",
+                    &market_label,
+                    &instance_label,
+                    no_op_filter(r),
+                )
+            },
         )
-        .await;
+        .await?;
         println!(
             "Sent garbage collection transaction for isntance {:?} with signature {:?}",
             i, sig
         );
+        if sig != Signature::default() {
+            track_reward(
+                Arc::clone(connection),
+                sig,
+                target_index,
+                expected_reward,
+                "garbage_collection",
+                market_label,
+                instance_label,
+            );
+        }
     }
+    Ok(())
 }
 
-async fn crank_funding_extraction_iteration(ctx: &Arc<Context>, swarm_size: u16, node_id: u8) {
-    if swarm_size == 0 {
-        panic!("Swarm size should be non-zero");
-    }
-    if !swarm_size.is_power_of_two() {
-        panic!("Swarm size must be a power of two");
-    }
-    if swarm_size > 256 {
-        panic!("Maximum supported swarm size is 256");
-    }
-    if node_id as u16 >= swarm_size {
-        panic!("Node id should be less than swarm size.")
-    }
-    let configs = get_node_filters(ctx, swarm_size, node_id);
-    let url = ctx.endpoint.clone();
-    let program_id = ctx.program_id;
-    let accounts = stream::iter(configs.into_iter())
-        .then(move |c| account_stream(program_id, url.clone(), c))
-        .flatten();
-    let connection = RpcClient::new(ctx.endpoint.to_owned());
+pub async fn crank_funding_extraction_iteration(
+    ctx: &Arc<Context>,
+    swarm: &Swarm,
+) -> Result<(), CrankError> {
+    swarm.check()?;
+    // The accounts are partitioned locally, every node fetches all the active user accounts
+    let swarm = *swarm;
+    let accounts = account_stream(
+        Arc::clone(&ctx.connection),
+        ctx.program_id,
+        ctx.retry,
+        get_user_account_filters(ctx),
+    )
+    .await
+    .filter(move |(k, _)| future::ready(swarm.is_assigned(k)));
 
     let accounts_mutex = Arc::new(Mutex::new(Box::pin(accounts)));
     let (market, _) = utils::retry(
-        &connection,
-        |c| get_market(ctx.program_id, ctx.market, &c),
+        &ctx.retry,
+        &*ctx.connection,
+        |c| get_market(ctx.program_id, ctx.market, *c),
         |r| r,
     )
-    .await;
+    .await?;
+    let market_data = utils::retry(
+        &ctx.retry,
+        &ctx.connection,
+        |c| c.get_account_data(&market.market_account),
+        |r| r,
+    )
+    .await?;
+    let market_state =
+        MarketState::unpack_from_slice(&market_data).map_err(|_| CrankError::InvalidMarketState)?;
+    let funding_history_offset = market_state.funding_history_offset;
+    // The accounts only have to be processed once per funding period. The funding offset wraps
+    // around the funding history, the timestamp of the last funding identifies the period.
+    let sweep = begin_sweep(ctx, "funding_extraction", |_| {
+        market_state.last_funding_timestamp
+    })?;
+    let receipts_accounts = Arc::new(get_liquidation_receipts_accounts(ctx).await);
     let market = Arc::new(market);
     let mut tasks = Vec::with_capacity(num_cpus::get());
     for _ in 0..tasks.capacity() {
         let task_mutex = Arc::clone(&accounts_mutex);
-        let connection = RpcClient::new(ctx.endpoint.to_owned());
         let c = Arc::clone(&ctx);
         let m = Arc::clone(&market);
+        let market_label = ctx.market.to_string();
+        let fee_payer_pk = ctx.fee_payer.pubkey();
+        let s = Arc::clone(&sweep);
+        let r = Arc::clone(&receipts_accounts);
         let t = async move {
+            // Extractions are packed across the user accounts processed by the task
+            let mut batch = vec![];
+            // Accounts whose extractions are all in the batch
+            let mut batch_accounts = vec![];
             loop {
+                // Stops taking accounts on shutdown, the transactions in flight are still sent
+                if health::is_shutting_down() {
+                    break;
+                }
                 // Can't use if let here due to borrow checker in an async context
                 let next = {
                     let mut f = task_mutex.lock().await;
@@ -404,194 +1032,300 @@ async fn crank_funding_extraction_iteration(ctx: &Arc<Context>, swarm_size: u16,
                     break;
                 };
                 let (k, a): (Pubkey, Account) = next.unwrap();
-                println!("Processing funding for {:?}", k);
-                let fee_payer_pk = c.fee_payer.pubkey();
-                let transactions = {
-                    let mut position_offset = UserAccountState::LEN;
-                    let header =
-                        UserAccountState::unpack_from_slice(&a.data[..UserAccountState::LEN])
-                            .unwrap();
-                    let mut cranked_instance_indices: Vec<u8> = vec![0; m.instances.len()];
-                    let mut instructions = vec![];
-                    for _ in 0..header.number_of_open_positions {
-                        let position = OpenPosition::unpack_from_slice(
-                            &a.data[position_offset..position_offset + OpenPosition::LEN],
-                        )
-                        .unwrap();
-                        cranked_instance_indices[position.instance_index as usize] = 1;
-                        instructions.push(extract_funding(&m, position.instance_index, k));
-                        position_offset += OpenPosition::LEN;
+                if is_processed(&s, &k) {
+                    continue;
+                }
+                let instance_indices = match get_lagging_instances(&a.data, funding_history_offset)
+                {
+                    Ok(i) => i,
+                    Err(e) => {
+                        println!("Failed to parse user account {:?} with {:?}", k, e);
+                        continue;
                     }
-                    for (i, l) in cranked_instance_indices.iter().enumerate() {
-                        if *l == 0 {
-                            continue;
+                };
+                if instance_indices.is_empty() {
+                    mark_processed(&s, &[k]);
+                    continue;
+                }
+                for i in instance_indices {
+                    println!("Processing funding for {:?} on instance {:?}", k, i);
+                    batch.push(extract_funding(&m, i, k, r.get(&k).copied()));
+                    if batch.len() > 1
+                        && !fits_in_funding_extraction_transaction(&batch, &fee_payer_pk)
+                    {
+                        let last = batch.pop().unwrap();
+                        let full_batch = std::mem::replace(&mut batch, vec![last]);
+                        // The current account is not in the batch accounts until all its
+                        // extractions are batched
+                        let accounts = std::mem::take(&mut batch_accounts);
+                        if send_funding_extractions(&c, full_batch, &market_label).await {
+                            mark_processed(&s, &accounts);
                         }
-                        instructions.push(extract_funding(&m, i as u8, k))
                     }
-                    instructions
-                        .into_iter()
-                        .map(|i| Transaction::new_with_payer(&[i], Some(&fee_payer_pk)))
-                };
-                for t in transactions {
-                    let sig = utils::retry(
-                        t,
-                        |t| {
-                            let mut tr = t.clone();
-                            let (recent_blockhash, _) = connection.get_recent_blockhash()?;
-                            tr.partial_sign::<Vec<&Keypair>>(&vec![&c.fee_payer], recent_blockhash);
-                            connection.send_transaction_with_config(
-                                &tr,
-                                RpcSendTransactionConfig {
-                                    skip_preflight: false,
-                                    ..RpcSendTransactionConfig::default()
-                                },
-                            )
-                        },
-                        no_op_filter,
-                    )
-                    .await;
-                    println!("Sent funding extraction transaction {:?}", sig);
                 }
+                batch_accounts.push(k);
+            }
+            if !batch.is_empty() && send_funding_extractions(&c, batch, &market_label).await {
+                mark_processed(&s, &batch_accounts);
             }
         };
         tasks.push(task::spawn(t))
     }
+    let mut completed = true;
     for t in tasks {
-        t.await.unwrap();
+        if let Err(e) = t.await {
+            println!("A funding extraction task panicked with {:?}", e);
+            completed = false;
+        }
     }
+    complete_sweep(&sweep, completed);
+    Ok(())
 }
 
-fn get_node_filters(
-    ctx: &Arc<Context>,
-    swarm_size: u16,
-    node_id: u8,
-) -> Vec<RpcProgramAccountsConfig> {
-    if swarm_size > 1 {
-        let mut res = Vec::with_capacity((256 / swarm_size + 1) as usize);
-        let mut offset = node_id;
-        if swarm_size > 256 {
-            panic!("Swarm sizes larger than 256 are unsupported");
-        }
-        let swarm_size_u8 = swarm_size as u8;
-        loop {
-            res.push(RpcProgramAccountsConfig {
-                filters: Some(vec![
-                    // Filter for user accounts
-                    RpcFilterType::Memcmp(Memcmp {
-                        offset: 0,
-                        bytes: rpc_filter::MemcmpEncodedBytes::Binary(
-                            bs58::encode(vec![StateObject::UserAccount as u8]).into_string(),
-                        ),
-                        encoding: None,
-                    }),
-                    // Filter for a subset of owners
-                    RpcFilterType::Memcmp(Memcmp {
-                        offset: 2,
-                        bytes: rpc_filter::MemcmpEncodedBytes::Binary(
-                            bs58::encode(vec![offset]).into_string(),
-                        ),
-                        encoding: None,
-                    }),
-                    // Filter for active user accounts (with open positions)
-                    RpcFilterType::Memcmp(Memcmp {
-                        offset: 34,
-                        bytes: rpc_filter::MemcmpEncodedBytes::Binary(
-                            bs58::encode(vec![1]).into_string(),
-                        ),
-                        encoding: None,
-                    }),
-                    // Filter for user accounts affiliated with the current market
-                    RpcFilterType::Memcmp(Memcmp {
-                        offset: 35,
-                        bytes: rpc_filter::MemcmpEncodedBytes::Binary(ctx.market.to_string()),
-                        encoding: None,
-                    }),
-                ]),
-                account_config: RpcAccountInfoConfig {
-                    encoding: None,
-                    data_slice: None,
-                    commitment: None,
-                },
-                with_context: None,
-            });
-            if let Some(o) = offset.checked_add(swarm_size_u8) {
-                offset = o;
-            } else {
-                break;
+// Starts a cycle of the sweep of the service, the epoch is computed from the position of the
+// last cycle
+fn begin_sweep<F: FnOnce(Option<(u64, bool)>) -> u64>(
+    ctx: &Context,
+    service: &str,
+    epoch: F,
+) -> Result<Arc<Option<Sweep>>, CrankError> {
+    let sweep = match &ctx.checkpoints {
+        Some(c) => c.sweep(service, &ctx.market)?,
+        None => return Ok(Arc::new(None)),
+    };
+    let epoch = epoch(sweep.position()?);
+    let processed = sweep.begin(epoch)?;
+    if processed > 0 {
+        println!(
+            "Resuming the {} sweep in epoch {} with {} accounts already processed",
+            service, epoch, processed
+        );
+    }
+    Ok(Arc::new(Some(sweep)))
+}
+
+// The accounts are processed again when the checkpoint store fails
+fn is_processed(sweep: &Option<Sweep>, account: &Pubkey) -> bool {
+    sweep.as_ref().map_or(false, |s| {
+        s.is_processed(account).unwrap_or_else(|e| {
+            println!(
+                "Failed to read the checkpoint of {:?} with {:?}",
+                account, e
+            );
+            false
+        })
+    })
+}
+
+fn mark_processed(sweep: &Option<Sweep>, accounts: &[Pubkey]) {
+    if let Some(s) = sweep {
+        for a in accounts {
+            if let Err(e) = s.mark_processed(a) {
+                println!("Failed to checkpoint {:?} with {:?}", a, e);
             }
         }
-        res
-    } else {
-        vec![RpcProgramAccountsConfig {
-            filters: Some(vec![
-                // Filter for user accounts
-                RpcFilterType::Memcmp(Memcmp {
-                    offset: 0,
-                    bytes: rpc_filter::MemcmpEncodedBytes::Binary(
-                        bs58::encode(&[StateObject::UserAccount as u8]).into_string(),
-                    ),
-                    encoding: None,
-                }),
-                // Filter for active user accounts (with open positions)
-                RpcFilterType::Memcmp(Memcmp {
-                    offset: 34,
-                    bytes: rpc_filter::MemcmpEncodedBytes::Binary(bs58::encode(&[1]).into_string()),
-                    encoding: None,
-                }),
-                // Filter for user accounts affiliated with the current market
-                RpcFilterType::Memcmp(Memcmp {
-                    offset: 35,
-                    bytes: rpc_filter::MemcmpEncodedBytes::Binary(ctx.market.to_string()),
-                    encoding: None,
-                }),
-            ]),
-            account_config: RpcAccountInfoConfig {
-                encoding: None,
-                data_slice: None,
-                commitment: None,
-            },
-            with_context: None,
-        }]
     }
 }
 
-async fn crank_liquidation_cleanup_iteration(ctx: &Arc<Context>, swarm_size: u16, node_id: u8) {
-    if swarm_size == 0 {
-        panic!("Swarm size should be non-zero");
+// An interrupted sweep resumes in the same epoch on the next cycle
+fn complete_sweep(sweep: &Option<Sweep>, completed: bool) {
+    if let Some(s) = sweep {
+        if completed && !health::is_shutting_down() {
+            if let Err(e) = s.complete() {
+                println!("Failed to complete the sweep with {:?}", e);
+            }
+        }
     }
-    if !swarm_size.is_power_of_two() {
-        panic!("Swarm size must be a power of two");
+}
+
+// Instances on which the user account has positions which have not been charged the last funding
+fn get_lagging_instances(data: &[u8], funding_history_offset: u8) -> Result<Vec<u8>, CrankError> {
+    let (_, positions) = parse_user_account(data)?;
+    let mut instance_indices = vec![];
+    for position in positions {
+        if position.last_funding_offset != funding_history_offset
+            && !instance_indices.contains(&position.instance_index)
+        {
+            instance_indices.push(position.instance_index);
+        }
     }
-    if swarm_size > 256 {
-        panic!("Maximum supported swarm size is 256");
+    Ok(instance_indices)
+}
+
+fn fits_in_funding_extraction_transaction(instructions: &[Instruction], payer: &Pubkey) -> bool {
+    if instructions.len() as u64 * FUNDING_EXTRACTION_COMPUTE_UNITS > MAX_TRANSACTION_COMPUTE_UNITS
+    {
+        return false;
     }
-    if node_id as u16 >= swarm_size {
-        panic!("Node id should be less than swarm size.")
+    let transaction = Transaction::new_with_payer(instructions, Some(payer));
+    bincode::serialized_size(&transaction).unwrap() as usize <= PACKET_DATA_SIZE
+}
+
+// The whole batch is rejected as a no-op when one of the extractions is, the remaining ones
+// are then sent on the next cycle.
+async fn send_funding_extractions(
+    ctx: &Context,
+    instructions: Vec<Instruction>,
+    market_label: &str,
+) -> bool {
+    let transaction = Transaction::new_with_payer(&instructions, Some(&ctx.fee_payer.pubkey()));
+    let sig = utils::retry(
+        &ctx.retry,
+        transaction,
+        |t| {
+            let mut tr = t.clone();
+            let recent_blockhash = ctx.connection.get_recent_blockhash()?;
+            tr.partial_sign(&[ctx.fee_payer.as_ref()], recent_blockhash);
+            ctx.connection.send_transaction_with_config(
+                &tr,
+                RpcSendTransactionConfig {
+                    skip_preflight: false,
+                    ..RpcSendTransactionConfig::default()
+                },
+            )
+        },
+        |r| metrics::record_transaction("funding_extraction", market_label, "", no_op_filter(r)),
+    )
+    .await;
+    match sig {
+        Ok(sig) => {
+            println!(
+                "Sent funding extraction transaction {:?} with {} extractions",
+                sig,
+                instructions.len()
+            );
+            sig != Signature::default()
+        }
+        Err(e) => {
+            println!(
+                "Failed funding extraction transaction with {} extractions with {:?}",
+                instructions.len(),
+                e
+            );
+            false
+        }
+    }
+}
+
+fn get_user_account_filters(ctx: &Context) -> RpcProgramAccountsConfig {
+    RpcProgramAccountsConfig {
+        filters: Some(vec![
+            // Filter for user accounts
+            RpcFilterType::Memcmp(Memcmp {
+                offset: 0,
+                bytes: rpc_filter::MemcmpEncodedBytes::Binary(
+                    bs58::encode(&[StateObject::UserAccount as u8]).into_string(),
+                ),
+                encoding: None,
+            }),
+            // Filter for active user accounts (with open positions)
+            RpcFilterType::Memcmp(Memcmp {
+                offset: 34,
+                bytes: rpc_filter::MemcmpEncodedBytes::Binary(bs58::encode(&[1]).into_string()),
+                encoding: None,
+            }),
+            // Filter for user accounts affiliated with the current market
+            RpcFilterType::Memcmp(Memcmp {
+                offset: 35,
+                bytes: rpc_filter::MemcmpEncodedBytes::Binary(ctx.market.to_string()),
+                encoding: None,
+            }),
+        ]),
+        account_config: RpcAccountInfoConfig {
+            encoding: None,
+            data_slice: None,
+            commitment: None,
+        },
+        with_context: None,
     }
-    let configs = get_node_filters(ctx, swarm_size, node_id);
-    let url = ctx.endpoint.clone();
-    let program_id = ctx.program_id;
-    let accounts = stream::iter(configs.into_iter())
-        .then(move |c| account_stream(program_id, url.clone(), c))
-        .flatten();
-    let connection = RpcClient::new(ctx.endpoint.to_owned());
+}
+
+fn get_liquidation_receipts_filters() -> RpcProgramAccountsConfig {
+    RpcProgramAccountsConfig {
+        filters: Some(vec![RpcFilterType::Memcmp(Memcmp {
+            offset: 0,
+            bytes: rpc_filter::MemcmpEncodedBytes::Binary(
+                bs58::encode(&[StateObject::LiquidationReceipts as u8]).into_string(),
+            ),
+            encoding: None,
+        })]),
+        account_config: RpcAccountInfoConfig {
+            encoding: None,
+            data_slice: None,
+            commitment: None,
+        },
+        with_context: None,
+    }
+}
+
+/// Liquidation receipts accounts indexed by the user account they record
+async fn get_liquidation_receipts_accounts(ctx: &Arc<Context>) -> HashMap<Pubkey, Pubkey> {
+    account_stream(
+        Arc::clone(&ctx.connection),
+        ctx.program_id,
+        ctx.retry,
+        get_liquidation_receipts_filters(),
+    )
+    .await
+    .filter_map(|(k, a)| {
+        future::ready(
+            LiquidationReceiptsState::unpack_from_slice(&a.data)
+                .ok()
+                .map(|h| (Pubkey::new(&h.user_account), k)),
+        )
+    })
+    .collect()
+    .await
+}
+
+pub async fn crank_liquidation_cleanup_iteration(
+    ctx: &Arc<Context>,
+    swarm: &Swarm,
+) -> Result<(), CrankError> {
+    swarm.check()?;
+    // The accounts are partitioned locally, every node fetches all the active user accounts
+    let swarm = *swarm;
+    let accounts = account_stream(
+        Arc::clone(&ctx.connection),
+        ctx.program_id,
+        ctx.retry,
+        get_user_account_filters(ctx),
+    )
+    .await
+    .filter(move |(k, _)| future::ready(swarm.is_assigned(k)));
 
     let accounts_mutex = Arc::new(Mutex::new(Box::pin(accounts)));
-    let (market, _) = utils::retry(
-        &connection,
-        |c| get_market(ctx.program_id, ctx.market, &c),
+    let (market, quote_mint) = utils::retry(
+        &ctx.retry,
+        &*ctx.connection,
+        |c| get_market(ctx.program_id, ctx.market, *c),
         |r| r,
     )
-    .await;
+    .await?;
+    // The purge bounties are sent to the associated token account of the fee payer
+    let target_token_account = get_associated_token_address(&ctx.fee_payer.pubkey(), &quote_mint);
+    // Positions can be liquidated at any time, every cycle starts a new epoch
+    let sweep = begin_sweep(ctx, "liquidation_cleanup", |position| match position {
+        Some((epoch, true)) => epoch + 1,
+        Some((epoch, false)) => epoch,
+        None => 0,
+    })?;
+    let receipts_accounts = Arc::new(get_liquidation_receipts_accounts(ctx).await);
     let market = Arc::new(market);
     let mut tasks = Vec::with_capacity(num_cpus::get());
     for _ in 0..tasks.capacity() {
         let task_mutex = Arc::clone(&accounts_mutex);
-        let connection = RpcClient::new(ctx.endpoint.to_owned());
         let c = Arc::clone(&ctx);
         let m = Arc::clone(&market);
+        let s = Arc::clone(&sweep);
+        let r = Arc::clone(&receipts_accounts);
+        let market_label = ctx.market.to_string();
         let t = async move {
             loop {
+                // Stops taking accounts on shutdown, the transactions in flight are still sent
+                if health::is_shutting_down() {
+                    break;
+                }
                 // Can't use if let here due to borrow checker in an async context
                 let next = {
                     let mut f = task_mutex.lock().await;
@@ -601,52 +1335,47 @@ async fn crank_liquidation_cleanup_iteration(ctx: &Arc<Context>, swarm_size: u16
                     break;
                 };
                 let (k, a): (Pubkey, Account) = next.unwrap();
-                println!("Processing funding for {:?}", k);
+                if is_processed(&s, &k) {
+                    continue;
+                }
+                println!("Purging the liquidated positions of {:?}", k);
                 let fee_payer_pk = c.fee_payer.pubkey();
-                let transactions = {
-                    let mut position_offset = UserAccountState::LEN;
-                    let header =
-                        UserAccountState::unpack_from_slice(&a.data[..UserAccountState::LEN])
-                            .unwrap();
-                    let mut cranked_instance_indices: Vec<u8> = vec![0; m.instances.len()];
-                    let mut instructions = vec![];
-                    for position_index in 0..(header.number_of_open_positions as u16) {
-                        let position = OpenPosition::unpack_from_slice(
-                            &a.data[position_offset..position_offset + OpenPosition::LEN],
-                        )
-                        .unwrap();
-                        cranked_instance_indices[position.instance_index as usize] = 1;
-                        let position_info = PositionInfo {
-                            user_account: k,
-                            user_account_owner: c.fee_payer.pubkey(), // This makes sense for the permissionless crank
-                            instance_index: position.instance_index,
-                            side: position.side,
-                        };
-                        instructions.push(close_position(
-                            &m,
-                            &position_info,
-                            0,
-                            0,
-                            position_index,
-                            0,
-                            u64::MAX,
-                            None,
-                            None,
-                        ));
-                        position_offset += OpenPosition::LEN;
+                let positions = match parse_user_account(&a.data) {
+                    Ok((_, p)) => p,
+                    Err(e) => {
+                        println!("Failed to parse user account {:?} with {:?}", k, e);
+                        continue;
                     }
-                    instructions
-                        .into_iter()
-                        .map(|i| Transaction::new_with_payer(&[i], Some(&fee_payer_pk)))
                 };
+                let mut instance_indices = vec![];
+                for p in positions {
+                    if !instance_indices.contains(&p.instance_index) {
+                        instance_indices.push(p.instance_index);
+                    }
+                }
+                let transactions = instance_indices.into_iter().map(|i| {
+                    let instruction = purge_liquidated_positions(
+                        &m,
+                        i,
+                        k,
+                        target_token_account,
+                        r.get(&k).copied(),
+                    );
+                    Transaction::new_with_payer(&[instruction], Some(&fee_payer_pk))
+                });
+                let mut processed = true;
                 for t in transactions {
                     let sig = utils::retry(
+                        &c.retry,
                         t,
                         |t| {
                             let mut tr = t.clone();
-                            let (recent_blockhash, _) = connection.get_recent_blockhash()?;
-                            tr.partial_sign::<Vec<&Keypair>>(&vec![&c.fee_payer], recent_blockhash);
-                            connection.send_transaction_with_config(
+                            let recent_blockhash = c.connection.get_recent_blockhash()?;
+                            tr.partial_sign::<Vec<&Keypair>>(
+                                &vec![c.fee_payer.borrow()],
+                                recent_blockhash,
+                            );
+                            c.connection.send_transaction_with_config(
                                 &tr,
                                 RpcSendTransactionConfig {
                                     skip_preflight: false,
@@ -654,33 +1383,588 @@ async fn crank_liquidation_cleanup_iteration(ctx: &Arc<Context>, swarm_size: u16
                                 },
                             )
                         },
-                        invalid_signature_filter,
+                        |r| {
+                            metrics::record_transaction(
+                                "liquidation_cleanup",
+                                &market_label,
+                                "",
+                                no_op_filter(r),
+                            )
+                        },
                     )
                     .await;
-                    println!("Sent liquidation cleanup transaction {:?}", sig);
+                    match sig {
+                        Ok(sig) => println!("Sent liquidation cleanup transaction {:?}", sig),
+                        Err(e) => {
+                            println!("Failed liquidation cleanup for {:?} with {:?}", k, e);
+                            processed = false;
+                        }
+                    }
+                }
+                if processed {
+                    mark_processed(&s, &[k]);
                 }
             }
         };
         tasks.push(task::spawn(t))
     }
+    let mut completed = true;
     for t in tasks {
-        t.await.unwrap();
+        if let Err(e) = t.await {
+            println!("A liquidation cleanup task panicked with {:?}", e);
+            completed = false;
+        }
+    }
+    complete_sweep(&sweep, completed);
+    Ok(())
+}
+
+fn get_insurance_fund(
+    market: &MarketContext,
+    connection: &dyn RpcBackend,
+) -> Result<(MarketState, i64), CrankError> {
+    let market_data = connection
+        .get_account_data(&market.market_account)
+        .map_err(|_| CrankError::ConnectionError)?;
+    let market_state =
+        MarketState::unpack_from_slice(&market_data).map_err(|_| CrankError::InvalidMarketState)?;
+    let vault_data = connection
+        .get_account_data(&market.market_vault)
+        .map_err(|_| CrankError::ConnectionError)?;
+    let vault_balance = spl_token::state::Account::unpack(&vault_data)
+        .map_err(|_| CrankError::InvalidMarketState)?
+        .amount;
+    let insurance_fund = market_state.get_insurance_fund(vault_balance);
+    Ok((market_state, insurance_fund))
+}
+
+// Returns the open position with the highest return on collateral, which ranks the most profitable,
+// highest leverage positions first. Only the positions on the side of the net open interest can be
+// deleveraged.
+fn select_deleveraging_candidate(
+    market_state: &MarketState,
+    user_accounts: &[(Pubkey, Account)],
+) -> Option<(usize, u16, OpenPosition)> {
+    let side = market_state.get_deleveraging_side()?;
+    let mut best: Option<(u128, (usize, u16, OpenPosition))> = None;
+    for (account_index, (_, a)) in user_accounts.iter().enumerate() {
+        let positions = match parse_user_account(&a.data) {
+            Ok((_, p)) => p,
+            Err(_) => continue,
+        };
+        for (position_index, position) in positions.into_iter().enumerate() {
+            let position_index = position_index as u16;
+            if position.side != side {
+                continue;
+            }
+            let score = match compute_deleveraging_score(
+                market_state,
+                position.collateral,
+                position.v_coin_amount,
+                position.v_pc_amount,
+                position.side,
+            ) {
+                Ok(Some(s)) => s,
+                _ => continue,
+            };
+            if best.as_ref().map(|(s, _)| score > *s).unwrap_or(true) {
+                best = Some((score, (account_index, position_index, position)));
+            }
+        }
     }
+    best.map(|(_, c)| c)
+}
+
+pub async fn crank_auto_deleverage_iteration(ctx: &Arc<Context>) -> Result<(), CrankError> {
+    let connection = &ctx.connection;
+    let (market, _) = utils::retry(
+        &ctx.retry,
+        &*ctx.connection,
+        |c| get_market(ctx.program_id, ctx.market, *c),
+        |r| r,
+    )
+    .await?;
+    loop {
+        let (market_state, insurance_fund) = utils::retry(
+            &ctx.retry,
+            &**connection,
+            |c| get_insurance_fund(&market, *c),
+            |r| r,
+        )
+        .await?;
+        if insurance_fund >= 0 {
+            break;
+        }
+        println!(
+            "The insurance fund is depleted by {:?}, deleveraging",
+            -insurance_fund
+        );
+        let user_accounts: Vec<(Pubkey, Account)> = account_stream(
+            Arc::clone(connection),
+            ctx.program_id,
+            ctx.retry,
+            get_user_account_filters(ctx),
+        )
+        .await
+        .collect()
+        .await;
+        let (account_index, position_index, position) = match select_deleveraging_candidate(
+            &market_state,
+            &user_accounts,
+        ) {
+            Some(c) => c,
+            None => {
+                println!("No profitable position left to deleverage, the market remains in reduce-only mode");
+                break;
+            }
+        };
+        let (k, a) = &user_accounts[account_index];
+        let k = *k;
+        let (header, positions) = parse_user_account(&a.data)?;
+        let mut instructions = vec![];
+        if header.last_funding_offset != market_state.funding_history_offset {
+            let mut instance_indices = vec![];
+            for p in positions {
+                if !instance_indices.contains(&p.instance_index) {
+                    instance_indices.push(p.instance_index);
+                }
+            }
+            for i in instance_indices {
+                instructions.push(extract_funding(&market, i, k, None));
+            }
+        }
+        instructions.push(auto_deleverage(
+            &market,
+            position.instance_index,
+            k,
+            position_index,
+        ));
+        let transaction = Transaction::new_with_payer(&instructions, Some(&ctx.fee_payer.pubkey()));
+        let sig = utils::retry(
+            &ctx.retry,
+            transaction,
+            |t| {
+                let mut tr = t.clone();
+                let recent_blockhash = connection.get_recent_blockhash()?;
+                tr.partial_sign(&[ctx.fee_payer.as_ref()], recent_blockhash);
+                connection.send_and_confirm_transaction(&tr)
+            },
+            |r| {
+                metrics::record_transaction(
+                    "auto_deleverage",
+                    &ctx.market.to_string(),
+                    &position.instance_index.to_string(),
+                    no_op_filter(r),
+                )
+            },
+        )
+        .await?;
+        println!(
+            "Sent auto-deleveraging transaction for position {:?} of user account {:?} with signature {:?}",
+            position_index, k, sig
+        );
+        if sig == Signature::default() {
+            // The position could not be deleveraged, wait for the next cycle
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Adds a page to the instances whose ratio of used slots reached the threshold. The page is
+// created and attached in the same transaction.
+pub async fn crank_page_provisioning_iteration(
+    ctx: &Arc<Context>,
+    provisioning: &Provisioning,
+) -> Result<(), CrankError> {
+    // Pages may have been added since the last iteration
+    let (market, _) = utils::retry(
+        &ctx.retry,
+        &*ctx.connection,
+        |c| get_market(ctx.program_id, ctx.market, *c),
+        |r| r,
+    )
+    .await?;
+    let mut keys = vec![];
+    for i in &market.instances {
+        keys.push(i.instance_account);
+        keys.extend(&i.memory_pages);
+    }
+    let accounts = fetch_accounts(ctx, keys)
+        .await?
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+    let market_label = ctx.market.to_string();
+    for (i, instance) in market.instances.iter().enumerate() {
+        let instance_label = i.to_string();
+        let instance_data = accounts
+            .get(&instance.instance_account)
+            .ok_or(CrankError::AccountNotFound(instance.instance_account))?;
+        let full_ratio = match get_instance_full_ratio(instance_data, &accounts) {
+            Ok(r) => r,
+            Err(e) => {
+                println!(
+                    "Failed to compute the ratio of used slots of instance {:?} with {:?}",
+                    i, e
+                );
+                continue;
+            }
+        };
+        if full_ratio < provisioning.threshold {
+            continue;
+        }
+        if instance.memory_pages.len() >= get_page_capacity(instance_data) {
+            println!(
+                "Instance {:?} is {:.1}% full but has no room for an additional page",
+                i,
+                full_ratio * 100.
+            );
+            continue;
+        }
+        if provisioning.dry_run {
+            println!(
+                "Dry run: would add a page of {:?} slots to instance {:?}, which is {:.1}% full",
+                provisioning.page_slots,
+                i,
+                full_ratio * 100.
+            );
+            continue;
+        }
+        let space = TAG_SIZE + (provisioning.page_slots as usize) * SLOT_SIZE;
+        let lamports = utils::retry(
+            &ctx.retry,
+            space,
+            |s| ctx.connection.get_minimum_balance_for_rent_exemption(*s),
+            |r| r,
+        )
+        .await?;
+        let page = Keypair::new();
+        let instructions = [
+            create_account(
+                &ctx.fee_payer.pubkey(),
+                &page.pubkey(),
+                lamports,
+                space as u64,
+                &ctx.program_id,
+            ),
+            add_page(&market, i as u8, page.pubkey()),
+        ];
+        let transaction = Transaction::new_with_payer(&instructions, Some(&ctx.fee_payer.pubkey()));
+        let sig = utils::retry(
+            &ctx.retry,
+            transaction,
+            |t| {
+                let mut tr = t.clone();
+                let recent_blockhash = ctx.connection.get_recent_blockhash()?;
+                tr.partial_sign(&[ctx.fee_payer.as_ref(), &page], recent_blockhash);
+                ctx.connection.send_and_confirm_transaction(&tr)
+            },
+            |r| metrics::record_transaction("page_provisioning", &market_label, &instance_label, r),
+        )
+        .await?;
+        println!(
+            "Added page {:?} to instance {:?}, which was {:.1}% full, with signature {:?}",
+            page.pubkey(),
+            i,
+            full_ratio * 100.,
+            sig
+        );
+    }
+    Ok(())
+}
+
+// Moves the nodes of the sparsest page of each instance into the other pages, so that new
+// allocations fill the dense pages and the sparse ones are eventually emptied
+pub async fn crank_defragmentation_iteration(ctx: &Arc<Context>) -> Result<(), CrankError> {
+    // Pages may have been added since the last iteration
+    let (market, _) = utils::retry(
+        &ctx.retry,
+        &*ctx.connection,
+        |c| get_market(ctx.program_id, ctx.market, *c),
+        |r| r,
+    )
+    .await?;
+    let mut keys = vec![];
+    for i in &market.instances {
+        keys.push(i.instance_account);
+        keys.extend(&i.memory_pages);
+    }
+    let accounts = fetch_accounts(ctx, keys)
+        .await?
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+    let market_label = ctx.market.to_string();
+    for (i, instance) in market.instances.iter().enumerate() {
+        let instance_label = i.to_string();
+        let page_index = match accounts
+            .get(&instance.instance_account)
+            .ok_or(CrankError::AccountNotFound(instance.instance_account))
+            .and_then(|d| get_defragmentation_page(d, &accounts, DEFRAGMENTATION_MAX_USED_RATIO))
+        {
+            Ok(Some(p)) => p,
+            Ok(None) => continue,
+            Err(e) => {
+                println!(
+                    "Failed to select the page to defragment of instance {:?} with {:?}",
+                    i, e
+                );
+                continue;
+            }
+        };
+        let instruction =
+            defragment_page(&market, i as u8, page_index, 0, DEFRAGMENTATION_MAX_MOVES);
+        let transaction =
+            Transaction::new_with_payer(&[instruction], Some(&ctx.fee_payer.pubkey()));
+        let sig = utils::retry(
+            &ctx.retry,
+            transaction,
+            |t| {
+                let mut tr = t.clone();
+                let recent_blockhash = ctx.connection.get_recent_blockhash()?;
+                tr.partial_sign(&[ctx.fee_payer.as_ref()], recent_blockhash);
+                ctx.connection.send_and_confirm_transaction(&tr)
+            },
+            |r| {
+                metrics::record_transaction(
+                    "defragmentation",
+                    &market_label,
+                    &instance_label,
+                    no_op_filter(r),
+                )
+            },
+        )
+        .await?;
+        println!(
+            "Defragmented page {:?} of instance {:?} with signature {:?}",
+            page_index, i, sig
+        );
+    }
+    Ok(())
+}
+
+// Rebalances the market when its bias exceeds the threshold, or closes all the positions of the
+// rebalancing book once the traders' imbalance is gone.
+pub async fn crank_rebalancing_iteration(
+    ctx: &Arc<Context>,
+    user_account: &Pubkey,
+    rebalancing: &Rebalancing,
+) -> Result<(), CrankError> {
+    // Pages may have been added since the last iteration
+    let (market, _) = utils::retry(
+        &ctx.retry,
+        &*ctx.connection,
+        |c| get_market(ctx.program_id, ctx.market, *c),
+        |r| r,
+    )
+    .await?;
+    let accounts = fetch_accounts(
+        ctx,
+        vec![market.market_account, market.oracle_account, *user_account],
+    )
+    .await?
+    .into_iter()
+    .collect::<HashMap<_, _>>();
+    let get_data = |k: &Pubkey| accounts.get(k).ok_or(CrankError::AccountNotFound(*k));
+    let market_state = MarketState::unpack_from_slice(get_data(&market.market_account)?)
+        .map_err(|_| CrankError::InvalidMarketState)?;
+    let oracle_price = get_oracle_price(
+        get_data(&market.oracle_account)?,
+        market_state.coin_decimals,
+        market_state.quote_decimals,
+    )
+    .map_err(|_| CrankError::InvalidMarketState)?;
+    let (header, book) = parse_user_account(get_data(user_account)?)?;
+
+    let market_label = ctx.market.to_string();
+    let unrealized_pnl = get_unrealized_pnl(&market_state, &book)?;
+    metrics::REBALANCING_PNL
+        .with_label_values(&[&market_label, "unrealized"])
+        .set(unrealized_pnl);
+    println!(
+        "Rebalancing book of {:?} positions with an unrealized PnL of {:?}",
+        book.len(),
+        unrealized_pnl
+    );
+
+    // Funding has to be extracted before trading with the user account
+    let mut funding_instructions = vec![];
+    if header.last_funding_offset != market_state.funding_history_offset {
+        let mut instance_indices = book.iter().map(|p| p.instance_index).collect::<Vec<_>>();
+        instance_indices.sort_unstable();
+        instance_indices.dedup();
+        for i in instance_indices {
+            funding_instructions.push(extract_funding(&market, i, *user_account, None));
+        }
+    }
+
+    match decide(rebalancing, &market_state, oracle_price, &book)? {
+        RebalancingAction::Nothing => {}
+        RebalancingAction::OverBudget { collateral } => {
+            println!(
+                "Rebalancing requires a collateral of {:?} which exceeds the remaining budget",
+                collateral
+            );
+        }
+        RebalancingAction::Rebalance { collateral } => {
+            let mut instructions = funding_instructions;
+            instructions.push(rebalance(
+                &market,
+                *user_account,
+                ctx.fee_payer.pubkey(),
+                rebalancing.instance_index,
+                collateral,
+            ));
+            let sig = send_rebalancing_transaction(
+                ctx,
+                &instructions,
+                &market_label,
+                &rebalancing.instance_index.to_string(),
+            )
+            .await?;
+            println!(
+                "Rebalanced the market with a collateral of {:?} with signature {:?}",
+                collateral, sig
+            );
+        }
+        RebalancingAction::Unwind => {
+            // Closing a position moves the last one in its place
+            for (position_index, position) in book.iter().enumerate().rev() {
+                let position_info = PositionInfo {
+                    user_account: *user_account,
+                    user_account_owner: ctx.fee_payer.pubkey(),
+                    instance_index: position.instance_index,
+                    side: position.side,
+                };
+                let mut instructions = std::mem::take(&mut funding_instructions);
+                instructions.push(close_position(
+                    &market,
+                    &position_info,
+                    position.collateral,
+                    position.v_coin_amount,
+                    position_index as u16,
+                    0,
+                    u64::MAX,
+                    None,
+                    None,
+                ));
+                let sig = send_rebalancing_transaction(
+                    ctx,
+                    &instructions,
+                    &market_label,
+                    &position.instance_index.to_string(),
+                )
+                .await?;
+                println!(
+                    "Unwound rebalancing position {:?} with signature {:?}",
+                    position_index, sig
+                );
+            }
+            // Payouts are credited to the balance of the user account
+            let data = utils::retry(
+                &ctx.retry,
+                user_account,
+                |k| ctx.connection.get_account_data(k),
+                |r| r,
+            )
+            .await?;
+            let (unwound_header, _) = parse_user_account(&data)?;
+            let collateral = book.iter().map(|p| p.collateral).sum::<u64>();
+            let realized_pnl =
+                (unwound_header.balance as i64) - (header.balance as i64) - (collateral as i64);
+            metrics::REBALANCING_PNL
+                .with_label_values(&[&market_label, "realized"])
+                .add(realized_pnl);
+            println!(
+                "Unwound the rebalancing book with a realized PnL of {:?}",
+                realized_pnl
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn send_rebalancing_transaction(
+    ctx: &Arc<Context>,
+    instructions: &[Instruction],
+    market_label: &str,
+    instance_label: &str,
+) -> Result<Signature, CrankError> {
+    let transaction = Transaction::new_with_payer(instructions, Some(&ctx.fee_payer.pubkey()));
+    utils::retry(
+        &ctx.retry,
+        transaction,
+        |t| {
+            let mut tr = t.clone();
+            let recent_blockhash = ctx.connection.get_recent_blockhash()?;
+            tr.partial_sign(&[ctx.fee_payer.as_ref()], recent_blockhash);
+            ctx.connection.send_and_confirm_transaction(&tr)
+        },
+        |r| metrics::record_transaction("rebalancing", market_label, instance_label, r),
+    )
+    .await
+}
+
+fn get_account_index(transaction: &Transaction, account: &Pubkey) -> usize {
+    transaction
+        .message
+        .account_keys
+        .iter()
+        .position(|k| k == account)
+        .unwrap()
+}
+
+// Measures the reward paid to the target token account once the crank transaction is confirmed
+fn track_reward(
+    connection: Arc<dyn RpcBackend>,
+    signature: Signature,
+    target_index: usize,
+    expected_reward: u64,
+    service: &'static str,
+    market_label: String,
+    instance_label: String,
+) {
+    task::spawn_blocking(move || {
+        let realized_reward = get_token_balance_change(&*connection, &signature, target_index);
+        if let Ok(r) = realized_reward {
+            println!(
+                "Expected a {} reward of {} for {:?}, realized {}",
+                service, expected_reward, signature, r
+            );
+        }
+        match realized_reward {
+            Ok(reward) if reward > 0 => {
+                metrics::REWARDS_EARNED
+                    .with_label_values(&[service, &market_label, &instance_label])
+                    .inc_by(reward as u64);
+                if service == "garbage_collection" {
+                    metrics::GC_SLOTS_FREED
+                        .with_label_values(&[&market_label, &instance_label])
+                        .inc_by((reward as u64) / ALLOCATION_FEE);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => println!("Failed to fetch the reward of {:?} with {:?}", signature, e),
+        }
+    });
 }
 
 async fn account_stream(
+    connection: Arc<dyn RpcBackend>,
     program_id: Pubkey,
-    url: String,
+    retry_policy: RetryPolicy,
     c: RpcProgramAccountsConfig,
 ) -> Iter<IntoIter<(Pubkey, Account)>> {
-    let k: Vec<(Pubkey, Account)> = utils::retry(
+    let k = utils::retry(
+        &retry_policy,
         c,
-        move |conf| {
-            let conn = RpcClient::new(url.clone());
-            conn.get_program_accounts_with_config(&program_id, conf.to_owned())
-        },
+        move |conf| connection.get_program_accounts_with_config(&program_id, conf.to_owned()),
         |r| r,
     )
-    .await;
+    .await
+    .unwrap_or_else(|e| {
+        // The accounts are skipped until the next cycle
+        println!("Failed to fetch the program accounts with {:?}", e);
+        vec![]
+    });
     stream::iter(k)
 }
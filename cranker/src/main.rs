@@ -1,10 +1,20 @@
-use clap::{value_t_or_exit, App, Arg, SubCommand};
-use perps_crank::Context;
+use clap::{value_t_or_exit, App, Arg, ArgMatches, SubCommand};
+use perps_crank::{
+    backend::MultiEndpointClient,
+    checkpoint::CheckpointStore,
+    config::Config,
+    provisioning::Provisioning,
+    rebalancing::Rebalancing,
+    swarm::Swarm,
+    utils::{compute_websocket_url, RetryPolicy},
+    Context, Periods, Service,
+};
 use solana_clap_utils::{
     fee_payer::{fee_payer_arg, FEE_PAYER_ARG},
     input_parsers::{keypair_of, pubkey_of},
     input_validators::is_pubkey,
 };
+use std::{net::SocketAddr, path::Path, sync::Arc};
 
 fn main() {
     let default_threads = num_cpus::get().to_string();
@@ -17,6 +27,117 @@ fn main() {
         .subcommand(
             SubCommand::with_name("garbage-collect").about("Crank garbage collection operations"),
         )
+        .subcommand(
+            SubCommand::with_name("defragment")
+                .about("Move the nodes of sparse memory pages into the other pages"),
+        )
+        .subcommand(
+            SubCommand::with_name("auto-deleverage")
+                .about("Deleverage profitable positions when the insurance fund is depleted (market admin only)"),
+        )
+        .subcommand(
+            SubCommand::with_name("page-provisioning")
+                .about("Add memory pages to the instances which are running out of space (market admin only)")
+                .arg(
+                    Arg::with_name("threshold")
+                        .long("threshold")
+                        .help("The ratio of used slots of an instance above which a page is added")
+                        .takes_value(true)
+                        .default_value("0.8")
+                        .validator(|s| match s.parse::<f64>() {
+                            Ok(t) if t > 0. && t <= 1. => Ok(()),
+                            _ => Err(String::from("The threshold must be a number in (0, 1]")),
+                        }),
+                )
+                .arg(
+                    Arg::with_name("page_slots")
+                        .long("page-slots")
+                        .help("The number of slots of the added pages")
+                        .takes_value(true)
+                        .default_value("20000")
+                        .validator(|s| match s.parse::<u64>() {
+                            Ok(n) if n > 0 => Ok(()),
+                            _ => Err(String::from("The number of page slots must be a positive integer")),
+                        }),
+                )
+                .arg(
+                    Arg::with_name("dry_run")
+                        .long("dry-run")
+                        .help("Only log the pages which would be added"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rebalance")
+                .about("Take the opposite side of the net open interest when the market is unbalanced (market admin only)")
+                .arg(
+                    Arg::with_name("user_account")
+                        .long("user-account")
+                        .help("The user account holding the rebalancing positions, owned by the fee payer")
+                        .takes_value(true)
+                        .validator(is_pubkey)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("max_collateral")
+                        .long("max-collateral")
+                        .help("The total collateral of the rebalancing positions, in quote token with precision")
+                        .takes_value(true)
+                        .required(true)
+                        .validator(|s| {
+                            s.parse::<u64>()
+                                .map(|_| ())
+                                .map_err(|_| String::from("The collateral budget must be an integer"))
+                        }),
+                )
+                .arg(
+                    Arg::with_name("max_leverage")
+                        .long("max-leverage")
+                        .help("The leverage of the rebalancing positions")
+                        .takes_value(true)
+                        .default_value("1")
+                        .validator(|s| {
+                            s.parse::<f64>()
+                                .map(|_| ())
+                                .map_err(|_| String::from("The leverage must be a number"))
+                        }),
+                )
+                .arg(
+                    Arg::with_name("threshold")
+                        .long("threshold")
+                        .help("The relative difference between the oracle and equilibrium prices above which the market is rebalanced")
+                        .takes_value(true)
+                        .default_value("0.1")
+                        .validator(|s| {
+                            s.parse::<f64>()
+                                .map(|_| ())
+                                .map_err(|_| String::from("The threshold must be a number"))
+                        }),
+                )
+                .arg(
+                    Arg::with_name("unwind_threshold")
+                        .long("unwind-threshold")
+                        .help("The difference between the oracle and equilibrium prices, without the rebalancing positions, below which these are closed")
+                        .takes_value(true)
+                        .default_value("0.02")
+                        .validator(|s| {
+                            s.parse::<f64>()
+                                .map(|_| ())
+                                .map_err(|_| String::from("The unwind threshold must be a number"))
+                        }),
+                )
+                .arg(
+                    Arg::with_name("instance_index")
+                        .long("instance-index")
+                        .help("The instance on which the rebalancing positions are opened")
+                        .takes_value(true)
+                        .default_value("0")
+                        .validator(|s| {
+                            s.parse::<u8>()
+                                .map(|_| ())
+                                .map_err(|_| String::from("The instance index must be an integer"))
+                        }),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("funding-extraction")
                 .about("Crank funding extraction operations")
@@ -43,6 +164,18 @@ fn main() {
                                 String::from("The integer node identifer  must be an integer")
                             })
                         }),
+                )
+                .arg(
+                    Arg::with_name("redundancy")
+                        .long("redundancy")
+                        .help("The number of nodes of the swarm covering each user account")
+                        .takes_value(true)
+                        .default_value("1")
+                        .validator(|s| {
+                            s.parse::<u32>()
+                                .map(|_| ())
+                                .map_err(|_| String::from("The redundancy must be an integer"))
+                        }),
                 ),
         )
         .subcommand(
@@ -71,13 +204,38 @@ fn main() {
                                 String::from("The integer node identifer  must be an integer")
                             })
                         }),
+                )
+                .arg(
+                    Arg::with_name("redundancy")
+                        .long("redundancy")
+                        .help("The number of nodes of the swarm covering each user account")
+                        .takes_value(true)
+                        .default_value("1")
+                        .validator(|s| {
+                            s.parse::<u32>()
+                                .map(|_| ())
+                                .map_err(|_| String::from("The redundancy must be an integer"))
+                        }),
                 ),
         )
         .arg(
             Arg::with_name("url")
                 .short("u")
                 .long("url")
-                .help("A Solana RPC endpoint url")
+                .help("A Solana RPC endpoint url, can be repeated to route requests to the healthiest endpoint")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("broadcast")
+                .long("broadcast")
+                .help("Send the transactions through all the RPC endpoints"),
+        )
+        .arg(
+            Arg::with_name("ws_url")
+                .long("ws-url")
+                .help("A Solana websocket endpoint url, derived from the RPC endpoint url by default")
                 .takes_value(true),
         )
         .arg(fee_payer_arg())
@@ -88,7 +246,7 @@ fn main() {
                 .help("The pubkey of the Audaces Protocol program")
                 .takes_value(true)
                 .validator(is_pubkey)
-                .required(true),
+                .required_unless("config"),
         )
         .arg(
             Arg::with_name("market")
@@ -97,7 +255,7 @@ fn main() {
                 .help("The pubkey of the Audaces Protocol market to interact with")
                 .takes_value(true)
                 .validator(is_pubkey)
-                .required(true),
+                .required_unless("config"),
         )
         .arg(
             Arg::with_name("threads")
@@ -107,55 +265,156 @@ fn main() {
                 .takes_value(true)
                 .default_value(&default_threads),
         )
+        .arg(
+            Arg::with_name("metrics_address")
+                .long("metrics-address")
+                .help("The address on which to serve Prometheus metrics, for instance 0.0.0.0:9090")
+                .takes_value(true)
+                .validator(|s| {
+                    s.parse::<SocketAddr>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("The metrics address must be a socket address"))
+                }),
+        )
+        .arg(
+            Arg::with_name("max_attempts")
+                .long("max-attempts")
+                .help("The maximum number of attempts of an RPC operation before giving up, 0 to retry indefinitely")
+                .takes_value(true)
+                .validator(|s| {
+                    s.parse::<u32>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("The maximum number of attempts must be an integer"))
+                }),
+        )
+        .arg(
+            Arg::with_name("sol_price")
+                .long("sol-price")
+                .help("The price of SOL in quote token, used to skip liquidations and garbage collections which cost more than their reward")
+                .takes_value(true)
+                .validator(|s| {
+                    s.parse::<f64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("The price of SOL must be a number"))
+                }),
+        )
+        .arg(
+            Arg::with_name("checkpoint_path")
+                .long("checkpoint-path")
+                .help("The directory of the store in which the funding extraction and liquidation cleanup sweeps are checkpointed, to resume them after a restart")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .help("A TOML or YAML configuration file describing the markets and services to crank")
+                .takes_value(true)
+                .conflicts_with("market"),
+        )
         .get_matches();
-    let endpoint = matches
-        .value_of("url")
-        .unwrap_or("https://solana-api.projectserum.com");
+    if let Some(path) = matches.value_of("config") {
+        let config = Config::load(Path::new(path)).expect("Invalid configuration file");
+        let metrics_address = config.metrics_address;
+        let services = config.into_services().expect("Invalid configuration file");
+        if let Err(e) = perps_crank::run(services, metrics_address) {
+            panic!("The cranker stopped with {}", e);
+        }
+        return;
+    }
+    let urls = matches
+        .values_of("url")
+        .map(|v| v.map(String::from).collect())
+        .unwrap_or_else(|| vec![String::from("https://solana-api.projectserum.com")]);
     let program_id = pubkey_of(&matches, "program_id").unwrap();
     let market = pubkey_of(&matches, "market").expect("Invalid market Pubkey");
     let fee_payer = keypair_of(&matches, FEE_PAYER_ARG.name).unwrap();
+    let websocket_url = matches
+        .value_of("ws_url")
+        .map(String::from)
+        .unwrap_or_else(|| compute_websocket_url(&urls[0]));
     let num_threads = value_t_or_exit!(matches.value_of("threads"), usize);
+    let metrics_address = matches
+        .value_of("metrics_address")
+        .map(|s| s.parse::<SocketAddr>().unwrap());
+    let mut retry = RetryPolicy::default();
+    if let Some(m) = matches.value_of("max_attempts") {
+        retry.max_attempts = Some(m.parse::<u32>().unwrap()).filter(|m| *m != 0);
+    }
     let context = Context {
         market,
-        fee_payer,
-        endpoint: String::from(endpoint),
+        fee_payer: Arc::new(fee_payer),
+        connection: Arc::new(MultiEndpointClient::new(
+            urls,
+            matches.is_present("broadcast"),
+        )),
+        websocket_url,
         program_id,
         num_threads,
+        periods: Periods::default(),
+        retry,
+        sol_price: matches
+            .value_of("sol_price")
+            .map(|s| s.parse::<f64>().unwrap()),
+        checkpoints: matches.value_of("checkpoint_path").map(|p| {
+            CheckpointStore::open(Path::new(p)).expect("Failed to open the checkpoint store")
+        }),
     };
-    match matches.subcommand() {
-        ("liquidate", _) => context.crank_liquidation(),
-        ("funding", _) => context.crank_funding(),
-        ("garbage-collect", _) => context.garbage_collect(),
-        ("funding-extraction", m) => {
-            let swarm_size = m
-                .unwrap()
-                .value_of("swarm_size")
-                .unwrap()
-                .parse::<u16>()
-                .unwrap();
-            let node_id = m
-                .unwrap()
-                .value_of("node_id")
-                .unwrap()
-                .parse::<u8>()
-                .unwrap();
-            context.crank_funding_extraction(swarm_size, node_id);
+    let service = match matches.subcommand() {
+        ("liquidate", _) => Service::Liquidate,
+        ("funding", _) => Service::Funding,
+        ("garbage-collect", _) => Service::GarbageCollect,
+        ("auto-deleverage", _) => Service::AutoDeleverage,
+        ("defragment", _) => Service::Defragment,
+        ("page-provisioning", m) => {
+            let m = m.unwrap();
+            Service::PageProvisioning {
+                provisioning: Provisioning {
+                    threshold: m.value_of("threshold").unwrap().parse::<f64>().unwrap(),
+                    page_slots: m.value_of("page_slots").unwrap().parse::<u64>().unwrap(),
+                    dry_run: m.is_present("dry_run"),
+                },
+            }
         }
-        ("liquidation-cleanup", m) => {
-            let swarm_size = m
-                .unwrap()
-                .value_of("swarm_size")
-                .unwrap()
-                .parse::<u16>()
-                .unwrap();
-            let node_id = m
-                .unwrap()
-                .value_of("node_id")
-                .unwrap()
-                .parse::<u8>()
-                .unwrap();
-            context.crank_liquidation_cleanup(swarm_size, node_id);
+        ("rebalance", m) => {
+            let m = m.unwrap();
+            let rebalancing = Rebalancing {
+                threshold: m.value_of("threshold").unwrap().parse::<f64>().unwrap(),
+                unwind_threshold: m
+                    .value_of("unwind_threshold")
+                    .unwrap()
+                    .parse::<f64>()
+                    .unwrap(),
+                max_leverage: m.value_of("max_leverage").unwrap().parse::<f64>().unwrap(),
+                max_collateral: m
+                    .value_of("max_collateral")
+                    .unwrap()
+                    .parse::<u64>()
+                    .unwrap(),
+                instance_index: m.value_of("instance_index").unwrap().parse::<u8>().unwrap(),
+            };
+            rebalancing.check().expect("Invalid rebalancing settings");
+            Service::Rebalance {
+                user_account: pubkey_of(m, "user_account").unwrap(),
+                rebalancing,
+            }
         }
+        ("funding-extraction", m) => Service::FundingExtraction {
+            swarm: swarm_of(m.unwrap()),
+        },
+        ("liquidation-cleanup", m) => Service::LiquidationCleanup {
+            swarm: swarm_of(m.unwrap()),
+        },
         _ => panic!("Invalid subcommand"),
+    };
+    if let Err(e) = perps_crank::run(vec![(Arc::new(context), service)], metrics_address) {
+        panic!("The cranker stopped with {}", e);
     }
 }
+
+fn swarm_of(matches: &ArgMatches) -> Swarm {
+    let parse = |name| matches.value_of(name).unwrap().parse::<u16>().unwrap();
+    Swarm::new(parse("swarm_size"), parse("node_id"), parse("redundancy")).expect(
+        "The node id must be less than the swarm size, and the redundancy at most the swarm size",
+    )
+}
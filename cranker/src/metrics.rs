@@ -0,0 +1,271 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use audaces_protocol::utils::get_market_data;
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_gauge_vec, register_int_counter_vec, register_int_gauge_vec, Encoder, GaugeVec,
+    IntCounterVec, IntGaugeVec, TextEncoder,
+};
+use solana_client::client_error::ClientError;
+use solana_program::pubkey::Pubkey;
+use solana_sdk::{native_token::lamports_to_sol, signature::Signature};
+use tokio::{task, time::interval};
+
+use crate::{backend::RpcBackend, error::CrankError, health};
+
+const METRICS_SAMPLING_PERIOD: u64 = 10_000;
+
+lazy_static! {
+    pub static ref TRANSACTIONS_SENT: IntCounterVec = register_int_counter_vec!(
+        "perps_crank_transactions_sent_total",
+        "Number of transactions sent",
+        &["service", "market", "instance"]
+    )
+    .unwrap();
+    pub static ref TRANSACTIONS_CONFIRMED: IntCounterVec = register_int_counter_vec!(
+        "perps_crank_transactions_confirmed_total",
+        "Number of transactions accepted by the RPC node",
+        &["service", "market", "instance"]
+    )
+    .unwrap();
+    pub static ref TRANSACTIONS_FAILED: IntCounterVec = register_int_counter_vec!(
+        "perps_crank_transactions_failed_total",
+        "Number of transactions which failed and have to be retried",
+        &["service", "market", "instance"]
+    )
+    .unwrap();
+    pub static ref NOP_REJECTIONS: IntCounterVec = register_int_counter_vec!(
+        "perps_crank_nop_rejections_total",
+        "Number of transactions rejected in preflight because there was nothing to crank",
+        &["service", "market", "instance"]
+    )
+    .unwrap();
+    pub static ref REWARDS_EARNED: IntCounterVec = register_int_counter_vec!(
+        "perps_crank_rewards_earned_total",
+        "Cranking rewards earned, in quote token with precision",
+        &["service", "market", "instance"]
+    )
+    .unwrap();
+    pub static ref GC_SLOTS_FREED: IntCounterVec = register_int_counter_vec!(
+        "perps_crank_gc_slots_freed_total",
+        "Number of memory slots freed by garbage collection",
+        &["market", "instance"]
+    )
+    .unwrap();
+    pub static ref UNPROFITABLE_SKIPS: IntCounterVec = register_int_counter_vec!(
+        "perps_crank_unprofitable_skips_total",
+        "Number of transactions not sent because their expected reward did not cover their cost",
+        &["service", "market", "instance"]
+    )
+    .unwrap();
+    pub static ref SERVICE_RESTARTS: IntCounterVec = register_int_counter_vec!(
+        "perps_crank_service_restarts_total",
+        "Number of times a service was restarted after a panic",
+        &["service", "market"]
+    )
+    .unwrap();
+    pub static ref REBALANCING_PNL: IntGaugeVec = register_int_gauge_vec!(
+        "perps_crank_rebalancing_pnl",
+        "Realized and unrealized profit of the rebalancing book, in quote token with precision",
+        &["market", "kind"]
+    )
+    .unwrap();
+    pub static ref CYCLE_DURATION: GaugeVec = register_gauge_vec!(
+        "perps_crank_cycle_duration_seconds",
+        "Duration of the last sweep over all user accounts",
+        &["service", "market"]
+    )
+    .unwrap();
+    pub static ref RPC_LATENCY: GaugeVec = register_gauge_vec!(
+        "perps_crank_rpc_latency_milliseconds",
+        "Moving average of the latency of an RPC endpoint",
+        &["endpoint"]
+    )
+    .unwrap();
+    pub static ref RPC_ERROR_RATE: GaugeVec = register_gauge_vec!(
+        "perps_crank_rpc_error_rate",
+        "Moving average of the ratio of failed calls to an RPC endpoint",
+        &["endpoint"]
+    )
+    .unwrap();
+    pub static ref FEE_PAYER_BALANCE: GaugeVec = register_gauge_vec!(
+        "perps_crank_fee_payer_balance_sol",
+        "Balance of the fee payer",
+        &["fee_payer"]
+    )
+    .unwrap();
+    pub static ref INSURANCE_FUND: IntGaugeVec = register_int_gauge_vec!(
+        "perps_market_insurance_fund",
+        "Insurance fund of the market, in quote token with precision",
+        &["market"]
+    )
+    .unwrap();
+    pub static ref OPEN_INTEREST: IntGaugeVec = register_int_gauge_vec!(
+        "perps_market_open_interest_v_coin",
+        "Open interest of the market in virtual coin, with precision",
+        &["market", "side"]
+    )
+    .unwrap();
+    pub static ref PRICE: GaugeVec = register_gauge_vec!(
+        "perps_market_price",
+        "Mark, oracle and equilibrium prices of the market",
+        &["market", "kind"]
+    )
+    .unwrap();
+    pub static ref GC_LIST_LENGTH: IntGaugeVec = register_int_gauge_vec!(
+        "perps_market_gc_list_length",
+        "Number of slots waiting to be garbage collected",
+        &["market", "instance"]
+    )
+    .unwrap();
+    pub static ref PAGE_FULL_RATIO: GaugeVec = register_gauge_vec!(
+        "perps_market_page_full_ratio",
+        "Ratio of used slots in a memory page",
+        &["market", "instance", "page"]
+    )
+    .unwrap();
+    pub static ref PAGE_FRAGMENTATION_RATIO: GaugeVec = register_gauge_vec!(
+        "perps_market_page_fragmentation_ratio",
+        "Ratio of free slots in the initialized memory of a page",
+        &["market", "instance", "page"]
+    )
+    .unwrap();
+}
+
+// Wraps the result of a send after filtering. Filtered no-op transactions are returned with a default signature.
+pub fn record_transaction(
+    service: &str,
+    market: &str,
+    instance: &str,
+    r: Result<Signature, ClientError>,
+) -> Result<Signature, ClientError> {
+    let labels = [service, market, instance];
+    TRANSACTIONS_SENT.with_label_values(&labels).inc();
+    match &r {
+        Ok(s) if *s == Signature::default() => NOP_REJECTIONS.with_label_values(&labels).inc(),
+        Ok(_) => TRANSACTIONS_CONFIRMED.with_label_values(&labels).inc(),
+        Err(_) => TRANSACTIONS_FAILED.with_label_values(&labels).inc(),
+    }
+    r
+}
+
+async fn handle(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    match req.uri().path() {
+        "/metrics" => {}
+        "/health/live" => return Ok(Response::new(Body::from("ok"))),
+        "/health/ready" => {
+            let (ready, report) = health::report();
+            let status = if ready {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            return Ok(Response::builder()
+                .status(status)
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(report))
+                .unwrap());
+        }
+        _ => {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap())
+        }
+    }
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    encoder.encode(&prometheus::gather(), &mut buffer).unwrap();
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))
+        .unwrap())
+}
+
+pub async fn serve(address: SocketAddr) {
+    let make_service = make_service_fn(|_| async { Ok::<_, hyper::Error>(service_fn(handle)) });
+    println!("Serving metrics on {:?}", address);
+    if let Err(e) = Server::bind(&address).serve(make_service).await {
+        println!("Metrics server failed with {:?}", e);
+    }
+}
+
+// Periodically samples the fee payer balance and the market state
+pub async fn sample(connection: Arc<dyn RpcBackend>, market: Pubkey, fee_payer: Pubkey) {
+    let market_label = market.to_string();
+    let mut ticker = interval(Duration::from_millis(METRICS_SAMPLING_PERIOD));
+    loop {
+        ticker.tick().await;
+        let c = Arc::clone(&connection);
+        let sample = task::spawn_blocking(move || {
+            let balance = c.get_balance(&fee_payer);
+            let market_data = get_market_data(market, &|k| {
+                c.get_account_data(k)
+                    .map_err(|_| CrankError::ConnectionError)
+            });
+            (balance, market_data)
+        })
+        .await;
+        let (balance, market_data) = match sample {
+            Ok(s) => s,
+            Err(e) => {
+                println!("The market data sampler panicked with {:?}", e);
+                continue;
+            }
+        };
+        if let Ok(b) = balance {
+            FEE_PAYER_BALANCE
+                .with_label_values(&[&fee_payer.to_string()])
+                .set(lamports_to_sol(b));
+        }
+        let market_data = match market_data {
+            Ok(m) => m,
+            Err(e) => {
+                println!("Failed to sample market data with {:?}", e);
+                continue;
+            }
+        };
+        INSURANCE_FUND
+            .with_label_values(&[&market_label])
+            .set(market_data.insurance_fund);
+        OPEN_INTEREST
+            .with_label_values(&[&market_label, "long"])
+            .set(market_data.open_longs_v_coin as i64);
+        OPEN_INTEREST
+            .with_label_values(&[&market_label, "short"])
+            .set(market_data.open_shorts_v_coin as i64);
+        PRICE
+            .with_label_values(&[&market_label, "mark"])
+            .set(market_data.market_price);
+        PRICE
+            .with_label_values(&[&market_label, "oracle"])
+            .set(market_data.oracle_price);
+        PRICE
+            .with_label_values(&[&market_label, "equilibrium"])
+            .set(market_data.equilibrium_price);
+        for (i, l) in market_data.gc_list_lengths.iter().enumerate() {
+            GC_LIST_LENGTH
+                .with_label_values(&[&market_label, &i.to_string()])
+                .set(*l as i64);
+        }
+        for (i, ratios) in market_data.page_full_ratios.iter().enumerate() {
+            for (j, r) in ratios.iter().enumerate() {
+                PAGE_FULL_RATIO
+                    .with_label_values(&[&market_label, &i.to_string(), &j.to_string()])
+                    .set(*r);
+            }
+        }
+        for (i, ratios) in market_data.page_fragmentation_ratios.iter().enumerate() {
+            for (j, r) in ratios.iter().enumerate() {
+                PAGE_FRAGMENTATION_RATIO
+                    .with_label_values(&[&market_label, &i.to_string(), &j.to_string()])
+                    .set(*r);
+            }
+        }
+    }
+}
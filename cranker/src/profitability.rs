@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+
+use audaces_protocol::{
+    error::PerpError,
+    positions_book::{memory::Memory, page::Page, positions_book_tree::PositionsBook},
+    processor::{ALLOCATION_FEE, FEE_REFERRER},
+    state::{instance::parse_instance, PositionType},
+};
+use solana_program::pubkey::Pubkey;
+use solana_sdk::native_token::lamports_to_sol;
+
+use crate::{error::CrankError, metrics, utils, Context};
+
+// Rebuilds a copy of the positions book of an instance from the fetched accounts
+fn with_positions_book<T, F>(
+    instance_data: &[u8],
+    accounts: &HashMap<Pubkey, Vec<u8>>,
+    f: F,
+) -> Result<T, CrankError>
+where
+    F: FnOnce(&mut PositionsBook) -> Result<T, PerpError>,
+{
+    let (instance, page_infos) =
+        parse_instance(instance_data).map_err(|_| CrankError::InvalidInstanceState)?;
+    let mut page_datas = Vec::with_capacity(page_infos.len());
+    for p in &page_infos {
+        let page_key = Pubkey::new(&p.address);
+        let page_data = accounts
+            .get(&page_key)
+            .ok_or(CrankError::AccountNotFound(page_key))?;
+        page_datas.push(page_data.clone());
+    }
+    let mut pages = Vec::with_capacity(page_infos.len());
+    for (page_data, page_info) in page_datas.iter_mut().zip(page_infos.iter()) {
+        pages.push(
+            Page::new_from_slice_unchecked(page_data, page_info)
+                .map_err(|_| CrankError::InvalidInstanceState)?,
+        );
+    }
+    let mut book = PositionsBook::new(
+        instance.shorts_pointer,
+        instance.longs_pointer,
+        Memory::new(pages, instance.garbage_pointer),
+    );
+    f(&mut book).map_err(|_| CrankError::InvalidInstanceState)
+}
+
+/// The cranker receives the referrer share of the liquidated collateral
+pub fn estimate_liquidation_reward(
+    instance_data: &[u8],
+    accounts: &HashMap<Pubkey, Vec<u8>>,
+    liquidation_index: u64,
+) -> Result<u64, CrankError> {
+    with_positions_book(instance_data, accounts, |book| {
+        if !book.is_liquidatable(liquidation_index)? {
+            return Ok(0);
+        }
+        let collateral = book.get_collateral()?;
+        book.liquidate(liquidation_index, PositionType::Short, None)?;
+        book.liquidate(liquidation_index, PositionType::Long, None)?;
+        let liquidated_collateral = collateral - book.get_collateral()?;
+        Ok(((liquidated_collateral as u128) * (FEE_REFERRER as u128) / 100) as u64)
+    })
+}
+
+/// The cranker receives the allocation fee of every freed slot
+pub fn estimate_garbage_collection_reward(
+    instance_data: &[u8],
+    accounts: &HashMap<Pubkey, Vec<u8>>,
+    max_iterations: u64,
+) -> Result<u64, CrankError> {
+    with_positions_book(instance_data, accounts, |book| {
+        let freed_slots = book.memory.get_gc_list_len()?.min(max_iterations);
+        Ok(freed_slots * ALLOCATION_FEE)
+    })
+}
+
+/// Fee of a transaction with a single signature in quote token with precision, when the price of
+/// SOL is configured.
+pub async fn get_transaction_cost(
+    ctx: &Context,
+    quote_decimals: u8,
+) -> Result<Option<u64>, CrankError> {
+    let sol_price = match ctx.sol_price {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+    let lamports = utils::retry(
+        &ctx.retry,
+        &ctx.connection,
+        |c| c.get_lamports_per_signature(),
+        |r| r,
+    )
+    .await?;
+    let cost = lamports_to_sol(lamports) * sol_price * 10f64.powi(quote_decimals as i32);
+    Ok(Some(cost.ceil() as u64))
+}
+
+/// Checks that a crank transaction is expected to pay more than it costs
+pub fn is_profitable(
+    service: &str,
+    market_label: &str,
+    instance_label: &str,
+    expected_reward: u64,
+    cost: Option<u64>,
+) -> bool {
+    let profitable = expected_reward > 0 && cost.map_or(true, |c| expected_reward > c);
+    if !profitable {
+        println!(
+            "Skipping {} on instance {} with an expected reward of {} for a cost of {:?}",
+            service, instance_label, expected_reward, cost
+        );
+        metrics::UNPROFITABLE_SKIPS
+            .with_label_values(&[service, market_label, instance_label])
+            .inc();
+    }
+    profitable
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+
+use audaces_protocol::{
+    positions_book::page::Page,
+    state::instance::{self, parse_instance},
+    utils::get_page_full_ratio,
+};
+use serde::Deserialize;
+use solana_program::pubkey::Pubkey;
+
+use crate::error::CrankError;
+
+const DEFAULT_THRESHOLD: f64 = 0.8;
+const DEFAULT_PAGE_SLOTS: u64 = 20_000;
+
+/// Settings of the page provisioning service, which adds a memory page to the instances which
+/// are running out of space. The fee payer must be the market admin.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Provisioning {
+    /// Ratio of used slots of an instance above which a page is added
+    pub threshold: f64,
+    /// Number of slots of the added pages
+    pub page_slots: u64,
+    /// Only logs the pages which would be added
+    pub dry_run: bool,
+}
+
+impl Default for Provisioning {
+    fn default() -> Self {
+        Self {
+            threshold: DEFAULT_THRESHOLD,
+            page_slots: DEFAULT_PAGE_SLOTS,
+            dry_run: false,
+        }
+    }
+}
+
+impl Provisioning {
+    pub fn check(&self) -> Result<(), CrankError> {
+        if !(self.threshold > 0. && self.threshold <= 1.) || self.page_slots == 0 {
+            return Err(CrankError::InvalidConfig(String::from(
+                "The provisioning threshold must be in (0, 1] and the page slots positive",
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Ratio of used slots over all the pages of an instance, weighted by page size. An instance
+/// without pages is full.
+pub fn get_instance_full_ratio(
+    instance_data: &[u8],
+    accounts: &HashMap<Pubkey, Vec<u8>>,
+) -> Result<f64, CrankError> {
+    let (_, page_infos) =
+        parse_instance(instance_data).map_err(|_| CrankError::InvalidInstanceState)?;
+    let mut used_slots = 0.;
+    let mut total_slots = 0.;
+    for p in &page_infos {
+        let page_key = Pubkey::new(&p.address);
+        let mut page_data = accounts
+            .get(&page_key)
+            .ok_or(CrankError::AccountNotFound(page_key))?
+            .clone();
+        let page = Page::new_from_slice_unchecked(&mut page_data, p)
+            .map_err(|_| CrankError::InvalidInstanceState)?;
+        let ratio = get_page_full_ratio(&page).map_err(|_| CrankError::InvalidInstanceState)?;
+        used_slots += ratio * (page.page_size as f64);
+        total_slots += page.page_size as f64;
+    }
+    if total_slots == 0. {
+        return Ok(1.);
+    }
+    Ok(used_slots / total_slots)
+}
+
+/// Number of pages the instance account can reference
+pub fn get_page_capacity(instance_data: &[u8]) -> usize {
+    instance::get_page_capacity(instance_data).unwrap_or(0)
+}
+
+/// Selects the page of an instance to defragment: the sparsest page which still holds nodes,
+/// provided that its used slots are at most `max_used_ratio` of its size and that they fit in the
+/// free slots of the other pages.
+pub fn get_defragmentation_page(
+    instance_data: &[u8],
+    accounts: &HashMap<Pubkey, Vec<u8>>,
+    max_used_ratio: f64,
+) -> Result<Option<u32>, CrankError> {
+    let (_, page_infos) =
+        parse_instance(instance_data).map_err(|_| CrankError::InvalidInstanceState)?;
+    let mut used_slots = Vec::with_capacity(page_infos.len());
+    let mut total_free_slots = 0;
+    for p in &page_infos {
+        let page_key = Pubkey::new(&p.address);
+        let mut page_data = accounts
+            .get(&page_key)
+            .ok_or(CrankError::AccountNotFound(page_key))?
+            .clone();
+        let page = Page::new_from_slice_unchecked(&mut page_data, p)
+            .map_err(|_| CrankError::InvalidInstanceState)?;
+        let free_slots = page
+            .get_nb_free_slots()
+            .map_err(|_| CrankError::InvalidInstanceState)? as u32;
+        let used = page.uninitialized_memory - free_slots;
+        total_free_slots += page.page_size - used;
+        used_slots.push((used, page.page_size));
+    }
+    let sparsest = used_slots
+        .iter()
+        .enumerate()
+        .filter(|(_, (used, _))| *used > 0)
+        .min_by_key(|(_, (used, _))| *used);
+    Ok(match sparsest {
+        Some((i, (used, size)))
+            if (*used as f64) <= max_used_ratio * (*size as f64)
+                && total_free_slots - (size - used) >= *used =>
+        {
+            Some(i as u32)
+        }
+        _ => None,
+    })
+}
@@ -0,0 +1,140 @@
+use audaces_protocol::{
+    processor::MAX_LEVERAGE,
+    state::{market::MarketState, user_account::OpenPosition},
+    utils::{compute_bias, compute_payout},
+};
+use serde::Deserialize;
+
+use crate::error::CrankError;
+
+const DEFAULT_THRESHOLD: f64 = 0.1;
+const DEFAULT_UNWIND_THRESHOLD: f64 = 0.02;
+const DEFAULT_MAX_LEVERAGE: f64 = 1.;
+
+/// Settings of the rebalancing service, which takes the opposite side of the net open interest
+/// when the market drifts away from the oracle, and unwinds once the imbalance closes. Biases are
+/// relative differences between the oracle price and the equilibrium price of the market.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Rebalancing {
+    /// Bias of the market above which it is rebalanced
+    pub threshold: f64,
+    /// Bias of the traders' open interest, without the rebalancing positions, below which these
+    /// positions are closed
+    pub unwind_threshold: f64,
+    /// Leverage of the rebalancing positions
+    pub max_leverage: f64,
+    /// Total collateral of the rebalancing positions, in quote token with precision
+    pub max_collateral: u64,
+    pub instance_index: u8,
+}
+
+impl Default for Rebalancing {
+    fn default() -> Self {
+        Self {
+            threshold: DEFAULT_THRESHOLD,
+            unwind_threshold: DEFAULT_UNWIND_THRESHOLD,
+            max_leverage: DEFAULT_MAX_LEVERAGE,
+            max_collateral: 0,
+            instance_index: 0,
+        }
+    }
+}
+
+impl Rebalancing {
+    pub fn check(&self) -> Result<(), CrankError> {
+        let max_leverage = (MAX_LEVERAGE >> 32) as f64;
+        if !(self.threshold > 0.)
+            || !(self.unwind_threshold >= 0. && self.unwind_threshold < self.threshold)
+            || !(self.max_leverage > 0. && self.max_leverage <= max_leverage)
+            || self.max_collateral == 0
+        {
+            return Err(CrankError::InvalidConfig(format!(
+                "The rebalancing thresholds must satisfy 0 <= unwind_threshold < threshold, the leverage must be in (0, {}] and the collateral budget positive",
+                max_leverage
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RebalancingAction {
+    Nothing,
+    Rebalance {
+        collateral: u64,
+    },
+    /// The market has to be rebalanced but the collateral budget is exhausted
+    OverBudget {
+        collateral: u64,
+    },
+    Unwind,
+}
+
+fn get_bias(market_state: &MarketState, delta: i64, oracle_price: u64) -> f64 {
+    let bias = compute_bias(
+        delta,
+        market_state.v_coin_amount,
+        market_state.v_pc_amount,
+        oracle_price,
+    );
+    (bias as f64) / ((1u64 << 32) as f64)
+}
+
+pub fn get_book_delta(book: &[OpenPosition]) -> i64 {
+    book.iter()
+        .map(|p| p.side.get_sign() * (p.v_coin_amount as i64))
+        .sum()
+}
+
+/// Decides whether to unwind the book, made of all the open positions of the rebalancing user
+/// account, or to take the opposite side of the net open interest
+pub fn decide(
+    rebalancing: &Rebalancing,
+    market_state: &MarketState,
+    oracle_price: u64,
+    book: &[OpenPosition],
+) -> Result<RebalancingAction, CrankError> {
+    let delta = (market_state.open_longs_v_coin as i64) - (market_state.open_shorts_v_coin as i64);
+    let book_delta = get_book_delta(book);
+    if book_delta != 0 {
+        let traders_delta = delta - book_delta;
+        let traders_bias = get_bias(market_state, traders_delta, oracle_price);
+        // The book does not offset the traders anymore once they switched sides
+        if traders_bias.abs() < rebalancing.unwind_threshold
+            || traders_delta.signum() == book_delta.signum()
+        {
+            return Ok(RebalancingAction::Unwind);
+        }
+    }
+    if delta == 0 || get_bias(market_state, delta, oracle_price).abs() <= rebalancing.threshold {
+        return Ok(RebalancingAction::Nothing);
+    }
+    let notional = market_state
+        .compute_add_v_pc(delta)
+        .map_err(|_| CrankError::InvalidMarketState)?
+        .abs() as f64;
+    let collateral = (notional / rebalancing.max_leverage).ceil() as u64;
+    let used_collateral = book.iter().map(|p| p.collateral).sum::<u64>();
+    if used_collateral + collateral > rebalancing.max_collateral {
+        return Ok(RebalancingAction::OverBudget { collateral });
+    }
+    Ok(RebalancingAction::Rebalance { collateral })
+}
+
+/// Profit of the book if it was closed at the current market price
+pub fn get_unrealized_pnl(
+    market_state: &MarketState,
+    book: &[OpenPosition],
+) -> Result<i64, CrankError> {
+    let mut pnl = 0;
+    for p in book {
+        let v_pc_closing_amount = market_state
+            .compute_add_v_pc(p.side.get_sign() * (p.v_coin_amount as i64))
+            .map_err(|_| CrankError::InvalidMarketState)?
+            .abs() as u64;
+        let payout = compute_payout(v_pc_closing_amount, p.v_pc_amount, p.collateral, &p.side);
+        pnl += payout - (p.collateral as i64);
+    }
+    Ok(pnl)
+}
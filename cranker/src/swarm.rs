@@ -0,0 +1,69 @@
+use std::convert::TryInto;
+
+use serde::Deserialize;
+use solana_program::{hash::hashv, pubkey::Pubkey};
+
+use crate::error::CrankError;
+
+/// Partition of the user accounts between the nodes of a cranking swarm.
+///
+/// Accounts are assigned with rendezvous hashing: every node ranks the account by a hash of the
+/// account key and of its node id, and the account goes to the `redundancy` best ranked nodes.
+/// Adding or removing a node only moves the accounts assigned to that node.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Swarm {
+    pub size: u16,
+    pub node_id: u16,
+    /// Number of nodes covering each account
+    pub redundancy: u16,
+}
+
+impl Default for Swarm {
+    fn default() -> Self {
+        Self {
+            size: 1,
+            node_id: 0,
+            redundancy: 1,
+        }
+    }
+}
+
+impl Swarm {
+    pub fn new(size: u16, node_id: u16, redundancy: u16) -> Result<Self, CrankError> {
+        let swarm = Self {
+            size,
+            node_id,
+            redundancy,
+        };
+        swarm.check()?;
+        Ok(swarm)
+    }
+
+    pub fn check(&self) -> Result<(), CrankError> {
+        if self.size == 0
+            || self.node_id >= self.size
+            || self.redundancy == 0
+            || self.redundancy > self.size
+        {
+            return Err(CrankError::InvalidSwarm);
+        }
+        Ok(())
+    }
+
+    pub fn is_assigned(&self, account: &Pubkey) -> bool {
+        if self.redundancy >= self.size {
+            return true;
+        }
+        let own_score = score(account, self.node_id);
+        let better_ranked = (0..self.size)
+            .filter(|n| *n != self.node_id && score(account, *n) > own_score)
+            .count();
+        better_ranked < self.redundancy as usize
+    }
+}
+
+fn score(account: &Pubkey, node_id: u16) -> u64 {
+    let hash = hashv(&[&account.to_bytes(), &node_id.to_le_bytes()]);
+    u64::from_le_bytes(hash.to_bytes()[..8].try_into().unwrap())
+}
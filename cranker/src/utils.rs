@@ -1,81 +1,228 @@
-use solana_client::client_error::ClientError;
-use solana_program::instruction::InstructionError;
-use solana_sdk::signature::Signature;
-use std::fmt::Debug;
-use tokio::task;
+use audaces_protocol::{
+    error::PerpError,
+    state::user_account::{OpenPosition, UserAccountState},
+};
+use rand::Rng;
+use serde::Deserialize;
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind},
+    rpc_request::{RpcError, RpcResponseErrorData},
+};
+use solana_program::{instruction::InstructionError, program_pack::Pack};
+use solana_sdk::{signature::Signature, transaction::TransactionError};
+use solana_transaction_status::UiTransactionTokenBalance;
+use std::{
+    fmt::Debug,
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
 
-pub async fn retry<F, T, K, E, R>(arg: T, f: F, e: R) -> K
+use crate::{backend::RpcBackend, error::CrankError};
+
+const DEFAULT_INITIAL_DELAY: u64 = 200;
+const DEFAULT_MAX_DELAY: u64 = 10_000;
+const DEFAULT_MAX_ATTEMPTS: u32 = 10;
+
+/// Retry settings of a single operation, delays are in milliseconds
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub initial_delay: u64,
+    pub max_delay: u64,
+    /// The operation is retried indefinitely when unset
+    pub max_attempts: Option<u32>,
+    /// Time after which the operation is not retried anymore
+    pub deadline: Option<u64>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: DEFAULT_INITIAL_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+            max_attempts: Some(DEFAULT_MAX_ATTEMPTS),
+            deadline: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retrying after the given failed attempt, counted from 1. The backoff doubles
+    /// with every attempt up to the maximum delay and the delay is drawn between half and all of it.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_delay
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u64::MAX)
+            .min(self.max_delay);
+        let jitter = rand::thread_rng().gen_range(0..=backoff / 2);
+        Duration::from_millis(backoff - backoff / 2 + jitter)
+    }
+}
+
+/// Errors which cannot be fixed by retrying the operation
+pub trait ErrorClassification {
+    fn is_fatal(&self) -> bool;
+}
+
+impl ErrorClassification for ClientError {
+    fn is_fatal(&self) -> bool {
+        match get_transaction_error(self) {
+            // Operations are signed with a fresh blockhash on every attempt
+            Some(TransactionError::BlockhashNotFound) => false,
+            Some(TransactionError::InsufficientFundsForFee)
+            | Some(TransactionError::AccountNotFound)
+            | Some(TransactionError::InvalidAccountForFee)
+            | Some(TransactionError::InstructionError(_, InstructionError::InsufficientFunds))
+            | Some(TransactionError::InstructionError(_, InstructionError::InvalidAccountData)) => {
+                true
+            }
+            // The program rejects the instruction for the current state of its accounts, except
+            // for no-ops which are left to the no-op filter
+            Some(TransactionError::InstructionError(_, InstructionError::Custom(c))) => {
+                is_program_error(*c) && *c != PerpError::Nop as u32
+            }
+            _ => matches!(self.kind, ClientErrorKind::SigningError(_)),
+        }
+    }
+}
+
+impl ErrorClassification for CrankError {
+    fn is_fatal(&self) -> bool {
+        !matches!(self, CrankError::ConnectionError)
+    }
+}
+
+/// Retries the operation with exponential backoff until it succeeds, fails with a fatal error
+/// or runs out of attempts. The filter is applied to every result before classification.
+pub async fn retry<F, T, K, E, R>(policy: &RetryPolicy, arg: T, f: F, e: R) -> Result<K, CrankError>
 where
     F: Fn(&T) -> Result<K, E>,
-    E: Debug,
+    E: Debug + ErrorClassification,
     R: Fn(Result<K, E>) -> Result<K, E>,
 {
+    let start = Instant::now();
+    let mut attempt = 0;
     loop {
-        let res = e(f(&arg));
-        if res.is_ok() {
-            return res.unwrap();
+        attempt += 1;
+        let err = match e(f(&arg)) {
+            Ok(k) => return Ok(k),
+            Err(err) => err,
+        };
+        if err.is_fatal() {
+            println!("Failed task with fatal error {:#?}", err);
+            return Err(CrankError::FatalError(format!("{:?}", err)));
+        }
+        let delay = policy.delay(attempt);
+        let out_of_attempts = policy.max_attempts.map_or(false, |m| attempt >= m);
+        let out_of_time = policy.deadline.map_or(false, |d| {
+            start.elapsed() + delay > Duration::from_millis(d)
+        });
+        if out_of_attempts || out_of_time {
+            println!(
+                "Failed task with {:#?}, giving up after {} attempts",
+                err, attempt
+            );
+            return Err(CrankError::RetriesExhausted(attempt));
         }
-        println!("Failed task with {:#?}, retrying", res.err().unwrap());
-        task::yield_now().await;
+        println!("Failed task with {:#?}, retrying in {:?}", err, delay);
+        sleep(delay).await;
     }
 }
 
-pub fn no_op_filter(r: Result<Signature, ClientError>) -> Result<Signature, ClientError> {
-    if let Err(e) = &r {
-        match &e.kind {
-            solana_client::client_error::ClientErrorKind::RpcError(
-                solana_client::rpc_request::RpcError::RpcResponseError {
-                    code: _,
-                    message: _,
-                    data,
-                },
-            ) => {
-                if let solana_client::rpc_request::RpcResponseErrorData::SendTransactionPreflightFailure(f) = data {
-                    match f.err {
-                        Some(solana_sdk::transaction::TransactionError::InstructionError(_, InstructionError::Custom(0x7))) => {
-                            println!("Operation was a no-op");
-                            Ok(Signature::new(&[0;64]))
-                        }
-                        _ => r
-                    }
-                } else {
-                    r
-                }
-            }
-            _ => r,
-        }
-    } else {
-        r
+/// Parses the header and the open positions of a user account without panicking on bad data
+pub fn parse_user_account(
+    data: &[u8],
+) -> Result<(UserAccountState, Vec<OpenPosition>), CrankError> {
+    let header = data
+        .get(..UserAccountState::LEN)
+        .and_then(|d| UserAccountState::unpack_from_slice(d).ok())
+        .ok_or(CrankError::InvalidUserAccountState)?;
+    let mut positions = Vec::with_capacity(header.number_of_open_positions as usize);
+    let mut offset = UserAccountState::LEN;
+    for _ in 0..header.number_of_open_positions {
+        let position = data
+            .get(offset..offset + OpenPosition::LEN)
+            .and_then(|d| OpenPosition::unpack_from_slice(d).ok())
+            .ok_or(CrankError::InvalidUserAccountState)?;
+        positions.push(position);
+        offset += OpenPosition::LEN;
     }
+    Ok((header, positions))
 }
 
-pub fn invalid_signature_filter(
-    r: Result<Signature, ClientError>,
-) -> Result<Signature, ClientError> {
-    if let Err(e) = &r {
-        match &e.kind {
-            solana_client::client_error::ClientErrorKind::RpcError(
-                solana_client::rpc_request::RpcError::RpcResponseError {
-                    code: _,
-                    message: _,
-                    data,
-                },
-            ) => {
-                if let solana_client::rpc_request::RpcResponseErrorData::SendTransactionPreflightFailure(f) = data {
-                    match f.err {
-                        Some(solana_sdk::transaction::TransactionError::InstructionError(_, InstructionError::InvalidArgument)) => {
-                            println!("The position has not been liquidated.");
-                            Ok(Signature::new(&[0;64]))
-                        }
-                        _ => r
-                    }
-                } else {
-                    r
-                }
-            }
-            _ => r,
-        }
+// Derives the websocket endpoint from the RPC endpoint, following the Solana CLI convention
+// of using the next port when one is specified.
+pub fn compute_websocket_url(url: &str) -> String {
+    let (scheme, rest) = if let Some(r) = url.strip_prefix("https://") {
+        ("wss://", r)
+    } else if let Some(r) = url.strip_prefix("http://") {
+        ("ws://", r)
     } else {
-        r
+        return url.to_owned();
+    };
+    let (host, path) = rest.split_at(rest.find('/').unwrap_or_else(|| rest.len()));
+    let host = match host.rsplit_once(':') {
+        Some((h, port)) => match port.parse::<u16>() {
+            Ok(p) => format!("{}:{}", h, p + 1),
+            Err(_) => host.to_owned(),
+        },
+        None => host.to_owned(),
+    };
+    format!("{}{}{}", scheme, host, path)
+}
+
+// Custom codes of the program errors, other custom codes are left to be retried
+fn is_program_error(code: u32) -> bool {
+    code <= PerpError::DeleveragingSide as u32
+}
+
+// Extracts the transaction error from a failed preflight check or from a rejected transaction
+fn get_transaction_error(e: &ClientError) -> Option<&TransactionError> {
+    match &e.kind {
+        ClientErrorKind::RpcError(RpcError::RpcResponseError {
+            data: RpcResponseErrorData::SendTransactionPreflightFailure(f),
+            ..
+        }) => f.err.as_ref(),
+        ClientErrorKind::TransactionError(te) => Some(te),
+        _ => None,
     }
 }
+
+pub fn no_op_filter(r: Result<Signature, ClientError>) -> Result<Signature, ClientError> {
+    let is_no_op = matches!(
+        r.as_ref().err().and_then(get_transaction_error),
+        Some(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(0x7)
+        ))
+    );
+    if is_no_op {
+        println!("Operation was a no-op");
+        return Ok(Signature::new(&[0; 64]));
+    }
+    r
+}
+
+// Waits for a transaction to be confirmed and returns the change in the token balance of
+// the account at the given index in the transaction's account keys.
+pub fn get_token_balance_change(
+    connection: &dyn RpcBackend,
+    signature: &Signature,
+    account_index: usize,
+) -> Result<i64, ClientError> {
+    let transaction = connection.get_confirmed_transaction(signature)?;
+    let get_balance = |balances: Option<Vec<UiTransactionTokenBalance>>| {
+        balances
+            .unwrap_or_default()
+            .into_iter()
+            .find(|b| b.account_index as usize == account_index)
+            .and_then(|b| b.ui_token_amount.amount.parse::<i64>().ok())
+            .unwrap_or(0)
+    };
+    let meta = match transaction.transaction.meta {
+        Some(m) => m,
+        None => return Ok(0),
+    };
+    Ok(get_balance(meta.post_token_balances) - get_balance(meta.pre_token_balances))
+}
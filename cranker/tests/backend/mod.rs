@@ -0,0 +1,132 @@
+use std::{future::Future, sync::Mutex};
+
+use perps_crank::backend::RpcBackend;
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind},
+    rpc_config::{RpcProgramAccountsConfig, RpcSendTransactionConfig},
+    rpc_filter::{MemcmpEncodedBytes, RpcFilterType},
+};
+use solana_program::{hash::Hash, pubkey::Pubkey};
+use solana_program_test::BanksClient;
+use solana_sdk::{account::Account, signature::Signature, transaction::Transaction};
+use solana_transaction_status::EncodedConfirmedTransaction;
+use tokio::{runtime::Handle, task};
+
+/// Runs the cranker against an in-process bank. The tests have to use the multi-threaded runtime
+/// since the backend blocks on the banks client.
+pub struct BanksBackend {
+    client: Mutex<BanksClient>,
+    // The bank cannot be scanned, the program accounts which can be returned have to be registered
+    program_accounts: Vec<Pubkey>,
+}
+
+impl BanksBackend {
+    pub fn new(client: BanksClient, program_accounts: Vec<Pubkey>) -> Self {
+        Self {
+            client: Mutex::new(client),
+            program_accounts,
+        }
+    }
+
+    fn block_on<F: Future>(&self, f: impl FnOnce(BanksClient) -> F) -> F::Output {
+        let client = self.client.lock().unwrap().clone();
+        task::block_in_place(|| Handle::current().block_on(f(client)))
+    }
+
+    fn fetch_account(&self, key: &Pubkey) -> Result<Option<Account>, ClientError> {
+        let key = *key;
+        self.block_on(|mut c| async move { c.get_account(key).await })
+            .map_err(ClientError::from)
+    }
+
+    fn process_transaction(&self, transaction: &Transaction) -> Result<Signature, ClientError> {
+        let tx = transaction.clone();
+        self.block_on(|mut c| async move { c.process_transaction(tx).await })?;
+        Ok(transaction.signatures[0])
+    }
+}
+
+fn custom_error(message: String) -> ClientError {
+    ClientErrorKind::Custom(message).into()
+}
+
+fn filter_matches(filter: &RpcFilterType, data: &[u8]) -> bool {
+    match filter {
+        RpcFilterType::DataSize(size) => data.len() as u64 == *size,
+        RpcFilterType::Memcmp(memcmp) => {
+            let MemcmpEncodedBytes::Binary(encoded) = &memcmp.bytes;
+            let bytes = bs58::decode(encoded).into_vec().unwrap();
+            data.get(memcmp.offset..memcmp.offset + bytes.len()) == Some(&bytes[..])
+        }
+    }
+}
+
+impl RpcBackend for BanksBackend {
+    fn get_account(&self, key: &Pubkey) -> Result<Account, ClientError> {
+        self.fetch_account(key)?
+            .ok_or_else(|| custom_error(format!("Account {} not found", key)))
+    }
+
+    fn get_multiple_accounts(&self, keys: &[Pubkey]) -> Result<Vec<Option<Account>>, ClientError> {
+        keys.iter().map(|k| self.fetch_account(k)).collect()
+    }
+
+    fn get_program_accounts_with_config(
+        &self,
+        program_id: &Pubkey,
+        config: RpcProgramAccountsConfig,
+    ) -> Result<Vec<(Pubkey, Account)>, ClientError> {
+        let filters = config.filters.unwrap_or_default();
+        let mut res = vec![];
+        for k in &self.program_accounts {
+            if let Some(a) = self.fetch_account(k)? {
+                if a.owner == *program_id && filters.iter().all(|f| filter_matches(f, &a.data)) {
+                    res.push((*k, a));
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    fn get_recent_blockhash(&self) -> Result<Hash, ClientError> {
+        self.block_on(|mut c| async move { c.get_recent_blockhash().await })
+            .map_err(ClientError::from)
+    }
+
+    fn get_lamports_per_signature(&self) -> Result<u64, ClientError> {
+        self.block_on(|mut c| async move { c.get_fees().await })
+            .map(|(f, _, _)| f.lamports_per_signature)
+            .map_err(ClientError::from)
+    }
+
+    fn get_minimum_balance_for_rent_exemption(&self, data_len: usize) -> Result<u64, ClientError> {
+        self.block_on(|mut c| async move { c.get_rent().await })
+            .map(|r| r.minimum_balance(data_len))
+            .map_err(ClientError::from)
+    }
+
+    fn send_transaction_with_config(
+        &self,
+        transaction: &Transaction,
+        _config: RpcSendTransactionConfig,
+    ) -> Result<Signature, ClientError> {
+        self.process_transaction(transaction)
+    }
+
+    fn send_and_confirm_transaction(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<Signature, ClientError> {
+        self.process_transaction(transaction)
+    }
+
+    fn get_confirmed_transaction(
+        &self,
+        signature: &Signature,
+    ) -> Result<EncodedConfirmedTransaction, ClientError> {
+        Err(custom_error(format!(
+            "Transaction metadata is unavailable for {}",
+            signature
+        )))
+    }
+}
@@ -0,0 +1,58 @@
+use std::fs;
+
+use perps_crank::checkpoint::CheckpointStore;
+use solana_program::pubkey::Pubkey;
+
+#[test]
+fn test_sweep_epochs() {
+    let store = CheckpointStore::temporary().unwrap();
+    let market = Pubkey::new_unique();
+    let accounts = (0..3).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+    let sweep = store.sweep("funding_extraction", &market).unwrap();
+    assert_eq!(sweep.position().unwrap(), None);
+    assert!(sweep.mark_processed(&accounts[0]).is_err());
+
+    assert_eq!(sweep.begin(4).unwrap(), 0);
+    sweep.mark_processed(&accounts[0]).unwrap();
+    sweep.mark_processed(&accounts[1]).unwrap();
+    assert_eq!(sweep.position().unwrap(), Some((4, false)));
+    sweep.complete().unwrap();
+    assert_eq!(sweep.position().unwrap(), Some((4, true)));
+
+    // The processed accounts are kept within an epoch
+    assert_eq!(sweep.begin(4).unwrap(), 2);
+    assert!(sweep.is_processed(&accounts[1]).unwrap());
+    assert!(!sweep.is_processed(&accounts[2]).unwrap());
+
+    // and forgotten in the next one
+    assert_eq!(sweep.begin(5).unwrap(), 0);
+    assert!(!sweep.is_processed(&accounts[0]).unwrap());
+
+    // Sweeps of other services and markets are independent
+    let other = store.sweep("liquidation_cleanup", &market).unwrap();
+    assert_eq!(other.position().unwrap(), None);
+    other.begin(0).unwrap();
+    other.mark_processed(&accounts[2]).unwrap();
+    assert!(!sweep.is_processed(&accounts[2]).unwrap());
+}
+
+#[test]
+fn test_sweep_resumes_after_restart() {
+    let path = std::env::temp_dir().join(format!("perps-crank-{}", Pubkey::new_unique()));
+    let market = Pubkey::new_unique();
+    let account = Pubkey::new_unique();
+    {
+        let store = CheckpointStore::open(&path).unwrap();
+        let sweep = store.sweep("liquidation_cleanup", &market).unwrap();
+        sweep.begin(7).unwrap();
+        sweep.mark_processed(&account).unwrap();
+    }
+    let store = CheckpointStore::open(&path).unwrap();
+    let sweep = store.sweep("liquidation_cleanup", &market).unwrap();
+    assert_eq!(sweep.position().unwrap(), Some((7, false)));
+    assert_eq!(sweep.begin(7).unwrap(), 1);
+    assert!(sweep.is_processed(&account).unwrap());
+    drop(sweep);
+    drop(store);
+    fs::remove_dir_all(&path).unwrap();
+}
@@ -0,0 +1,251 @@
+use std::{env, fs, path::PathBuf, sync::Arc};
+
+use perps_crank::{
+    config::{Config, ServiceName},
+    error::CrankError,
+    rebalancing::Rebalancing,
+    swarm::Swarm,
+    utils::RetryPolicy,
+    Periods, Service,
+};
+use solana_program::pubkey::Pubkey;
+use solana_sdk::signature::{write_keypair_file, Keypair};
+
+struct ConfigDir {
+    path: PathBuf,
+    fee_payer: String,
+}
+
+impl ConfigDir {
+    fn new() -> Self {
+        let path = env::temp_dir().join(format!("perps-crank-config-{}", Pubkey::new_unique()));
+        fs::create_dir_all(&path).unwrap();
+        let fee_payer = path.join("fee_payer.json");
+        write_keypair_file(&Keypair::new(), &fee_payer).unwrap();
+        Self {
+            fee_payer: fee_payer.to_str().unwrap().to_owned(),
+            path,
+        }
+    }
+
+    fn load(&self, file_name: &str, contents: &str) -> Result<Config, CrankError> {
+        let path = self.path.join(file_name);
+        fs::write(&path, contents).unwrap();
+        Config::load(&path)
+    }
+}
+
+impl Drop for ConfigDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.path).ok();
+    }
+}
+
+#[test]
+fn test_toml_config() {
+    let dir = ConfigDir::new();
+    let program_id = Pubkey::new_unique();
+    let markets = [Pubkey::new_unique(), Pubkey::new_unique()];
+    let user_account = Pubkey::new_unique();
+    let contents = format!(
+        r#"
+url = "http://127.0.0.1:8899"
+additional_urls = ["https://backup.example.com/rpc"]
+program_id = "{}"
+fee_payer = "{}"
+sol_price = 40.0
+
+[swarm]
+size = 3
+node_id = 1
+
+[retry]
+max_attempts = 5
+
+[[markets]]
+address = "{}"
+services = ["liquidate", "funding-extraction", "rebalance"]
+
+[markets.periods]
+funding_extraction = 5000
+
+[markets.rebalancing]
+user_account = "{}"
+threshold = 0.2
+max_collateral = 1000000
+
+[[markets]]
+address = "{}"
+services = ["liquidation-cleanup"]
+
+[markets.swarm]
+size = 2
+node_id = 0
+redundancy = 2
+"#,
+        program_id, dir.fee_payer, markets[0], user_account, markets[1]
+    );
+    let config = dir.load("crank.toml", &contents).unwrap();
+    assert_eq!(
+        config.additional_urls,
+        vec!["https://backup.example.com/rpc"]
+    );
+    assert!(!config.broadcast);
+    assert!(config.metrics_address.is_none());
+    assert_eq!(
+        config.markets[0].services,
+        vec![
+            ServiceName::Liquidate,
+            ServiceName::FundingExtraction,
+            ServiceName::Rebalance
+        ]
+    );
+    // The unset settings take their default value
+    assert_eq!(config.swarm, Swarm::new(3, 1, 1).unwrap());
+    assert_eq!(config.retry.max_attempts, Some(5));
+    assert_eq!(
+        config.retry.initial_delay,
+        RetryPolicy::default().initial_delay
+    );
+
+    let services = config.into_services().unwrap();
+    assert_eq!(services.len(), 4);
+    let (ctx, service) = &services[0];
+    assert_eq!(ctx.program_id, program_id);
+    assert_eq!(ctx.market, markets[0]);
+    assert_eq!(ctx.sol_price, Some(40.));
+    // The websocket endpoint is derived from the first RPC endpoint
+    assert_eq!(ctx.websocket_url, "ws://127.0.0.1:8900");
+    assert!(matches!(service, Service::Liquidate));
+
+    // The periods of a market override the defaults one by one
+    let default_periods = Periods::default();
+    assert_eq!(ctx.periods.funding_extraction, 5000);
+    assert_eq!(ctx.periods.liquidation, default_periods.liquidation);
+    assert_eq!(
+        services[3].0.periods.funding_extraction,
+        default_periods.funding_extraction
+    );
+
+    // The swarm settings of a market take precedence over the global ones
+    assert!(matches!(
+        services[1].1,
+        Service::FundingExtraction { swarm } if swarm == Swarm::new(3, 1, 1).unwrap()
+    ));
+    assert!(matches!(
+        services[3].1,
+        Service::LiquidationCleanup { swarm } if swarm == Swarm::new(2, 0, 2).unwrap()
+    ));
+
+    let expected_rebalancing = Rebalancing {
+        threshold: 0.2,
+        max_collateral: 1_000_000,
+        ..Rebalancing::default()
+    };
+    assert!(matches!(
+        services[2].1,
+        Service::Rebalance { user_account: u, rebalancing }
+            if u == user_account && rebalancing == expected_rebalancing
+    ));
+
+    // All the markets share the fee payer and the connection
+    assert_eq!(services[3].0.market, markets[1]);
+    assert!(Arc::ptr_eq(
+        &services[0].0.fee_payer,
+        &services[3].0.fee_payer
+    ));
+    assert_eq!(
+        Arc::as_ptr(&services[0].0.connection) as *const (),
+        Arc::as_ptr(&services[3].0.connection) as *const ()
+    );
+}
+
+#[test]
+fn test_yaml_config() {
+    let dir = ConfigDir::new();
+    let market = Pubkey::new_unique();
+    let contents = format!(
+        r#"
+ws_url: "wss://stream.example.com"
+broadcast: true
+program_id: "{}"
+fee_payer: "{}"
+metrics_address: "127.0.0.1:9090"
+markets:
+  - address: "{}"
+    services: [garbage-collect, page-provisioning]
+    provisioning:
+      dry_run: true
+"#,
+        Pubkey::new_unique(),
+        dir.fee_payer,
+        market
+    );
+    let config = dir.load("crank.yml", &contents).unwrap();
+    assert_eq!(config.url, "https://solana-api.projectserum.com");
+    assert!(config.broadcast);
+    assert_eq!(
+        config.metrics_address,
+        Some("127.0.0.1:9090".parse().unwrap())
+    );
+    assert!(config.markets[0].provisioning.dry_run);
+    assert!(config.markets[0].rebalancing.is_none());
+
+    // An explicit websocket endpoint takes precedence over the derived one
+    let services = config.into_services().unwrap();
+    assert_eq!(services.len(), 2);
+    assert_eq!(services[0].0.websocket_url, "wss://stream.example.com");
+    assert!(services[0].0.checkpoints.is_none());
+    assert!(matches!(services[0].1, Service::GarbageCollect));
+    assert!(matches!(
+        services[1].1,
+        Service::PageProvisioning { provisioning } if provisioning.dry_run
+    ));
+}
+
+#[test]
+fn test_invalid_config() {
+    let dir = ConfigDir::new();
+    let config = |market: &str, services: &str, extra: &str| {
+        format!(
+            "program_id = \"{}\"\nfee_payer = \"{}\"\n{}\n[[markets]]\naddress = \"{}\"\nservices = {}\n",
+            Pubkey::new_unique(),
+            dir.fee_payer,
+            extra,
+            market,
+            services
+        )
+    };
+    let market = Pubkey::new_unique().to_string();
+
+    // Files without a YAML extension are parsed as TOML
+    assert!(dir
+        .load("crank.conf", &config(&market, r#"["funding"]"#, ""))
+        .is_ok());
+    assert!(matches!(
+        dir.load("crank.yaml", &config(&market, r#"["funding"]"#, "")),
+        Err(CrankError::InvalidConfig(_))
+    ));
+    assert!(matches!(
+        dir.load("crank.toml", &config(&market, r#"["unknown"]"#, "")),
+        Err(CrankError::InvalidConfig(_))
+    ));
+    assert!(matches!(
+        Config::load(&dir.path.join("missing.toml")),
+        Err(CrankError::InvalidConfig(_))
+    ));
+
+    // The settings are checked when building the services
+    let invalid_configs = [
+        config("market", r#"["funding"]"#, ""),
+        config(&market, r#"["rebalance"]"#, ""),
+        config(&market, r#"["funding"]"#, "[swarm]\nsize = 2\nnode_id = 2"),
+    ];
+    for c in invalid_configs.iter() {
+        let config = dir.load("crank.toml", c).unwrap();
+        assert!(matches!(
+            config.into_services(),
+            Err(CrankError::InvalidConfig(_))
+        ));
+    }
+}
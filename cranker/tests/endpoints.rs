@@ -0,0 +1,219 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use perps_crank::backend::{endpoint_label, MultiEndpointClient, RpcBackend};
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind},
+    rpc_config::{RpcProgramAccountsConfig, RpcSendTransactionConfig},
+};
+use solana_program::{hash::Hash, pubkey::Pubkey};
+use solana_sdk::{account::Account, signature::Signature, transaction::Transaction};
+use solana_transaction_status::EncodedConfirmedTransaction;
+
+#[derive(Default)]
+struct MockState {
+    latency: Duration,
+    failing: AtomicBool,
+    calls: AtomicU32,
+    transactions: AtomicU32,
+}
+
+// An endpoint answering every request after its latency, or failing to connect
+#[derive(Clone)]
+struct MockClient(Arc<MockState>);
+
+impl MockClient {
+    fn new(latency: u64, failing: bool) -> Self {
+        Self(Arc::new(MockState {
+            latency: Duration::from_millis(latency),
+            failing: AtomicBool::new(failing),
+            ..MockState::default()
+        }))
+    }
+
+    fn calls(&self) -> u32 {
+        self.0.calls.load(Ordering::Relaxed)
+    }
+
+    fn answer<T>(&self, t: T) -> Result<T, ClientError> {
+        self.0.calls.fetch_add(1, Ordering::Relaxed);
+        if self.0.failing.load(Ordering::Relaxed) {
+            return Err(
+                ClientErrorKind::Io(io::Error::from(io::ErrorKind::ConnectionRefused)).into(),
+            );
+        }
+        thread::sleep(self.0.latency);
+        Ok(t)
+    }
+}
+
+impl RpcBackend for MockClient {
+    fn get_account(&self, _key: &Pubkey) -> Result<Account, ClientError> {
+        self.answer(Account::default())
+    }
+
+    fn get_multiple_accounts(&self, keys: &[Pubkey]) -> Result<Vec<Option<Account>>, ClientError> {
+        self.answer(vec![None; keys.len()])
+    }
+
+    fn get_program_accounts_with_config(
+        &self,
+        _program_id: &Pubkey,
+        _config: RpcProgramAccountsConfig,
+    ) -> Result<Vec<(Pubkey, Account)>, ClientError> {
+        self.answer(vec![])
+    }
+
+    fn get_recent_blockhash(&self) -> Result<Hash, ClientError> {
+        self.answer(Hash::default())
+    }
+
+    fn get_lamports_per_signature(&self) -> Result<u64, ClientError> {
+        self.answer(5_000)
+    }
+
+    fn get_minimum_balance_for_rent_exemption(&self, _data_len: usize) -> Result<u64, ClientError> {
+        self.answer(0)
+    }
+
+    fn send_transaction_with_config(
+        &self,
+        _transaction: &Transaction,
+        _config: RpcSendTransactionConfig,
+    ) -> Result<Signature, ClientError> {
+        self.0.transactions.fetch_add(1, Ordering::Relaxed);
+        self.answer(Signature::default())
+    }
+
+    fn send_and_confirm_transaction(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<Signature, ClientError> {
+        self.send_transaction_with_config(transaction, RpcSendTransactionConfig::default())
+    }
+
+    fn get_confirmed_transaction(
+        &self,
+        _signature: &Signature,
+    ) -> Result<EncodedConfirmedTransaction, ClientError> {
+        unimplemented!()
+    }
+}
+
+fn build_client(endpoints: &[MockClient], broadcast: bool) -> MultiEndpointClient<MockClient> {
+    let clients = endpoints
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (i.to_string(), c.clone()))
+        .collect();
+    MultiEndpointClient::from_clients(clients, broadcast)
+}
+
+#[test]
+fn test_endpoint_label() {
+    assert_eq!(
+        endpoint_label("https://rpc.example.com/v1/secret-api-key"),
+        "rpc.example.com"
+    );
+    assert_eq!(
+        endpoint_label("https://rpc.example.com?api-key=secret"),
+        "rpc.example.com"
+    );
+    assert_eq!(endpoint_label("http://127.0.0.1:8899"), "127.0.0.1:8899");
+    assert_eq!(endpoint_label("rpc.example.com/secret"), "rpc.example.com");
+}
+
+#[test]
+fn test_ranking() {
+    let endpoints = [MockClient::new(20, false), MockClient::new(1, false)];
+    let client = build_client(&endpoints, false);
+    // Endpoints without calls are tried first, in their configuration order
+    assert_eq!(client.ranking(), vec!["0", "1"]);
+    client.get_account(&Pubkey::new_unique()).unwrap();
+    client.get_account(&Pubkey::new_unique()).unwrap();
+    assert_eq!((endpoints[0].calls(), endpoints[1].calls()), (1, 1));
+
+    // The requests then go to the fastest endpoint
+    assert_eq!(client.ranking(), vec!["1", "0"]);
+    for _ in 0..5 {
+        client.get_account_data(&Pubkey::new_unique()).unwrap();
+    }
+    assert_eq!((endpoints[0].calls(), endpoints[1].calls()), (1, 6));
+}
+
+#[test]
+fn test_failover() {
+    let endpoints = [MockClient::new(0, true), MockClient::new(1, false)];
+    let client = build_client(&endpoints, false);
+
+    // The request fails over to the next endpoint, which takes the lead
+    client.get_account(&Pubkey::new_unique()).unwrap();
+    assert_eq!((endpoints[0].calls(), endpoints[1].calls()), (1, 1));
+    assert_eq!(client.ranking(), vec!["1", "0"]);
+    client.get_account(&Pubkey::new_unique()).unwrap();
+    assert_eq!((endpoints[0].calls(), endpoints[1].calls()), (1, 2));
+
+    // The last error is returned when all the endpoints fail
+    endpoints[1].0.failing.store(true, Ordering::Relaxed);
+    assert!(matches!(
+        client.get_account(&Pubkey::new_unique()).unwrap_err().kind,
+        ClientErrorKind::Io(_)
+    ));
+    assert_eq!((endpoints[0].calls(), endpoints[1].calls()), (2, 3));
+}
+
+#[test]
+fn test_health_decay() {
+    let endpoints = [MockClient::new(0, true), MockClient::new(5, false)];
+    let client = build_client(&endpoints, false).with_health_half_life(Duration::from_millis(20));
+    client.get_account(&Pubkey::new_unique()).unwrap();
+    assert_eq!(client.ranking(), vec!["1", "0"]);
+
+    // Once it recovers, the demoted endpoint is tried again as its failures are forgotten
+    endpoints[0].0.failing.store(false, Ordering::Relaxed);
+    for _ in 0..100 {
+        if endpoints[0].calls() > 1 {
+            break;
+        }
+        client.get_account(&Pubkey::new_unique()).unwrap();
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(endpoints[0].calls(), 2);
+}
+
+#[test]
+fn test_broadcast() {
+    let endpoints = [
+        MockClient::new(0, false),
+        MockClient::new(0, true),
+        MockClient::new(0, false),
+    ];
+    let transaction = Transaction::default();
+
+    // The transaction is sent through the healthiest endpoint only
+    let client = build_client(&endpoints, false);
+    client
+        .send_transaction_with_config(&transaction, RpcSendTransactionConfig::default())
+        .unwrap();
+    let transactions = || {
+        endpoints
+            .iter()
+            .map(|e| e.0.transactions.load(Ordering::Relaxed))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(transactions(), vec![1, 0, 0]);
+
+    // Or through all of them, ignoring the failures of the others
+    let client = build_client(&endpoints, true);
+    client
+        .send_transaction_with_config(&transaction, RpcSendTransactionConfig::default())
+        .unwrap();
+    assert_eq!(transactions(), vec![2, 1, 1]);
+}
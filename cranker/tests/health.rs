@@ -0,0 +1,30 @@
+use std::time::Duration;
+
+use perps_crank::health;
+use solana_program::pubkey::Pubkey;
+use tokio::time::interval;
+
+#[tokio::test]
+async fn test_health() {
+    let market = Pubkey::new_unique();
+    health::register("funding", &market, 60_000);
+    health::register("garbage_collection", &market, 60_000);
+    let (ready, report) = health::report();
+    assert!(!ready);
+    assert!(report.contains(r#""last_success":null"#));
+
+    health::record_success("funding", &market);
+    assert!(!health::report().0);
+    health::record_success("garbage_collection", &market);
+    let (ready, report) = health::report();
+    assert!(ready);
+    assert!(report.contains(&market.to_string()));
+
+    // The service loops stop at the next tick once shutting down
+    let mut ticker = interval(Duration::from_millis(10));
+    assert!(health::tick(&mut ticker).await);
+    health::shutdown();
+    assert!(!health::tick(&mut ticker).await);
+    health::wait_for_shutdown().await;
+    assert!(!health::report().0);
+}
@@ -0,0 +1,87 @@
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    time::Duration,
+};
+
+use perps_crank::metrics::{
+    self, NOP_REJECTIONS, TRANSACTIONS_CONFIRMED, TRANSACTIONS_FAILED, TRANSACTIONS_SENT,
+};
+use solana_client::client_error::{ClientError, ClientErrorKind};
+use solana_program::pubkey::Pubkey;
+use solana_sdk::signature::Signature;
+use tokio::{task, time::sleep};
+
+fn get(address: SocketAddr, path: &str) -> Option<String> {
+    let mut stream = TcpStream::connect(address).ok()?;
+    write!(stream, "GET {} HTTP/1.0\r\n\r\n", path).ok()?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).ok()?;
+    Some(response)
+}
+
+#[test]
+fn test_record_transaction() {
+    let market = Pubkey::new_unique().to_string();
+    let labels = ["liquidation", market.as_str(), "0"];
+
+    let signature = Signature::new(&[1; 64]);
+    assert_eq!(
+        metrics::record_transaction(labels[0], labels[1], labels[2], Ok(signature)).unwrap(),
+        signature
+    );
+    // Filtered no-op transactions come back with the default signature
+    metrics::record_transaction(labels[0], labels[1], labels[2], Ok(Signature::default())).unwrap();
+    let error = ClientError::from(ClientErrorKind::Custom(String::from("Node is down")));
+    assert!(metrics::record_transaction(labels[0], labels[1], labels[2], Err(error)).is_err());
+
+    assert_eq!(TRANSACTIONS_SENT.with_label_values(&labels).get(), 3);
+    assert_eq!(TRANSACTIONS_CONFIRMED.with_label_values(&labels).get(), 1);
+    assert_eq!(NOP_REJECTIONS.with_label_values(&labels).get(), 1);
+    assert_eq!(TRANSACTIONS_FAILED.with_label_values(&labels).get(), 1);
+
+    // Each instance has its own series
+    let other_labels = ["liquidation", market.as_str(), "1"];
+    assert_eq!(TRANSACTIONS_SENT.with_label_values(&other_labels).get(), 0);
+}
+
+#[tokio::test]
+async fn test_serve_metrics() {
+    let market = Pubkey::new_unique().to_string();
+    TRANSACTIONS_SENT
+        .with_label_values(&["funding", &market, "0"])
+        .inc();
+
+    let address: SocketAddr = "127.0.0.1:19898".parse().unwrap();
+    tokio::spawn(metrics::serve(address));
+    let mut response = None;
+    for _ in 0..50 {
+        response = task::spawn_blocking(move || get(address, "/metrics"))
+            .await
+            .unwrap();
+        if response.is_some() {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    // The registered metrics are exposed in the text format
+    let response = response.unwrap();
+    assert_eq!(response.split_whitespace().nth(1), Some("200"));
+    assert!(response.contains(&format!(
+        r#"perps_crank_transactions_sent_total{{instance="0",market="{}",service="funding"}} 1"#,
+        market
+    )));
+    assert!(response.contains("# TYPE perps_crank_transactions_sent_total counter"));
+
+    let response = task::spawn_blocking(move || get(address, "/health/live"))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(response.ends_with("ok"));
+    let response = task::spawn_blocking(move || get(address, "/unknown"))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(response.split_whitespace().nth(1), Some("404"));
+}
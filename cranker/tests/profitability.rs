@@ -0,0 +1,190 @@
+use std::{collections::HashMap, sync::Arc};
+
+use audaces_protocol::{
+    positions_book::{
+        memory::{Memory, SLOT_SIZE, TAG_SIZE},
+        page::Page,
+        positions_book_tree::PositionsBook,
+    },
+    processor::{ALLOCATION_FEE, FEE_REFERRER},
+    state::{
+        instance::{
+            get_page_infos_offset, write_instance_and_memory, Instance, PageInfo, INSTANCE_VERSION,
+        },
+        PositionType,
+    },
+};
+use perps_crank::{
+    backend::RpcBackend,
+    metrics::UNPROFITABLE_SKIPS,
+    profitability::{
+        estimate_garbage_collection_reward, estimate_liquidation_reward, get_transaction_cost,
+        is_profitable,
+    },
+    utils::RetryPolicy,
+    Context, Periods,
+};
+use solana_program::pubkey::Pubkey;
+use solana_program_test::ProgramTest;
+use solana_sdk::{native_token::lamports_to_sol, signature::Keypair};
+
+mod backend;
+
+use backend::BanksBackend;
+
+// (liquidation index, collateral, side) of the positions of the instance
+const POSITIONS: [(u64, u64, PositionType); 4] = [
+    (2_100, 1_000_000, PositionType::Short),
+    (2_200, 1_000_000, PositionType::Short),
+    (3_000, 3_000_000, PositionType::Short),
+    (500, 4_000_000, PositionType::Long),
+];
+
+// Serializes an instance holding the positions in a single page, after liquidating the shorts
+// reached by the liquidation index if there is one
+fn build_instance(liquidation_index: Option<u64>) -> (Vec<u8>, HashMap<Pubkey, Vec<u8>>) {
+    let page_key = Pubkey::new_unique();
+    let mut page_infos = vec![PageInfo::new(&page_key)];
+    let mut page_data = vec![0; TAG_SIZE + 32 * SLOT_SIZE];
+    let mut instance = Instance {
+        version: INSTANCE_VERSION,
+        shorts_pointer: None,
+        longs_pointer: None,
+        garbage_pointer: None,
+        number_of_pages: 1,
+    };
+    {
+        let page = Page::new_from_slice_unchecked(&mut page_data, &page_infos[0]).unwrap();
+        let mut book = PositionsBook::new(None, None, Memory::new(vec![page], None));
+        for (index, collateral, side) in POSITIONS.iter() {
+            book.open_position(
+                *index,
+                *collateral,
+                collateral * 10,
+                collateral * 10,
+                *side,
+                0,
+                &Pubkey::new_unique(),
+            )
+            .unwrap();
+        }
+        if let Some(index) = liquidation_index {
+            book.liquidate(index, PositionType::Short, None).unwrap();
+        }
+        instance.update(&book, &mut page_infos);
+    }
+    let mut instance_data = vec![0; get_page_infos_offset(INSTANCE_VERSION) + PageInfo::LEN];
+    write_instance_and_memory(&mut instance_data, &page_infos, &instance).unwrap();
+    let mut accounts = HashMap::new();
+    accounts.insert(page_key, page_data);
+    (instance_data, accounts)
+}
+
+fn referrer_share(collateral: u64) -> u64 {
+    collateral * FEE_REFERRER / 100
+}
+
+#[test]
+fn test_estimate_liquidation_reward() {
+    let (instance_data, accounts) = build_instance(None);
+    let estimate = |index| estimate_liquidation_reward(&instance_data, &accounts, index).unwrap();
+
+    // No position is reached between the highest long and the lowest short
+    assert_eq!(estimate(1_000), 0);
+    assert_eq!(estimate(2_150), referrer_share(1_000_000));
+    assert_eq!(estimate(2_500), referrer_share(2_000_000));
+    assert_eq!(estimate(3_500), referrer_share(5_000_000));
+    assert_eq!(estimate(400), referrer_share(4_000_000));
+
+    // The estimate works on a copy of the pages
+    assert_eq!(estimate(3_500), referrer_share(5_000_000));
+
+    // All the pages of the instance are required
+    assert!(estimate_liquidation_reward(&instance_data, &HashMap::new(), 3_500).is_err());
+}
+
+#[test]
+fn test_estimate_garbage_collection_reward() {
+    let (instance_data, accounts) = build_instance(None);
+    assert_eq!(
+        estimate_garbage_collection_reward(&instance_data, &accounts, 10).unwrap(),
+        0
+    );
+
+    // The liquidated leaves are freed right away, the liquidated subtree holding the two lowest
+    // shorts is flagged for garbage collection. Only the head of the list is known to be collected.
+    let (instance_data, accounts) = build_instance(Some(2_150));
+    assert_eq!(
+        estimate_garbage_collection_reward(&instance_data, &accounts, 10).unwrap(),
+        0
+    );
+    let (instance_data, accounts) = build_instance(Some(2_500));
+    assert_eq!(
+        estimate_garbage_collection_reward(&instance_data, &accounts, 10).unwrap(),
+        ALLOCATION_FEE
+    );
+    assert_eq!(
+        estimate_garbage_collection_reward(&instance_data, &accounts, 0).unwrap(),
+        0
+    );
+}
+
+#[test]
+fn test_is_profitable() {
+    let market = Pubkey::new_unique().to_string();
+    let skips = || {
+        UNPROFITABLE_SKIPS
+            .with_label_values(&["liquidation", &market, "0"])
+            .get()
+    };
+
+    // No-ops are skipped even when the cost is unknown
+    assert!(!is_profitable("liquidation", &market, "0", 0, None));
+    assert!(is_profitable("liquidation", &market, "0", 1, None));
+    assert_eq!(skips(), 1);
+
+    // Otherwise the reward has to exceed the fee
+    assert!(!is_profitable(
+        "liquidation",
+        &market,
+        "0",
+        5_000,
+        Some(5_000)
+    ));
+    assert!(is_profitable(
+        "liquidation",
+        &market,
+        "0",
+        5_001,
+        Some(5_000)
+    ));
+    assert_eq!(skips(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_get_transaction_cost() {
+    let (banks_client, _, _) = ProgramTest::default().start().await;
+    let mut ctx = Context {
+        program_id: Pubkey::new_unique(),
+        market: Pubkey::new_unique(),
+        fee_payer: Arc::new(Keypair::new()),
+        connection: Arc::new(BanksBackend::new(banks_client, vec![])),
+        websocket_url: String::new(),
+        num_threads: 1,
+        periods: Periods::default(),
+        retry: RetryPolicy::default(),
+        sol_price: None,
+        checkpoints: None,
+    };
+    // The cost is unknown without the price of SOL
+    assert_eq!(get_transaction_cost(&ctx, 6).await.unwrap(), None);
+
+    ctx.sol_price = Some(40.);
+    let lamports = ctx.connection.get_lamports_per_signature().unwrap();
+    let expected_cost = (lamports_to_sol(lamports) * 40. * 1e6).ceil() as u64;
+    assert!(expected_cost > 0);
+    assert_eq!(
+        get_transaction_cost(&ctx, 6).await.unwrap(),
+        Some(expected_cost)
+    );
+}
@@ -0,0 +1,389 @@
+#![cfg(feature = "mock-oracle")]
+use std::sync::Arc;
+
+use audaces_protocol::state::PositionType;
+use perps_crank::{
+    checkpoint::CheckpointStore, crank_auto_deleverage_iteration, crank_defragmentation_iteration,
+    crank_funding_extraction_iteration, crank_funding_iteration, crank_garbage_collection,
+    crank_liquidation_cleanup_iteration, crank_liquidation_iteration,
+    crank_page_provisioning_iteration, crank_rebalancing_iteration, get_market,
+    provisioning::Provisioning, rebalancing::Rebalancing, swarm::Swarm, utils::RetryPolicy,
+    Context as CrankContext, Periods,
+};
+use solana_program::system_instruction::transfer;
+use solana_sdk::signature::{Keypair, Signer};
+
+mod backend;
+#[allow(dead_code)]
+#[path = "../../program/tests/common/mod.rs"]
+pub mod common;
+
+use backend::BanksBackend;
+use common::{
+    context::Context,
+    utils::{create_and_get_associated_token_address, sign_send_instructions},
+};
+
+// Opens a long position on a fresh market and returns a cranker connected to the same bank
+async fn setup() -> (Context, Arc<CrankContext>) {
+    let mut context = Context::init(0, 6, 6).await;
+    context.change_oracle_price(10_000 << 32u64).await.unwrap();
+    context
+        .create_market("BTC/USD".to_string(), 1e10f64 as u64, 6, 6)
+        .await
+        .unwrap();
+    context.add_instance(1, 1_000_000).await.unwrap();
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+
+    // The cranking rewards are sent to the associated token account of the fee payer
+    let fee_payer = Keypair::from_bytes(&context.prg_test_ctx.payer.to_bytes()).unwrap();
+    let (transaction, _) = create_and_get_associated_token_address(
+        &context.prg_test_ctx,
+        &fee_payer.pubkey(),
+        &context.test_ctx.usdc_mint.pubkey(),
+    );
+    context
+        .prg_test_ctx
+        .banks_client
+        .process_transaction(transaction)
+        .await
+        .unwrap();
+
+    let connection = BanksBackend::new(
+        context.prg_test_ctx.banks_client.clone(),
+        context.user_ctx.user_accounts.clone(),
+    );
+    let ctx = Arc::new(CrankContext {
+        program_id: context.market_ctx.audaces_protocol_program_id,
+        market: context.market_ctx.market_account,
+        fee_payer: Arc::new(fee_payer),
+        connection: Arc::new(connection),
+        websocket_url: String::new(),
+        num_threads: 1,
+        periods: Periods::default(),
+        retry: RetryPolicy::default(),
+        sol_price: None,
+        checkpoints: None,
+    });
+    (context, ctx)
+}
+
+// Returns a cranker paying with the funded market admin
+async fn setup_admin(context: &mut Context, ctx: &Arc<CrankContext>) -> Arc<CrankContext> {
+    let admin = Keypair::from_bytes(&context.test_ctx.market_admin_keypair.to_bytes()).unwrap();
+    let payer = context.prg_test_ctx.payer.pubkey();
+    sign_send_instructions(
+        &mut context.prg_test_ctx,
+        vec![transfer(&payer, &admin.pubkey(), 1_000_000_000)],
+        vec![],
+    )
+    .await
+    .unwrap();
+    Arc::new(CrankContext {
+        program_id: ctx.program_id,
+        market: ctx.market,
+        fee_payer: Arc::new(admin),
+        connection: Arc::clone(&ctx.connection),
+        websocket_url: String::new(),
+        num_threads: 1,
+        periods: Periods::default(),
+        retry: RetryPolicy::default(),
+        sol_price: None,
+        checkpoints: None,
+    })
+}
+
+async fn liquidate(context: &mut Context, ctx: &Arc<CrankContext>) {
+    context.change_oracle_price(1 << 32u64).await.unwrap();
+    crank_liquidation_iteration(ctx).await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_liquidation() {
+    let (mut context, ctx) = setup().await;
+
+    // Nothing to liquidate at the opening price
+    crank_liquidation_iteration(&ctx).await.unwrap();
+    assert_ne!(
+        context.get_market_state().await.unwrap().total_collateral,
+        0
+    );
+
+    liquidate(&mut context, &ctx).await;
+    let market_state = context.get_market_state().await.unwrap();
+    assert_eq!(market_state.total_collateral, 0);
+    assert_eq!(market_state.open_longs_v_coin, 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_garbage_collection() {
+    let (mut context, ctx) = setup().await;
+    liquidate(&mut context, &ctx).await;
+    assert_ne!(
+        context.get_market_data().await.unwrap().gc_list_lengths[0],
+        0
+    );
+
+    let (market, quote_mint) = get_market(ctx.program_id, ctx.market, &*ctx.connection).unwrap();
+    let target = spl_associated_token_account::get_associated_token_address(
+        &ctx.fee_payer.pubkey(),
+        &quote_mint,
+    );
+    crank_garbage_collection(&ctx, &market, &target)
+        .await
+        .unwrap();
+    assert_eq!(
+        context.get_market_data().await.unwrap().gc_list_lengths[0],
+        0
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_liquidation_cleanup() {
+    let (mut context, ctx) = setup().await;
+    liquidate(&mut context, &ctx).await;
+    assert_eq!(
+        context
+            .get_user_account(0)
+            .await
+            .unwrap()
+            .number_of_open_positions,
+        1
+    );
+
+    crank_liquidation_cleanup_iteration(&ctx, &Swarm::default())
+        .await
+        .unwrap();
+    assert_eq!(
+        context
+            .get_user_account(0)
+            .await
+            .unwrap()
+            .number_of_open_positions,
+        0
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_funding() {
+    let (mut context, ctx) = setup().await;
+    // Both positions on the instance are charged by a single extraction
+    context
+        .open_position(PositionType::Short, 500_000, 5 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    let initial_market_state = context.get_market_state().await.unwrap();
+    let initial_balance = context.get_user_account(0).await.unwrap().balance;
+    // Past the funding period, the sample taken by the crank is turned into a funding ratio
+    context.prg_test_ctx.warp_to_slot(10_000).unwrap();
+
+    let (market, _) = get_market(ctx.program_id, ctx.market, &*ctx.connection).unwrap();
+    crank_funding_iteration(&ctx, &market).await.unwrap();
+    let market_state = context.get_market_state().await.unwrap();
+    assert_eq!(
+        market_state.funding_history_offset,
+        initial_market_state.funding_history_offset + 1
+    );
+    // The long bias keeps the mark price above the oracle price, the longs pay the shorts
+    assert!(market_state.funding_history[initial_market_state.funding_history_offset as usize] > 0);
+
+    crank_funding_extraction_iteration(&ctx, &Swarm::default())
+        .await
+        .unwrap();
+
+    let market_state = context.get_market_state().await.unwrap();
+    for position_index in 0..2 {
+        let position = context.get_position(position_index, 0).await.unwrap();
+        assert_eq!(
+            position.last_funding_offset,
+            market_state.funding_history_offset
+        );
+    }
+    // The funding owed by the net long exposure of the account is taken from its balance
+    let balance = context.get_user_account(0).await.unwrap().balance;
+    assert!(balance < initial_balance);
+    assert_eq!(
+        market_state.total_user_balances,
+        initial_market_state.total_user_balances - (initial_balance - balance)
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_funding_checkpoint() {
+    let (mut context, ctx) = setup().await;
+    let store = CheckpointStore::temporary().unwrap();
+    let ctx = Arc::new(CrankContext {
+        program_id: ctx.program_id,
+        market: ctx.market,
+        fee_payer: Arc::clone(&ctx.fee_payer),
+        connection: Arc::clone(&ctx.connection),
+        websocket_url: String::new(),
+        num_threads: 1,
+        periods: Periods::default(),
+        retry: RetryPolicy::default(),
+        sol_price: None,
+        checkpoints: Some(store.clone()),
+    });
+    let initial_market_state = context.get_market_state().await.unwrap();
+    let initial_balance = context.get_user_account(0).await.unwrap().balance;
+    context.prg_test_ctx.warp_to_slot(10_000).unwrap();
+    let (market, _) = get_market(ctx.program_id, ctx.market, &*ctx.connection).unwrap();
+    crank_funding_iteration(&ctx, &market).await.unwrap();
+    crank_funding_extraction_iteration(&ctx, &Swarm::default())
+        .await
+        .unwrap();
+
+    // The funding was extracted from the long
+    let market_state = context.get_market_state().await.unwrap();
+    assert_eq!(
+        market_state.funding_history_offset,
+        initial_market_state.funding_history_offset + 1
+    );
+    assert!(context.get_user_account(0).await.unwrap().balance < initial_balance);
+
+    // The account is skipped until the next funding offset
+    let sweep = store.sweep("funding_extraction", &ctx.market).unwrap();
+    assert_eq!(
+        sweep.position().unwrap(),
+        Some((market_state.last_funding_timestamp, true))
+    );
+    assert!(sweep
+        .is_processed(&context.user_ctx.user_accounts[0])
+        .unwrap());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_auto_deleverage() {
+    let (mut context, ctx) = setup().await;
+
+    // The insurance fund is healthy, positions are left untouched
+    crank_auto_deleverage_iteration(&ctx).await.unwrap();
+    assert_eq!(
+        context
+            .get_user_account(0)
+            .await
+            .unwrap()
+            .number_of_open_positions,
+        1
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_page_provisioning() {
+    let (mut context, ctx) = setup().await;
+
+    // Pages are added by the market admin
+    let admin_ctx = setup_admin(&mut context, &ctx).await;
+    let number_of_pages = || {
+        get_market(ctx.program_id, ctx.market, &*ctx.connection)
+            .unwrap()
+            .0
+            .instances[0]
+            .memory_pages
+            .len()
+    };
+
+    // The single open position is far from filling the page
+    let mut provisioning = Provisioning {
+        threshold: 0.5,
+        page_slots: 100,
+        dry_run: false,
+    };
+    crank_page_provisioning_iteration(&admin_ctx, &provisioning)
+        .await
+        .unwrap();
+    assert_eq!(number_of_pages(), 1);
+
+    provisioning.threshold = f64::MIN_POSITIVE;
+    provisioning.dry_run = true;
+    crank_page_provisioning_iteration(&admin_ctx, &provisioning)
+        .await
+        .unwrap();
+    assert_eq!(number_of_pages(), 1);
+
+    provisioning.dry_run = false;
+    crank_page_provisioning_iteration(&admin_ctx, &provisioning)
+        .await
+        .unwrap();
+    assert_eq!(number_of_pages(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_defragmentation() {
+    let (mut context, ctx) = setup().await;
+
+    // A single page has nowhere to move its nodes
+    crank_defragmentation_iteration(&ctx).await.unwrap();
+    let market_data = context.get_market_data().await.unwrap();
+    assert_ne!(market_data.page_full_ratios[0][0], 0.);
+    let total_collateral = market_data.total_collateral;
+
+    // The nodes of the open position move to the new page
+    context.add_page(0, 1_000_000).await.unwrap();
+    crank_defragmentation_iteration(&ctx).await.unwrap();
+    let market_data = context.get_market_data().await.unwrap();
+    assert_eq!(market_data.page_full_ratios[0][0], 0.);
+    assert_ne!(market_data.page_full_ratios[0][1], 0.);
+    assert_eq!(market_data.total_collateral, total_collateral);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_rebalancing() {
+    let (mut context, ctx) = setup().await;
+    let admin_ctx = setup_admin(&mut context, &ctx).await;
+
+    // The rebalancing book is a funded user account owned by the market admin
+    context.create_user_accounts(1).await.unwrap();
+    context.add_budget(50_000_000, 1).await.unwrap();
+    let admin = Keypair::from_bytes(&context.test_ctx.market_admin_keypair.to_bytes()).unwrap();
+    context.transfer_user_account(admin, 1).await.unwrap();
+    let user_account = context.user_ctx.user_accounts[1];
+
+    // The long position is offset by a short of the rebalancing book
+    let mut rebalancing = Rebalancing {
+        threshold: 0.001,
+        unwind_threshold: 0.,
+        max_leverage: 1.,
+        max_collateral: 20_000_000,
+        instance_index: 0,
+    };
+    crank_rebalancing_iteration(&admin_ctx, &user_account, &rebalancing)
+        .await
+        .unwrap();
+    let market_state = context.get_market_state().await.unwrap();
+    assert_eq!(
+        market_state.open_longs_v_coin,
+        market_state.open_shorts_v_coin
+    );
+    assert_eq!(
+        context
+            .get_user_account(1)
+            .await
+            .unwrap()
+            .number_of_open_positions,
+        1
+    );
+
+    // The book is unwound once the remaining imbalance is tolerated
+    rebalancing.threshold = 0.5;
+    rebalancing.unwind_threshold = 0.4;
+    crank_rebalancing_iteration(&admin_ctx, &user_account, &rebalancing)
+        .await
+        .unwrap();
+    assert_eq!(
+        context
+            .get_user_account(1)
+            .await
+            .unwrap()
+            .number_of_open_positions,
+        0
+    );
+    assert_eq!(
+        context.get_market_state().await.unwrap().open_shorts_v_coin,
+        0
+    );
+}
@@ -0,0 +1,50 @@
+use perps_crank::swarm::Swarm;
+use solana_program::pubkey::Pubkey;
+
+fn assigned_nodes(size: u16, redundancy: u16, account: &Pubkey) -> Vec<u16> {
+    (0..size)
+        .filter(|n| {
+            Swarm::new(size, *n, redundancy)
+                .unwrap()
+                .is_assigned(account)
+        })
+        .collect()
+}
+
+#[test]
+fn test_swarm_coverage() {
+    let accounts = (0..1_000).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+    for (size, redundancy) in &[(1, 1), (3, 1), (7, 2), (300, 3)] {
+        let mut loads = vec![0; *size as usize];
+        for a in &accounts {
+            let nodes = assigned_nodes(*size, *redundancy, a);
+            assert_eq!(nodes.len(), *redundancy as usize);
+            for n in nodes {
+                loads[n as usize] += 1;
+            }
+        }
+        // Every node gets a share of the accounts for small swarms
+        if *size <= 7 {
+            assert!(loads.iter().all(|l| *l > 0));
+        }
+    }
+    assert!(Swarm::new(0, 0, 1).is_err());
+    assert!(Swarm::new(3, 3, 1).is_err());
+    assert!(Swarm::new(3, 0, 4).is_err());
+}
+
+#[test]
+fn test_swarm_resize() {
+    let accounts = (0..1_000).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+    let mut moved = 0;
+    for a in &accounts {
+        let before = assigned_nodes(4, 1, a);
+        let after = assigned_nodes(5, 1, a);
+        if before != after {
+            // Accounts only move to the new node
+            assert_eq!(after, vec![4]);
+            moved += 1;
+        }
+    }
+    assert!(moved > 0 && moved < accounts.len() / 2);
+}
@@ -0,0 +1,123 @@
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use audaces_protocol::error::PerpError;
+use perps_crank::{
+    error::CrankError,
+    utils::{self, ErrorClassification, RetryPolicy},
+};
+use solana_client::client_error::{ClientError, ClientErrorKind};
+use solana_program::instruction::InstructionError;
+use solana_sdk::transaction::TransactionError;
+
+fn instruction_error(e: InstructionError) -> ClientError {
+    ClientError::from(ClientErrorKind::TransactionError(
+        TransactionError::InstructionError(0, e),
+    ))
+}
+
+fn program_error(e: PerpError) -> ClientError {
+    instruction_error(InstructionError::Custom(e as u32))
+}
+
+#[test]
+fn test_client_error_classification() {
+    // The program errors depend on the state of the accounts, retrying doesn't help
+    for e in [
+        PerpError::OutOfSpace,
+        PerpError::PositionNotFound,
+        PerpError::MarginTooLow,
+        PerpError::PendingFunding,
+        PerpError::MissingPage,
+        PerpError::DeleveragingSide,
+    ]
+    .iter()
+    {
+        assert!(program_error(e.clone()).is_fatal(), "{:?}", e);
+    }
+    // No-ops are left to the no-op filter
+    assert!(!program_error(PerpError::Nop).is_fatal());
+    // Unknown custom codes are retried
+    assert!(!instruction_error(InstructionError::Custom(
+        PerpError::DeleveragingSide as u32 + 1
+    ))
+    .is_fatal());
+
+    assert!(instruction_error(InstructionError::InsufficientFunds).is_fatal());
+    assert!(!instruction_error(InstructionError::ProgramFailedToComplete).is_fatal());
+    assert!(ClientError::from(ClientErrorKind::TransactionError(
+        TransactionError::InsufficientFundsForFee
+    ))
+    .is_fatal());
+    assert!(!ClientError::from(ClientErrorKind::TransactionError(
+        TransactionError::BlockhashNotFound
+    ))
+    .is_fatal());
+    assert!(!ClientError::from(ClientErrorKind::Custom(String::from("Node is down"))).is_fatal());
+
+    assert!(!CrankError::ConnectionError.is_fatal());
+    assert!(CrankError::InvalidMarketState.is_fatal());
+}
+
+#[test]
+fn test_retry_delay() {
+    let policy = RetryPolicy {
+        initial_delay: 100,
+        max_delay: 1_000,
+        max_attempts: None,
+        deadline: None,
+    };
+    // The backoff doubles with every attempt until it reaches the maximum delay
+    let backoffs = [100, 200, 400, 800, 1_000, 1_000];
+    for _ in 0..100 {
+        for (attempt, backoff) in backoffs.iter().enumerate() {
+            let delay = policy.delay(attempt as u32 + 1);
+            assert!(delay >= Duration::from_millis(backoff / 2), "{:?}", delay);
+            assert!(delay <= Duration::from_millis(*backoff), "{:?}", delay);
+        }
+    }
+    // The shift doesn't overflow after many attempts
+    assert!(policy.delay(u32::MAX) <= Duration::from_millis(1_000));
+}
+
+#[tokio::test]
+async fn test_retry() {
+    let policy = RetryPolicy {
+        initial_delay: 1,
+        max_delay: 1,
+        max_attempts: Some(3),
+        deadline: None,
+    };
+    let attempts = AtomicU32::new(0);
+    let failing_task = |e: &CrankError| {
+        attempts.fetch_add(1, Ordering::Relaxed);
+        Err::<(), _>(e.clone())
+    };
+
+    // Fatal errors are not retried
+    assert!(matches!(
+        utils::retry(&policy, CrankError::InvalidMarketState, failing_task, |r| r).await,
+        Err(CrankError::FatalError(_))
+    ));
+    assert_eq!(attempts.swap(0, Ordering::Relaxed), 1);
+
+    assert_eq!(
+        utils::retry(&policy, CrankError::ConnectionError, failing_task, |r| r).await,
+        Err(CrankError::RetriesExhausted(3))
+    );
+    assert_eq!(attempts.swap(0, Ordering::Relaxed), 3);
+
+    // The filter is applied before the classification
+    assert_eq!(
+        utils::retry(
+            &policy,
+            CrankError::InvalidMarketState,
+            failing_task,
+            |_| Ok(())
+        )
+        .await,
+        Ok(())
+    );
+}
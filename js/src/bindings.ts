@@ -702,13 +702,15 @@ export async function increasePosition(
  * @param connection The solana connection object to the RPC node.
  * @param marketAddress The market's address
  * @param userAccount The user account.
+ * @param receiptsAccount The liquidation receipts account of the user account, if it has one.
  * @returns An array of signer accounts and an array of instructions.
  */
 export async function fundingExtraction(
   connection: Connection,
   marketAddress: PublicKey,
   instanceIndex: number,
-  userAccount: PublicKey
+  userAccount: PublicKey,
+  receiptsAccount?: PublicKey
 ): Promise<PrimedTransaction> {
   let marketState = await MarketState.retrieve(connection, marketAddress);
   let instructions: TransactionInstruction[] = [];
@@ -725,7 +727,8 @@ export async function fundingExtraction(
     marketState.instanceAddresses[instanceIndex],
     userAccount,
     marketState.oracleAddress,
-    memoryPages
+    memoryPages,
+    receiptsAccount
   );
   instructions.push(instruction);
 
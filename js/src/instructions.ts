@@ -1161,7 +1161,8 @@ export class extractFundingInstruction {
     instanceAccount: PublicKey,
     userAccount: PublicKey,
     oracleAccount: PublicKey,
-    memory_pages: PublicKey[]
+    memory_pages: PublicKey[],
+    receiptsAccount?: PublicKey
   ): TransactionInstruction {
     const data = Buffer.from(this.serialize());
     let keys = [
@@ -1200,6 +1201,21 @@ export class extractFundingInstruction {
         };
       })
     );
+    // Required when the user account has a liquidation receipts account
+    if (receiptsAccount) {
+      keys.push(
+        {
+          pubkey: SYSVAR_CLOCK_PUBKEY,
+          isSigner: false,
+          isWritable: false,
+        },
+        {
+          pubkey: receiptsAccount,
+          isSigner: false,
+          isWritable: true,
+        }
+      );
+    }
 
     return new TransactionInstruction({
       keys,
@@ -50,7 +50,8 @@ impl PrintProgramError for PerpError {
             ),
             PerpError::NegativePayout => msg!("Error: This open position cannot be closed as it should be liquidated."),
             PerpError::ImbalancedMarket => msg!("Error: The market is imbalanced."),
-            PerpError::NetworkSlippageTooLarge => msg!("Error: The price slippage due to execution latency exceeds the specified margin")
+            PerpError::NetworkSlippageTooLarge => msg!("Error: The price slippage due to execution latency exceeds the specified margin"),
+            PerpError::MarketInsolvent => msg!("Error: The insurance fund is depleted, the market only accepts position reductions until it is deleveraged.")
         }
     }
 }
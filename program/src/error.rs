@@ -32,6 +32,14 @@ pub enum PerpError {
     ImbalancedMarket,
     #[error("The price slippage due to execution latency exceeds the provided margin")]
     NetworkSlippageTooLarge,
+    #[error("The insurance fund is depleted, the market only accepts position reductions")]
+    MarketInsolvent,
+    #[error("A liquidation of the instance is pending")]
+    PendingLiquidation,
+    #[error("A memory page required by the instruction is missing")]
+    MissingPage,
+    #[error("Only the positions on the side of the net open interest can be deleveraged")]
+    DeleveragingSide,
 }
 
 pub type PerpResult = Result<(), PerpError>;
@@ -4,14 +4,18 @@ use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::{
     instruction::{AccountMeta, Instruction},
     pubkey::Pubkey,
-    sysvar::clock,
+    sysvar::{clock, rent},
 };
 
 #[cfg(feature = "fuzz")]
 use arbitrary::Arbitrary;
 
 use crate::{
-    processor::{FUNDING_EXTRACTION_LABEL, FUNDING_LABEL, LIQUIDATION_LABEL, TRADE_LABEL},
+    positions_book::memory::get_page_bitmap,
+    processor::{
+        AUTO_DELEVERAGE_LABEL, FUNDING_EXTRACTION_LABEL, FUNDING_LABEL, LIQUIDATION_LABEL,
+        TRADE_LABEL,
+    },
     state::PositionType,
 };
 #[repr(C)]
@@ -218,7 +222,16 @@ pub enum PerpInstruction {
     ///   1. `[writable]` The market account
     ///   2. `[writable]` The instance account
     ///   3. `[writable]` The user account
-    ///   4... `[writable]` The positions book page accounts
+    ///   4. `[]` The funding extraction label account
+    ///   5. `[]` The price oracle account
+    ///   6..N `[writable]` The positions book page accounts
+    ///   N+1. `[]` (Receipts) The clock sysvar account
+    ///   N+2. `[writable]` (Receipts) The liquidation receipts account of the user account, which
+    ///   records the positions liquidated for lack of funds
+    ///
+    /// The receipts accounts are required when the user account has a liquidation receipts account.
+    /// The positions indexed with the margin preceding the margin tiers are moved to the liquidation
+    /// index of their tier.
     FundingExtraction {
         instance_index: u8,
     },
@@ -260,6 +273,146 @@ pub enum PerpInstruction {
     TransferPosition {
         position_index: u16,
     },
+    /// Reduce a profitable position when the insurance fund is depleted.
+    /// Only the share of the position whose profit covers the insurance fund shortfall is closed,
+    /// and that profit is reduced by the shortfall. The funding of the current cycle is charged on
+    /// the closed part as when closing it.
+    /// The position has to be on the side of the net open interest. The admin deleverages the
+    /// positions with the highest return on collateral first.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[writable]` The market account
+    ///   2. `[writable]` The instance account
+    ///   3. `[]` The market vault account
+    ///   4. `[]` The price oracle account
+    ///   5. `[signer]` The market admin account
+    ///   6. `[writable]` The user account
+    ///   7. `[]` The auto-deleveraging label account
+    ///   8. `[]` The clock sysvar account
+    ///   9... `[writable]` The positions book page accounts
+    AutoDeleverage {
+        instance_index: u8,
+        position_index: u16,
+    },
+    /// Remove the positions of a user account on an instance which were liquidated.
+    /// A flat reward per removed position is transferred to the cranker.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[]` The spl token program account
+    ///   2. `[writable]` The market account
+    ///   3. `[]` The instance account
+    ///   4. `[writable]` The market vault account
+    ///   5. `[]` The market signer program account
+    ///   6. `[writable]` The user account
+    ///   7. `[writable]` The target USDC account
+    ///   8. `[]` The liquidation label account
+    ///   9..N `[]` The positions book page accounts
+    ///   N+1. `[]` (Receipts) The clock sysvar account
+    ///   N+2. `[writable]` (Receipts) The liquidation receipts account of the user account
+    ///
+    /// The receipts accounts are required when the user account has a liquidation receipts account.
+    PurgeLiquidatedPositions {
+        instance_index: u8,
+    },
+    /// Initialize an account recording the last liquidated positions of a user account.
+    /// The account has to be created beforehand, with a size of at least one receipt.
+    /// A user account is bound to a single receipts account, which the instructions removing its
+    /// liquidated positions then require.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[signer]` The user account owner
+    ///   2. `[writable]` The user account
+    ///   3. `[writable]` The liquidation receipts account
+    InitLiquidationReceipts,
+    /// Copy a memory page of the legacy layout, whose leaves don't hold their owner, into a new
+    /// page and close it.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[]` The market account
+    ///   2. `[signer]` The market admin account
+    ///   3. `[writable]` The instance account
+    ///   4. `[writable]` The legacy page account
+    ///   5. `[writable]` The new page account
+    ///   6. `[writable]` The lamports target account
+    ///   7. `[]` The rent sysvar account
+    MigratePage {
+        instance_index: u8,
+        page_index: u32,
+    },
+    /// Crank the liquidation of the losing positions in the market, processing at most
+    /// `max_nodes` nodes of the positions book. An unfinished liquidation is stored in the instance
+    /// and resumed by the next calls, the instance being locked until its completion.
+    /// A reward is transferred to the cranker by the call which starts the liquidation.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[]` The spl token program account
+    ///   2. `[writable]` The market account
+    ///   3. `[writable]` The instance account
+    ///   4. `[]` The market signer program account
+    ///   5. `[writable]` The bonfida buy and burn account
+    ///   6. `[writable]` The market vault account
+    ///   7. `[]` The price oracle account
+    ///   8. `[writable]` The target USDC account
+    ///   9. `[]` The liquidation label account
+    ///   10... `[writable]` The positions book page accounts
+    CrankBoundedLiquidation {
+        instance_index: u8,
+        max_nodes: u16,
+    },
+    /// Copy an instance into a new, larger, instance account which can reference more memory
+    /// pages, and close it. The instance is upgraded to the latest version in the process.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[writable]` The market account
+    ///   2. `[signer]` The market admin account
+    ///   3. `[writable]` The instance account
+    ///   4. `[writable]` The new instance account
+    ///   5. `[writable]` The lamports target account
+    ///   6. `[]` The rent sysvar account
+    MigrateInstance {
+        instance_index: u8,
+    },
+    /// Move at most `max_moves` nodes of a memory page, from the slot `first_slot` on, into the
+    /// free slots of the densest other pages. A page left without nodes is reset.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[]` The market account
+    ///   2. `[writable]` The instance account
+    ///   3... `[writable]` The positions book page accounts
+    DefragmentPage {
+        instance_index: u8,
+        page_index: u32,
+        first_slot: u32,
+        max_moves: u16,
+    },
+}
+
+impl PerpInstruction {
+    /// Whether the instruction uses the positions book, in which case its data can be followed by
+    /// a page selection bitmap. The data of the other instructions can't have trailing bytes.
+    pub fn takes_page_selection(&self) -> bool {
+        matches!(
+            self,
+            PerpInstruction::OpenPosition { .. }
+                | PerpInstruction::IncreasePosition { .. }
+                | PerpInstruction::ClosePosition { .. }
+                | PerpInstruction::CollectGarbage { .. }
+                | PerpInstruction::CrankLiquidation { .. }
+                | PerpInstruction::FundingExtraction { .. }
+                | PerpInstruction::Rebalance { .. }
+                | PerpInstruction::AutoDeleverage { .. }
+                | PerpInstruction::PurgeLiquidatedPositions { .. }
+                | PerpInstruction::CrankBoundedLiquidation { .. }
+                | PerpInstruction::DefragmentPage { .. }
+        )
+    }
 }
 
 pub enum CloseOrOpen {
@@ -267,6 +420,7 @@ pub enum CloseOrOpen {
     ClosePosition,
 }
 
+#[derive(Clone)]
 pub struct MarketContext {
     pub audaces_protocol_program_id: Pubkey,
     pub signer_nonce: u8,
@@ -279,6 +433,7 @@ pub struct MarketContext {
     pub instances: Vec<InstanceContext>,
 }
 
+#[derive(Clone)]
 pub struct InstanceContext {
     pub instance_account: Pubkey,
     pub memory_pages: Vec<Pubkey>,
@@ -660,6 +815,22 @@ pub fn crank_liquidation(
     }
 }
 
+pub fn crank_bounded_liquidation(
+    ctx: &MarketContext,
+    instance_index: u8,
+    max_nodes: u16,
+    target_token_account: Pubkey,
+) -> Instruction {
+    let mut instruction = crank_liquidation(ctx, instance_index, target_token_account);
+    instruction.data = PerpInstruction::CrankBoundedLiquidation {
+        instance_index,
+        max_nodes,
+    }
+    .try_to_vec()
+    .unwrap();
+    instruction
+}
+
 pub fn crank_funding(ctx: &MarketContext) -> Instruction {
     let instruction_data = PerpInstruction::CrankFunding;
     let data = instruction_data.try_to_vec().unwrap();
@@ -681,6 +852,7 @@ pub fn extract_funding(
     ctx: &MarketContext,
     instance_index: u8,
     open_positions_account: Pubkey,
+    receipts_account: Option<Pubkey>,
 ) -> Instruction {
     let instance = &ctx.instances[instance_index as usize];
     let instruction_data = PerpInstruction::FundingExtraction { instance_index };
@@ -697,6 +869,10 @@ pub fn extract_funding(
     for p in &instance.memory_pages {
         accounts.push(AccountMeta::new(*p, false))
     }
+    if let Some(r) = receipts_account {
+        accounts.push(AccountMeta::new_readonly(clock::id(), false));
+        accounts.push(AccountMeta::new(r, false));
+    }
     Instruction {
         program_id: ctx.audaces_protocol_program_id,
         accounts,
@@ -774,7 +950,7 @@ pub fn rebalance(
         AccountMeta::new_readonly(spl_token::id(), false),
         AccountMeta::new_readonly(clock::id(), false),
         AccountMeta::new(ctx.market_account, false),
-        AccountMeta::new(ctx.instances[0].instance_account, false),
+        AccountMeta::new(instance.instance_account, false),
         AccountMeta::new_readonly(ctx.market_signer_account, false),
         AccountMeta::new(ctx.market_vault, false),
         AccountMeta::new(ctx.bonfida_bnb, false),
@@ -839,3 +1015,206 @@ pub fn transfer_position(
         data,
     }
 }
+
+pub fn auto_deleverage(
+    ctx: &MarketContext,
+    instance_index: u8,
+    user_account: Pubkey,
+    position_index: u16,
+) -> Instruction {
+    let instance = &ctx.instances[instance_index as usize];
+    let data = PerpInstruction::AutoDeleverage {
+        instance_index,
+        position_index,
+    }
+    .try_to_vec()
+    .unwrap();
+    let mut accounts = vec![
+        AccountMeta::new(ctx.market_account, false),
+        AccountMeta::new(instance.instance_account, false),
+        AccountMeta::new_readonly(ctx.market_vault, false),
+        AccountMeta::new_readonly(ctx.oracle_account, false),
+        AccountMeta::new_readonly(ctx.admin_account, true),
+        AccountMeta::new(user_account, false),
+        AccountMeta::new_readonly(Pubkey::from_str(AUTO_DELEVERAGE_LABEL).unwrap(), false),
+        AccountMeta::new_readonly(clock::id(), false),
+    ];
+
+    for p in &instance.memory_pages {
+        accounts.push(AccountMeta::new(*p, false))
+    }
+    Instruction {
+        program_id: ctx.audaces_protocol_program_id,
+        accounts,
+        data,
+    }
+}
+
+pub fn purge_liquidated_positions(
+    ctx: &MarketContext,
+    instance_index: u8,
+    user_account: Pubkey,
+    target_token_account: Pubkey,
+    receipts_account: Option<Pubkey>,
+) -> Instruction {
+    let instance = &ctx.instances[instance_index as usize];
+    let data = PerpInstruction::PurgeLiquidatedPositions { instance_index }
+        .try_to_vec()
+        .unwrap();
+    let mut accounts = vec![
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new(ctx.market_account, false),
+        AccountMeta::new_readonly(instance.instance_account, false),
+        AccountMeta::new(ctx.market_vault, false),
+        AccountMeta::new_readonly(ctx.market_signer_account, false),
+        AccountMeta::new(user_account, false),
+        AccountMeta::new(target_token_account, false),
+        AccountMeta::new_readonly(Pubkey::from_str(LIQUIDATION_LABEL).unwrap(), false),
+    ];
+
+    for p in &instance.memory_pages {
+        accounts.push(AccountMeta::new_readonly(*p, false))
+    }
+    if let Some(r) = receipts_account {
+        accounts.push(AccountMeta::new_readonly(clock::id(), false));
+        accounts.push(AccountMeta::new(r, false));
+    }
+    Instruction {
+        program_id: ctx.audaces_protocol_program_id,
+        accounts,
+        data,
+    }
+}
+
+pub fn init_liquidation_receipts(
+    ctx: &MarketContext,
+    user_account: Pubkey,
+    user_account_owner: Pubkey,
+    receipts_account: Pubkey,
+) -> Instruction {
+    let data = PerpInstruction::InitLiquidationReceipts
+        .try_to_vec()
+        .unwrap();
+    let accounts = vec![
+        AccountMeta::new_readonly(user_account_owner, true),
+        AccountMeta::new(user_account, false),
+        AccountMeta::new(receipts_account, false),
+    ];
+
+    Instruction {
+        program_id: ctx.audaces_protocol_program_id,
+        accounts,
+        data,
+    }
+}
+
+pub fn migrate_page(
+    ctx: &MarketContext,
+    instance_index: u8,
+    page_index: u32,
+    new_memory_page: Pubkey,
+    lamports_target: Pubkey,
+) -> Instruction {
+    let instance = &ctx.instances[instance_index as usize];
+    let instruction_data = PerpInstruction::MigratePage {
+        instance_index,
+        page_index,
+    };
+    let data = instruction_data.try_to_vec().unwrap();
+    let accounts = vec![
+        AccountMeta::new_readonly(ctx.market_account, false),
+        AccountMeta::new_readonly(ctx.admin_account, true),
+        AccountMeta::new(instance.instance_account, false),
+        AccountMeta::new(instance.memory_pages[page_index as usize], false),
+        AccountMeta::new(new_memory_page, false),
+        AccountMeta::new(lamports_target, false),
+        AccountMeta::new_readonly(rent::id(), false),
+    ];
+
+    Instruction {
+        program_id: ctx.audaces_protocol_program_id,
+        accounts,
+        data,
+    }
+}
+
+pub fn migrate_instance(
+    ctx: &MarketContext,
+    instance_index: u8,
+    new_instance_account: Pubkey,
+    lamports_target: Pubkey,
+) -> Instruction {
+    let instance = &ctx.instances[instance_index as usize];
+    let instruction_data = PerpInstruction::MigrateInstance { instance_index };
+    let data = instruction_data.try_to_vec().unwrap();
+    let accounts = vec![
+        AccountMeta::new(ctx.market_account, false),
+        AccountMeta::new_readonly(ctx.admin_account, true),
+        AccountMeta::new(instance.instance_account, false),
+        AccountMeta::new(new_instance_account, false),
+        AccountMeta::new(lamports_target, false),
+        AccountMeta::new_readonly(rent::id(), false),
+    ];
+
+    Instruction {
+        program_id: ctx.audaces_protocol_program_id,
+        accounts,
+        data,
+    }
+}
+
+pub fn defragment_page(
+    ctx: &MarketContext,
+    instance_index: u8,
+    page_index: u32,
+    first_slot: u32,
+    max_moves: u16,
+) -> Instruction {
+    let instance = &ctx.instances[instance_index as usize];
+    let instruction_data = PerpInstruction::DefragmentPage {
+        instance_index,
+        page_index,
+        first_slot,
+        max_moves,
+    };
+    let data = instruction_data.try_to_vec().unwrap();
+    let mut accounts = Vec::with_capacity(2 + instance.memory_pages.len());
+
+    accounts.push(AccountMeta::new_readonly(ctx.market_account, false));
+    accounts.push(AccountMeta::new(instance.instance_account, false));
+
+    for p in &instance.memory_pages {
+        accounts.push(AccountMeta::new(*p, false))
+    }
+    Instruction {
+        program_id: ctx.audaces_protocol_program_id,
+        accounts,
+        data,
+    }
+}
+
+/// Removes from an instruction the memory pages of the instance which are not listed in
+/// `page_indices`, and appends to its data the bitmap of the selected pages which the program checks
+/// the page accounts against. The program only loads the pages it is given, so an instruction which
+/// dereferences a page outside of the selection fails with a `MissingPage` error. The page path
+/// of a position can be computed client-side with `PositionsBook::get_page_path`.
+pub fn select_pages(
+    mut instruction: Instruction,
+    instance: &InstanceContext,
+    page_indices: &[u32],
+) -> Instruction {
+    instruction.accounts.retain(|a| {
+        match instance.memory_pages.iter().position(|p| p == &a.pubkey) {
+            Some(page_index) => page_indices.contains(&(page_index as u32)),
+            None => true,
+        }
+    });
+    // A previous selection is replaced
+    let mut data = instruction.data.as_slice();
+    let perp_instruction = PerpInstruction::deserialize(&mut data).unwrap();
+    assert!(perp_instruction.takes_page_selection());
+    let instruction_len = instruction.data.len() - data.len();
+    instruction.data.truncate(instruction_len);
+    instruction.data.extend(get_page_bitmap(page_indices));
+    instruction
+}
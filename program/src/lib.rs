@@ -3,6 +3,8 @@ pub mod entrypoint;
 pub mod instruction;
 pub mod positions_book;
 pub mod processor;
+#[cfg(not(target_arch = "bpf"))]
+pub mod quote;
 pub mod state;
 pub mod utils;
 
@@ -13,12 +13,33 @@ use crate::state::instance::{Instance, PageInfo};
 use super::page::Page;
 use super::tree_nodes::InnerNodeSchema;
 
-pub const SLOT_SIZE: usize = 47;
-pub(super) const PAGE_MASK: u32 = 0xf << 28;
+pub const SLOT_SIZE: usize = 74;
+/// Size of the slots of the pages written before the leaves held their owner
+pub const LEGACY_SLOT_SIZE: usize = 47;
 pub const TAG_SIZE: usize = 1;
+/// Largest number of pages of an instance
+pub const MAX_PAGES: usize = 256;
+/// Largest number of slots of a page, which are indexed by the low 24 bits of a pointer
+pub const MAX_PAGE_SLOTS: u32 = 1 << 24;
 
 pub type Pointer = u32;
 
+/// The low 4 bits of the page index of a pointer are stored in its top 4 bits, where the whole
+/// page index was stored when an instance had at most 16 pages. The high bits are stored in the
+/// next 4 bits, which are always zero in those older pointers as pages never got that large.
+pub fn get_page_index(pointer: Pointer) -> usize {
+    ((pointer >> 28) | (((pointer >> 24) & 0xf) << 4)) as usize
+}
+
+pub fn get_slot_index(pointer: Pointer) -> Pointer {
+    pointer & (MAX_PAGE_SLOTS - 1)
+}
+
+pub fn make_pointer(page_index: usize, slot_index: Pointer) -> Pointer {
+    let page_index = page_index as u32;
+    ((page_index & 0xf) << 28) | ((page_index >> 4) << 24) | slot_index
+}
+
 pub enum GarbageNodeSchema {
     Critbit = InnerNodeSchema::Critbit as isize,
     LeftPointer = InnerNodeSchema::LeftPointer as isize,
@@ -27,16 +48,99 @@ pub enum GarbageNodeSchema {
     PointerToNext = GarbageNodeSchema::IsLastToCollect as isize + 1,
 }
 
+/// Memory of an instance, over the pages given to an instruction. A missing page only fails the
+/// instruction when one of its slots is dereferenced.
 pub struct Memory<'a> {
-    pub pages: Vec<Page<'a>>,
+    pub pages: Vec<Option<Page<'a>>>,
     pub gc_list_hd: Option<Pointer>,
 }
 
 impl<'a> Memory<'a> {
     pub fn new(pages: Vec<Page<'a>>, gc_list_hd: Option<Pointer>) -> Self {
+        Self::new_partial(pages.into_iter().map(Some).collect(), gc_list_hd)
+    }
+
+    pub fn new_partial(pages: Vec<Option<Page<'a>>>, gc_list_hd: Option<Pointer>) -> Self {
         Memory { pages, gc_list_hd }
     }
 
+    pub fn get_page(&self, page_index: usize) -> Result<&Page<'a>, PerpError> {
+        match self.pages.get(page_index) {
+            Some(Some(page)) => Ok(page),
+            _ => {
+                msg!(
+                    "Memory page {:?} is required but was not provided",
+                    page_index
+                );
+                Err(PerpError::MissingPage)
+            }
+        }
+    }
+
+    pub fn get_page_mut(&mut self, page_index: usize) -> Result<&mut Page<'a>, PerpError> {
+        match self.pages.get_mut(page_index) {
+            Some(Some(page)) => Ok(page),
+            _ => {
+                msg!(
+                    "Memory page {:?} is required but was not provided",
+                    page_index
+                );
+                Err(PerpError::MissingPage)
+            }
+        }
+    }
+
+    /// Indices of the pages which the next allocations of slots are made in, following the
+    /// policy of `allocate`. Opening a position allocates two slots, so its instruction needs these
+    /// pages on top of the page path of the position, which is a larger set than closing it.
+    #[cfg(not(target_arch = "bpf"))]
+    pub fn get_allocation_pages(&self, number_of_slots: u64) -> Result<Vec<u32>, PerpError> {
+        // Slots left in the uninitialized memory, the uncounted free list and the counted free list
+        let mut free_slots = vec![];
+        for page in &self.pages {
+            free_slots.push(match page {
+                Some(page) if !page.is_legacy => {
+                    let uninitialized = page.page_size.saturating_sub(page.uninitialized_memory);
+                    match page.get_free_list_length()? {
+                        Some(length) => (uninitialized, 0, length),
+                        None => (uninitialized, page.get_nb_free_slots()? as u32, 0),
+                    }
+                }
+                _ => (0, 0, 0),
+            });
+        }
+        let mut res = vec![];
+        for _ in 0..number_of_slots {
+            let mut allocation_page = None;
+            let mut max_free_slots = 0;
+            for (i, (uninitialized, uncounted, counted)) in free_slots.iter().enumerate() {
+                // An uncounted free list stands for a single slot, as in `Page::get_free_slots`
+                let page_free_slots = uninitialized + (*uncounted).min(1) + counted;
+                if page_free_slots > max_free_slots {
+                    allocation_page = Some(i);
+                    max_free_slots = page_free_slots;
+                }
+            }
+            let page_index = match allocation_page {
+                Some(i) => i,
+                None => break,
+            };
+            let (uninitialized, uncounted, counted) = &mut free_slots[page_index];
+            if *uncounted > 0 {
+                *uncounted -= 1;
+            } else if *counted > 0 {
+                *counted -= 1;
+            } else {
+                *uninitialized -= 1;
+            }
+            if !res.contains(&(page_index as u32)) {
+                res.push(page_index as u32);
+            }
+        }
+        res.sort_unstable();
+        Ok(res)
+    }
+
     pub fn crank_garbage_collector(&mut self, max_iterations: u64) -> Result<u64, PerpError> {
         let mut freed_slots = 0;
         for _ in 0..max_iterations {
@@ -92,53 +196,96 @@ impl<'a> Memory<'a> {
         offset: usize,
         length: usize,
     ) -> Result<Vec<u8>, PerpError> {
-        let page_index = pointer >> 28;
-        self.pages[page_index as usize].read(!PAGE_MASK & pointer, offset, length)
+        self.get_page(get_page_index(pointer))?
+            .read(get_slot_index(pointer), offset, length)
     }
 
     pub fn free(&mut self, pointer: Pointer) -> PerpResult {
-        let page_index = pointer >> 28;
-        self.pages[page_index as usize].free(!PAGE_MASK & pointer)
+        self.get_page_mut(get_page_index(pointer))?
+            .free(get_slot_index(pointer))
     }
 
+    /// Allocates a slot in the provided page with the most free slots, so that the slots are
+    /// spread over the pages
     pub fn allocate(&mut self, slot_type: SlotType) -> Result<Pointer, PerpError> {
-        for (i, page) in self.pages.iter_mut().enumerate() {
-            if page.page_size != page.uninitialized_memory || page.free_slot_list_hd.is_some() {
-                let page_index = (i as u32) << 28;
-                return page.allocate(slot_type).map(|p| page_index | p);
+        let mut allocation_page = None;
+        let mut max_free_slots = 0;
+        for (i, page) in self.pages.iter().enumerate() {
+            if let Some(page) = page {
+                let free_slots = page.get_free_slots()?;
+                if free_slots > max_free_slots {
+                    allocation_page = Some(i);
+                    max_free_slots = free_slots;
+                }
+            }
+        }
+        match allocation_page {
+            Some(i) => self.allocate_in_page(i, slot_type),
+            None => {
+                if self.pages.iter().any(|p| p.is_none()) {
+                    msg!("The provided memory pages are full, a page with free slots is required");
+                    return Err(PerpError::MissingPage);
+                }
+                Err(PerpError::OutOfSpace)
             }
         }
-        Err(PerpError::OutOfSpace)
+    }
+
+    pub fn allocate_in_page(
+        &mut self,
+        page_index: usize,
+        slot_type: SlotType,
+    ) -> Result<Pointer, PerpError> {
+        self.get_page_mut(page_index)?
+            .allocate(slot_type)
+            .map(|p| make_pointer(page_index, p))
+    }
+
+    /// Returns the provided page with the fewest free slots left, other than the excluded one,
+    /// which slots are moved into when defragmenting the memory
+    pub fn get_densest_page(&self, excluded_page_index: usize) -> Result<Option<usize>, PerpError> {
+        let mut densest_page = None;
+        let mut min_free_slots = u32::MAX;
+        for (i, page) in self.pages.iter().enumerate() {
+            if let Some(page) = page {
+                let free_slots = page.get_free_slots()?;
+                if i != excluded_page_index && free_slots > 0 && free_slots < min_free_slots {
+                    densest_page = Some(i);
+                    min_free_slots = free_slots;
+                }
+            }
+        }
+        Ok(densest_page)
     }
 
     pub fn read_byte(&self, pointer: Pointer, offset: usize) -> Result<u8, PerpError> {
-        let page_index = pointer >> 28;
-        self.pages[page_index as usize].read_byte(!PAGE_MASK & pointer, offset)
+        self.get_page(get_page_index(pointer))?
+            .read_byte(get_slot_index(pointer), offset)
     }
 
     pub fn read_u64_be(&self, pointer: Pointer, offset: usize) -> Result<u64, PerpError> {
-        let page_index = pointer >> 28;
-        self.pages[page_index as usize].read_u64_be(!PAGE_MASK & pointer, offset)
+        self.get_page(get_page_index(pointer))?
+            .read_u64_be(get_slot_index(pointer), offset)
     }
 
     pub fn read_u64_le(&self, pointer: Pointer, offset: usize) -> Result<u64, PerpError> {
-        let page_index = pointer >> 28;
-        self.pages[page_index as usize].read_u64_le(!PAGE_MASK & pointer, offset)
+        self.get_page(get_page_index(pointer))?
+            .read_u64_le(get_slot_index(pointer), offset)
     }
 
     pub fn read_u32_le(&self, pointer: Pointer, offset: usize) -> Result<u32, PerpError> {
-        let page_index = pointer >> 28;
-        self.pages[page_index as usize].read_u32_le(!PAGE_MASK & pointer, offset)
+        self.get_page(get_page_index(pointer))?
+            .read_u32_le(get_slot_index(pointer), offset)
     }
 
     pub fn read_u16_le(&self, pointer: Pointer, offset: usize) -> Result<u16, PerpError> {
-        let page_index = pointer >> 28;
-        self.pages[page_index as usize].read_u16_le(!PAGE_MASK & pointer, offset)
+        self.get_page(get_page_index(pointer))?
+            .read_u16_le(get_slot_index(pointer), offset)
     }
 
     pub fn write(&mut self, pointer: Pointer, offset: usize, input: &[u8]) -> PerpResult {
-        let page_index = pointer >> 28;
-        self.pages[page_index as usize].write(!PAGE_MASK & pointer, offset, input)
+        self.get_page_mut(get_page_index(pointer))?
+            .write(get_slot_index(pointer), offset, input)
     }
 
     #[cfg(not(target_arch = "bpf"))]
@@ -157,21 +304,82 @@ impl<'a> Memory<'a> {
     }
 }
 
+/// Memory pages given to an instruction, as a bitmap of page indices appended to the instruction
+/// data. All the pages of the instance are expected when the bitmap is omitted.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PageSelection<'a>(Option<&'a [u8]>);
+
+impl<'a> PageSelection<'a> {
+    pub fn new(bitmap: &'a [u8]) -> Self {
+        if bitmap.is_empty() {
+            Self(None)
+        } else {
+            Self(Some(bitmap))
+        }
+    }
+
+    pub fn contains(&self, page_index: usize) -> bool {
+        match self.0 {
+            None => true,
+            Some(bitmap) => bitmap
+                .get(page_index / 8)
+                .map_or(false, |b| (b >> (page_index % 8)) & 1 == 1),
+        }
+    }
+
+    fn check(&self, number_of_pages: usize) -> Result<(), ProgramError> {
+        if let Some(bitmap) = self.0 {
+            if bitmap.len() > MAX_PAGES / 8
+                || (number_of_pages..bitmap.len() * 8).any(|i| self.contains(i))
+            {
+                msg!("The page selection references memory pages which don't exist");
+                return Err(ProgramError::InvalidInstructionData);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Bitmap of a page selection, to be appended to the instruction data
+pub fn get_page_bitmap(page_indices: &[u32]) -> Vec<u8> {
+    let len = page_indices
+        .iter()
+        .max()
+        .map_or(1, |i| (*i as usize) / 8 + 1);
+    let mut bitmap = vec![0; len];
+    for i in page_indices {
+        bitmap[(*i as usize) / 8] |= 1 << (i % 8);
+    }
+    bitmap
+}
+
+/// Parses the page accounts given to an instruction, which are the selected pages of the instance
+/// in the order of their page index
 pub fn parse_memory<'a>(
     instance: &Instance,
     pages_infos: &[PageInfo],
+    page_selection: PageSelection,
     accounts_iter: &mut Iter<AccountInfo<'a>>,
 ) -> Result<Memory<'a>, ProgramError> {
-    let mut pages = vec![];
-    for page_info in pages_infos {
+    page_selection.check(pages_infos.len())?;
+    let mut pages = Vec::with_capacity(pages_infos.len());
+    for (i, page_info) in pages_infos.iter().enumerate() {
+        if !page_selection.contains(i) {
+            pages.push(None);
+            continue;
+        }
         let account = next_account_info(accounts_iter)?;
         if account.key != &Pubkey::new(&page_info.address) {
             msg!("An invalid memory page was provided");
             return Err(ProgramError::InvalidArgument);
         }
-        pages.push(Page::new(account, page_info)?);
+        pages.push(Some(Page::new(account, page_info)?));
+    }
+    if pages.iter().all(|p| p.is_none()) && !pages.is_empty() {
+        msg!("No memory page was provided");
+        return Err(ProgramError::NotEnoughAccountKeys);
     }
-    Ok(Memory::new(pages, instance.garbage_pointer))
+    Ok(Memory::new_partial(pages, instance.garbage_pointer))
 }
 
 #[cfg(all(test, feature = "test-bpf"))]
@@ -203,13 +411,11 @@ mod tests {
                 data: Rc::clone(&data[i]),
                 free_slot_list_hd: None,
                 uninitialized_memory: 0,
+                is_legacy: false,
             });
         }
 
-        let mut mem = Memory {
-            pages: pages,
-            gc_list_hd: None,
-        };
+        let mut mem = Memory::new(pages, None);
 
         let mut rng = thread_rng();
 
@@ -250,42 +456,42 @@ mod tests {
                 tp.typ as u8,
                 "with pt: {:?} and page_index {:?}",
                 pt,
-                ((0xf << 28) & pt) >> 28
+                super::get_page_index(*pt)
             );
             assert_eq!(
                 mem.read_u32_le(*pt, 1).unwrap(),
                 tp.test_u32,
                 "with pt: {:?} and page_index {:?}",
                 pt,
-                ((0xf << 28) & pt) >> 28
+                super::get_page_index(*pt)
             );
             assert_eq!(
                 mem.read_u64_le(*pt, 5).unwrap(),
                 tp.test_u64,
                 "with pt: {:?} and page_index {:?}",
                 pt,
-                ((0xf << 28) & pt) >> 28
+                super::get_page_index(*pt)
             );
             assert_eq!(
                 mem.read_u16_le(*pt, 13).unwrap(),
                 tp.test_u16,
                 "with pt: {:?} and page_index {:?}",
                 pt,
-                ((0xf << 28) & pt) >> 28
+                super::get_page_index(*pt)
             );
             assert_eq!(
                 mem.read_byte(*pt, 15).unwrap(),
                 tp.test_byte,
                 "with pt: {:?} and page_index {:?}",
                 pt,
-                ((0xf << 28) & pt) >> 28
+                super::get_page_index(*pt)
             );
             assert_eq!(
                 mem.read(*pt, 16, 17).unwrap(),
                 tp.test_array,
                 "with pt: {:?} and page_index {:?}",
                 pt,
-                ((0xf << 28) & pt) >> 28
+                super::get_page_index(*pt)
             );
         }
         let choice_iter = nodes.choose_multiple(&mut rng, 10);
@@ -328,7 +534,7 @@ mod tests {
                 tp.typ as u8,
                 "with pt: {:?} and page_index {:?}",
                 pt,
-                ((0xf << 28) & pt) >> 28
+                super::get_page_index(*pt)
             );
             assert_eq!(mem.read_u32_le(*pt, 1).unwrap(), tp.test_u32);
             assert_eq!(mem.read_u64_le(*pt, 5).unwrap(), tp.test_u64);
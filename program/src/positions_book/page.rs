@@ -3,20 +3,26 @@ use std::{cell::RefCell, convert::TryInto, rc::Rc};
 use borsh::{BorshDeserialize, BorshSerialize};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
-use solana_program::{account_info::AccountInfo, program_error::ProgramError};
+use solana_program::{account_info::AccountInfo, msg, program_error::ProgramError};
 
 use crate::{
     error::{PerpError, PerpResult},
     state::{instance::PageInfo, StateObject},
 };
 
-use super::memory::{Pointer, SLOT_SIZE, TAG_SIZE};
+use super::{
+    memory::{Pointer, LEGACY_SLOT_SIZE, MAX_PAGE_SLOTS, SLOT_SIZE, TAG_SIZE},
+    tree_nodes::{LeafNodeSchema, LEGACY_MARGIN_FLAG, UNKNOWN_OWNER_FLAG},
+};
 
 pub struct Page<'a> {
     pub page_size: u32,
     pub data: Rc<RefCell<&'a mut [u8]>>,
     pub uninitialized_memory: Pointer,
     pub free_slot_list_hd: Option<Pointer>,
+    /// Set for the pages of the legacy layout which weren't migrated yet. Their slots are read as
+    /// if they were migrated, and no slot is allocated in them.
+    pub is_legacy: bool,
 }
 
 #[derive(FromPrimitive, Copy, Clone, PartialEq)]
@@ -26,6 +32,24 @@ pub enum SlotType {
     LastFreeSlot,
     InnerNode,
     LeafNode,
+    /// Free slot which also holds the length of the free list from it. The lists of free slots
+    /// written before hold no length and are drained by the allocator before counted lists start.
+    CountedFreeSlot,
+}
+
+pub enum FreeSlotSchema {
+    PointerToNext = 1,
+    FreeListLength = 5,
+}
+
+// Slots past the ones a pointer can index are left unused
+fn get_page_size(account_data_len: usize, is_legacy: bool) -> u32 {
+    let slot_size = if is_legacy {
+        LEGACY_SLOT_SIZE
+    } else {
+        SLOT_SIZE
+    };
+    (((account_data_len - TAG_SIZE) / slot_size) as u32).min(MAX_PAGE_SLOTS)
 }
 
 impl<'a> Page<'a> {
@@ -34,19 +58,22 @@ impl<'a> Page<'a> {
             let mut buf: &[u8] = &account.data.borrow();
             StateObject::deserialize(&mut buf)?
         };
-        match obj {
-            StateObject::MemoryPage => {}
+        let is_legacy = match obj {
+            StateObject::MemoryPageV2 => false,
             StateObject::Uninitialized => {
                 let mut p: &mut [u8] = &mut account.data.borrow_mut();
-                StateObject::MemoryPage.serialize(&mut p)?;
+                StateObject::MemoryPageV2.serialize(&mut p)?;
+                false
             }
+            StateObject::MemoryPage => true,
             _ => return Err(ProgramError::InvalidAccountData),
-        }
+        };
         Ok(Page {
-            page_size: ((account.data_len() - TAG_SIZE) / SLOT_SIZE) as u32,
+            page_size: get_page_size(account.data_len(), is_legacy),
             data: Rc::clone(&account.data),
             uninitialized_memory: page_info.unitialized_memory_index,
             free_slot_list_hd: page_info.free_slot_list_hd,
+            is_legacy,
         })
     }
 
@@ -55,54 +82,80 @@ impl<'a> Page<'a> {
         account_data: &'a mut [u8],
         page_info: &PageInfo,
     ) -> Result<Self, ProgramError> {
+        let is_legacy = account_data.first() == Some(&(StateObject::MemoryPage as u8));
         Ok(Page {
-            page_size: ((account_data.len() - TAG_SIZE) / SLOT_SIZE) as u32,
+            page_size: get_page_size(account_data.len(), is_legacy),
             data: Rc::new(RefCell::new(account_data)),
             uninitialized_memory: page_info.unitialized_memory_index,
             free_slot_list_hd: page_info.free_slot_list_hd,
+            is_legacy,
         })
     }
 
-    pub fn free(&mut self, pointer: Pointer) -> PerpResult {
-        let offset = TAG_SIZE + (pointer as usize) * SLOT_SIZE;
-        let tag;
+    /// Length of the free list of the page, which is unknown for the lists written before they
+    /// were counted
+    pub fn get_free_list_length(&self) -> Result<Option<u32>, PerpError> {
         match self.free_slot_list_hd {
-            Some(pt) => {
-                tag = SlotType::FreeSlot as u8;
-                self.data
-                    .borrow_mut()
-                    .get_mut(offset + 1..offset + 5)
-                    .ok_or(PerpError::MemoryError)?
-                    .copy_from_slice(&pt.to_le_bytes());
-            }
-            None => {
-                tag = SlotType::LastFreeSlot as u8;
-            }
+            Some(pt) => match FromPrimitive::from_u8(self.read_byte(pt, 0)?).unwrap() {
+                SlotType::CountedFreeSlot => Ok(Some(
+                    self.read_u32_le(pt, FreeSlotSchema::FreeListLength as usize)?,
+                )),
+                _ => Ok(None),
+            },
+            None => Ok(Some(0)),
+        }
+    }
+
+    /// Number of slots left to allocate, an uncounted free list standing for a single slot
+    pub fn get_free_slots(&self) -> Result<u32, PerpError> {
+        if self.is_legacy {
+            return Ok(0);
         }
+        let free_list_length = self.get_free_list_length()?.unwrap_or(1);
+        Ok(self.page_size.saturating_sub(self.uninitialized_memory) + free_list_length)
+    }
 
-        self.data.borrow_mut()[offset] = tag;
+    pub fn free(&mut self, pointer: Pointer) -> PerpResult {
+        let next_pt = self.free_slot_list_hd.unwrap_or(0);
+        self.write(
+            pointer,
+            FreeSlotSchema::PointerToNext as usize,
+            &next_pt.to_le_bytes(),
+        )?;
+        match self.get_free_list_length()? {
+            Some(length) => {
+                self.write(
+                    pointer,
+                    FreeSlotSchema::FreeListLength as usize,
+                    &(length + 1).to_le_bytes(),
+                )?;
+                self.write(pointer, 0, &[SlotType::CountedFreeSlot as u8])?;
+            }
+            None => self.write(pointer, 0, &[SlotType::FreeSlot as u8])?,
+        }
         self.free_slot_list_hd = Some(pointer);
         Ok(())
     }
 
     pub fn allocate(&mut self, slot_type: SlotType) -> Result<Pointer, PerpError> {
+        if self.is_legacy {
+            msg!("No slot can be allocated in a memory page which has to be migrated");
+            return Err(PerpError::OutOfSpace);
+        }
         let pointer: Pointer;
         let offset: usize;
         match self.free_slot_list_hd {
             Some(pt) => {
                 offset = TAG_SIZE + (pt as usize) * SLOT_SIZE;
+                let next_pt = self.read_u32_le(pt, FreeSlotSchema::PointerToNext as usize)?;
                 match FromPrimitive::from_u8(*self.data.borrow().get(offset).unwrap()).unwrap() {
-                    SlotType::FreeSlot => {
-                        self.free_slot_list_hd = Some(u32::from_le_bytes(
-                            self.data
-                                .borrow()
-                                .get(offset + 1..offset + 5)
-                                .unwrap()
-                                .try_into()
-                                .unwrap(),
-                        ))
-                    }
+                    SlotType::FreeSlot => self.free_slot_list_hd = Some(next_pt),
                     SlotType::LastFreeSlot => self.free_slot_list_hd = None,
+                    SlotType::CountedFreeSlot => {
+                        let length =
+                            self.read_u32_le(pt, FreeSlotSchema::FreeListLength as usize)?;
+                        self.free_slot_list_hd = if length > 1 { Some(next_pt) } else { None };
+                    }
                     _ => unreachable!(),
                 };
                 pointer = pt;
@@ -120,60 +173,87 @@ impl<'a> Page<'a> {
         Ok(pointer)
     }
 
+    /// Forgets the slots of a page which holds no node anymore
+    pub fn reset(&mut self) {
+        self.uninitialized_memory = 0;
+        self.free_slot_list_hd = None;
+    }
+
+    // Calls `f` with bytes of a slot. The slots of a legacy page are read as migrated slots.
+    fn read_with<T, F: FnOnce(&[u8]) -> T>(
+        &self,
+        pointer: Pointer,
+        offset: usize,
+        length: usize,
+        f: F,
+    ) -> Result<T, PerpError> {
+        if self.is_legacy {
+            let legacy_offset = TAG_SIZE + (pointer as usize) * LEGACY_SLOT_SIZE;
+            let mut slot = [0u8; SLOT_SIZE];
+            upgrade_legacy_slot(
+                self.data
+                    .borrow()
+                    .get(legacy_offset..legacy_offset + LEGACY_SLOT_SIZE)
+                    .ok_or(PerpError::MemoryError)?,
+                &mut slot,
+            );
+            return Ok(f(&slot[offset..offset + length]));
+        }
+        let mem_offset = TAG_SIZE + (pointer as usize) * SLOT_SIZE + offset;
+        Ok(f(&self.data.borrow()[mem_offset..mem_offset + length]))
+    }
+
     pub fn read(
         &self,
         pointer: Pointer,
         offset: usize,
         length: usize,
     ) -> Result<Vec<u8>, PerpError> {
-        let mem_offset = TAG_SIZE + (pointer as usize) * SLOT_SIZE + offset;
-        Ok(self.data.borrow()[mem_offset..mem_offset + length].to_vec())
+        self.read_with(pointer, offset, length, |b| b.to_vec())
     }
 
     pub fn read_byte(&self, pointer: Pointer, offset: usize) -> Result<u8, PerpError> {
-        let mem_offset = TAG_SIZE + (pointer as usize) * SLOT_SIZE + offset;
-        Ok(self.data.borrow()[mem_offset])
+        self.read_with(pointer, offset, 1, |b| b[0])
     }
 
     pub fn read_u64_be(&self, pointer: Pointer, offset: usize) -> Result<u64, PerpError> {
-        let mem_offset = TAG_SIZE + (pointer as usize) * SLOT_SIZE + offset;
-        Ok(u64::from_be_bytes(
-            self.data.borrow()[mem_offset..mem_offset + 8]
-                .try_into()
-                .unwrap(),
-        ))
+        self.read_with(pointer, offset, 8, |b| {
+            u64::from_be_bytes(b.try_into().unwrap())
+        })
     }
 
     pub fn read_u64_le(&self, pointer: Pointer, offset: usize) -> Result<u64, PerpError> {
-        let mem_offset = TAG_SIZE + (pointer as usize) * SLOT_SIZE + offset;
-        Ok(u64::from_le_bytes(
-            self.data.borrow()[mem_offset..mem_offset + 8]
-                .try_into()
-                .unwrap(),
-        ))
+        self.read_with(pointer, offset, 8, |b| {
+            u64::from_le_bytes(b.try_into().unwrap())
+        })
     }
 
     pub fn read_u32_le(&self, pointer: Pointer, offset: usize) -> Result<u32, PerpError> {
-        let mem_offset = TAG_SIZE + (pointer as usize) * SLOT_SIZE + offset;
-        Ok(u32::from_le_bytes(
-            self.data.borrow()[mem_offset..mem_offset + 4]
-                .try_into()
-                .unwrap(),
-        ))
+        self.read_with(pointer, offset, 4, |b| {
+            u32::from_le_bytes(b.try_into().unwrap())
+        })
     }
 
     pub fn read_u16_le(&self, pointer: Pointer, offset: usize) -> Result<u16, PerpError> {
-        let mem_offset = TAG_SIZE + (pointer as usize) * SLOT_SIZE + offset;
-        Ok(u16::from_le_bytes(
-            self.data.borrow()[mem_offset..mem_offset + 2]
-                .try_into()
-                .unwrap(),
-        ))
+        self.read_with(pointer, offset, 2, |b| {
+            u16::from_le_bytes(b.try_into().unwrap())
+        })
     }
 
+    /// The slots of a legacy page don't hold the owner of their leaf, the bytes written past
+    /// their size are dropped.
     pub fn write(&mut self, pointer: Pointer, offset: usize, input: &[u8]) -> PerpResult {
-        let mem_offset = TAG_SIZE + (pointer as usize) * SLOT_SIZE + offset;
-        self.data.borrow_mut()[mem_offset..mem_offset + input.len()].copy_from_slice(&input);
+        let (slot_size, length) = if self.is_legacy {
+            let length = input.len().min(LEGACY_SLOT_SIZE.saturating_sub(offset));
+            (LEGACY_SLOT_SIZE, length)
+        } else {
+            (SLOT_SIZE, input.len())
+        };
+        if length == 0 {
+            return Ok(());
+        }
+        let mem_offset = TAG_SIZE + (pointer as usize) * slot_size + offset;
+        self.data.borrow_mut()[mem_offset..mem_offset + length].copy_from_slice(&input[..length]);
         Ok(())
     }
 
@@ -182,17 +262,60 @@ impl<'a> Page<'a> {
         let mut count = 0;
 
         if let Some(mut pointer) = self.free_slot_list_hd {
-            let mut slot_type = SlotType::FreeSlot as usize;
-            while slot_type == SlotType::FreeSlot as usize {
-                slot_type = self.read_byte(pointer, 0)? as usize;
-                pointer = self.read_u32_le(pointer, 1)?;
-                count += 1;
+            loop {
+                match FromPrimitive::from_u8(self.read_byte(pointer, 0)?).unwrap() {
+                    SlotType::FreeSlot => {
+                        pointer =
+                            self.read_u32_le(pointer, FreeSlotSchema::PointerToNext as usize)?;
+                        count += 1;
+                    }
+                    SlotType::CountedFreeSlot => {
+                        let length =
+                            self.read_u32_le(pointer, FreeSlotSchema::FreeListLength as usize)?;
+                        return Ok(count + length as u64);
+                    }
+                    _ => return Ok(count + 1),
+                }
             }
         }
         Ok(count)
     }
 }
 
+/// Copies a slot of a legacy page into a slot of the current layout. The owner of a leaf is cleared
+/// and flagged as unknown, and the leaf is flagged as indexed with the margin preceding the margin
+/// tiers.
+fn upgrade_legacy_slot(legacy_slot: &[u8], slot: &mut [u8]) {
+    slot[..LEGACY_SLOT_SIZE].copy_from_slice(legacy_slot);
+    if slot[0] == SlotType::LeafNode as u8 {
+        let owner = LeafNodeSchema::Owner as usize..LeafNodeSchema::OwnerFlags as usize;
+        slot[owner].iter_mut().for_each(|b| *b = 0);
+        slot[LeafNodeSchema::OwnerFlags as usize] = UNKNOWN_OWNER_FLAG | LEGACY_MARGIN_FLAG;
+    }
+}
+
+/// Copies the first slots of a legacy page into a page of the current layout. The slots keep their
+/// index so that the pointers to them remain valid.
+pub fn migrate_legacy_page(
+    legacy_data: &[u8],
+    data: &mut [u8],
+    number_of_slots: u32,
+) -> PerpResult {
+    for i in 0..number_of_slots as usize {
+        let legacy_offset = TAG_SIZE + i * LEGACY_SLOT_SIZE;
+        let offset = TAG_SIZE + i * SLOT_SIZE;
+        upgrade_legacy_slot(
+            legacy_data
+                .get(legacy_offset..legacy_offset + LEGACY_SLOT_SIZE)
+                .ok_or(PerpError::MemoryError)?,
+            data.get_mut(offset..offset + SLOT_SIZE)
+                .ok_or(PerpError::OutOfSpace)?,
+        );
+    }
+    data[0] = StateObject::MemoryPageV2 as u8;
+    Ok(())
+}
+
 #[cfg(all(test, feature = "test-bpf"))]
 mod tests {
     use std::{cell::RefCell, rc::Rc};
@@ -208,6 +331,7 @@ mod tests {
             data: Rc::new(RefCell::new(data)),
             free_slot_list_hd: None,
             uninitialized_memory: 0,
+            is_legacy: false,
         };
         let inner_node = page.allocate(SlotType::InnerNode).unwrap();
         let leaf = page.allocate(SlotType::LeafNode).unwrap();
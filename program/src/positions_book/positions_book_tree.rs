@@ -1,16 +1,17 @@
 use crate::{
     error::{PerpError, PerpResult},
     positions_book::{
-        memory::{Memory, Pointer},
+        memory::{get_page_index, make_pointer, Memory, Pointer, SLOT_SIZE},
         page::SlotType,
-        tree_nodes::{InnerNode, InnerNodeSchema, Leaf, Node},
+        tree_nodes::{InnerNode, InnerNodeSchema, Leaf, LeafOwner, Node},
     },
     state::PositionType,
-    utils::compute_liquidation_index_inverse,
 };
 #[cfg(feature = "fuzz")]
 use arbitrary::Arbitrary;
+use borsh::{BorshDeserialize, BorshSerialize};
 use num_traits::FromPrimitive;
+use solana_program::pubkey::Pubkey;
 
 use super::tree_nodes::LeafNodeSchema;
 
@@ -20,6 +21,44 @@ pub struct PositionsBook<'a> {
     pub memory: Memory<'a>,
 }
 
+/// Node at which a liquidation walk ends
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq)]
+pub enum LiquidationEnd {
+    /// The inner node with the given critbit is liquidated along with its subtree
+    InnerNode(u8),
+    /// The leaf reached by the liquidation index is liquidated if it holds anything to liquidate
+    Leaf,
+    /// Only the siblings of the walk are liquidated, it ends once they are all detached
+    Siblings,
+}
+
+/// State of a liquidation walk on one side of the book, which lets it be split across
+/// transactions. The book must not be modified otherwise until the walk is over.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq)]
+pub struct LiquidationCursor {
+    pub liquidation_index: u64,
+    pub side: PositionType,
+    pub end: LiquidationEnd,
+    pub pointer: Pointer,              // The next node to process
+    pub mother: Option<(Pointer, u8)>, // The parent of that node and the offset of its pointer to it
+    pub grandmother: Option<(Pointer, u8)>,
+    pub collateral: u64, // Amounts left to liquidate under the next node
+    pub v_coin: u64,
+    pub v_pc: u64,
+}
+
+impl LiquidationCursor {
+    /// Largest serialized size of a cursor
+    pub const LEN: usize = 51;
+
+    fn split_link(link: Option<(Pointer, u8)>) -> (Option<Pointer>, Option<InnerNodeSchema>) {
+        match link {
+            Some((pt, offset)) => (Some(pt), FromPrimitive::from_u8(offset)),
+            None => (None, None),
+        }
+    }
+}
+
 impl<'a> PositionsBook<'a> {
     pub fn new(shorts_root: Option<u32>, longs_root: Option<u32>, memory: Memory<'a>) -> Self {
         PositionsBook {
@@ -159,6 +198,7 @@ impl<'a> PositionsBook<'a> {
         collateral: u64,
         v_coin: u64,
         v_pc: u64,
+        user_account: &Pubkey,
     ) -> Result<Pointer, PerpError> {
         let pt = self.memory.allocate(SlotType::LeafNode)?;
         self.memory.write(
@@ -180,9 +220,42 @@ impl<'a> PositionsBook<'a> {
         )?;
         self.memory
             .write(pt, LeafNodeSchema::VPc as usize, &v_pc.to_le_bytes())?;
+        Leaf(pt).set_owner(&mut self.memory, user_account)?;
         Ok(pt)
     }
 
+    /// Appends the owners of the leaves under a node
+    fn collect_owners(&self, pt: Pointer, owners: &mut Vec<LeafOwner>) -> PerpResult {
+        let mut stack = vec![pt];
+        while let Some(pt) = stack.pop() {
+            match self.get_node(pt)? {
+                Node::InnerNode(_) => {
+                    stack.push(
+                        self.memory
+                            .read_u32_le(pt, InnerNodeSchema::LeftPointer as usize)?,
+                    );
+                    stack.push(
+                        self.memory
+                            .read_u32_le(pt, InnerNodeSchema::RightPointer as usize)?,
+                    );
+                }
+                Node::Leaf(leaf) => owners.push(leaf.get_owner(&self.memory)?),
+            }
+        }
+        Ok(())
+    }
+
+    fn report_owners(
+        &self,
+        pt: Pointer,
+        liquidated_owners: &mut Option<&mut Vec<LeafOwner>>,
+    ) -> PerpResult {
+        if let Some(owners) = liquidated_owners {
+            self.collect_owners(pt, owners)?;
+        }
+        Ok(())
+    }
+
     #[allow(clippy::clippy::too_many_arguments)]
     fn write_inner(
         &mut self,
@@ -226,303 +299,217 @@ impl<'a> PositionsBook<'a> {
         Ok(pt)
     }
 
-    pub fn liquidate(&mut self, liquidation_index: u64, position_type: PositionType) -> PerpResult {
+    /// Liquidates the positions of a side which are reached by the liquidation index. The owners of
+    /// the liquidated leaves are appended to `liquidated_owners` when it is given, which walks the
+    /// liquidated subtrees.
+    pub fn liquidate(
+        &mut self,
+        liquidation_index: u64,
+        position_type: PositionType,
+        mut liquidated_owners: Option<&mut Vec<LeafOwner>>,
+    ) -> PerpResult {
+        if let Some(mut cursor) = self.start_liquidation(liquidation_index, position_type)? {
+            while !self.step_liquidation(&mut cursor, &mut liquidated_owners)? {}
+        }
+        Ok(())
+    }
+
+    /// Finds the positions of a side which are reached by the liquidation index without modifying
+    /// the book. The returned cursor holds the amounts to liquidate and is consumed by
+    /// `resume_liquidation`, there is nothing to liquidate when it is `None`.
+    pub fn start_liquidation(
+        &self,
+        liquidation_index: u64,
+        position_type: PositionType,
+    ) -> Result<Option<LiquidationCursor>, PerpError> {
         let (root, is_short) = match position_type {
             PositionType::Short => (self.shorts_root, true),
             PositionType::Long => (self.longs_root, false),
         };
-        if root.is_none() {
-            println!("Early return");
-            return Ok(());
-        }
-        let mut pt = root.unwrap();
+        let root = match root {
+            Some(root) => root,
+            None => return Ok(None),
+        };
+        let mut pt = root;
         let mut collateral_to_liquidate = 0;
         let mut v_coin_to_liquidate = 0;
         let mut v_pc_to_liquidate = 0;
 
-        loop {
+        let end = loop {
             match self.get_node(pt)? {
                 Node::InnerNode(inner_node) => {
                     let critbit = inner_node.get_critbit(&self.memory)?;
                     let (liq_index_min, liq_index_max) =
                         inner_node.get_liquidation_index_min_max(critbit, &self.memory)?;
-                    println!("On Inner node : critbit {:#4x}", 1u64 << critbit);
                     if liquidation_index > liq_index_max || liquidation_index < liq_index_min {
                         if is_short ^ (liquidation_index < liq_index_min) {
-                            // The walk ends here; Liquidate current pt
-                            println!("Liquidating current node");
+                            // The walk ends here; the current node is liquidated
                             collateral_to_liquidate += inner_node.get_collateral(&self.memory)?;
                             v_coin_to_liquidate += inner_node.get_v_coin(&self.memory)?;
                             v_pc_to_liquidate += inner_node.get_v_pc(&self.memory)?;
-                            pt = root.unwrap();
-                            let liquidation_critbit = critbit;
-                            let mut mother_pt = None;
-                            let mut mother_offset = None;
-                            let mut grandmother_pt = None;
-                            let mut grandmother_offset = None;
-                            loop {
-                                match self.get_node(pt)? {
-                                    Node::InnerNode(inner_node) => {
-                                        let critbit = inner_node.get_critbit(&self.memory)?;
-                                        if liquidation_critbit == critbit {
-                                            self.remove_node(
-                                                pt,
-                                                position_type,
-                                                mother_pt,
-                                                grandmother_pt,
-                                                mother_offset,
-                                                grandmother_offset,
-                                            )?;
-                                            break;
-                                        }
-                                        let current_collateral =
-                                            inner_node.get_collateral(&self.memory)?;
-                                        let current_v_coin = inner_node.get_v_coin(&self.memory)?;
-                                        let current_v_pc = inner_node.get_v_pc(&self.memory)?;
-                                        inner_node.set_collateral(
-                                            &mut self.memory,
-                                            &(current_collateral - collateral_to_liquidate),
-                                        )?;
-                                        inner_node.set_v_coin(
-                                            &mut self.memory,
-                                            &(current_v_coin - v_coin_to_liquidate),
-                                        )?;
-                                        inner_node.set_v_pc(
-                                            &mut self.memory,
-                                            &(current_v_pc - v_pc_to_liquidate),
-                                        )?;
-                                        let (direction, next_offset, next_pt, sibling_pt) =
-                                            self.walk(pt, &liquidation_index, &critbit)?;
-
-                                        if direction ^ is_short {
-                                            let sibling_node = self.get_node(sibling_pt)?;
-                                            collateral_to_liquidate -=
-                                                sibling_node.get_collateral(&self.memory)?;
-                                            v_coin_to_liquidate -=
-                                                sibling_node.get_v_coin(&self.memory)?;
-                                            v_pc_to_liquidate -=
-                                                sibling_node.get_v_pc(&self.memory)?;
-                                            match mother_pt {
-                                                Some(m_pt) => {
-                                                    self.memory.write(
-                                                        m_pt,
-                                                        mother_offset.unwrap() as usize,
-                                                        &next_pt.to_le_bytes(),
-                                                    )?;
-                                                    self.memory.free(pt)?;
-                                                }
-                                                None => {
-                                                    self.memory.free(pt)?;
-                                                    self.set_root(Some(next_pt), position_type);
-                                                }
-                                            }
-                                            sibling_node.free(&mut self.memory)?;
-                                            pt = next_pt;
-                                        } else {
-                                            grandmother_pt = mother_pt;
-                                            grandmother_offset = mother_offset;
-                                            mother_pt = Some(pt);
-                                            pt = next_pt;
-                                            mother_offset = Some(next_offset);
-                                        }
-                                    }
-                                    _ => unreachable!(),
-                                }
-                            }
-                        } else {
-                            pt = root.unwrap();
-                            let mut mother_pt = None;
-                            let mut mother_offset = None;
-                            while collateral_to_liquidate != 0 {
-                                match self.get_node(pt)? {
-                                    Node::InnerNode(inner_node) => {
-                                        let critbit = inner_node.get_critbit(&self.memory)?;
-                                        let (direction, next_offset, next_pt, sibling_pt) =
-                                            self.walk(pt, &liquidation_index, &critbit)?;
-                                        let current_collateral =
-                                            inner_node.get_collateral(&self.memory)?;
-                                        let current_v_coin = inner_node.get_v_coin(&self.memory)?;
-                                        let current_v_pc = inner_node.get_v_pc(&self.memory)?;
-                                        inner_node.set_collateral(
-                                            &mut self.memory,
-                                            &current_collateral
-                                                .checked_sub(collateral_to_liquidate)
-                                                .unwrap(),
-                                        )?;
-                                        inner_node.set_v_coin(
-                                            &mut self.memory,
-                                            &current_v_coin
-                                                .checked_sub(v_coin_to_liquidate)
-                                                .unwrap(),
-                                        )?;
-                                        inner_node.set_v_pc(
-                                            &mut self.memory,
-                                            &current_v_pc.checked_sub(v_pc_to_liquidate).unwrap(),
-                                        )?;
-
-                                        if direction ^ is_short {
-                                            let sibling_node = self.get_node(sibling_pt)?;
-                                            collateral_to_liquidate -=
-                                                sibling_node.get_collateral(&self.memory)?;
-                                            v_coin_to_liquidate -=
-                                                sibling_node.get_v_coin(&self.memory)?;
-                                            v_pc_to_liquidate -=
-                                                sibling_node.get_v_pc(&self.memory)?;
-                                            match mother_pt {
-                                                Some(m_pt) => {
-                                                    self.memory.write(
-                                                        m_pt,
-                                                        mother_offset.unwrap() as usize,
-                                                        &next_pt.to_le_bytes(),
-                                                    )?;
-                                                    self.memory.free(pt)?;
-                                                }
-                                                None => {
-                                                    self.memory.free(pt)?;
-                                                    self.set_root(Some(next_pt), position_type);
-                                                }
-                                            }
-                                            sibling_node.free(&mut self.memory)?;
-                                            pt = next_pt;
-                                        } else {
-                                            mother_pt = Some(pt);
-                                            pt = next_pt;
-                                            mother_offset = Some(next_offset);
-                                        }
-                                    }
-                                    _ => unreachable!(),
-                                }
-                            }
-                        }
-                        return Ok(());
-                    }
-
-                    let direction = liquidation_index & (1u64 << critbit) == 0;
-                    println!("Headed towards {:?}", !direction);
-                    let sibling_pt;
-                    match direction {
-                        true => {
-                            sibling_pt = self
-                                .memory
-                                .read_u32_le(pt, InnerNodeSchema::RightPointer as usize)?;
-                            pt = self
-                                .memory
-                                .read_u32_le(pt, InnerNodeSchema::LeftPointer as usize)?;
+                            break LiquidationEnd::InnerNode(critbit);
                         }
-                        false => {
-                            sibling_pt = self
-                                .memory
-                                .read_u32_le(pt, InnerNodeSchema::LeftPointer as usize)?;
-                            pt = self
-                                .memory
-                                .read_u32_le(pt, InnerNodeSchema::RightPointer as usize)?;
+                        if collateral_to_liquidate == 0 {
+                            return Ok(None);
                         }
-                    };
+                        break LiquidationEnd::Siblings;
+                    }
+                    let (direction, _, next_pt, sibling_pt) =
+                        self.walk(pt, &liquidation_index, &critbit)?;
                     if direction ^ is_short {
                         // We liquidate positions which are lower than the liquidation index in the shorts tree and vice versa.
-                        collateral_to_liquidate +=
-                            self.get_node(sibling_pt)?.get_collateral(&self.memory)?;
-                        v_coin_to_liquidate +=
-                            self.get_node(sibling_pt)?.get_v_coin(&self.memory)?;
-                        v_pc_to_liquidate += self.get_node(sibling_pt)?.get_v_pc(&self.memory)?;
+                        let sibling_node = self.get_node(sibling_pt)?;
+                        collateral_to_liquidate += sibling_node.get_collateral(&self.memory)?;
+                        v_coin_to_liquidate += sibling_node.get_v_coin(&self.memory)?;
+                        v_pc_to_liquidate += sibling_node.get_v_pc(&self.memory)?;
                     }
+                    pt = next_pt;
                 }
                 Node::Leaf(leaf) => {
                     let leaf_liquidation_index = leaf.get_liquidation_index(&self.memory)?;
                     if ((liquidation_index < leaf_liquidation_index) ^ is_short)
                         || liquidation_index == leaf_liquidation_index
                     {
-                        println!("Liquidating this leaf");
                         collateral_to_liquidate += leaf.get_collateral(&self.memory)?;
                         v_coin_to_liquidate += leaf.get_v_coin(&self.memory)?;
                         v_pc_to_liquidate += leaf.get_v_pc(&self.memory)?;
                     }
                     if collateral_to_liquidate == 0 {
                         //Nothing to liquidate
-                        return Ok(());
+                        return Ok(None);
                     }
-                    println!("Starting liquidation walk.");
-                    pt = root.unwrap();
-                    let mut mother_pt = None;
-                    let mut mother_offset = None;
-                    let mut grandmother_pt = None;
-                    let mut grandmother_offset = None;
-                    loop {
-                        match self.get_node(pt)? {
-                            Node::InnerNode(inner_node) => {
-                                let critbit = inner_node.get_critbit(&self.memory)?;
-                                let current_collateral = inner_node.get_collateral(&self.memory)?;
-                                let current_v_coin = inner_node.get_v_coin(&self.memory)?;
-                                let current_v_pc = inner_node.get_v_pc(&self.memory)?;
-                                inner_node.set_collateral(
-                                    &mut self.memory,
-                                    &(current_collateral - collateral_to_liquidate),
-                                )?;
-                                inner_node.set_v_coin(
-                                    &mut self.memory,
-                                    &(current_v_coin - v_coin_to_liquidate),
-                                )?;
-                                inner_node.set_v_pc(
-                                    &mut self.memory,
-                                    &(current_v_pc - v_pc_to_liquidate),
-                                )?;
-
-                                let (direction, next_offset, next_pt, sibling_pt) =
-                                    self.walk(pt, &liquidation_index, &critbit)?;
-                                println!("Headed towards {:?}", !direction);
-
-                                if direction ^ is_short {
-                                    println!("Liquidating sibling");
-                                    let sibling_node = self.get_node(sibling_pt)?;
-                                    collateral_to_liquidate -=
-                                        sibling_node.get_collateral(&self.memory)?;
-                                    v_coin_to_liquidate -= sibling_node.get_v_coin(&self.memory)?;
-                                    v_pc_to_liquidate -= sibling_node.get_v_pc(&self.memory)?;
-                                    match mother_pt {
-                                        Some(m_pt) => {
-                                            self.memory.write(
-                                                m_pt,
-                                                mother_offset.unwrap() as usize,
-                                                &next_pt.to_le_bytes(),
-                                            )?;
-                                            self.memory.free(pt)?;
-                                        }
-                                        None => {
-                                            self.memory.free(pt)?;
-                                            self.set_root(Some(next_pt), position_type);
-                                        }
-                                    }
-                                    sibling_node.free(&mut self.memory)?;
-                                    pt = next_pt;
-                                } else {
-                                    grandmother_pt = mother_pt;
-                                    grandmother_offset = mother_offset;
-                                    mother_pt = Some(pt);
-                                    pt = next_pt;
-                                    mother_offset = Some(next_offset);
-                                }
-                            }
-                            Node::Leaf(_) => {
-                                if collateral_to_liquidate != 0 {
-                                    println!("Liquidating leaf");
-                                    self.remove_node(
-                                        pt,
-                                        position_type,
-                                        mother_pt,
-                                        grandmother_pt,
-                                        mother_offset,
-                                        grandmother_offset,
-                                    )?;
-                                }
-                                break;
-                            }
+                    break LiquidationEnd::Leaf;
+                }
+            }
+        };
+
+        Ok(Some(LiquidationCursor {
+            liquidation_index,
+            side: position_type,
+            end,
+            pointer: root,
+            mother: None,
+            grandmother: None,
+            collateral: collateral_to_liquidate,
+            v_coin: v_coin_to_liquidate,
+            v_pc: v_pc_to_liquidate,
+        }))
+    }
+
+    /// Walks down from the root of a side while the node budget isn't exhausted, updating the
+    /// aggregates of the ancestors of the liquidated positions and detaching them. The budget is
+    /// decremented by the number of processed nodes. Returns the cursor to resume the liquidation
+    /// with when it isn't over.
+    pub fn resume_liquidation(
+        &mut self,
+        mut cursor: LiquidationCursor,
+        node_budget: &mut u16,
+        mut liquidated_owners: Option<&mut Vec<LeafOwner>>,
+    ) -> Result<Option<LiquidationCursor>, PerpError> {
+        while *node_budget > 0 {
+            *node_budget -= 1;
+            if self.step_liquidation(&mut cursor, &mut liquidated_owners)? {
+                return Ok(None);
+            }
+        }
+        Ok(Some(cursor))
+    }
+
+    /// Processes the node under the cursor, returns true once the liquidation is over
+    fn step_liquidation(
+        &mut self,
+        cursor: &mut LiquidationCursor,
+        liquidated_owners: &mut Option<&mut Vec<LeafOwner>>,
+    ) -> Result<bool, PerpError> {
+        let pt = cursor.pointer;
+        let position_type = cursor.side;
+        let is_short = position_type == PositionType::Short;
+        let (mother_pt, mother_offset) = LiquidationCursor::split_link(cursor.mother);
+        let (grandmother_pt, grandmother_offset) =
+            LiquidationCursor::split_link(cursor.grandmother);
+        match self.get_node(pt)? {
+            Node::InnerNode(inner_node) => {
+                let critbit = inner_node.get_critbit(&self.memory)?;
+                match cursor.end {
+                    LiquidationEnd::InnerNode(liquidation_critbit)
+                        if liquidation_critbit == critbit =>
+                    {
+                        self.report_owners(pt, liquidated_owners)?;
+                        self.remove_node(
+                            pt,
+                            position_type,
+                            mother_pt,
+                            grandmother_pt,
+                            mother_offset,
+                            grandmother_offset,
+                        )?;
+                        return Ok(true);
+                    }
+                    LiquidationEnd::Siblings if cursor.collateral == 0 => return Ok(true),
+                    _ => {}
+                }
+                let current_collateral = inner_node.get_collateral(&self.memory)?;
+                let current_v_coin = inner_node.get_v_coin(&self.memory)?;
+                let current_v_pc = inner_node.get_v_pc(&self.memory)?;
+                inner_node.set_collateral(
+                    &mut self.memory,
+                    &current_collateral.checked_sub(cursor.collateral).unwrap(),
+                )?;
+                inner_node.set_v_coin(
+                    &mut self.memory,
+                    &current_v_coin.checked_sub(cursor.v_coin).unwrap(),
+                )?;
+                inner_node.set_v_pc(
+                    &mut self.memory,
+                    &current_v_pc.checked_sub(cursor.v_pc).unwrap(),
+                )?;
+
+                let (direction, next_offset, next_pt, sibling_pt) =
+                    self.walk(pt, &cursor.liquidation_index, &critbit)?;
+
+                if direction ^ is_short {
+                    let sibling_node = self.get_node(sibling_pt)?;
+                    cursor.collateral -= sibling_node.get_collateral(&self.memory)?;
+                    cursor.v_coin -= sibling_node.get_v_coin(&self.memory)?;
+                    cursor.v_pc -= sibling_node.get_v_pc(&self.memory)?;
+                    match mother_pt {
+                        Some(m_pt) => {
+                            self.memory.write(
+                                m_pt,
+                                mother_offset.unwrap() as usize,
+                                &next_pt.to_le_bytes(),
+                            )?;
+                            self.memory.free(pt)?;
+                        }
+                        None => {
+                            self.memory.free(pt)?;
+                            self.set_root(Some(next_pt), position_type);
                         }
                     }
-                    break;
+                    self.report_owners(sibling_pt, liquidated_owners)?;
+                    sibling_node.free(&mut self.memory)?;
+                } else {
+                    cursor.grandmother = cursor.mother;
+                    cursor.mother = Some((pt, next_offset as u8));
+                }
+                cursor.pointer = next_pt;
+                Ok(false)
+            }
+            Node::Leaf(_) => {
+                if cursor.end == LiquidationEnd::Leaf && cursor.collateral != 0 {
+                    self.report_owners(pt, liquidated_owners)?;
+                    self.remove_node(
+                        pt,
+                        position_type,
+                        mother_pt,
+                        grandmother_pt,
+                        mother_offset,
+                        grandmother_offset,
+                    )?;
                 }
+                Ok(true)
             }
         }
-        Ok(())
     }
 
     pub fn close_position(
@@ -660,6 +647,11 @@ impl<'a> PositionsBook<'a> {
         Ok(())
     }
 
+    /// Inserts a position, which is merged into the leaf of the same liquidation index if there is
+    /// one. The leaf records the user account which created it.
+    /// Besides the page path of the liquidation index, the insertion of a new leaf allocates a leaf
+    /// and an inner node, in the pages given by `Memory::get_allocation_pages(2)`.
+    #[allow(clippy::clippy::too_many_arguments)]
     pub fn open_position(
         &mut self,
         liquidation_index: u64,
@@ -668,14 +660,21 @@ impl<'a> PositionsBook<'a> {
         v_pc: u64,
         position_type: PositionType,
         current_slot: u64,
+        user_account: &Pubkey,
     ) -> Result<Leaf, PerpError> {
         let root = match position_type {
             PositionType::Short => self.shorts_root,
             PositionType::Long => self.longs_root,
         };
         if root.is_none() {
-            let new_leaf_pt =
-                self.write_leaf(liquidation_index, current_slot, collateral, v_coin, v_pc)?;
+            let new_leaf_pt = self.write_leaf(
+                liquidation_index,
+                current_slot,
+                collateral,
+                v_coin,
+                v_pc,
+                user_account,
+            )?;
             self.set_root(Some(new_leaf_pt), position_type);
 
             return Ok(Leaf(new_leaf_pt));
@@ -703,6 +702,7 @@ impl<'a> PositionsBook<'a> {
                             collateral,
                             v_coin,
                             v_pc,
+                            user_account,
                         )?;
 
                         let (left_pt, right_pt) = match liquidation_index & (1 << new_critbit) == 0
@@ -775,13 +775,22 @@ impl<'a> PositionsBook<'a> {
                         leaf.set_collateral(&mut self.memory, &(collateral + current_collateral))?;
                         leaf.set_v_coin(&mut self.memory, &(v_coin + current_v_coin))?;
                         leaf.set_v_pc(&mut self.memory, &(v_pc + current_v_pc))?;
+                        if &leaf.get_owner(&self.memory)?.user_account != user_account {
+                            leaf.set_shared(&mut self.memory)?;
+                        }
                         return Ok(Leaf(pt));
                     }
                     let critbit = find_critbit(&liquidation_index, &leaf_liquidation_index);
                     let new_liq_index_min =
                         leaf_liquidation_index & liquidation_index & !((1u64 << critbit) - 1);
-                    let new_leaf_pt =
-                        self.write_leaf(liquidation_index, current_slot, collateral, v_coin, v_pc)?;
+                    let new_leaf_pt = self.write_leaf(
+                        liquidation_index,
+                        current_slot,
+                        collateral,
+                        v_coin,
+                        v_pc,
+                        user_account,
+                    )?;
 
                     let (left_pt, right_pt) = match liquidation_index & (1 << critbit) == 0 {
                         true => (new_leaf_pt, pt),
@@ -818,6 +827,198 @@ impl<'a> PositionsBook<'a> {
         Ok(Leaf(pt))
     }
 
+    /// Returns the liquidation index of the first position to be liquidated on a given side:
+    /// the highest one for longs, the lowest one for shorts.
+    pub fn get_liquidation_bound(
+        &self,
+        position_type: PositionType,
+    ) -> Result<Option<u64>, PerpError> {
+        let (root, offset) = match position_type {
+            PositionType::Short => (self.shorts_root, InnerNodeSchema::LeftPointer),
+            PositionType::Long => (self.longs_root, InnerNodeSchema::RightPointer),
+        };
+        let mut pt = match root {
+            Some(pt) => pt,
+            None => return Ok(None),
+        };
+        loop {
+            match self.get_node(pt)? {
+                Node::InnerNode(_) => pt = self.memory.read_u32_le(pt, offset as usize)?,
+                Node::Leaf(leaf) => return Ok(Some(leaf.get_liquidation_index(&self.memory)?)),
+            }
+        }
+    }
+
+    /// Checks whether a liquidation at the given index would liquidate at least one position.
+    pub fn is_liquidatable(&self, liquidation_index: u64) -> Result<bool, PerpError> {
+        let longs = self
+            .get_liquidation_bound(PositionType::Long)?
+            .map(|b| liquidation_index <= b)
+            .unwrap_or(false);
+        let shorts = self
+            .get_liquidation_bound(PositionType::Short)?
+            .map(|b| liquidation_index >= b)
+            .unwrap_or(false);
+        Ok(longs || shorts)
+    }
+
+    /// Returns the sorted indices of the memory pages holding the nodes on the path from the
+    /// root of a side to the leaf reached by the liquidation index. These are the only pages
+    /// needed to close the position held by that leaf.
+    pub fn get_page_path(
+        &self,
+        liquidation_index: u64,
+        position_type: PositionType,
+    ) -> Result<Vec<u32>, PerpError> {
+        let mut page_path = vec![];
+        let mut current_pt = match position_type {
+            PositionType::Short => self.shorts_root,
+            PositionType::Long => self.longs_root,
+        };
+        while let Some(pt) = current_pt {
+            let page_index = get_page_index(pt) as u32;
+            if !page_path.contains(&page_index) {
+                page_path.push(page_index);
+            }
+            current_pt = match self.get_node(pt)? {
+                Node::InnerNode(inner_node) => {
+                    let critbit = inner_node.get_critbit(&self.memory)?;
+                    Some(self.walk(pt, &liquidation_index, &critbit)?.2)
+                }
+                Node::Leaf(_) => None,
+            };
+        }
+        page_path.sort_unstable();
+        Ok(page_path)
+    }
+
+    /// Returns the leaf holding a position, if it wasn't liquidated
+    pub fn find_leaf(
+        &self,
+        liquidation_index: u64,
+        position_type: PositionType,
+        position_slot: u64,
+    ) -> Result<Option<Leaf>, PerpError> {
+        let mut current_pt = match position_type {
+            PositionType::Short => self.shorts_root,
+            PositionType::Long => self.longs_root,
+        };
+        while let Some(pt) = current_pt {
+            current_pt = match self.get_node(pt)? {
+                Node::InnerNode(inner_node) => {
+                    let critbit = inner_node.get_critbit(&self.memory)?;
+                    Some(self.walk(pt, &liquidation_index, &critbit)?.2)
+                }
+                Node::Leaf(leaf) => {
+                    if leaf.get_liquidation_index(&self.memory)? == liquidation_index
+                        && leaf.get_slot_number(&self.memory)? == position_slot
+                    {
+                        return Ok(Some(leaf));
+                    }
+                    None
+                }
+            };
+        }
+        Ok(None)
+    }
+
+    // Finds the side of the tree holding a node and the parent pointing to it, by walking down
+    // from the roots with the liquidation index of any leaf below the node
+    #[allow(clippy::type_complexity)]
+    fn find_parent(
+        &self,
+        pt: Pointer,
+        liquidation_index: u64,
+    ) -> Result<Option<(PositionType, Option<(Pointer, InnerNodeSchema)>)>, PerpError> {
+        for &position_type in [PositionType::Short, PositionType::Long].iter() {
+            let mut parent = None;
+            let mut current_pt = match position_type {
+                PositionType::Short => self.shorts_root,
+                PositionType::Long => self.longs_root,
+            };
+            while let Some(node_pt) = current_pt {
+                if node_pt == pt {
+                    return Ok(Some((position_type, parent)));
+                }
+                current_pt = match self.get_node(node_pt)? {
+                    Node::InnerNode(inner_node) => {
+                        let critbit = inner_node.get_critbit(&self.memory)?;
+                        let (_, next_offset, next_pt, _) =
+                            self.walk(node_pt, &liquidation_index, &critbit)?;
+                        parent = Some((node_pt, next_offset));
+                        Some(next_pt)
+                    }
+                    Node::Leaf(_) => None,
+                };
+            }
+        }
+        Ok(None)
+    }
+
+    /// Moves the nodes of a page, from the given slot index on, into the free slots of the densest
+    /// other pages and fixes the pointers to them. Slots which aren't part of the tree, such as the
+    /// ones waiting for the garbage collector, are left in place. A page left without nodes is
+    /// reset. Returns the number of moved nodes and the slot index to resume from, if any.
+    pub fn defragment_page(
+        &mut self,
+        page_index: usize,
+        first_slot: u32,
+        max_moves: u16,
+    ) -> Result<(u16, Option<u32>), PerpError> {
+        let uninitialized_memory = self.memory.get_page(page_index)?.uninitialized_memory;
+        let mut moves = 0;
+        for slot_index in first_slot..uninitialized_memory {
+            let pt = make_pointer(page_index, slot_index);
+            let (slot_type, liquidation_index) =
+                match FromPrimitive::from_u8(self.memory.read_byte(pt, 0)?).unwrap() {
+                    SlotType::InnerNode => (
+                        SlotType::InnerNode,
+                        self.memory
+                            .read_u64_le(pt, InnerNodeSchema::LiquidationIndexMin as usize)?,
+                    ),
+                    SlotType::LeafNode => (
+                        SlotType::LeafNode,
+                        self.memory
+                            .read_u64_le(pt, LeafNodeSchema::LiquidationIndex as usize)?,
+                    ),
+                    _ => continue,
+                };
+            let (position_type, parent) = match self.find_parent(pt, liquidation_index)? {
+                Some(link) => link,
+                None => continue,
+            };
+            if moves == max_moves {
+                return Ok((moves, Some(slot_index)));
+            }
+            let target_page_index = match self.memory.get_densest_page(page_index)? {
+                Some(i) => i,
+                None => return Ok((moves, Some(slot_index))),
+            };
+
+            let slot = self.memory.read(pt, 0, SLOT_SIZE)?;
+            let new_pt = self.memory.allocate_in_page(target_page_index, slot_type)?;
+            self.memory.write(new_pt, 0, &slot)?;
+            match parent {
+                Some((parent_pt, offset)) => {
+                    self.memory
+                        .write(parent_pt, offset as usize, &new_pt.to_le_bytes())?
+                }
+                None => self.set_root(Some(new_pt), position_type),
+            }
+            self.memory.free(pt)?;
+            moves += 1;
+        }
+
+        let page = self.memory.get_page_mut(page_index)?;
+        if page.get_free_list_length()? == Some(page.uninitialized_memory) {
+            page.reset();
+        }
+        Ok((moves, None))
+    }
+
+    /// Sums the v_pc, v_coin and collateral amounts of the leaves of a side. The v_pc amounts are
+    /// read from the leaves rather than derived from their liquidation index, which depends on the
+    /// margin ratio it was computed with.
     pub fn compute_aggregate_position(
         &self,
         side: PositionType,
@@ -853,15 +1054,9 @@ impl<'a> PositionsBook<'a> {
                     if liquidation_index != 0 {
                         let leaf_v_coin = l.get_v_coin(&self.memory)?;
                         let leaf_collateral = l.get_collateral(&self.memory)?;
+                        let leaf_v_pc = l.get_v_pc(&self.memory)?;
                         total_v_coin = total_v_coin.checked_add(leaf_v_coin).unwrap();
-                        total_v_pc = total_v_pc
-                            .checked_add(compute_liquidation_index_inverse(
-                                leaf_collateral,
-                                leaf_v_coin,
-                                liquidation_index,
-                                side,
-                            ))
-                            .unwrap();
+                        total_v_pc = total_v_pc.checked_add(leaf_v_pc).unwrap();
                         total_collateral = total_collateral.checked_add(leaf_collateral).unwrap();
                     }
 
@@ -891,11 +1086,25 @@ mod tests {
     use super::*;
     use crate::{
         positions_book::{
-            memory::{Memory, SLOT_SIZE},
-            page::Page,
+            memory::{
+                get_page_bitmap, get_slot_index, make_pointer, parse_memory, Memory, PageSelection,
+                LEGACY_SLOT_SIZE, MAX_PAGES, MAX_PAGE_SLOTS, SLOT_SIZE, TAG_SIZE,
+            },
+            page::{migrate_legacy_page, FreeSlotSchema, Page},
+        },
+        state::{
+            instance::{Instance, PageInfo, INSTANCE_VERSION},
+            user_account::{
+                find_leaf_positions, remove_position, write_position, OpenPosition,
+                UserAccountState,
+            },
+            StateObject,
         },
         utils::print_tree,
     };
+    use solana_program::{
+        account_info::AccountInfo, program_error::ProgramError, program_pack::Pack,
+    };
 
     fn init_tree<'a>(data: &[Rc<RefCell<&'a mut [u8]>>]) -> PositionsBook<'a> {
         let mut pages = vec![];
@@ -907,6 +1116,7 @@ mod tests {
                 data: Rc::clone(d),
                 free_slot_list_hd: None,
                 uninitialized_memory: 0,
+                is_legacy: false,
             });
         }
 
@@ -948,8 +1158,16 @@ mod tests {
         let mut total_v_pc = 0;
 
         for (liq_index, coll, v_coin, v_pc) in &positions {
-            book.open_position(*liq_index, *coll, *v_coin, *v_pc, position_type, 0)
-                .unwrap();
+            book.open_position(
+                *liq_index,
+                *coll,
+                *v_coin,
+                *v_pc,
+                position_type,
+                0,
+                &Pubkey::default(),
+            )
+            .unwrap();
             total_coll += coll;
             total_v_coin += v_coin;
             total_v_pc += v_pc;
@@ -1011,8 +1229,16 @@ mod tests {
         let mut total_v_pc = 0;
 
         for (liq_index, coll, v_coin, v_pc) in &positions {
-            book.open_position(*liq_index, *coll, *v_coin, *v_pc, position_type, 0)
-                .unwrap();
+            book.open_position(
+                *liq_index,
+                *coll,
+                *v_coin,
+                *v_pc,
+                position_type,
+                0,
+                &Pubkey::default(),
+            )
+            .unwrap();
             total_coll += coll;
             total_v_coin += v_coin;
             total_v_pc += v_pc;
@@ -1058,6 +1284,15 @@ mod tests {
         liquidation_index: u64,
         position_type: PositionType,
         positions: Vec<(u64, u64, u64, u64)>,
+    ) {
+        test_liquidate_in_steps(liquidation_index, position_type, positions, None)
+    }
+
+    fn test_liquidate_in_steps(
+        liquidation_index: u64,
+        position_type: PositionType,
+        positions: Vec<(u64, u64, u64, u64)>,
+        max_nodes: Option<u16>,
     ) {
         let (mut data0, mut data1, mut data2, mut data3) =
             ([0u8; 1024], [0u8; 1024], [0u8; 1024], [0u8; 1024]);
@@ -1079,8 +1314,16 @@ mod tests {
         let mut total_v_pc_after_liquidation = 0;
 
         for (liq_index, coll, v_coin, v_pc) in &positions {
-            book.open_position(*liq_index, *coll, *v_coin, *v_pc, position_type, 0)
-                .unwrap();
+            book.open_position(
+                *liq_index,
+                *coll,
+                *v_coin,
+                *v_pc,
+                position_type,
+                0,
+                &Pubkey::default(),
+            )
+            .unwrap();
             total_coll += coll;
             total_v_coin += v_coin;
             total_v_pc += v_pc;
@@ -1103,7 +1346,26 @@ mod tests {
 
         print_tree(root.unwrap(), &book.memory, 0);
 
-        book.liquidate(liquidation_index, position_type).unwrap();
+        match max_nodes {
+            None => book
+                .liquidate(liquidation_index, position_type, None)
+                .unwrap(),
+            Some(max_nodes) => {
+                let mut cursor = book
+                    .start_liquidation(liquidation_index, position_type)
+                    .unwrap();
+                if let Some(c) = cursor {
+                    assert_eq!(c.collateral, total_coll - total_coll_after_liquidation);
+                    assert_eq!(c.v_coin, total_v_coin - total_v_coin_after_liquidation);
+                    assert_eq!(c.v_pc, total_v_pc - total_v_pc_after_liquidation);
+                }
+                while let Some(c) = cursor {
+                    let mut node_budget = max_nodes;
+                    cursor = book.resume_liquidation(c, &mut node_budget, None).unwrap();
+                    assert!(cursor.is_none() || node_budget == 0);
+                }
+            }
+        }
         println!("============AFTER=============");
 
         let root = match position_type {
@@ -1183,6 +1445,553 @@ mod tests {
         test_liquidate(4299262263296, PositionType::Short, positions);
     }
 
+    #[test]
+    fn test_bounded_liquidations() {
+        let positions = vec![
+            (0x84, 100, 42, 908),
+            (0xfe, 101, 75, 98),
+            (0x0f, 107, 4500, 708),
+            (0x9b, 123, 78000, 408),
+            (0x52, 144, 9685, 958),
+            (0xc1, 177, 7584, 108),
+            (0xaf, 295, 4681, 444),
+            (0x2f, 1045, 12346, 322),
+            (0xfb, 4049, 47958413, 2),
+            (0xb7, 7940, 42, 907),
+        ];
+        for max_nodes in [1, 2, 5].iter() {
+            for liquidation_index in [0x01, 0x52, 0x85, 0xb7, 0xf4].iter() {
+                for position_type in [PositionType::Short, PositionType::Long].iter() {
+                    test_liquidate_in_steps(
+                        *liquidation_index,
+                        *position_type,
+                        positions.clone(),
+                        Some(*max_nodes),
+                    );
+                }
+            }
+        }
+
+        // A walk with a budget of zero nodes leaves the book untouched
+        let (mut data0, mut data1) = ([0u8; 1024], [0u8; 1024]);
+        let data: Vec<Rc<RefCell<&mut [u8]>>> = vec![
+            Rc::new(RefCell::new(&mut data0)),
+            Rc::new(RefCell::new(&mut data1)),
+        ];
+        let mut book = init_tree(&data);
+        for (liq_index, coll, v_coin, v_pc) in &positions {
+            book.open_position(
+                *liq_index,
+                *coll,
+                *v_coin,
+                *v_pc,
+                PositionType::Long,
+                0,
+                &Pubkey::default(),
+            )
+            .unwrap();
+        }
+        let collateral = book.get_collateral().unwrap();
+        let cursor = book
+            .start_liquidation(0x85, PositionType::Long)
+            .unwrap()
+            .unwrap();
+        let cursor = book
+            .resume_liquidation(cursor, &mut 0, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(book.get_collateral().unwrap(), collateral);
+        let cursor = book
+            .resume_liquidation(cursor, &mut 1, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            book.get_collateral().unwrap(),
+            collateral - cursor.collateral
+        );
+        assert!(book
+            .start_liquidation(0xff, PositionType::Long)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_liquidation_bounds() {
+        let (mut data0, mut data1, mut data2, mut data3) =
+            ([0u8; 1024], [0u8; 1024], [0u8; 1024], [0u8; 1024]);
+        let data: Vec<Rc<RefCell<&mut [u8]>>> = vec![
+            Rc::new(RefCell::new(&mut data0)),
+            Rc::new(RefCell::new(&mut data1)),
+            Rc::new(RefCell::new(&mut data2)),
+            Rc::new(RefCell::new(&mut data3)),
+        ];
+        let mut book = init_tree(&data);
+        assert!(!book.is_liquidatable(0x84).unwrap());
+
+        let longs = vec![
+            (0x0f, 107, 4500, 708),
+            (0x52, 144, 9685, 958),
+            (0x2f, 1045, 12346, 322),
+        ];
+        let shorts = vec![
+            (0xfe, 101, 75, 98),
+            (0xc1, 177, 7584, 108),
+            (0xb7, 7940, 42, 907),
+        ];
+        for (liq_index, coll, v_coin, v_pc) in &longs {
+            book.open_position(
+                *liq_index,
+                *coll,
+                *v_coin,
+                *v_pc,
+                PositionType::Long,
+                0,
+                &Pubkey::default(),
+            )
+            .unwrap();
+        }
+        for (liq_index, coll, v_coin, v_pc) in &shorts {
+            book.open_position(
+                *liq_index,
+                *coll,
+                *v_coin,
+                *v_pc,
+                PositionType::Short,
+                0,
+                &Pubkey::default(),
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            book.get_liquidation_bound(PositionType::Long).unwrap(),
+            Some(0x52)
+        );
+        assert_eq!(
+            book.get_liquidation_bound(PositionType::Short).unwrap(),
+            Some(0xb7)
+        );
+        for liquidation_index in 0..0x100 {
+            let expected = longs.iter().any(|p| p.0 >= liquidation_index)
+                || shorts.iter().any(|p| p.0 <= liquidation_index);
+            assert_eq!(book.is_liquidatable(liquidation_index).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_liquidated_owners() {
+        let (mut data0, mut data1, mut data2, mut data3) =
+            ([0u8; 1024], [0u8; 1024], [0u8; 1024], [0u8; 1024]);
+        let data: Vec<Rc<RefCell<&mut [u8]>>> = vec![
+            Rc::new(RefCell::new(&mut data0)),
+            Rc::new(RefCell::new(&mut data1)),
+            Rc::new(RefCell::new(&mut data2)),
+            Rc::new(RefCell::new(&mut data3)),
+        ];
+        let mut book = init_tree(&data);
+        let owners = (0..4).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        let positions = vec![
+            (0x0f, 107, 4500, 708),
+            (0x52, 144, 9685, 958),
+            (0x2f, 1045, 12346, 322),
+            (0x84, 100, 42, 908),
+        ];
+        for (i, (liq_index, coll, v_coin, v_pc)) in positions.iter().enumerate() {
+            book.open_position(
+                *liq_index,
+                *coll,
+                *v_coin,
+                *v_pc,
+                PositionType::Long,
+                i as u64,
+                &owners[i],
+            )
+            .unwrap();
+        }
+        // A position with the same liquidation index is merged into the existing leaf
+        book.open_position(0x52, 10, 10, 10, PositionType::Long, 4, &owners[0])
+            .unwrap();
+
+        let mut liquidated_owners = vec![];
+        book.liquidate(0x30, PositionType::Long, Some(&mut liquidated_owners))
+            .unwrap();
+        liquidated_owners.sort_by_key(|o| o.slot_number);
+        assert_eq!(
+            liquidated_owners,
+            vec![
+                LeafOwner {
+                    user_account: owners[1],
+                    liquidation_index: 0x52,
+                    slot_number: 1,
+                    is_shared: true,
+                    is_unknown: false,
+                },
+                LeafOwner {
+                    user_account: owners[3],
+                    liquidation_index: 0x84,
+                    slot_number: 3,
+                    is_shared: false,
+                    is_unknown: false,
+                },
+            ]
+        );
+        assert_eq!(book.get_collateral().unwrap(), 107 + 1045);
+    }
+
+    #[test]
+    fn test_liquidated_owner_after_remap() {
+        let mut data0 = [0u8; 1024];
+        let data: Vec<Rc<RefCell<&mut [u8]>>> = vec![Rc::new(RefCell::new(&mut data0))];
+        let mut book = init_tree(&data);
+        let user_account = Pubkey::new_unique();
+        let mut user_account_data = vec![0u8; UserAccountState::LEN + 3 * OpenPosition::LEN];
+        let mut user_account_header = UserAccountState {
+            version: 0,
+            owner: [1; 32],
+            active: false,
+            market: [0; 32],
+            balance: 0,
+            last_funding_offset: 0,
+            number_of_open_positions: 0,
+        };
+        let positions = vec![
+            (0x0f, 107, 4500, 708),
+            (0x2f, 1045, 12346, 322),
+            (0x84, 100, 42, 908),
+        ];
+        for (i, (liq_index, coll, v_coin, v_pc)) in positions.iter().enumerate() {
+            let leaf = book
+                .open_position(
+                    *liq_index,
+                    *coll,
+                    *v_coin,
+                    *v_pc,
+                    PositionType::Long,
+                    10 + i as u64,
+                    &user_account,
+                )
+                .unwrap();
+            let position = OpenPosition {
+                last_funding_offset: 0,
+                instance_index: 0,
+                side: PositionType::Long,
+                liquidation_index: *liq_index,
+                collateral: *coll,
+                slot_number: leaf.get_slot_number(&book.memory).unwrap(),
+                v_coin_amount: *v_coin,
+                v_pc_amount: *v_pc,
+            };
+            write_position(
+                &mut user_account_data,
+                i as u16,
+                &mut user_account_header,
+                &position,
+                false,
+            )
+            .unwrap();
+        }
+
+        // Closing the middle position moves the last one to its index
+        book.close_position(0x2f, 1045, 12346, 322, PositionType::Long, 11)
+            .unwrap();
+        remove_position(&mut user_account_data, &mut user_account_header, 1).unwrap();
+
+        let mut liquidated_owners = vec![];
+        book.liquidate(0x80, PositionType::Long, Some(&mut liquidated_owners))
+            .unwrap();
+        assert_eq!(liquidated_owners.len(), 1);
+        let owner = liquidated_owners[0];
+        assert_eq!(owner.user_account, user_account);
+        assert_eq!(
+            find_leaf_positions(
+                &mut user_account_data,
+                &user_account_header,
+                0,
+                PositionType::Long,
+                owner.liquidation_index,
+                owner.slot_number,
+            )
+            .unwrap(),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn test_legacy_page_migration() {
+        let mut data0 = [0u8; 1024];
+        let data: Vec<Rc<RefCell<&mut [u8]>>> = vec![Rc::new(RefCell::new(&mut data0))];
+        let mut book = init_tree(&data);
+        let owner = Pubkey::new_unique();
+        let positions = vec![
+            (0x0f, 107, 4500, 708),
+            (0x52, 144, 9685, 958),
+            (0x2f, 1045, 12346, 322),
+        ];
+        for (liq_index, coll, v_coin, v_pc) in &positions {
+            book.open_position(
+                *liq_index,
+                *coll,
+                *v_coin,
+                *v_pc,
+                PositionType::Short,
+                0,
+                &owner,
+            )
+            .unwrap();
+        }
+        let number_of_slots = book.memory.get_page(0).unwrap().uninitialized_memory;
+
+        // Pages of the legacy layout hold the same slots without the leaf owners
+        let mut legacy_data = [0u8; 1024];
+        for i in 0..number_of_slots as usize {
+            let offset = TAG_SIZE + i * SLOT_SIZE;
+            let legacy_offset = TAG_SIZE + i * LEGACY_SLOT_SIZE;
+            legacy_data[legacy_offset..legacy_offset + LEGACY_SLOT_SIZE]
+                .copy_from_slice(&data[0].borrow()[offset..offset + LEGACY_SLOT_SIZE]);
+        }
+        let mut migrated_data = [0xffu8; 1024];
+        migrate_legacy_page(&legacy_data, &mut migrated_data, number_of_slots).unwrap();
+
+        let mut migrated_book = init_tree(&[Rc::new(RefCell::new(&mut migrated_data[..]))]);
+        migrated_book.shorts_root = book.shorts_root;
+        migrated_book
+            .memory
+            .get_page_mut(0)
+            .unwrap()
+            .uninitialized_memory = number_of_slots;
+        assert_eq!(
+            migrated_book.get_collateral().unwrap(),
+            book.get_collateral().unwrap()
+        );
+        // The amounts of the leaves don't depend on the margin their liquidation index was
+        // computed with
+        assert_eq!(
+            migrated_book
+                .compute_aggregate_position(PositionType::Short)
+                .unwrap(),
+            (708 + 958 + 322, 4500 + 9685 + 12346, 107 + 144 + 1045)
+        );
+        migrated_book
+            .close_position(0x52, 144, 9685, 958, PositionType::Short, 0)
+            .unwrap();
+        let mut liquidated_owners = vec![];
+        migrated_book
+            .liquidate(0x30, PositionType::Short, Some(&mut liquidated_owners))
+            .unwrap();
+        liquidated_owners.sort_by_key(|o| o.liquidation_index);
+        assert_eq!(
+            liquidated_owners,
+            vec![0x0f, 0x2f]
+                .into_iter()
+                .map(|liquidation_index| LeafOwner {
+                    user_account: Pubkey::default(),
+                    liquidation_index,
+                    slot_number: 0,
+                    is_shared: false,
+                    is_unknown: true,
+                })
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_close_positions_after_page_migration() {
+        let mut data0 = [0u8; 1024];
+        let data: Vec<Rc<RefCell<&mut [u8]>>> = vec![Rc::new(RefCell::new(&mut data0))];
+        let mut book = init_tree(&data);
+        let owner = Pubkey::new_unique();
+        let positions = vec![
+            (0x0f, 107, 4500, 708, PositionType::Short),
+            (0x52, 144, 9685, 958, PositionType::Short),
+            (0x2f, 1045, 12346, 322, PositionType::Long),
+            (0x84, 100, 42, 908, PositionType::Long),
+        ];
+        for (i, (liq_index, coll, v_coin, v_pc, side)) in positions.iter().enumerate() {
+            book.open_position(*liq_index, *coll, *v_coin, *v_pc, *side, 0, &owner)
+                .unwrap();
+        }
+        let number_of_slots = book.memory.get_page(0).unwrap().uninitialized_memory;
+        let mut legacy_data = [0u8; 1024];
+        for i in 0..number_of_slots as usize {
+            let offset = TAG_SIZE + i * SLOT_SIZE;
+            let legacy_offset = TAG_SIZE + i * LEGACY_SLOT_SIZE;
+            legacy_data[legacy_offset..legacy_offset + LEGACY_SLOT_SIZE]
+                .copy_from_slice(&data[0].borrow()[offset..offset + LEGACY_SLOT_SIZE]);
+        }
+        let mut migrated_data = [0u8; 1024];
+        migrate_legacy_page(&legacy_data, &mut migrated_data, number_of_slots).unwrap();
+
+        let mut migrated_book = init_tree(&[Rc::new(RefCell::new(&mut migrated_data[..]))]);
+        migrated_book.shorts_root = book.shorts_root;
+        migrated_book.longs_root = book.longs_root;
+        migrated_book
+            .memory
+            .get_page_mut(0)
+            .unwrap()
+            .uninitialized_memory = number_of_slots;
+
+        // The migrated leaves were indexed with the margin preceding the margin tiers
+        let leaf = migrated_book
+            .find_leaf(0x2f, PositionType::Long, 0)
+            .unwrap()
+            .unwrap();
+        assert!(leaf.has_legacy_margin(&migrated_book.memory).unwrap());
+        assert!(migrated_book
+            .find_leaf(0x2f, PositionType::Short, 0)
+            .unwrap()
+            .is_none());
+        // A legacy position is moved by closing it and reinserting it with the same slot number
+        migrated_book
+            .close_position(0x2f, 1045, 12346, 322, PositionType::Long, 0)
+            .unwrap();
+        let leaf = migrated_book
+            .open_position(0x3f, 1045, 12346, 322, PositionType::Long, 0, &owner)
+            .unwrap();
+        assert!(!leaf.has_legacy_margin(&migrated_book.memory).unwrap());
+        assert!(migrated_book
+            .find_leaf(0x2f, PositionType::Long, 0)
+            .unwrap()
+            .is_none());
+
+        // A position merged into a migrated leaf keeps it flagged as having an unknown owner
+        let new_owner = Pubkey::new_unique();
+        let leaf = migrated_book
+            .open_position(0x84, 10, 10, 10, PositionType::Long, 0, &new_owner)
+            .unwrap();
+        let leaf_owner = leaf.get_owner(&migrated_book.memory).unwrap();
+        assert!(leaf_owner.is_shared && leaf_owner.is_unknown);
+        // New leaves record their owner
+        let leaf = migrated_book
+            .open_position(0x90, 10, 10, 10, PositionType::Long, 0, &new_owner)
+            .unwrap();
+        assert_eq!(
+            leaf.get_owner(&migrated_book.memory).unwrap(),
+            LeafOwner {
+                user_account: new_owner,
+                liquidation_index: 0x90,
+                slot_number: 0,
+                is_shared: false,
+                is_unknown: false,
+            }
+        );
+
+        // The positions of the migrated leaves can all be closed
+        for (liq_index, coll, v_coin, v_pc, side) in &positions {
+            let liq_index = if *liq_index == 0x2f { 0x3f } else { *liq_index };
+            migrated_book
+                .close_position(liq_index, *coll, *v_coin, *v_pc, *side, 0)
+                .unwrap();
+        }
+        for liq_index in [0x84, 0x90].iter() {
+            migrated_book
+                .close_position(*liq_index, 10, 10, 10, PositionType::Long, 0)
+                .unwrap();
+        }
+        assert_eq!(migrated_book.get_collateral().unwrap(), 0);
+        assert!(migrated_book.shorts_root.is_none());
+        assert!(migrated_book.longs_root.is_none());
+    }
+
+    #[test]
+    fn test_legacy_page_in_place() {
+        let mut data0 = [0u8; 1024];
+        let data: Vec<Rc<RefCell<&mut [u8]>>> = vec![Rc::new(RefCell::new(&mut data0))];
+        let mut book = init_tree(&data);
+        let owner = Pubkey::new_unique();
+        let positions = vec![
+            (0x0f, 107, 4500, 708, PositionType::Short),
+            (0x52, 144, 9685, 958, PositionType::Short),
+            (0x2f, 1045, 12346, 322, PositionType::Long),
+            (0x84, 100, 42, 908, PositionType::Long),
+        ];
+        for (liq_index, coll, v_coin, v_pc, side) in &positions {
+            book.open_position(*liq_index, *coll, *v_coin, *v_pc, *side, 0, &owner)
+                .unwrap();
+        }
+        let number_of_slots = book.memory.get_page(0).unwrap().uninitialized_memory;
+        let mut legacy_data = [0u8; 1024];
+        legacy_data[0] = StateObject::MemoryPage as u8;
+        for i in 0..number_of_slots as usize {
+            let offset = TAG_SIZE + i * SLOT_SIZE;
+            let legacy_offset = TAG_SIZE + i * LEGACY_SLOT_SIZE;
+            legacy_data[legacy_offset..legacy_offset + LEGACY_SLOT_SIZE]
+                .copy_from_slice(&data[0].borrow()[offset..offset + LEGACY_SLOT_SIZE]);
+        }
+
+        // The legacy page is read in place, next to a page of the current layout
+        let mut new_data = [0u8; 1024];
+        let legacy_page_info = PageInfo {
+            address: [0; 32],
+            unitialized_memory_index: number_of_slots,
+            free_slot_list_hd: None,
+        };
+        let new_page_info = PageInfo {
+            address: [0; 32],
+            unitialized_memory_index: 0,
+            free_slot_list_hd: None,
+        };
+        let pages = vec![
+            Page::new_from_slice_unchecked(&mut legacy_data, &legacy_page_info).unwrap(),
+            Page::new_from_slice_unchecked(&mut new_data, &new_page_info).unwrap(),
+        ];
+        assert!(pages[0].is_legacy);
+        assert!(!pages[1].is_legacy);
+        assert_eq!(pages[0].get_free_slots().unwrap(), 0);
+        let mut legacy_book = PositionsBook {
+            shorts_root: book.shorts_root,
+            longs_root: book.longs_root,
+            memory: Memory::new(pages, None),
+        };
+        assert_eq!(
+            legacy_book.get_collateral().unwrap(),
+            book.get_collateral().unwrap()
+        );
+
+        // The leaves of the legacy page read as the leaves of a migrated page
+        let leaf = legacy_book
+            .find_leaf(0x2f, PositionType::Long, 0)
+            .unwrap()
+            .unwrap();
+        assert!(leaf.has_legacy_margin(&legacy_book.memory).unwrap());
+        let leaf_owner = leaf.get_owner(&legacy_book.memory).unwrap();
+        assert!(leaf_owner.is_unknown);
+        assert_eq!(leaf_owner.user_account, Pubkey::default());
+
+        // The new nodes are allocated in the page of the current layout
+        let new_leaf = legacy_book
+            .open_position(0x90, 10, 10, 10, PositionType::Long, 0, &owner)
+            .unwrap();
+        assert_eq!(get_page_index(new_leaf.0), 1);
+        let new_leaf_owner = new_leaf.get_owner(&legacy_book.memory).unwrap();
+        assert!(!new_leaf_owner.is_unknown);
+        assert_eq!(new_leaf_owner.user_account, owner);
+        legacy_book
+            .close_position(0x52, 144, 9685, 958, PositionType::Short, 0)
+            .unwrap();
+
+        // Defragmenting the legacy page moves its nodes to the other page as migrated slots
+        let (_, resume_slot) = legacy_book.defragment_page(0, 0, 16).unwrap();
+        assert_eq!(resume_slot, None);
+        let leaf = legacy_book
+            .find_leaf(0x2f, PositionType::Long, 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(get_page_index(leaf.0), 1);
+        assert!(leaf.has_legacy_margin(&legacy_book.memory).unwrap());
+        assert!(leaf.get_owner(&legacy_book.memory).unwrap().is_unknown);
+
+        for (liq_index, coll, v_coin, v_pc, side) in &positions {
+            if *liq_index != 0x52 {
+                legacy_book
+                    .close_position(*liq_index, *coll, *v_coin, *v_pc, *side, 0)
+                    .unwrap();
+            }
+        }
+        legacy_book
+            .close_position(0x90, 10, 10, 10, PositionType::Long, 0)
+            .unwrap();
+        assert_eq!(legacy_book.get_collateral().unwrap(), 0);
+    }
+
     #[test]
     fn test_builds() {
         test_build(PositionType::Long);
@@ -1197,6 +2006,372 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_missing_pages() {
+        let (mut data0, mut data1) = ([0u8; 1024], [0u8; 1024]);
+        let data: Vec<Rc<RefCell<&mut [u8]>>> = vec![
+            Rc::new(RefCell::new(&mut data0)),
+            Rc::new(RefCell::new(&mut data1)),
+        ];
+        let mut book = init_tree(&data);
+
+        let positions = vec![
+            (0x84, 100, 42, 908),
+            (0xfe, 101, 75, 98),
+            (0x0f, 107, 4500, 708),
+            (0x9b, 123, 78000, 408),
+            (0x52, 144, 9685, 958),
+            (0xc1, 177, 7584, 108),
+            (0xaf, 295, 4681, 444),
+            (0x2f, 1045, 12346, 333),
+            (0xfb, 4049, 47958413, 12),
+            (0xb7, 7940, 42, 24),
+        ];
+        // The first positions are allocated in page 0, which is the only one provided
+        let mut page = book.memory.pages[1].take();
+        for (i, (liq_index, coll, v_coin, v_pc)) in positions.iter().enumerate() {
+            if i == 6 {
+                book.memory.pages[1] = page.take();
+            }
+            book.open_position(
+                *liq_index,
+                *coll,
+                *v_coin,
+                *v_pc,
+                PositionType::Long,
+                0,
+                &Pubkey::default(),
+            )
+            .unwrap();
+        }
+        let page_paths = positions
+            .iter()
+            .map(|p| book.get_page_path(p.0, PositionType::Long).unwrap())
+            .collect::<Vec<_>>();
+        assert!(page_paths.iter().any(|p| p.contains(&1)));
+        assert!(page_paths.iter().any(|p| !p.contains(&1)));
+
+        book.memory.pages[1] = None;
+        for (position, page_path) in positions.iter().zip(&page_paths) {
+            let r = book.close_position(position.0, 0, 0, 0, PositionType::Long, 0);
+            if page_path.contains(&1) {
+                assert_eq!(r, Err(PerpError::MissingPage));
+            } else {
+                assert_eq!(r, Ok(()));
+            }
+        }
+
+        let (position, _) = positions
+            .iter()
+            .zip(&page_paths)
+            .find(|(_, p)| !p.contains(&1))
+            .unwrap();
+        book.close_position(
+            position.0,
+            position.1,
+            position.2,
+            position.3,
+            PositionType::Long,
+            0,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_page_selection() {
+        let keys = (0..3).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        let owner = Pubkey::new_unique();
+        let (mut lamports, mut data) = ([0u64; 3], [[0u8; TAG_SIZE + SLOT_SIZE]; 3]);
+        let accounts = keys
+            .iter()
+            .zip(lamports.iter_mut())
+            .zip(data.iter_mut())
+            .map(|((k, l), d)| AccountInfo::new(k, false, true, l, d, &owner, false, 0))
+            .collect::<Vec<_>>();
+        let instance = Instance {
+            version: INSTANCE_VERSION,
+            shorts_pointer: None,
+            longs_pointer: None,
+            garbage_pointer: None,
+            number_of_pages: 3,
+        };
+        let page_infos = keys.iter().map(PageInfo::new).collect::<Vec<_>>();
+        let parse = |selection: PageSelection, accounts: &[AccountInfo]| {
+            parse_memory(&instance, &page_infos, selection, &mut accounts.iter())
+                .map(|m| m.pages.iter().map(|p| p.is_some()).collect::<Vec<_>>())
+        };
+
+        assert_eq!(get_page_bitmap(&[0, 2]), vec![0b101]);
+        assert_eq!(get_page_bitmap(&[9]), vec![0, 0b10]);
+        assert_eq!(get_page_bitmap(&[]), vec![0]);
+
+        // All the pages are expected without a bitmap
+        assert_eq!(
+            parse(PageSelection::default(), &accounts),
+            Ok(vec![true, true, true])
+        );
+        assert_eq!(
+            parse(PageSelection::default(), &accounts[..2]),
+            Err(ProgramError::NotEnoughAccountKeys)
+        );
+
+        let bitmap = get_page_bitmap(&[0, 2]);
+        let selection = PageSelection::new(&bitmap);
+        assert_eq!(
+            parse(selection, &[accounts[0].clone(), accounts[2].clone()]),
+            Ok(vec![true, false, true])
+        );
+        // A page account which doesn't match the selection is rejected rather than skipped
+        assert_eq!(
+            parse(selection, &accounts),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert_eq!(
+            parse(selection, &accounts[..1]),
+            Err(ProgramError::NotEnoughAccountKeys)
+        );
+
+        let bitmap = get_page_bitmap(&[0, 3]);
+        assert_eq!(
+            parse(PageSelection::new(&bitmap), &accounts),
+            Err(ProgramError::InvalidInstructionData)
+        );
+        let bitmap = get_page_bitmap(&[]);
+        assert_eq!(
+            parse(PageSelection::new(&bitmap), &accounts),
+            Err(ProgramError::NotEnoughAccountKeys)
+        );
+    }
+
+    #[test]
+    fn test_many_pages() {
+        // Pointers written when instances had at most 16 pages are decoded the same way
+        for page_index in 0..16 {
+            assert_eq!(
+                make_pointer(page_index, 42),
+                ((page_index as u32) << 28) | 42
+            );
+        }
+        for page_index in 0..MAX_PAGES {
+            let pt = make_pointer(page_index, MAX_PAGE_SLOTS - 1);
+            assert_eq!(get_page_index(pt), page_index);
+            assert_eq!(get_slot_index(pt), MAX_PAGE_SLOTS - 1);
+        }
+
+        let mut datas = vec![[0u8; 1 + 3 * SLOT_SIZE]; 40];
+        let data: Vec<Rc<RefCell<&mut [u8]>>> = datas
+            .iter_mut()
+            .map(|d| Rc::new(RefCell::new(&mut d[..])))
+            .collect();
+        let mut book = init_tree(&data);
+
+        let positions = (0..50u64)
+            .map(|i| ((i * 0x9e37_79b9) % 0x1_0000, 100 + i, 1000 + i, 10 + i))
+            .collect::<Vec<_>>();
+        for (liq_index, coll, v_coin, v_pc) in &positions {
+            book.open_position(
+                *liq_index,
+                *coll,
+                *v_coin,
+                *v_pc,
+                PositionType::Short,
+                0,
+                &Pubkey::default(),
+            )
+            .unwrap();
+        }
+        assert!(positions.iter().any(|p| book
+            .get_page_path(p.0, PositionType::Short)
+            .unwrap()
+            .iter()
+            .any(|i| *i >= 16)));
+        assert_eq!(
+            book.get_collateral().unwrap(),
+            positions.iter().map(|p| p.1).sum::<u64>()
+        );
+
+        for (liq_index, coll, v_coin, v_pc) in &positions {
+            book.close_position(*liq_index, *coll, *v_coin, *v_pc, PositionType::Short, 0)
+                .unwrap();
+        }
+        assert_eq!(book.shorts_root, None);
+    }
+
+    #[test]
+    fn test_defragmentation() {
+        let (mut data0, mut data1, mut data2) = ([0u8; 1024], [0u8; 1024], [0u8; 1024]);
+        let data: Vec<Rc<RefCell<&mut [u8]>>> = vec![
+            Rc::new(RefCell::new(&mut data0)),
+            Rc::new(RefCell::new(&mut data1)),
+            Rc::new(RefCell::new(&mut data2)),
+        ];
+        let mut book = init_tree(&data);
+
+        let positions = vec![
+            (0x84, 100, 42, 908),
+            (0xfe, 101, 75, 98),
+            (0x0f, 107, 4500, 708),
+            (0x9b, 123, 78000, 408),
+            (0x52, 144, 9685, 958),
+            (0xc1, 177, 7584, 108),
+            (0xaf, 295, 4681, 444),
+            (0x2f, 1045, 12346, 333),
+            (0xfb, 4049, 47958413, 12),
+            (0xb7, 7940, 42, 24),
+        ];
+        for (liq_index, coll, v_coin, v_pc) in &positions {
+            book.open_position(
+                *liq_index,
+                *coll,
+                *v_coin,
+                *v_pc,
+                PositionType::Long,
+                0,
+                &Pubkey::default(),
+            )
+            .unwrap();
+        }
+        // The slots are spread over the pages
+        let used_slots = (0..3)
+            .map(|i| book.memory.get_page(i).unwrap().uninitialized_memory)
+            .collect::<Vec<_>>();
+        assert!(used_slots.iter().max().unwrap() - used_slots.iter().min().unwrap() <= 1);
+
+        for (liq_index, coll, v_coin, v_pc) in &positions[..6] {
+            book.close_position(*liq_index, *coll, *v_coin, *v_pc, PositionType::Long, 0)
+                .unwrap();
+        }
+        for i in 0..3 {
+            let page = book.memory.get_page(i).unwrap();
+            assert_eq!(
+                page.get_free_list_length().unwrap(),
+                Some(page.get_nb_free_slots().unwrap() as u32)
+            );
+        }
+
+        // Pages 0 and 1 have the most free slots, page 1 holds the root in its slot 1 and a leaf in
+        // its slot 4 while page 2 is the densest
+        let free_slots = (0..3)
+            .map(|i| book.memory.get_page(i).unwrap().get_free_slots().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(free_slots, vec![11, 11, 10]);
+        let source_page_index = 1;
+        assert_eq!(book.longs_root, Some(make_pointer(source_page_index, 1)));
+        let collateral = book.get_collateral().unwrap();
+
+        // The root is moved with a budget of one move, the next call resumes at the leaf
+        assert_eq!(
+            book.defragment_page(source_page_index, 0, 1).unwrap(),
+            (1, Some(4))
+        );
+        assert_eq!(book.longs_root, Some(make_pointer(2, 2)));
+        assert_eq!(
+            book.defragment_page(source_page_index, 4, u16::MAX)
+                .unwrap(),
+            (1, None)
+        );
+        assert_eq!(
+            book.memory
+                .get_page(source_page_index)
+                .unwrap()
+                .uninitialized_memory,
+            0
+        );
+        assert_eq!(book.get_collateral().unwrap(), collateral);
+
+        for (liq_index, coll, v_coin, v_pc) in &positions[6..] {
+            assert!(!book
+                .get_page_path(*liq_index, PositionType::Long)
+                .unwrap()
+                .contains(&(source_page_index as u32)));
+            book.close_position(*liq_index, *coll, *v_coin, *v_pc, PositionType::Long, 0)
+                .unwrap();
+        }
+        assert_eq!(book.longs_root, None);
+    }
+
+    #[test]
+    fn test_allocation_pages() {
+        let (mut data0, mut data1, mut data2) = ([0u8; 1024], [0u8; 1024], [0u8; 1024]);
+        let data: Vec<Rc<RefCell<&mut [u8]>>> = vec![
+            Rc::new(RefCell::new(&mut data0)),
+            Rc::new(RefCell::new(&mut data1)),
+            Rc::new(RefCell::new(&mut data2)),
+        ];
+        let mut book = init_tree(&data);
+        let positions = vec![
+            (0x84, 100, 42, 908),
+            (0xfe, 101, 75, 98),
+            (0x0f, 107, 4500, 708),
+            (0x9b, 123, 78000, 408),
+            (0x52, 144, 9685, 958),
+            (0xc1, 177, 7584, 108),
+            (0xaf, 295, 4681, 444),
+            (0x2f, 1045, 12346, 333),
+        ];
+        for (liq_index, coll, v_coin, v_pc) in &positions {
+            book.open_position(
+                *liq_index,
+                *coll,
+                *v_coin,
+                *v_pc,
+                PositionType::Long,
+                0,
+                &Pubkey::default(),
+            )
+            .unwrap();
+        }
+        for (liq_index, coll, v_coin, v_pc) in &positions[..5] {
+            book.close_position(*liq_index, *coll, *v_coin, *v_pc, PositionType::Long, 0)
+                .unwrap();
+        }
+
+        // The free list of page 0 is rewritten as an uncounted list of the legacy layout
+        let page = book.memory.get_page_mut(0).unwrap();
+        let length = page.get_free_list_length().unwrap().unwrap();
+        assert!(length > 1);
+        let mut pt = page.free_slot_list_hd.unwrap();
+        for i in 0..length {
+            let slot_type = if i + 1 == length {
+                SlotType::LastFreeSlot
+            } else {
+                SlotType::FreeSlot
+            };
+            page.write(pt, 0, &[slot_type as u8]).unwrap();
+            pt = page
+                .read_u32_le(pt, FreeSlotSchema::PointerToNext as usize)
+                .unwrap();
+        }
+        assert_eq!(page.get_free_list_length().unwrap(), None);
+        assert_eq!(page.get_nb_free_slots().unwrap(), length as u64);
+
+        // The pages predicted client-side are the ones the program allocates in
+        let total_free_slots = (0..3)
+            .map(|i| book.memory.get_page(i).unwrap().get_free_slots().unwrap())
+            .sum::<u32>()
+            + length
+            - 1;
+        let allocation_pages = (0..=total_free_slots)
+            .map(|n| book.memory.get_allocation_pages(n as u64).unwrap())
+            .collect::<Vec<_>>();
+        let mut used_pages = vec![];
+        for expected_pages in &allocation_pages[1..] {
+            let pt = book.memory.allocate(SlotType::LeafNode).unwrap();
+            let page_index = get_page_index(pt) as u32;
+            if !used_pages.contains(&page_index) {
+                used_pages.push(page_index);
+                used_pages.sort_unstable();
+            }
+            assert_eq!(&used_pages, expected_pages);
+        }
+        assert_eq!(used_pages, vec![0, 1, 2]);
+        assert_eq!(
+            book.memory.allocate(SlotType::LeafNode),
+            Err(PerpError::OutOfSpace)
+        );
+        assert_eq!(book.memory.get_allocation_pages(1).unwrap(), vec![]);
+    }
+
     // #[test]
     // fn test_aggregate_position() {
     //     let (mut data0, mut data1, mut data2, mut data3) =
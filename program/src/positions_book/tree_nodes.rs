@@ -1,8 +1,11 @@
+use num_derive::FromPrimitive;
+use solana_program::pubkey::Pubkey;
+
 use crate::error::{PerpError, PerpResult};
 
 use super::memory::{Memory, Pointer};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, FromPrimitive)]
 pub enum InnerNodeSchema {
     Critbit = 1, // Critbit from the right
     LiquidationIndexMin = 2,
@@ -20,6 +23,29 @@ pub enum LeafNodeSchema {
     Collateral = 17,
     VCoin = 25,
     VPc = 33,
+    Owner = 41,
+    OwnerFlags = 73,
+}
+
+// Bits of the owner flags of a leaf
+pub const SHARED_OWNER_FLAG: u8 = 1;
+pub const UNKNOWN_OWNER_FLAG: u8 = 2;
+// Set on the leaves written before the margin tiers, whose liquidation index was computed with a
+// flat 500bps maintenance margin
+pub const LEGACY_MARGIN_FLAG: u8 = 4;
+
+/// User account which opened the positions of a leaf. The positions are found in the user accounts
+/// as those with the liquidation index and slot number of the leaf, which they keep when they are
+/// moved to another position index or transferred to another user account.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LeafOwner {
+    pub user_account: Pubkey,
+    pub liquidation_index: u64,
+    pub slot_number: u64,
+    // Set when positions of other owners with the same liquidation index were merged into the leaf
+    pub is_shared: bool,
+    // Set on the leaves of migrated legacy pages, which didn't record their owner
+    pub is_unknown: bool,
 }
 
 pub struct InnerNode(pub(super) Pointer);
@@ -157,6 +183,40 @@ impl Leaf {
         mem.read_u64_le(self.0, LeafNodeSchema::LiquidationIndex as usize)
     }
 
+    pub fn get_owner(&self, mem: &Memory) -> Result<LeafOwner, PerpError> {
+        let user_account = mem.read(self.0, LeafNodeSchema::Owner as usize, 32)?;
+        let flags = mem.read_byte(self.0, LeafNodeSchema::OwnerFlags as usize)?;
+        Ok(LeafOwner {
+            user_account: Pubkey::new(&user_account),
+            liquidation_index: self.get_liquidation_index(mem)?,
+            slot_number: self.get_slot_number(mem)?,
+            is_shared: flags & SHARED_OWNER_FLAG != 0,
+            is_unknown: flags & UNKNOWN_OWNER_FLAG != 0,
+        })
+    }
+
+    pub(super) fn set_owner(&self, mem: &mut Memory, user_account: &Pubkey) -> PerpResult {
+        mem.write(
+            self.0,
+            LeafNodeSchema::Owner as usize,
+            &user_account.to_bytes(),
+        )?;
+        mem.write(self.0, LeafNodeSchema::OwnerFlags as usize, &[0])
+    }
+
+    pub fn has_legacy_margin(&self, mem: &Memory) -> Result<bool, PerpError> {
+        Ok(mem.read_byte(self.0, LeafNodeSchema::OwnerFlags as usize)? & LEGACY_MARGIN_FLAG != 0)
+    }
+
+    pub(super) fn set_shared(&self, mem: &mut Memory) -> PerpResult {
+        let flags = mem.read_byte(self.0, LeafNodeSchema::OwnerFlags as usize)?;
+        mem.write(
+            self.0,
+            LeafNodeSchema::OwnerFlags as usize,
+            &[flags | SHARED_OWNER_FLAG],
+        )
+    }
+
     pub(super) fn free(&self, mem: &mut Memory) -> PerpResult {
         mem.free(self.0)
     }
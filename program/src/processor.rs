@@ -6,16 +6,21 @@ use solana_program::{
 
 use crate::{
     instruction::PerpInstruction,
+    positions_book::memory::PageSelection,
     processor::{
         add_budget::process_add_budget, add_instance::process_add_instance,
-        add_page::process_add_page, change_k::process_change_k,
-        close_account::process_close_account, close_position::process_close_position,
-        create_market::process_create_market, funding::process_funding,
+        add_page::process_add_page, auto_deleverage::process_auto_deleverage,
+        change_k::process_change_k, close_account::process_close_account,
+        close_position::process_close_position, create_market::process_create_market,
+        defragment_page::process_defragment_page, funding::process_funding,
         funding_extraction::process_funding_extraction,
         garbage_collection::process_garbage_collection,
-        increase_position::process_increase_position, liquidation::process_liquidation,
-        open_position::process_open_position, rebalance::process_rebalance,
-        transfer_position::process_transfer_position,
+        increase_position::process_increase_position,
+        init_liquidation_receipts::process_init_liquidation_receipts,
+        liquidation::process_liquidation, migrate_instance::process_migrate_instance,
+        migrate_page::process_migrate_page, open_position::process_open_position,
+        purge_liquidated_positions::process_purge_liquidated_positions,
+        rebalance::process_rebalance, transfer_position::process_transfer_position,
         transfer_user_account::process_transfer_user_account,
         update_oracle_account::process_update_oracle_account,
         withdraw_budget::process_withdraw_budget,
@@ -24,9 +29,8 @@ use crate::{
 
 ////////////////////////////////////////////////////////////
 
-pub(crate) const MARGIN_RATIO: u64 = ((1u128 << 64) / 20) as u64; // 64 fixed point
-const FUNDING_PERIOD: u64 = 3_600; // in s
-const FUNDING_NORMALIZATION: u64 = 86400 / FUNDING_PERIOD; // in s
+pub(crate) const FUNDING_PERIOD: u64 = 3_600; // in s
+pub(crate) const FUNDING_NORMALIZATION: u64 = 86400 / FUNDING_PERIOD; // in s
 const HISTORY_PERIOD: u64 = 300; // in s
 pub const REBALANCING_MARGIN: i64 = 429496729; // FP32 the relative difference in longs vs shorts open interests which enables rebalancing.
 pub const REBALANCING_LEVERAGE: u64 = 1;
@@ -38,6 +42,7 @@ pub const LIQUIDATION_LABEL: &str = "LiquidationRecord11111111111111111111111111
 pub const FUNDING_LABEL: &str = "FundingRecord1111111111111111111111111111111";
 pub const TRADE_LABEL: &str = "TradeRecord11111111111111111111111111111111";
 pub const FUNDING_EXTRACTION_LABEL: &str = "FundingExtraction111111111111111111111111111";
+pub const AUTO_DELEVERAGE_LABEL: &str = "AutoDeLeverageRecord11111111111111111111111";
 
 pub const MAX_LEVERAGE: u64 = 20 << 32;
 pub const MAX_POSITION_SIZE: u64 = 500_000_000_000; // in USDC
@@ -52,6 +57,7 @@ pub const _FEE_INSURANCE_FUND: u64 = 30; // Percentage of total fee
 pub const FEE_REBALANCING_FUND: u64 = 30; // Percentage of total fee
 pub const FEE_REFERRER: u64 = 10; // Percentage of total fee, gets split up between Insurance fund and BNB if referrer is not specified
 pub const ALLOCATION_FEE: u64 = 10_000; // Flat fee that balances out the rewards, refunded if closing without liquidation
+pub const PURGE_BOUNTY: u64 = 1_000; // Flat reward per liquidated position removed from a user account, paid from the fees
 pub const HIGH_LEVERAGE_MIN: u64 = 8 << 32;
 // Amount of fees taken for opening or closing an order, expressed in bps of order size
 pub const FEES_LOW_LEVERAGE: &[u64] = &[20, 15, 15, 10, 10, 10]; // Fees for low leverage orders for tiers [0, 1 ,2 ,3, 4, 5]
@@ -73,21 +79,42 @@ pub const FEE_TIERS: [u64; 5] = [
 // | 4    | 10bps                   | 20bps                    | 100,000 FIDA   |
 // | 5    | 10bps                   | 15bps                    | 1,000,000 FIDA |
 
+// Margin requirements, expressed in bps of position notional
+pub const INITIAL_MARGIN_BPS: &[u64] = &[500, 750, 1_000, 1_500]; // Required to open, increase or remove collateral, for tiers [0, 1, 2, 3]
+pub const MAINTENANCE_MARGIN_BPS: &[u64] = &[300, 600, 750, 1_000]; // Below this the position gets liquidated, for tiers [0, 1, 2, 3]
+pub const MARGIN_TIERS: [u64; 3] = [50_000_000_000, 200_000_000_000, 350_000_000_000]; // Position notional (in USDC with precision) above which the next tier applies
+
+// | Tier | Notional            | Initial margin | Maintenance margin |
+// | ---- | ------------------- | -------------- | ------------------ |
+// | 0    | < 50,000 USDC       | 500bps (20x)   | 300bps             |
+// | 1    | < 200,000 USDC      | 750bps (13.3x) | 600bps             |
+// | 2    | < 350,000 USDC      | 1000bps (10x)  | 750bps             |
+// | 3    | >= 350,000 USDC     | 1500bps (6.6x) | 1000bps            |
+//
+// Every tier has an initial margin above its maintenance margin, so that a position opened at the
+// maximum leverage of its tier isn't liquidatable right away.
+
 ////////////////////////////////////////////////////////////
 
 pub mod add_budget;
 pub mod add_instance;
 pub mod add_page;
+pub mod auto_deleverage;
 pub mod change_k;
 pub mod close_account;
 pub mod close_position;
 pub mod create_market;
+pub mod defragment_page;
 pub mod funding;
 pub mod funding_extraction;
 pub mod garbage_collection;
 pub mod increase_position;
+pub mod init_liquidation_receipts;
 pub mod liquidation;
+pub mod migrate_instance;
+pub mod migrate_page;
 pub mod open_position;
+pub mod purge_liquidated_positions;
 pub mod rebalance;
 pub mod transfer_position;
 pub mod transfer_user_account;
@@ -103,8 +130,15 @@ impl Processor {
         instruction_data: &[u8],
     ) -> ProgramResult {
         msg!("Beginning processing");
-        let instruction = PerpInstruction::try_from_slice(instruction_data)
+        // The instructions using the positions book can be followed by a page selection
+        let mut data = instruction_data;
+        let instruction = PerpInstruction::deserialize(&mut data)
             .map_err(|_| ProgramError::InvalidInstructionData)?;
+        if !data.is_empty() && !instruction.takes_page_selection() {
+            msg!("The instruction data has trailing bytes");
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        let page_selection = PageSelection::new(data);
         msg!("Instruction unpacked");
 
         match instruction {
@@ -149,6 +183,7 @@ impl Processor {
                     leverage,
                     predicted_entry_price,
                     maximum_slippage_margin,
+                    page_selection,
                 )?;
             }
             PerpInstruction::IncreasePosition {
@@ -169,6 +204,7 @@ impl Processor {
                     add_collateral,
                     predicted_entry_price,
                     maximum_slippage_margin,
+                    page_selection,
                 )?;
             }
             PerpInstruction::ClosePosition {
@@ -187,6 +223,7 @@ impl Processor {
                     closing_v_coin,
                     predicted_entry_price,
                     maximum_slippage_margin,
+                    page_selection,
                 )?;
             }
             PerpInstruction::CollectGarbage {
@@ -194,13 +231,19 @@ impl Processor {
                 max_iterations,
             } => {
                 msg!("Instruction: Collect Garbage");
-                process_garbage_collection(program_id, accounts, leverage_index, max_iterations)?;
+                process_garbage_collection(
+                    program_id,
+                    accounts,
+                    leverage_index,
+                    max_iterations,
+                    page_selection,
+                )?;
             }
             PerpInstruction::CrankLiquidation {
                 instance_index: leverage_index,
             } => {
                 msg!("Instruction: Liquidate positions");
-                process_liquidation(program_id, accounts, leverage_index)?;
+                process_liquidation(program_id, accounts, leverage_index, None, page_selection)?;
             }
             PerpInstruction::CrankFunding => {
                 msg!("Instruction: Crank Funding");
@@ -208,7 +251,7 @@ impl Processor {
             }
             PerpInstruction::FundingExtraction { instance_index } => {
                 msg!("Instruction: Funding extraction");
-                process_funding_extraction(program_id, instance_index, accounts)?;
+                process_funding_extraction(program_id, instance_index, accounts, page_selection)?;
             }
             PerpInstruction::AddBudget { amount } => {
                 msg!("Instruction: Add budget");
@@ -239,7 +282,13 @@ impl Processor {
                 instance_index,
             } => {
                 msg!("Instruction: Rebalance");
-                process_rebalance(program_id, accounts, instance_index, collateral)?;
+                process_rebalance(
+                    program_id,
+                    accounts,
+                    instance_index,
+                    collateral,
+                    page_selection,
+                )?;
             }
             PerpInstruction::TransferUserAccount {} => {
                 msg!("Instruction: Transfer User Account");
@@ -249,6 +298,73 @@ impl Processor {
                 msg!("Instruction: Transfer Position");
                 process_transfer_position(program_id, accounts, position_index)?;
             }
+            PerpInstruction::AutoDeleverage {
+                instance_index,
+                position_index,
+            } => {
+                msg!("Instruction: Auto Deleverage");
+                process_auto_deleverage(
+                    program_id,
+                    accounts,
+                    instance_index,
+                    position_index,
+                    page_selection,
+                )?;
+            }
+            PerpInstruction::PurgeLiquidatedPositions { instance_index } => {
+                msg!("Instruction: Purge Liquidated Positions");
+                process_purge_liquidated_positions(
+                    program_id,
+                    accounts,
+                    instance_index,
+                    page_selection,
+                )?;
+            }
+            PerpInstruction::InitLiquidationReceipts => {
+                msg!("Instruction: Init Liquidation Receipts");
+                process_init_liquidation_receipts(program_id, accounts)?;
+            }
+            PerpInstruction::MigratePage {
+                instance_index,
+                page_index,
+            } => {
+                msg!("Instruction: Migrate Page");
+                process_migrate_page(program_id, accounts, instance_index, page_index)?;
+            }
+            PerpInstruction::CrankBoundedLiquidation {
+                instance_index,
+                max_nodes,
+            } => {
+                msg!("Instruction: Liquidate positions with a node budget");
+                process_liquidation(
+                    program_id,
+                    accounts,
+                    instance_index,
+                    Some(max_nodes),
+                    page_selection,
+                )?;
+            }
+            PerpInstruction::MigrateInstance { instance_index } => {
+                msg!("Instruction: Migrate Instance");
+                process_migrate_instance(program_id, accounts, instance_index)?;
+            }
+            PerpInstruction::DefragmentPage {
+                instance_index,
+                page_index,
+                first_slot,
+                max_moves,
+            } => {
+                msg!("Instruction: Defragment Page");
+                process_defragment_page(
+                    program_id,
+                    accounts,
+                    instance_index,
+                    page_index,
+                    first_slot,
+                    max_moves,
+                    page_selection,
+                )?;
+            }
         }
         Ok(())
     }
@@ -9,7 +9,7 @@ use solana_program::{
 
 use crate::{
     state::{
-        instance::{write_instance, write_page_info, Instance, PageInfo},
+        instance::{write_instance, write_page_info, Instance, PageInfo, INSTANCE_VERSION},
         is_initialized,
         market::{write_instance_address, MarketState},
     },
@@ -60,17 +60,8 @@ impl<'a, 'b: 'a> Accounts<'a, 'b> {
 pub fn process_add_instance(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
     let accounts = Accounts::parse(program_id, accounts)?;
 
-    for page_idx in 0..accounts.memory_pages.len() {
-        let page_info = PageInfo::new(accounts.memory_pages[page_idx].key);
-        write_page_info(
-            &mut accounts.instance.data.borrow_mut(),
-            page_idx,
-            &page_info,
-        )?;
-    }
-
     let instance = Instance {
-        version: 0,
+        version: INSTANCE_VERSION,
         shorts_pointer: None,
         longs_pointer: None,
         garbage_pointer: None,
@@ -90,6 +81,17 @@ pub fn process_add_instance(program_id: &Pubkey, accounts: &[AccountInfo]) -> Pr
         &accounts.instance.key,
     )?;
     write_instance(&mut accounts.instance.data.borrow_mut(), &instance)?;
+
+    // The page infos are placed according to the instance version
+    for page_idx in 0..accounts.memory_pages.len() {
+        let page_info = PageInfo::new(accounts.memory_pages[page_idx].key);
+        write_page_info(
+            &mut accounts.instance.data.borrow_mut(),
+            page_idx,
+            &page_info,
+        )?;
+    }
+
     market_state.number_of_instances += 1;
 
     market_state.pack_into_slice(&mut accounts.market.data.borrow_mut());
@@ -8,8 +8,9 @@ use solana_program::{
 };
 
 use crate::{
+    positions_book::memory::MAX_PAGES,
     state::{
-        instance::{parse_instance, write_instance, write_page_info, PageInfo},
+        instance::{get_page_capacity, parse_instance, write_instance, write_page_info, PageInfo},
         is_initialized,
         market::{get_instance_address, MarketState},
     },
@@ -75,6 +76,14 @@ pub fn process_add_page(
     }
 
     let (mut instance, _) = parse_instance(&accounts.instance.data.borrow())?;
+    if instance.number_of_pages as usize >= MAX_PAGES {
+        msg!("The instance already has the maximum number of pages");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if instance.number_of_pages as usize >= get_page_capacity(&accounts.instance.data.borrow())? {
+        msg!("The instance account is full, it has to be migrated to a larger account");
+        return Err(ProgramError::AccountDataTooSmall);
+    }
 
     let page_info = PageInfo::new(accounts.new_memory_page.key);
     write_page_info(
@@ -0,0 +1,315 @@
+use std::{slice::Iter, str::FromStr};
+
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    sysvar::{self, clock::Clock, Sysvar},
+};
+use spl_token::state::Account;
+
+use crate::{
+    error::PerpError,
+    positions_book::{
+        memory::{parse_memory, PageSelection},
+        positions_book_tree::PositionsBook,
+    },
+    processor::{ALLOCATION_FEE, AUTO_DELEVERAGE_LABEL},
+    state::{
+        instance::{check_no_pending_liquidation, parse_instance, write_instance_and_memory},
+        market::{get_instance_address, MarketState},
+        user_account::{get_position, remove_position, write_position, UserAccountState},
+    },
+    utils::{
+        check_account_key, check_account_owner, check_remaining_position, check_signer,
+        compute_deleveraging_score, compute_payout, get_oracle_price,
+    },
+};
+
+struct Accounts<'a, 'b: 'a> {
+    market: &'a AccountInfo<'b>,
+    instance: &'a AccountInfo<'b>,
+    market_vault: &'a AccountInfo<'b>,
+    oracle: &'a AccountInfo<'b>,
+    admin: &'a AccountInfo<'b>,
+    user_account: &'a AccountInfo<'b>,
+    clock_sysvar: &'a AccountInfo<'b>,
+    remaining: Iter<'a, AccountInfo<'b>>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, 'b> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let mut accounts_iter = accounts.iter();
+
+        let market = next_account_info(&mut accounts_iter)?;
+        let instance = next_account_info(&mut accounts_iter)?;
+        let market_vault = next_account_info(&mut accounts_iter)?;
+        let oracle = next_account_info(&mut accounts_iter)?;
+        let admin = next_account_info(&mut accounts_iter)?;
+        let user_account = next_account_info(&mut accounts_iter)?;
+        let label = next_account_info(&mut accounts_iter)?;
+        let clock_sysvar = next_account_info(&mut accounts_iter)?;
+
+        check_account_key(label, &Pubkey::from_str(AUTO_DELEVERAGE_LABEL).unwrap()).unwrap();
+        check_account_key(clock_sysvar, &sysvar::clock::ID).unwrap();
+        check_account_owner(market, program_id).unwrap();
+        check_account_owner(instance, program_id).unwrap();
+        check_account_owner(market_vault, &spl_token::id()).unwrap();
+        check_account_owner(user_account, program_id).unwrap();
+        check_signer(admin).unwrap();
+
+        Ok(Self {
+            market,
+            instance,
+            market_vault,
+            oracle,
+            admin,
+            user_account,
+            clock_sysvar,
+            remaining: accounts_iter,
+        })
+    }
+}
+
+pub fn process_auto_deleverage(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instance_index: u8,
+    position_index: u16,
+    page_selection: PageSelection,
+) -> ProgramResult {
+    let mut accounts = Accounts::parse(program_id, accounts)?;
+
+    // Parsing
+    let mut market_state = MarketState::unpack_from_slice(&accounts.market.data.borrow())?;
+
+    let mut user_account_header =
+        UserAccountState::unpack_from_slice(&accounts.user_account.data.borrow())?;
+
+    // Verifications
+    if &Pubkey::new(&market_state.admin_address) != accounts.admin.key {
+        msg!("Invalid admin account for the current market");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if market_state.vault_address != accounts.market_vault.key.to_bytes() {
+        msg!("Provided market vault account is incorrect.");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if market_state.oracle_address != accounts.oracle.key.to_bytes() {
+        msg!("Provided oracle account is incorrect.");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if &Pubkey::new(&user_account_header.market) != accounts.market.key {
+        msg!("The user account market doesn't match the given market account");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if user_account_header.last_funding_offset != market_state.funding_history_offset {
+        msg!("Funding must be processed for this account.");
+        return Err(PerpError::PendingFunding.into());
+    }
+    if user_account_header.number_of_open_positions <= (position_index as u32) {
+        msg!("Position index is invalid");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let market_vault_balance = Account::unpack(&accounts.market_vault.data.borrow())?.amount;
+    let insurance_fund = market_state.get_insurance_fund(market_vault_balance);
+    if insurance_fund >= 0 {
+        msg!("The insurance fund is not depleted, no deleveraging is required.");
+        return Err(PerpError::Nop.into());
+    }
+    let shortfall = (-insurance_fund) as u64;
+
+    let mut open_position = get_position(
+        &mut accounts.user_account.data.borrow_mut(),
+        &user_account_header,
+        position_index,
+    )?;
+    if open_position.instance_index != instance_index {
+        msg!("The position does not belong to the given instance");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let instance_address =
+        get_instance_address(&accounts.market.data.borrow(), instance_index as u32)?;
+    if &instance_address != accounts.instance.key {
+        msg!("Invalid instance account or instance index provided");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    check_no_pending_liquidation(&accounts.instance.data.borrow())?;
+    let (mut instance, mut page_infos) = parse_instance(&accounts.instance.data.borrow())?;
+    let memory = parse_memory(
+        &instance,
+        &page_infos,
+        page_selection,
+        &mut accounts.remaining,
+    )?;
+    let mut book = PositionsBook::new(instance.shorts_pointer, instance.longs_pointer, memory);
+
+    let oracle_price = get_oracle_price(
+        &accounts.oracle.data.borrow(),
+        market_state.coin_decimals,
+        market_state.quote_decimals,
+    )?;
+
+    // The profit owed to the positions opposing the vAMM is what depletes the insurance fund.
+    // Ranking the positions of that side is left to the admin, the book isn't walked.
+    if market_state.get_deleveraging_side() != Some(open_position.side) {
+        msg!("The position does not oppose the insurance fund shortfall.");
+        return Err(PerpError::DeleveragingSide.into());
+    }
+
+    // Compute the payout of the position if it were closed entirely
+    let signed_closing_v_coin =
+        open_position.side.get_sign() * (open_position.v_coin_amount as i64);
+    let v_pc_closing_amount = market_state.compute_add_v_pc(signed_closing_v_coin)?;
+    let payout = compute_payout(
+        v_pc_closing_amount.abs() as u64,
+        open_position.v_pc_amount,
+        open_position.collateral,
+        &open_position.side,
+    );
+    let profit = payout - (open_position.collateral as i64);
+    let score = match compute_deleveraging_score(
+        &market_state,
+        open_position.collateral,
+        open_position.v_coin_amount,
+        open_position.v_pc_amount,
+        open_position.side,
+    )? {
+        Some(s) => s,
+        None => {
+            msg!("Only profitable positions can be deleveraged.");
+            return Err(PerpError::Nop.into());
+        }
+    };
+
+    // Only the share of the position whose profit covers the shortfall is closed. A smaller trade
+    // has a lower price impact, its profit is at least the same share of the total profit.
+    let haircut = std::cmp::min(shortfall, profit as u64);
+    let closing_v_coin =
+        (((open_position.v_coin_amount as u128) * (haircut as u128) + (profit as u128) - 1)
+            / (profit as u128)) as u64;
+    let closing_collateral = (((open_position.collateral as u128) * (closing_v_coin as u128))
+        / (open_position.v_coin_amount as u128)) as u64;
+
+    book.close_position(
+        open_position.liquidation_index,
+        open_position.collateral,
+        open_position.v_coin_amount,
+        open_position.v_pc_amount,
+        open_position.side,
+        open_position.slot_number,
+    )?;
+    let trade = market_state.close_trade(
+        &open_position,
+        closing_collateral,
+        closing_v_coin,
+        oracle_price,
+    )?;
+
+    // The profit is reduced by the shortfall, which stays in the vault to restore solvency
+    let realized_profit = std::cmp::max(trade.payout - (trade.collateral as i64), 0) as u64;
+    let haircut = std::cmp::min(haircut, realized_profit);
+    let payout_ltd = (std::cmp::max(trade.payout, 0) as u64) - haircut;
+
+    open_position.collateral -= trade.collateral;
+    open_position.v_coin_amount -= trade.v_coin_amount;
+    open_position.v_pc_amount -= trade.v_pc_to_settle;
+
+    // Funding owed for the current cycle is charged on the closed part as when closing a position
+    let clock = Clock::from_account_info(accounts.clock_sysvar)?;
+    let debt = market_state.get_funding_debt(
+        open_position.side,
+        trade.v_coin_amount,
+        clock.unix_timestamp,
+    );
+    let allocation_refund = if open_position.collateral == 0 {
+        ALLOCATION_FEE
+    } else {
+        0
+    };
+    user_account_header.balance = user_account_header
+        .balance
+        .checked_add(payout_ltd + allocation_refund)
+        .and_then(|n| n.checked_sub(debt))
+        .ok_or_else(|| {
+            msg!("Not enough available balance to pay for current round of funding.");
+            PerpError::NoMoreFunds
+        })?;
+
+    market_state.total_collateral -= trade.collateral;
+    market_state.total_fee_balance -= allocation_refund;
+    market_state.total_user_balances += payout_ltd + allocation_refund;
+    market_state.total_user_balances -= debt;
+
+    msg!(
+        "Auto-deleveraged position {:?} of user account {:?} with side {:?} and score {:?} by collateral {:?} and v_coin {:?}. Profit: {:?}, haircut: {:?}, funding: {:?}",
+        position_index,
+        accounts.user_account.key,
+        open_position.side,
+        score,
+        trade.collateral,
+        trade.v_coin_amount,
+        realized_profit,
+        haircut,
+        debt
+    );
+
+    if open_position.collateral == 0 {
+        remove_position(
+            &mut accounts.user_account.data.borrow_mut(),
+            &mut user_account_header,
+            position_index as u32,
+        )?;
+    } else {
+        // The collateral is released in proportion to the closed v_coin, the margin of the
+        // remaining position isn't decreased
+        let new_liquidation_index = check_remaining_position(
+            &market_state,
+            open_position.collateral,
+            open_position.v_coin_amount,
+            open_position.v_pc_amount,
+            open_position.side,
+            false,
+            oracle_price,
+        )?;
+        let insertion_leaf = book.open_position(
+            new_liquidation_index,
+            open_position.collateral,
+            open_position.v_coin_amount,
+            open_position.v_pc_amount,
+            open_position.side,
+            clock.slot,
+            accounts.user_account.key,
+        )?;
+        open_position.slot_number = insertion_leaf.get_slot_number(&book.memory)?;
+        open_position.liquidation_index = new_liquidation_index;
+
+        write_position(
+            &mut accounts.user_account.data.borrow_mut(),
+            position_index,
+            &mut user_account_header,
+            &open_position,
+            true,
+        )?;
+    }
+
+    user_account_header.pack_into_slice(&mut accounts.user_account.data.borrow_mut());
+    instance.update(&book, &mut page_infos);
+    write_instance_and_memory(
+        &mut accounts.instance.data.borrow_mut(),
+        &page_infos,
+        &instance,
+    )?;
+    market_state.pack_into_slice(&mut accounts.market.data.borrow_mut());
+
+    Ok(())
+}
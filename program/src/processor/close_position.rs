@@ -13,17 +13,19 @@ use solana_program::{
 
 use crate::{
     error::PerpError,
-    positions_book::{memory::parse_memory, positions_book_tree::PositionsBook},
-    processor::{FUNDING_NORMALIZATION, FUNDING_PERIOD, MAX_LEVERAGE},
+    positions_book::{
+        memory::{parse_memory, PageSelection},
+        positions_book_tree::PositionsBook,
+    },
+    state::user_account::UserAccountState,
     state::{
-        instance::{parse_instance, write_instance_and_memory},
+        instance::{check_no_pending_liquidation, parse_instance, write_instance_and_memory},
         market::{get_instance_address, MarketState},
         user_account::{get_position, remove_position, write_position},
     },
-    state::{user_account::UserAccountState, PositionType},
     utils::{
-        check_account_key, check_account_owner, check_signer, compute_fee_tier, compute_fees,
-        compute_liquidation_index, get_oracle_price,
+        check_account_key, check_account_owner, check_leverage, check_remaining_position,
+        check_signer, compute_fee_tier, compute_fees, get_oracle_price,
     },
 };
 
@@ -96,6 +98,7 @@ pub fn process_close_position(
     closing_v_coin: u64,
     predicted_entry_price: u64,   // 32 bit FP
     maximum_slippage_margin: u64, // 32 bit FP
+    page_selection: PageSelection,
 ) -> ProgramResult {
     let mut accounts = Accounts::parse(program_id, accounts)?;
 
@@ -121,6 +124,7 @@ pub fn process_close_position(
         return Err(ProgramError::InvalidArgument);
     }
 
+    check_no_pending_liquidation(&accounts.instance.data.borrow())?;
     let (mut instance, mut page_infos) = parse_instance(&accounts.instance.data.borrow())?;
 
     if user_account_header.number_of_open_positions <= (position_index as u32) {
@@ -128,7 +132,12 @@ pub fn process_close_position(
         return Err(ProgramError::InvalidArgument);
     }
 
-    let memory = parse_memory(&instance, &page_infos, &mut accounts.remaining)?;
+    let memory = parse_memory(
+        &instance,
+        &page_infos,
+        page_selection,
+        &mut accounts.remaining,
+    )?;
     let mut positions_book =
         PositionsBook::new(instance.shorts_pointer, instance.longs_pointer, memory);
 
@@ -142,6 +151,10 @@ pub fn process_close_position(
         msg!("The user account market doesn't match the given market account");
         return Err(ProgramError::InvalidArgument);
     }
+    if *accounts.user_account_owner.key != Pubkey::new(&user_account_header.owner) {
+        msg!("The user account owner is invalid");
+        return Err(ProgramError::InvalidArgument);
+    }
     if user_account_header.last_funding_offset != market_state.funding_history_offset {
         msg!("Funding must be processed for this account.");
         return Err(PerpError::PendingFunding.into());
@@ -159,8 +172,8 @@ pub fn process_close_position(
     match r {
         Ok(()) => {}
         Err(PerpError::PositionNotFound) => {
-            msg!("Order not found, it was liquidated at index: {:?}, with collateral {:?}, with parent node slot {:?}",
-                    open_position.liquidation_index, open_position.collateral, open_position.slot_number);
+            msg!("Order not found, position {:?} of user account {:?} was liquidated at index: {:?}, with collateral {:?}, with parent node slot {:?}",
+                    position_index, accounts.user_account.key, open_position.liquidation_index, open_position.collateral, open_position.slot_number);
             remove_position(
                 &mut accounts.user_account.data.borrow_mut(),
                 &mut user_account_header,
@@ -172,13 +185,6 @@ pub fn process_close_position(
         Err(e) => Err(e).unwrap(),
     }
 
-    // User account owner verification delay to allow for permissionless purging of liquidated positions.
-
-    if *accounts.user_account_owner.key != Pubkey::new(&user_account_header.owner) {
-        msg!("The user account owner is invalid");
-        return Err(ProgramError::InvalidArgument);
-    }
-
     let clock = Clock::from_account_info(accounts.clock_sysvar)?;
     let current_timestamp = clock.unix_timestamp;
 
@@ -187,96 +193,41 @@ pub fn process_close_position(
         market_state.coin_decimals,
         market_state.quote_decimals,
     )?;
-    let mut closing_collateral_ltd = core::cmp::min(closing_collateral, open_position.collateral);
-
-    let closing_v_coin_ltd = core::cmp::min(closing_v_coin, open_position.v_coin_amount);
-
-    let side_sign = open_position.side.get_sign();
-
-    let signed_closing_v_coin = side_sign * (closing_v_coin_ltd as i64);
-    let v_pc_closing_amount = market_state.compute_add_v_pc(signed_closing_v_coin)?;
-
-    msg!(
-        "Transaction info: v_coin_amount {:?}, v_pc_amount {:?}",
-        closing_v_coin_ltd,
-        v_pc_closing_amount.abs()
-    );
-
-    // Keep entry price constant for position
-    let v_pc_to_settle = (((closing_v_coin_ltd as u128) * (open_position.v_pc_amount as u128))
-        / (open_position.v_coin_amount as u128)) as i64;
-
-    let payout = match open_position.side {
-        PositionType::Long => (((v_pc_closing_amount.abs() as u64) + closing_collateral_ltd)
-            as i64)
-            .checked_sub(v_pc_to_settle),
-        PositionType::Short => (v_pc_to_settle + closing_collateral_ltd as i64)
-            .checked_sub(v_pc_closing_amount.abs() as i64),
-    }
-    .ok_or(PerpError::Overflow)?;
-
-    if payout < 0 {
-        closing_collateral_ltd = core::cmp::min(
-            closing_collateral_ltd + ((-payout) as u64),
-            open_position.collateral,
-        ); // The insurance fund buffers the payout in the second case
-    }
-
-    let (balanced_pc_closing_amount, balanced_closing_v_coin) =
-        market_state.balance_operation(v_pc_closing_amount, signed_closing_v_coin, oracle_price)?;
-
-    if v_pc_to_settle < 0 {
-        panic!()
-    }
-
-    market_state.add_v_coin(balanced_closing_v_coin as i64)?;
-    market_state.add_v_pc(balanced_pc_closing_amount)?;
-    market_state.sub_open_interest(
-        closing_v_coin_ltd,
-        v_pc_to_settle as u64,
-        open_position.side,
+    let trade = market_state.close_trade(
+        &open_position,
+        closing_collateral,
+        closing_v_coin,
+        oracle_price,
     )?;
 
     msg!(
         "Mark price for this transaction (FP32): {:?}, with size: {:?} and side {:?}",
-        ((v_pc_closing_amount.abs() as u128) << 32)
-            .checked_div(closing_v_coin_ltd as u128)
+        ((trade.v_pc_amount as u128) << 32)
+            .checked_div(trade.v_coin_amount as u128)
             .unwrap_or(0),
-        closing_v_coin_ltd,
+        trade.v_coin_amount,
         open_position.side,
     );
 
-    let payout_ltd = core::cmp::max(payout, 0) as u64;
+    let payout_ltd = core::cmp::max(trade.payout, 0) as u64;
 
     // Update the open positions account
-    open_position.collateral -= closing_collateral_ltd;
-    open_position.v_coin_amount -= closing_v_coin_ltd;
-    open_position.v_pc_amount -= v_pc_to_settle as u64;
+    open_position.collateral -= trade.collateral;
+    open_position.v_coin_amount -= trade.v_coin_amount;
+    open_position.v_pc_amount -= trade.v_pc_to_settle;
 
     // Pay funding on the closed position
-    // Closing a position doesn't entitle the user to receiving any funding
-    if (current_timestamp as u64) < market_state.last_funding_timestamp + FUNDING_PERIOD {
-        // The position doesn't have to pay funding when it happens before the current cycle's funding crank (unlikely)
-        // We calculate the funding ratio for the current funding cycle until now
-
-        let s = market_state.funding_samples_sum;
-        let denom = (market_state.funding_samples_count as u64) * FUNDING_NORMALIZATION;
-        let funding_ratio = s.signum() * ((s.abs() as u64).checked_div(denom).unwrap_or(0)) as i64;
-
-        let position_v_coin = open_position.side.get_sign() * (open_position.v_coin_amount as i64);
-        let mut funding_ratio = (position_v_coin.signum() * funding_ratio) as i128;
-        if funding_ratio.is_negative() {
-            funding_ratio = 0;
-        }
-        let debt = (((open_position.v_coin_amount as i128) * funding_ratio) >> 32) as i64;
-
-        if debt as u64 > user_account_header.balance {
-            msg!("Not enough available balance to pay for current round of funding.");
-            return Err(PerpError::NoMoreFunds.into());
-        }
-        user_account_header.balance -= debt as u64;
-        market_state.total_user_balances -= debt as u64;
+    let debt = market_state.get_funding_debt(
+        open_position.side,
+        open_position.v_coin_amount,
+        current_timestamp,
+    );
+    if debt > user_account_header.balance {
+        msg!("Not enough available balance to pay for current round of funding.");
+        return Err(PerpError::NoMoreFunds.into());
     }
+    user_account_header.balance -= debt;
+    market_state.total_user_balances -= debt;
 
     if open_position.collateral == 0 {
         remove_position(
@@ -285,31 +236,15 @@ pub fn process_close_position(
             position_index as u32,
         )?;
     } else {
-        msg!("VCoin Amount {:?}", open_position.v_coin_amount);
-        if open_position.v_coin_amount == 0 {
-            msg!("There is some collateral left on this position. Zero-leverage positions are not supported.");
-            return Err(PerpError::AmountTooLow.into());
-        }
-        // TODO: We don't need to compute the liquidation index here. Optimize
-        let new_liquidation_index = compute_liquidation_index(
+        let new_liquidation_index = check_remaining_position(
+            &market_state,
             open_position.collateral,
             open_position.v_coin_amount,
             open_position.v_pc_amount,
             open_position.side,
-            market_state.get_k(),
-        );
-        msg!(
-            "Liquidation index for this position: {:?}",
-            new_liquidation_index
-        );
-        let preliquidation = match open_position.side {
-            PositionType::Long => new_liquidation_index >= oracle_price,
-            PositionType::Short => new_liquidation_index <= oracle_price,
-        };
-        if preliquidation {
-            msg!("Position margin is too low");
-            return Err(PerpError::MarginTooLow.into());
-        }
+            trade.is_removing_collateral,
+            oracle_price,
+        )?;
         let current_slot = clock.slot;
         let insertion_leaf = positions_book.open_position(
             new_liquidation_index,
@@ -318,6 +253,7 @@ pub fn process_close_position(
             open_position.v_pc_amount,
             open_position.side,
             current_slot,
+            accounts.user_account.key,
         )?;
         open_position.slot_number = insertion_leaf.get_slot_number(&positions_book.memory)?;
         open_position.liquidation_index = new_liquidation_index;
@@ -331,25 +267,16 @@ pub fn process_close_position(
         )?;
     }
 
-    let new_leverage = ((open_position.v_pc_amount << 32) as u128)
-        .checked_div(open_position.collateral as u128)
-        .unwrap_or(0) as u64; // In the case in which there is no collateral (closing the position), the leverage is 0
-    if new_leverage > MAX_LEVERAGE {
-        msg!(
-            "New leverage cannot be higher than: {:?}. Found: {:?}",
-            MAX_LEVERAGE >> 32,
-            new_leverage >> 32
-        );
-        return Err(PerpError::MarginTooLow.into());
-    }
+    // In the case in which there is no collateral (closing the position), the leverage is 0
+    let new_leverage = check_leverage(open_position.collateral, open_position.v_pc_amount)?;
 
     // Fees for the partial closing
     let fee_tier = compute_fee_tier(&mut accounts.remaining)?;
-    let mut closing_fees = compute_fees(fee_tier, v_pc_closing_amount.abs() as u64, new_leverage)?;
+    let mut closing_fees = compute_fees(fee_tier, trade.v_pc_amount, new_leverage)?;
 
     msg!(
         "Closing_collateral_ltd : {:?}, new_leverage : {:?}",
-        closing_collateral_ltd,
+        trade.collateral,
         new_leverage,
     );
 
@@ -383,10 +310,10 @@ pub fn process_close_position(
                 PerpError::Overflow
             })?;
     }
-    msg!("Payout : {:?}", payout);
+    msg!("Payout : {:?}", trade.payout);
 
     // Transfer the payout
-    market_state.total_collateral -= closing_collateral_ltd;
+    market_state.total_collateral -= trade.collateral;
     market_state.total_user_balances += payout_ltd;
 
     // Write into the states
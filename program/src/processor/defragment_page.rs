@@ -0,0 +1,94 @@
+use std::slice::Iter;
+
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+use crate::{
+    error::PerpError,
+    positions_book::{
+        memory::{parse_memory, PageSelection},
+        positions_book_tree::PositionsBook,
+    },
+    state::{
+        instance::{check_no_pending_liquidation, parse_instance, write_instance_and_memory},
+        market::get_instance_address,
+    },
+    utils::check_account_owner,
+};
+
+struct Accounts<'a, 'b: 'a> {
+    market: &'a AccountInfo<'b>,
+    instance: &'a AccountInfo<'b>,
+    remaining: Iter<'a, AccountInfo<'b>>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, 'b> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let mut accounts_iter = accounts.iter();
+
+        let market = next_account_info(&mut accounts_iter)?;
+        let instance = next_account_info(&mut accounts_iter)?;
+
+        check_account_owner(market, program_id).unwrap();
+        check_account_owner(instance, program_id).unwrap();
+
+        Ok(Self {
+            market,
+            instance,
+            remaining: accounts_iter,
+        })
+    }
+}
+
+pub fn process_defragment_page(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instance_index: u8,
+    page_index: u32,
+    first_slot: u32,
+    max_moves: u16,
+    page_selection: PageSelection,
+) -> ProgramResult {
+    let mut accounts = Accounts::parse(program_id, accounts)?;
+
+    let instance_address =
+        get_instance_address(&accounts.market.data.borrow(), instance_index as u32)?;
+    if &instance_address != accounts.instance.key {
+        msg!("Invalid instance account or instance index provided");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    check_no_pending_liquidation(&accounts.instance.data.borrow())?;
+    let (mut instance, mut page_infos) = parse_instance(&accounts.instance.data.borrow())?;
+    let memory = parse_memory(
+        &instance,
+        &page_infos,
+        page_selection,
+        &mut accounts.remaining,
+    )?;
+    let mut book = PositionsBook::new(instance.shorts_pointer, instance.longs_pointer, memory);
+
+    let (moves, next_slot) = book.defragment_page(page_index as usize, first_slot, max_moves)?;
+    if moves == 0 {
+        msg!("No node to move.");
+        return Err(PerpError::Nop.into());
+    }
+    msg!("Moved {:?} nodes, next slot: {:?}", moves, next_slot);
+
+    instance.update(&book, &mut page_infos);
+    write_instance_and_memory(
+        &mut accounts.instance.data.borrow_mut(),
+        &page_infos,
+        &instance,
+    )?;
+
+    Ok(())
+}
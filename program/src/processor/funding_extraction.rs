@@ -11,14 +11,21 @@ use solana_program::{
 
 use crate::{
     error::PerpError,
-    positions_book::{memory::parse_memory, positions_book_tree::PositionsBook},
+    positions_book::{
+        memory::{parse_memory, PageSelection},
+        positions_book_tree::PositionsBook,
+    },
     state::{
-        instance::{parse_instance, write_instance_and_memory},
+        instance::{check_no_pending_liquidation, parse_instance, write_instance_and_memory},
+        liquidation_receipts::ReceiptsWriter,
         market::{get_instance_address, MarketState},
         user_account::{get_position, remove_position, write_position},
     },
     state::{user_account::UserAccountState, PositionType},
-    utils::{check_account_key, check_account_owner, compute_payout, get_oracle_price},
+    utils::{
+        check_account_key, check_account_owner, compute_liquidation_index, compute_payout,
+        get_oracle_price,
+    },
 };
 
 use super::FUNDING_EXTRACTION_LABEL;
@@ -67,6 +74,7 @@ pub fn process_funding_extraction(
     program_id: &Pubkey,
     instance_index: u8,
     accounts: &[AccountInfo],
+    page_selection: PageSelection,
 ) -> ProgramResult {
     let mut accounts = Accounts::parse(program_id, accounts)?;
 
@@ -95,9 +103,21 @@ pub fn process_funding_extraction(
         return Err(ProgramError::InvalidArgument);
     }
 
+    check_no_pending_liquidation(&accounts.instance.data.borrow())?;
     let (mut instance, mut page_infos) = parse_instance(&accounts.instance.data.borrow())?;
-    let memory = parse_memory(&instance, &page_infos, &mut accounts.remaining)?;
+    let memory = parse_memory(
+        &instance,
+        &page_infos,
+        page_selection,
+        &mut accounts.remaining,
+    )?;
     let mut book = PositionsBook::new(instance.shorts_pointer, instance.longs_pointer, memory);
+    let mut receipts = ReceiptsWriter::parse(
+        program_id,
+        &mut accounts.remaining,
+        accounts.user_account.key,
+        &user_account_header,
+    )?;
 
     let mut positions_v_coin = 0i64;
     let mut positions_collateral = 0u64;
@@ -125,6 +145,46 @@ pub fn process_funding_extraction(
                     .and_then(|n| n.checked_add(positions_v_coin))
                     .unwrap();
                 positions_collateral = positions_collateral.checked_add(p.collateral).unwrap();
+                // A position indexed with the margin preceding the margin tiers is moved to the
+                // liquidation index of its tier, computed with the current k. It keeps its slot
+                // number, which identifies it along with its user account.
+                let leaf = book.find_leaf(p.liquidation_index, p.side, p.slot_number)?;
+                if leaf.map_or(Ok(false), |l| l.has_legacy_margin(&book.memory))? {
+                    let liquidation_index = compute_liquidation_index(
+                        p.collateral,
+                        p.v_coin_amount,
+                        p.v_pc_amount,
+                        p.side,
+                        market_state.get_k(),
+                    );
+                    if liquidation_index != p.liquidation_index {
+                        book.close_position(
+                            p.liquidation_index,
+                            p.collateral,
+                            p.v_coin_amount,
+                            p.v_pc_amount,
+                            p.side,
+                            p.slot_number,
+                        )?;
+                        let leaf = book.open_position(
+                            liquidation_index,
+                            p.collateral,
+                            p.v_coin_amount,
+                            p.v_pc_amount,
+                            p.side,
+                            p.slot_number,
+                            accounts.user_account.key,
+                        )?;
+                        msg!(
+                            "Position {:?} moved from the liquidation index {:?} to {:?}",
+                            position_index,
+                            p.liquidation_index,
+                            liquidation_index
+                        );
+                        p.liquidation_index = liquidation_index;
+                        p.slot_number = leaf.get_slot_number(&book.memory)?;
+                    }
+                }
             }
             p.last_funding_offset = market_state.funding_history_offset;
             write_position(
@@ -223,6 +283,9 @@ pub fn process_funding_extraction(
                     .total_collateral
                     .checked_sub(p.collateral)
                     .unwrap();
+                if let Some(r) = &mut receipts {
+                    r.record(&p)?;
+                }
                 remove_position(
                     &mut accounts.user_account.data.borrow_mut(),
                     &mut user_account_header,
@@ -13,7 +13,10 @@ use spl_token::instruction::transfer;
 
 use crate::{
     error::PerpError,
-    positions_book::{memory::parse_memory, positions_book_tree::PositionsBook},
+    positions_book::{
+        memory::{parse_memory, PageSelection},
+        positions_book_tree::PositionsBook,
+    },
     state::{
         instance::{parse_instance, write_instance_and_memory},
         market::{get_instance_address, MarketState},
@@ -67,6 +70,7 @@ pub fn process_garbage_collection(
     accounts: &[AccountInfo],
     instance_index: u8,
     max_iterations: u64,
+    page_selection: PageSelection,
 ) -> ProgramResult {
     let mut accounts = Accounts::parse(program_id, accounts)?;
 
@@ -80,7 +84,12 @@ pub fn process_garbage_collection(
     }
 
     let (mut instance, mut page_infos) = parse_instance(&accounts.instance.data.borrow())?;
-    let memory = parse_memory(&instance, &page_infos, &mut accounts.remaining)?;
+    let memory = parse_memory(
+        &instance,
+        &page_infos,
+        page_selection,
+        &mut accounts.remaining,
+    )?;
     let mut book = PositionsBook::new(instance.shorts_pointer, instance.longs_pointer, memory);
 
     let freed_slots = book.memory.crank_garbage_collector(max_iterations)?;
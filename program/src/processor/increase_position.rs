@@ -13,17 +13,20 @@ use solana_program::{
 
 use crate::{
     error::PerpError,
-    positions_book::{memory::parse_memory, positions_book_tree::PositionsBook},
+    positions_book::{
+        memory::{parse_memory, PageSelection},
+        positions_book_tree::PositionsBook,
+    },
     processor::{MAX_LEVERAGE, MAX_POSITION_SIZE},
     state::{
-        instance::{parse_instance, write_instance_and_memory},
+        instance::{check_no_pending_liquidation, parse_instance, write_instance_and_memory},
         market::{get_instance_address, MarketState},
         user_account::{get_position, write_position},
     },
     state::{user_account::UserAccountState, PositionType},
     utils::{
-        check_account_key, check_account_owner, check_signer, compute_fee_tier, compute_fees,
-        compute_liquidation_index, get_oracle_price,
+        check_account_key, check_account_owner, check_initial_margin, check_market_solvency,
+        check_signer, compute_fee_tier, compute_fees, compute_liquidation_index, get_oracle_price,
     },
 };
 
@@ -96,6 +99,7 @@ pub fn process_increase_position(
     add_collateral: u64,
     predicted_entry_price: u64,   // 32 bit FP
     maximum_slippage_margin: u64, // 32 bit FP
+    page_selection: PageSelection,
 ) -> ProgramResult {
     let mut accounts = Accounts::parse(program_id, accounts)?;
 
@@ -119,8 +123,14 @@ pub fn process_increase_position(
         return Err(ProgramError::InvalidArgument);
     }
 
+    check_no_pending_liquidation(&accounts.instance.data.borrow())?;
     let (mut instance, mut page_infos) = parse_instance(&accounts.instance.data.borrow())?;
-    let memory = parse_memory(&instance, &page_infos, &mut accounts.remaining)?;
+    let memory = parse_memory(
+        &instance,
+        &page_infos,
+        page_selection,
+        &mut accounts.remaining,
+    )?;
     let mut book = PositionsBook::new(instance.shorts_pointer, instance.longs_pointer, memory);
 
     let mut open_position = get_position(
@@ -160,6 +170,7 @@ pub fn process_increase_position(
         msg!("Provided oracle account is incorrect.");
         return Err(ProgramError::InvalidArgument);
     }
+    check_market_solvency(&market_state, accounts.market_vault)?;
 
     user_account_header.balance -= add_collateral;
     market_state.total_collateral += add_collateral;
@@ -200,6 +211,7 @@ pub fn process_increase_position(
         );
         return Err(PerpError::AmountTooLarge.into());
     }
+    check_initial_margin(new_collateral, new_v_pc_amount)?;
 
     msg!("Add_v_pc_amount: {:?}", add_v_pc_amount_signed);
     msg!("Add_v_coin_amount: {:?}", add_v_coin_amount);
@@ -233,6 +245,7 @@ pub fn process_increase_position(
         new_v_pc_amount,
         open_position.side,
         current_slot,
+        accounts.user_account.key,
     )?;
 
     let oracle_price = get_oracle_price(
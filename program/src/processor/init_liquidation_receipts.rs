@@ -0,0 +1,87 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+};
+
+use crate::{
+    state::{
+        is_initialized,
+        liquidation_receipts::{LiquidationReceipt, LiquidationReceiptsState},
+        user_account::{UserAccountState, USER_ACCOUNT_RECEIPTS_VERSION},
+    },
+    utils::{check_account_owner, check_signer},
+};
+
+struct Accounts<'a, 'b: 'a> {
+    user_account_owner: &'a AccountInfo<'b>,
+    user_account: &'a AccountInfo<'b>,
+    receipts_account: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, 'b> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let mut accounts_iter = accounts.iter();
+
+        let user_account_owner = next_account_info(&mut accounts_iter)?;
+        let user_account = next_account_info(&mut accounts_iter)?;
+        let receipts_account = next_account_info(&mut accounts_iter)?;
+
+        check_signer(user_account_owner)?;
+        check_account_owner(user_account, program_id).unwrap();
+        check_account_owner(receipts_account, program_id).unwrap();
+
+        Ok(Self {
+            user_account_owner,
+            user_account,
+            receipts_account,
+        })
+    }
+}
+
+pub fn process_init_liquidation_receipts(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let mut user_account_header =
+        UserAccountState::unpack_from_slice(&accounts.user_account.data.borrow())?;
+
+    // Verifications
+    if *accounts.user_account_owner.key != Pubkey::new(&user_account_header.owner) {
+        msg!("The user account owner is invalid");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if user_account_header.has_liquidation_receipts() {
+        msg!("The user account already has a liquidation receipts account");
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+    if is_initialized(accounts.receipts_account) {
+        msg!("The liquidation receipts account is already initialized");
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+    if accounts.receipts_account.data_len()
+        < LiquidationReceiptsState::LEN + LiquidationReceipt::LEN
+    {
+        msg!("The liquidation receipts account is too small");
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+
+    let receipts_header = LiquidationReceiptsState {
+        version: 0,
+        user_account: accounts.user_account.key.to_bytes(),
+        number_of_receipts: 0,
+    };
+    receipts_header.pack_into_slice(&mut accounts.receipts_account.data.borrow_mut());
+    user_account_header.version = USER_ACCOUNT_RECEIPTS_VERSION;
+    user_account_header.pack_into_slice(&mut accounts.user_account.data.borrow_mut());
+
+    Ok(())
+}
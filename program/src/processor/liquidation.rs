@@ -11,10 +11,17 @@ use solana_program::{
 
 use crate::{
     error::PerpError,
-    positions_book::{memory::parse_memory, positions_book_tree::PositionsBook},
+    positions_book::{
+        memory::{parse_memory, PageSelection},
+        positions_book_tree::PositionsBook,
+    },
     processor::LIQUIDATION_LABEL,
     state::{
-        instance::{parse_instance, write_instance_and_memory},
+        instance::{
+            check_no_pending_liquidation, get_pending_liquidation, parse_instance,
+            upgrade_instance, write_instance_and_memory, write_pending_liquidation,
+            PendingLiquidation,
+        },
         market::{get_instance_address, MarketState},
     },
     state::{Fees, PositionType},
@@ -68,10 +75,16 @@ impl<'a, 'b: 'a> Accounts<'a, 'b> {
     }
 }
 
+/// Liquidates the positions reached by the oracle price. When a node budget is given, the
+/// liquidation walks stop once it is exhausted and are stored in the instance to be resumed by the
+/// next calls, the reward being transferred by the call which starts the liquidation. The owners of
+/// the liquidated positions are reported when the positions are removed from their user account.
 pub fn process_liquidation(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
     instance_index: u8,
+    max_nodes: Option<u16>,
+    page_selection: PageSelection,
 ) -> ProgramResult {
     let mut accounts = Accounts::parse(program_id, accounts)?;
 
@@ -86,80 +99,112 @@ pub fn process_liquidation(
         return Err(ProgramError::InvalidArgument);
     }
 
-    let (mut instance, mut page_infos) = parse_instance(&accounts.instance.data.borrow())?;
-    let memory = parse_memory(&instance, &page_infos, &mut accounts.remaining)?;
-    let mut book = PositionsBook::new(instance.shorts_pointer, instance.longs_pointer, memory);
+    if max_nodes.is_some() {
+        upgrade_instance(&mut accounts.instance.data.borrow_mut())?;
+    } else {
+        check_no_pending_liquidation(&accounts.instance.data.borrow())?;
+    }
+    let mut pending_liquidation = get_pending_liquidation(&accounts.instance.data.borrow())?;
 
-    let liquidation_index = get_oracle_price(
-        &accounts.oracle.data.borrow(),
-        market_state.coin_decimals,
-        market_state.quote_decimals,
+    let (mut instance, mut page_infos) = parse_instance(&accounts.instance.data.borrow())?;
+    let memory = parse_memory(
+        &instance,
+        &page_infos,
+        page_selection,
+        &mut accounts.remaining,
     )?;
+    let mut book = PositionsBook::new(instance.shorts_pointer, instance.longs_pointer, memory);
 
-    msg!("Liquidation index: {:?}", liquidation_index);
-
-    // Verifications
-    if market_state.oracle_address != accounts.oracle.key.to_bytes() {
-        msg!("Provided oracle account is incorrect.");
-        return Err(ProgramError::InvalidArgument);
+    if pending_liquidation.is_empty() {
+        let liquidation_index = get_oracle_price(
+            &accounts.oracle.data.borrow(),
+            market_state.coin_decimals,
+            market_state.quote_decimals,
+        )?;
+
+        msg!("Liquidation index: {:?}", liquidation_index);
+
+        // Verifications
+        if market_state.oracle_address != accounts.oracle.key.to_bytes() {
+            msg!("Provided oracle account is incorrect.");
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        pending_liquidation = PendingLiquidation {
+            shorts: book.start_liquidation(liquidation_index, PositionType::Short)?,
+            longs: book.start_liquidation(liquidation_index, PositionType::Long)?,
+        };
+        let (liquidated_shorts_collateral, liquidated_shorts, liquidated_shorts_v_pc) =
+            pending_liquidation
+                .shorts
+                .map(|c| (c.collateral, c.v_coin, c.v_pc))
+                .unwrap_or_default();
+        let (liquidated_longs_collateral, liquidated_longs, liquidated_longs_v_pc) =
+            pending_liquidation
+                .longs
+                .map(|c| (c.collateral, c.v_coin, c.v_pc))
+                .unwrap_or_default();
+        let liquidated_collateral = liquidated_shorts_collateral + liquidated_longs_collateral;
+
+        if liquidated_collateral == 0 {
+            msg!("No orders to liquidate.");
+            return Err(PerpError::Nop.into());
+        }
+
+        market_state.total_collateral -= liquidated_collateral;
+        market_state.sub_open_interest(
+            liquidated_longs,
+            liquidated_longs_v_pc,
+            PositionType::Long,
+        )?;
+        market_state.sub_open_interest(
+            liquidated_shorts,
+            liquidated_shorts_v_pc,
+            PositionType::Short,
+        )?;
+
+        let total_v_coin_difference = (liquidated_longs as i64) - (liquidated_shorts as i64);
+
+        let total_v_pc_difference = market_state.compute_add_v_pc(total_v_coin_difference)?;
+
+        let (balanced_v_pc, balanced_v_coin) = market_state.balance_operation(
+            total_v_pc_difference,
+            total_v_coin_difference,
+            liquidation_index,
+        )?;
+        market_state.add_v_pc(balanced_v_pc)?;
+        market_state.add_v_coin(balanced_v_coin)?;
+
+        // Transfer the Reward using the fees structure
+        let mut liq_payout = Fees {
+            total: liquidated_collateral as i64,
+            refundable: 0,
+            fixed: liquidated_collateral,
+        };
+        market_state.apply_fees(&liq_payout, false, false)?;
+        market_state.transfer_fees(
+            &mut liq_payout,
+            accounts.spl_token_program,
+            accounts.market,
+            accounts.market_vault,
+            accounts.market_signer,
+            accounts.bnb_bonfida,
+            Some(accounts.target),
+        )?;
+    } else {
+        msg!("Resuming the pending liquidation");
     }
 
-    let collateral = book.get_collateral()?;
-    let (longs_v_coin_before, shorts_v_coin_before) = book.get_v_coin()?;
-    let (longs_v_pc_before, shorts_v_pc_before) = book.get_v_pc()?;
-
-    book.liquidate(liquidation_index, PositionType::Short)?;
-    book.liquidate(liquidation_index, PositionType::Long)?;
-
-    let (longs_v_coin_after, shorts_v_coin_after) = book.get_v_coin()?;
-    let (longs_v_pc_after, shorts_v_pc_after) = book.get_v_pc()?;
-    let liquidated_collateral = collateral - book.get_collateral()?;
-    let liquidated_longs = longs_v_coin_before - longs_v_coin_after;
-    let liquidated_shorts = shorts_v_coin_before - shorts_v_coin_after;
-    let liquidated_longs_v_pc = longs_v_pc_before - longs_v_pc_after;
-    let liquidated_shorts_v_pc = shorts_v_pc_before - shorts_v_pc_after;
-
-    if liquidated_collateral == 0 {
-        msg!("No orders to liquidate.");
-        return Err(PerpError::Nop.into());
+    let mut node_budget = max_nodes.unwrap_or(u16::MAX);
+    if let Some(cursor) = pending_liquidation.shorts {
+        pending_liquidation.shorts = book.resume_liquidation(cursor, &mut node_budget, None)?;
+    }
+    if let Some(cursor) = pending_liquidation.longs {
+        pending_liquidation.longs = book.resume_liquidation(cursor, &mut node_budget, None)?;
+    }
+    if !pending_liquidation.is_empty() {
+        msg!("The liquidation is pending and has to be resumed");
     }
-
-    market_state.total_collateral -= liquidated_collateral;
-    market_state.sub_open_interest(liquidated_longs, liquidated_longs_v_pc, PositionType::Long)?;
-    market_state.sub_open_interest(
-        liquidated_shorts,
-        liquidated_shorts_v_pc,
-        PositionType::Short,
-    )?;
-
-    let total_v_coin_difference = (liquidated_longs as i64) - (liquidated_shorts as i64);
-
-    let total_v_pc_difference = market_state.compute_add_v_pc(total_v_coin_difference)?;
-
-    let (balanced_v_pc, balanced_v_coin) = market_state.balance_operation(
-        total_v_pc_difference,
-        total_v_coin_difference,
-        liquidation_index,
-    )?;
-    market_state.add_v_pc(balanced_v_pc)?;
-    market_state.add_v_coin(balanced_v_coin)?;
-
-    // Transfer the Reward using the fees structure
-    let mut liq_payout = Fees {
-        total: liquidated_collateral as i64,
-        refundable: 0,
-        fixed: liquidated_collateral,
-    };
-    market_state.apply_fees(&liq_payout, false, false)?;
-    market_state.transfer_fees(
-        &mut liq_payout,
-        accounts.spl_token_program,
-        accounts.market,
-        accounts.market_vault,
-        accounts.market_signer,
-        accounts.bnb_bonfida,
-        Some(accounts.target),
-    )?;
 
     instance.update(&book, &mut page_infos);
     write_instance_and_memory(
@@ -167,6 +212,12 @@ pub fn process_liquidation(
         &page_infos,
         &instance,
     )?;
+    if max_nodes.is_some() {
+        write_pending_liquidation(
+            &mut accounts.instance.data.borrow_mut(),
+            &pending_liquidation,
+        )?;
+    }
     market_state.pack_into_slice(&mut accounts.market.data.borrow_mut());
     Ok(())
 }
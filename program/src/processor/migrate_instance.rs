@@ -0,0 +1,129 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    rent::Rent,
+    sysvar::Sysvar,
+};
+
+use crate::{
+    state::{
+        instance::{
+            get_page_capacity, get_pending_liquidation, parse_instance, write_instance_and_memory,
+            write_pending_liquidation, INSTANCE_VERSION,
+        },
+        is_initialized,
+        market::{get_instance_address, write_instance_address, MarketState},
+        StateObject,
+    },
+    utils::{check_account_key, check_account_owner, check_signer},
+};
+
+struct Accounts<'a, 'b: 'a> {
+    market: &'a AccountInfo<'b>,
+    admin: &'a AccountInfo<'b>,
+    instance: &'a AccountInfo<'b>,
+    new_instance: &'a AccountInfo<'b>,
+    lamports_target: &'a AccountInfo<'b>,
+    rent_sysvar: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, 'b> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+
+        let market = next_account_info(accounts_iter)?;
+        let admin = next_account_info(accounts_iter)?;
+        let instance = next_account_info(accounts_iter)?;
+        let new_instance = next_account_info(accounts_iter)?;
+        let lamports_target = next_account_info(accounts_iter)?;
+        let rent_sysvar = next_account_info(accounts_iter)?;
+
+        check_signer(admin).unwrap();
+        check_account_owner(market, program_id).unwrap();
+        check_account_owner(instance, program_id).unwrap();
+        check_account_owner(new_instance, program_id).unwrap();
+        check_account_key(rent_sysvar, &solana_program::sysvar::rent::ID).unwrap();
+
+        if is_initialized(new_instance) {
+            msg!("Instance account is already initialized!");
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        Ok(Self {
+            market,
+            admin,
+            instance,
+            new_instance,
+            lamports_target,
+            rent_sysvar,
+        })
+    }
+}
+
+pub fn process_migrate_instance(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instance_index: u8,
+) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let instance_address =
+        get_instance_address(&accounts.market.data.borrow(), instance_index as u32)?;
+    let market_state = MarketState::unpack_from_slice(&accounts.market.data.borrow())?;
+    // Verifications
+    if &instance_address != accounts.instance.key {
+        msg!("Invalid instance account or instance index provided");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if &Pubkey::new(&market_state.admin_address) != accounts.admin.key {
+        msg!("Invalid admin account for the current market");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // The new instance account holds the book for good, it must not be reclaimed for rent
+    let rent = Rent::from_account_info(accounts.rent_sysvar)?;
+    if !rent.is_exempt(
+        accounts.new_instance.lamports(),
+        accounts.new_instance.data_len(),
+    ) {
+        msg!("The new instance account is not rent exempt");
+        return Err(ProgramError::AccountNotRentExempt);
+    }
+
+    let (mut instance, page_infos) = parse_instance(&accounts.instance.data.borrow())?;
+    let pending_liquidation = get_pending_liquidation(&accounts.instance.data.borrow())?;
+    let capacity = get_page_capacity(&accounts.instance.data.borrow())?;
+
+    // The new account is written with the latest layout
+    instance.version = INSTANCE_VERSION;
+    let mut new_instance_data = accounts.new_instance.data.borrow_mut();
+    write_instance_and_memory(&mut new_instance_data, &page_infos, &instance)?;
+    write_pending_liquidation(&mut new_instance_data, &pending_liquidation)?;
+    if get_page_capacity(&new_instance_data)? < capacity {
+        msg!("The new instance account has to reference at least as many pages as the current one");
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+
+    write_instance_address(
+        &mut accounts.market.data.borrow_mut(),
+        instance_index as u32,
+        accounts.new_instance.key,
+    )?;
+
+    // Close the previous instance account
+    accounts.instance.data.borrow_mut()[0] = StateObject::Uninitialized as u8;
+    let mut instance_lamports = accounts.instance.lamports.borrow_mut();
+    let mut target_lamports = accounts.lamports_target.lamports.borrow_mut();
+
+    **target_lamports += **instance_lamports;
+    **instance_lamports = 0;
+
+    Ok(())
+}
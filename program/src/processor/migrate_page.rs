@@ -0,0 +1,145 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    rent::Rent,
+    sysvar::Sysvar,
+};
+
+use crate::{
+    positions_book::{
+        memory::{SLOT_SIZE, TAG_SIZE},
+        page::migrate_legacy_page,
+    },
+    state::{
+        instance::{parse_instance, write_page_info},
+        is_initialized,
+        market::{get_instance_address, MarketState},
+        StateObject,
+    },
+    utils::{check_account_key, check_account_owner, check_signer},
+};
+
+struct Accounts<'a, 'b: 'a> {
+    market: &'a AccountInfo<'b>,
+    admin: &'a AccountInfo<'b>,
+    instance: &'a AccountInfo<'b>,
+    legacy_memory_page: &'a AccountInfo<'b>,
+    new_memory_page: &'a AccountInfo<'b>,
+    lamports_target: &'a AccountInfo<'b>,
+    rent_sysvar: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, 'b> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+
+        let market = next_account_info(accounts_iter)?;
+        let admin = next_account_info(accounts_iter)?;
+        let instance = next_account_info(accounts_iter)?;
+        let legacy_memory_page = next_account_info(accounts_iter)?;
+        let new_memory_page = next_account_info(accounts_iter)?;
+        let lamports_target = next_account_info(accounts_iter)?;
+        let rent_sysvar = next_account_info(accounts_iter)?;
+
+        check_signer(admin).unwrap();
+        check_account_owner(market, program_id).unwrap();
+        check_account_owner(instance, program_id).unwrap();
+        check_account_owner(legacy_memory_page, program_id).unwrap();
+        check_account_owner(new_memory_page, program_id).unwrap();
+        check_account_key(rent_sysvar, &solana_program::sysvar::rent::ID).unwrap();
+
+        if is_initialized(new_memory_page) {
+            msg!("Memory page account is already initialized!");
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        Ok(Self {
+            market,
+            admin,
+            instance,
+            legacy_memory_page,
+            new_memory_page,
+            lamports_target,
+            rent_sysvar,
+        })
+    }
+}
+
+pub fn process_migrate_page(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instance_index: u8,
+    page_index: u32,
+) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let instance_address =
+        get_instance_address(&accounts.market.data.borrow(), instance_index as u32)?;
+    let market_state = MarketState::unpack_from_slice(&accounts.market.data.borrow())?;
+    // Verifications
+    if &instance_address != accounts.instance.key {
+        msg!("Invalid instance account or instance index provided");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if &Pubkey::new(&market_state.admin_address) != accounts.admin.key {
+        msg!("Invalid admin account for the current market");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let (_, page_infos) = parse_instance(&accounts.instance.data.borrow())?;
+    let mut page_info = page_infos
+        .into_iter()
+        .nth(page_index as usize)
+        .ok_or(ProgramError::InvalidArgument)?;
+    if &Pubkey::new(&page_info.address) != accounts.legacy_memory_page.key {
+        msg!("An invalid memory page was provided");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if accounts.legacy_memory_page.data.borrow()[0] != StateObject::MemoryPage as u8 {
+        msg!("The memory page doesn't have to be migrated");
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let rent = Rent::from_account_info(accounts.rent_sysvar)?;
+    if !rent.is_exempt(
+        accounts.new_memory_page.lamports(),
+        accounts.new_memory_page.data_len(),
+    ) {
+        msg!("The new memory page is not rent exempt");
+        return Err(ProgramError::AccountNotRentExempt);
+    }
+    let number_of_slots = page_info.unitialized_memory_index;
+    if accounts.new_memory_page.data_len() < TAG_SIZE + (number_of_slots as usize) * SLOT_SIZE {
+        msg!("The new memory page is too small");
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+
+    migrate_legacy_page(
+        &accounts.legacy_memory_page.data.borrow(),
+        &mut accounts.new_memory_page.data.borrow_mut(),
+        number_of_slots,
+    )?;
+
+    page_info.address = accounts.new_memory_page.key.to_bytes();
+    write_page_info(
+        &mut accounts.instance.data.borrow_mut(),
+        page_index as usize,
+        &page_info,
+    )?;
+
+    // Close the legacy page
+    accounts.legacy_memory_page.data.borrow_mut()[0] = StateObject::Uninitialized as u8;
+    let mut page_lamports = accounts.legacy_memory_page.lamports.borrow_mut();
+    let mut target_lamports = accounts.lamports_target.lamports.borrow_mut();
+
+    **target_lamports += **page_lamports;
+    **page_lamports = 0;
+
+    Ok(())
+}
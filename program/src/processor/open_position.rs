@@ -13,17 +13,19 @@ use solana_program::{
 
 use crate::{
     error::PerpError,
-    positions_book::{memory::parse_memory, positions_book_tree::PositionsBook},
-    processor::{MAX_LEVERAGE, MAX_POSITION_SIZE},
+    positions_book::{
+        memory::{parse_memory, PageSelection},
+        positions_book_tree::PositionsBook,
+    },
     state::PositionType,
     state::{
-        instance::{parse_instance, write_instance_and_memory},
+        instance::{check_no_pending_liquidation, parse_instance, write_instance_and_memory},
         market::{get_instance_address, MarketState},
         user_account::{write_position, OpenPosition, UserAccountState},
     },
     utils::{
-        check_account_key, check_account_owner, check_signer, compute_fee_tier, compute_fees,
-        compute_liquidation_index, get_oracle_price,
+        check_account_key, check_account_owner, check_market_solvency, check_open_order,
+        check_signer, compute_fee_tier, compute_fees, compute_liquidation_index, get_oracle_price,
     },
 };
 
@@ -96,6 +98,7 @@ pub fn process_open_position(
     leverage: u64,                // 32 bit FP
     predicted_entry_price: u64,   // 32 bit FP
     maximum_slippage_margin: u64, // 32 bit FP
+    page_selection: PageSelection,
 ) -> ProgramResult {
     let mut accounts = Accounts::parse(program_id, accounts)?;
 
@@ -120,19 +123,17 @@ pub fn process_open_position(
         return Err(ProgramError::InvalidArgument);
     }
 
+    check_no_pending_liquidation(&accounts.instance.data.borrow())?;
     let (mut instance, mut page_infos) = parse_instance(&accounts.instance.data.borrow())?;
-    let memory = parse_memory(&instance, &page_infos, &mut accounts.remaining)?;
+    let memory = parse_memory(
+        &instance,
+        &page_infos,
+        page_selection,
+        &mut accounts.remaining,
+    )?;
     let mut book = PositionsBook::new(instance.shorts_pointer, instance.longs_pointer, memory);
 
     //Verifications
-    if leverage > MAX_LEVERAGE {
-        msg!(
-            "Leverage cannot be higher than: {:?}. Found: {:?}",
-            MAX_LEVERAGE >> 32,
-            leverage >> 32
-        );
-        return Err(PerpError::MarginTooLow.into());
-    }
     if accounts.user_account_owner.key != &Pubkey::new(&user_account_header.owner) {
         msg!("The user account owner doesn't match");
         return Err(ProgramError::InvalidArgument);
@@ -155,8 +156,10 @@ pub fn process_open_position(
         msg!("Provided oracle account is incorrect.");
         return Err(ProgramError::InvalidArgument);
     }
+    check_market_solvency(&market_state, accounts.market_vault)?;
 
     let v_pc_amount = ((collateral as u128 * (leverage as u128)) >> 32) as u64;
+    check_open_order(&market_state, collateral, v_pc_amount, leverage, side)?;
 
     // Fees
     let fee_tier = compute_fee_tier(&mut accounts.remaining)?;
@@ -188,43 +191,13 @@ pub fn process_open_position(
     market_state.total_collateral += collateral;
     user_account_header.balance -= collateral;
 
-    if v_pc_amount >= market_state.v_pc_amount && side == PositionType::Long {
-        msg!("The given order size is too large!");
-        return Err(PerpError::AmountTooLarge.into());
-    }
-    if v_pc_amount >= MAX_POSITION_SIZE {
-        msg!(
-            "The given order size is too large! The maximum size is: {:?}",
-            MAX_POSITION_SIZE
-        );
-        return Err(PerpError::AmountTooLarge.into());
-    }
-
-    let signed_v_pc_amount = side.get_sign() * (v_pc_amount as i64);
-    let signed_v_coin_amount = market_state.compute_add_v_coin(signed_v_pc_amount)?;
-
     let oracle_price = get_oracle_price(
         &accounts.oracle.data.borrow(),
         market_state.coin_decimals,
         market_state.quote_decimals,
     )?;
 
-    let (balanced_v_pc_amount, balanced_v_coin_amount) =
-        market_state.balance_operation(signed_v_pc_amount, signed_v_coin_amount, oracle_price)?;
-
-    market_state.add_v_pc(balanced_v_pc_amount)?;
-    market_state.add_v_coin(balanced_v_coin_amount)?;
-
-    let v_coin_amount = signed_v_coin_amount.abs() as u64;
-    market_state.add_open_interest(v_coin_amount, v_pc_amount, side)?;
-
-    msg!("Add_v_pc_amount: {:?}", signed_v_pc_amount);
-    msg!("Add_v_coin_amount: {:?}", signed_v_coin_amount);
-
-    if v_coin_amount == 0 {
-        msg!("The given order size is not sufficient!");
-        return Err(PerpError::AmountTooLow.into());
-    }
+    let v_coin_amount = market_state.open_trade(side, v_pc_amount, oracle_price)?;
 
     let current_slot = Clock::from_account_info(accounts.clock_sysvar)?.slot;
 
@@ -254,6 +227,7 @@ pub fn process_open_position(
         v_pc_amount,
         side,
         current_slot,
+        accounts.user_account.key,
     )?;
 
     let position = OpenPosition {
@@ -0,0 +1,202 @@
+use std::{slice::Iter, str::FromStr};
+
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::invoke_signed,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+};
+use spl_token::instruction::transfer;
+
+use crate::{
+    error::PerpError,
+    positions_book::{
+        memory::{parse_memory, PageSelection},
+        positions_book_tree::PositionsBook,
+    },
+    processor::{LIQUIDATION_LABEL, PURGE_BOUNTY},
+    state::{
+        instance::{check_no_pending_liquidation, parse_instance},
+        liquidation_receipts::ReceiptsWriter,
+        market::{get_instance_address, MarketState},
+        user_account::{get_position, remove_position, UserAccountState},
+    },
+    utils::{check_account_key, check_account_owner},
+};
+
+struct Accounts<'a, 'b: 'a> {
+    spl_token_program: &'a AccountInfo<'b>,
+    market: &'a AccountInfo<'b>,
+    instance: &'a AccountInfo<'b>,
+    market_vault: &'a AccountInfo<'b>,
+    market_signer: &'a AccountInfo<'b>,
+    user_account: &'a AccountInfo<'b>,
+    target: &'a AccountInfo<'b>,
+    remaining: Iter<'a, AccountInfo<'b>>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, 'b> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let mut accounts_iter = accounts.iter();
+
+        let spl_token_program = next_account_info(&mut accounts_iter)?;
+        let market = next_account_info(&mut accounts_iter)?;
+        let instance = next_account_info(&mut accounts_iter)?;
+        let market_vault = next_account_info(&mut accounts_iter)?;
+        let market_signer = next_account_info(&mut accounts_iter)?;
+        let user_account = next_account_info(&mut accounts_iter)?;
+        let target = next_account_info(&mut accounts_iter)?;
+        let label = next_account_info(&mut accounts_iter)?;
+
+        check_account_key(spl_token_program, &spl_token::id()).unwrap();
+        check_account_key(label, &Pubkey::from_str(LIQUIDATION_LABEL).unwrap()).unwrap();
+        check_account_owner(market, program_id).unwrap();
+        check_account_owner(instance, program_id).unwrap();
+        check_account_owner(market_vault, &spl_token::id()).unwrap();
+        check_account_owner(user_account, program_id).unwrap();
+
+        Ok(Self {
+            spl_token_program,
+            market,
+            instance,
+            market_vault,
+            market_signer,
+            user_account,
+            target,
+            remaining: accounts_iter,
+        })
+    }
+}
+
+pub fn process_purge_liquidated_positions(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instance_index: u8,
+    page_selection: PageSelection,
+) -> ProgramResult {
+    let mut accounts = Accounts::parse(program_id, accounts)?;
+
+    // Parsing
+    let mut market_state = MarketState::unpack_from_slice(&accounts.market.data.borrow())?;
+    let mut user_account_header =
+        UserAccountState::unpack_from_slice(&accounts.user_account.data.borrow())?;
+
+    // Verifications
+    if &Pubkey::new(&user_account_header.market) != accounts.market.key {
+        msg!("The user account market doesn't match the given market account");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if market_state.vault_address != accounts.market_vault.key.to_bytes() {
+        msg!("Provided market vault account is incorrect.");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let instance_address =
+        get_instance_address(&accounts.market.data.borrow(), instance_index as u32)?;
+    if &instance_address != accounts.instance.key {
+        msg!("Invalid instance account or instance index provided");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    check_no_pending_liquidation(&accounts.instance.data.borrow())?;
+    let (instance, page_infos) = parse_instance(&accounts.instance.data.borrow())?;
+    let memory = parse_memory(
+        &instance,
+        &page_infos,
+        page_selection,
+        &mut accounts.remaining,
+    )?;
+    let mut book = PositionsBook::new(instance.shorts_pointer, instance.longs_pointer, memory);
+    let mut receipts = ReceiptsWriter::parse(
+        program_id,
+        &mut accounts.remaining,
+        accounts.user_account.key,
+        &user_account_header,
+    )?;
+
+    // Removing a position moves the last one to its index, the positions are walked backwards so
+    // that every position is checked once
+    let mut purged_positions = 0;
+    for position_index in (0..user_account_header.number_of_open_positions).rev() {
+        let p = get_position(
+            &mut accounts.user_account.data.borrow_mut(),
+            &user_account_header,
+            position_index as u16,
+        )?;
+        if p.instance_index != instance_index {
+            continue;
+        }
+        // Closing nothing only checks that the position is still in the book
+        match book.close_position(p.liquidation_index, 0, 0, 0, p.side, p.slot_number) {
+            Ok(()) => continue,
+            Err(PerpError::PositionNotFound) => {}
+            Err(e) => return Err(e.into()),
+        }
+        msg!(
+            "Liquidation receipt: position {:?} of user account {:?} on instance {:?} with side {:?}, collateral {:?}, v_coin {:?}, v_pc {:?} and liquidation index {:?}",
+            position_index,
+            accounts.user_account.key,
+            instance_index,
+            p.side,
+            p.collateral,
+            p.v_coin_amount,
+            p.v_pc_amount,
+            p.liquidation_index
+        );
+        if let Some(r) = &mut receipts {
+            r.record(&p)?;
+        }
+        remove_position(
+            &mut accounts.user_account.data.borrow_mut(),
+            &mut user_account_header,
+            position_index,
+        )?;
+        purged_positions += 1;
+    }
+
+    if purged_positions == 0 {
+        msg!("No liquidated positions to purge.");
+        return Err(PerpError::Nop.into());
+    }
+
+    // The bounty is paid out of the fees, it is skipped once they are exhausted
+    let bounty = std::cmp::min(
+        purged_positions * PURGE_BOUNTY,
+        market_state.total_fee_balance,
+    );
+    if bounty > 0 {
+        let instruction = transfer(
+            &spl_token::id(),
+            accounts.market_vault.key,
+            accounts.target.key,
+            accounts.market_signer.key,
+            &[],
+            bounty,
+        )?;
+        invoke_signed(
+            &instruction,
+            &[
+                accounts.spl_token_program.clone(),
+                accounts.market_vault.clone(),
+                accounts.target.clone(),
+                accounts.market_signer.clone(),
+            ],
+            &[&[
+                &accounts.market.key.to_bytes(),
+                &[market_state.signer_nonce],
+            ]],
+        )?;
+        market_state.total_fee_balance -= bounty;
+    }
+
+    user_account_header.pack_into_slice(&mut accounts.user_account.data.borrow_mut());
+    market_state.pack_into_slice(&mut accounts.market.data.borrow_mut());
+
+    Ok(())
+}
@@ -13,11 +13,14 @@ use solana_program::{
 
 use crate::{
     error::PerpError,
-    positions_book::{memory::parse_memory, positions_book_tree::PositionsBook},
+    positions_book::{
+        memory::{parse_memory, PageSelection},
+        positions_book_tree::PositionsBook,
+    },
     processor::MAX_LEVERAGE,
     state::PositionType,
     state::{
-        instance::{parse_instance, write_instance_and_memory},
+        instance::{check_no_pending_liquidation, parse_instance, write_instance_and_memory},
         market::{get_instance_address, MarketState},
         user_account::{write_position, OpenPosition, UserAccountState},
     },
@@ -89,6 +92,7 @@ pub fn process_rebalance(
     accounts: &[AccountInfo<'_>],
     instance_index: u8,
     collateral: u64,
+    page_selection: PageSelection,
 ) -> ProgramResult {
     let mut accounts = Accounts::parse(program_id, accounts)?;
 
@@ -137,8 +141,14 @@ pub fn process_rebalance(
         return Err(ProgramError::InvalidArgument);
     }
 
+    check_no_pending_liquidation(&accounts.instance.data.borrow())?;
     let (mut instance, mut page_infos) = parse_instance(&accounts.instance.data.borrow())?;
-    let memory = parse_memory(&instance, &page_infos, &mut accounts.remaining)?;
+    let memory = parse_memory(
+        &instance,
+        &page_infos,
+        page_selection,
+        &mut accounts.remaining,
+    )?;
     let mut book = PositionsBook::new(instance.shorts_pointer, instance.longs_pointer, memory);
 
     //Verifications
@@ -219,6 +229,7 @@ pub fn process_rebalance(
         v_pc_amount,
         side,
         current_slot,
+        accounts.user_account.key,
     )?;
 
     let position = OpenPosition {
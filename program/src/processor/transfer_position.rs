@@ -91,6 +91,15 @@ pub fn process_transfer_position(
         &position,
         false,
     )?;
+    // The leaf of the position still records the source user account
+    msg!(
+        "Transferred the position with liquidation index {:?} and slot number {:?} on instance {:?} from user account {:?} to {:?}",
+        position.liquidation_index,
+        position.slot_number,
+        position.instance_index,
+        accounts.source_user_account.key,
+        accounts.destination_user_account.key
+    );
     source_user_account_header.pack_into_slice(&mut accounts.source_user_account.data.borrow_mut());
     destination_user_account_header
         .pack_into_slice(&mut accounts.destination_user_account.data.borrow_mut());
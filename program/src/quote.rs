@@ -0,0 +1,427 @@
+//! Host-side trade simulation.
+//!
+//! The quotes are computed by calling the same checks and trade functions as the `OpenPosition`
+//! and `ClosePosition` instructions on a copy of the market state, so that clients don't have to
+//! reimplement them. This includes the market solvency check and the funding of the current cycle.
+
+use crate::{
+    error::PerpError,
+    processor::FUNDING_NORMALIZATION,
+    state::{market::MarketState, user_account::OpenPosition, Fees, PositionType},
+    utils::{
+        check_insurance_fund, check_leverage, check_open_order, check_remaining_position,
+        compute_fees, compute_liquidation_index,
+    },
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OrderSize {
+    /// The leverage applied to the collateral, as fixed point 32
+    Leverage(u64),
+    /// The target position size in quote, with precision
+    QuoteSize(u64),
+    /// The target position size in coin, with precision
+    BaseSize(u64),
+}
+
+#[derive(Debug)]
+pub struct OpenQuote {
+    pub v_coin_amount: u64,
+    pub v_pc_amount: u64,
+    pub leverage: u64,     // FP32
+    pub fill_price: u64,   // FP32
+    pub price_impact: u64, // FP32 relative difference between the fill price and the current mark price
+    pub fees: Fees,
+    pub liquidation_price: u64, // FP32
+    pub funding_ratio: i64, // FP32 estimate of the next funding ratio if the mark price stays at its post-trade value
+    pub funding_payment: i64, // Estimated funding paid (if positive) or received (if negative) by the position per funding period
+}
+
+#[derive(Debug)]
+pub struct CloseQuote {
+    pub v_coin_amount: u64,
+    pub v_pc_amount: u64,
+    pub fill_price: u64,   // FP32
+    pub price_impact: u64, // FP32 relative difference between the fill price and the current mark price
+    pub fees: Fees,
+    pub funding_debt: u64, // Funding of the current cycle charged on the remaining position
+    pub payout: i64,       // Amount credited to the user balance, net of fees and funding
+    pub remaining_collateral: u64,
+    pub liquidation_price: u64, // FP32 liquidation price of the remaining position, 0 if it is closed entirely
+    pub funding_ratio: i64, // FP32 estimate of the next funding ratio if the mark price stays at its post-trade value
+}
+
+// The market vault balance is used to check that the market accepts new positions.
+pub fn quote_open_position(
+    market_state: &MarketState,
+    market_vault_balance: u64,
+    oracle_price: u64, // FP32
+    side: PositionType,
+    collateral: u64,
+    size: OrderSize,
+    fee_tier: usize,
+) -> Result<OpenQuote, PerpError> {
+    let mut market_state = market_state.clone();
+    check_insurance_fund(&market_state, market_vault_balance)?;
+    let (v_pc_amount, leverage) = match size {
+        OrderSize::Leverage(leverage) => (
+            ((collateral as u128 * (leverage as u128)) >> 32) as u64,
+            leverage,
+        ),
+        OrderSize::QuoteSize(v_pc_amount) => (v_pc_amount, get_leverage(collateral, v_pc_amount)?),
+        OrderSize::BaseSize(v_coin_amount) => {
+            let v_pc_amount = market_state
+                .compute_add_v_pc(-side.get_sign() * (v_coin_amount as i64))?
+                .abs() as u64;
+            (v_pc_amount, get_leverage(collateral, v_pc_amount)?)
+        }
+    };
+    check_open_order(&market_state, collateral, v_pc_amount, leverage, side)?;
+
+    let fees = compute_fees(fee_tier, v_pc_amount, leverage).map_err(|_| PerpError::Overflow)?;
+    let mark_price = get_mark_price(&market_state);
+
+    let v_coin_amount = market_state.open_trade(side, v_pc_amount, oracle_price)?;
+
+    let liquidation_price = compute_liquidation_index(
+        collateral,
+        v_coin_amount,
+        v_pc_amount,
+        side,
+        market_state.get_k(),
+    );
+    let fill_price = (((v_pc_amount as u128) << 32) / (v_coin_amount as u128)) as u64;
+    let funding_ratio = estimate_funding_ratio(&market_state, oracle_price);
+    let funding_payment =
+        (((side.get_sign() as i128) * (funding_ratio as i128) * (v_coin_amount as i128)) >> 32)
+            as i64;
+
+    Ok(OpenQuote {
+        v_coin_amount,
+        v_pc_amount,
+        leverage,
+        fill_price,
+        price_impact: compute_price_impact(fill_price, mark_price),
+        fees,
+        liquidation_price,
+        funding_ratio,
+        funding_payment,
+    })
+}
+
+// The current timestamp is used to charge the funding of the current cycle, as the instruction does.
+pub fn quote_close_position(
+    market_state: &MarketState,
+    oracle_price: u64, // FP32
+    current_timestamp: i64,
+    open_position: &OpenPosition,
+    closing_collateral: u64,
+    closing_v_coin: u64,
+    fee_tier: usize,
+) -> Result<CloseQuote, PerpError> {
+    let mut market_state = market_state.clone();
+    let mark_price = get_mark_price(&market_state);
+
+    let trade = market_state.close_trade(
+        open_position,
+        closing_collateral,
+        closing_v_coin,
+        oracle_price,
+    )?;
+    let remaining_collateral = open_position.collateral - trade.collateral;
+    let remaining_v_coin = open_position.v_coin_amount - trade.v_coin_amount;
+    let remaining_v_pc = open_position.v_pc_amount - trade.v_pc_to_settle;
+
+    let funding_debt =
+        market_state.get_funding_debt(open_position.side, remaining_v_coin, current_timestamp);
+
+    let liquidation_price = if remaining_collateral == 0 {
+        0
+    } else {
+        check_remaining_position(
+            &market_state,
+            remaining_collateral,
+            remaining_v_coin,
+            remaining_v_pc,
+            open_position.side,
+            trade.is_removing_collateral,
+            oracle_price,
+        )?
+    };
+    let new_leverage = check_leverage(remaining_collateral, remaining_v_pc)?;
+
+    let fees =
+        compute_fees(fee_tier, trade.v_pc_amount, new_leverage).map_err(|_| PerpError::Overflow)?;
+    let mut net_payout =
+        core::cmp::max(trade.payout, 0) - (fees.fixed as i64) - (funding_debt as i64);
+    if remaining_collateral == 0 {
+        net_payout += fees.refundable as i64;
+    }
+
+    let fill_price = (((trade.v_pc_amount as u128) << 32)
+        .checked_div(trade.v_coin_amount as u128)
+        .unwrap_or(0)) as u64;
+
+    Ok(CloseQuote {
+        v_coin_amount: trade.v_coin_amount,
+        v_pc_amount: trade.v_pc_amount,
+        fill_price,
+        price_impact: compute_price_impact(fill_price, mark_price),
+        fees,
+        funding_debt,
+        payout: net_payout,
+        remaining_collateral,
+        liquidation_price,
+        funding_ratio: estimate_funding_ratio(&market_state, oracle_price),
+    })
+}
+
+fn get_leverage(collateral: u64, v_pc_amount: u64) -> Result<u64, PerpError> {
+    Ok((((v_pc_amount as u128) << 32)
+        .checked_div(collateral as u128)
+        .ok_or(PerpError::AmountTooLow)?) as u64)
+}
+
+pub fn get_mark_price(market_state: &MarketState) -> u64 {
+    (((market_state.v_pc_amount as u128) << 32) / (market_state.v_coin_amount as u128)) as u64
+}
+
+fn compute_price_impact(fill_price: u64, mark_price: u64) -> u64 {
+    let delta = (fill_price as i64 - mark_price as i64).abs() as u128;
+    ((delta << 32) / (mark_price as u128)) as u64
+}
+
+// Mirrors the funding ratio computed by the funding crank when all samples are taken at the current mark price
+fn estimate_funding_ratio(market_state: &MarketState, oracle_price: u64) -> i64 {
+    let mark_price = get_mark_price(market_state);
+    let current_delta = (mark_price as i64) - (oracle_price as i64);
+    let current_value = current_delta.signum()
+        * ((((current_delta.abs() as u128) << 32) / (oracle_price as u128)) as i64);
+    current_value.signum() * ((current_value.abs() as u64) / FUNDING_NORMALIZATION) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::check_initial_margin;
+
+    fn get_market_state(v_coin_amount: u64, v_pc_amount: u64) -> MarketState {
+        MarketState {
+            version: 0,
+            signer_nonce: 0,
+            market_symbol: [0; 32],
+            oracle_address: [0; 32],
+            admin_address: [0; 32],
+            vault_address: [0; 32],
+            quote_decimals: 6,
+            coin_decimals: 6,
+            total_collateral: 0,
+            total_user_balances: 0,
+            total_fee_balance: 0,
+            rebalancing_funds: 0,
+            rebalanced_v_coin: 0,
+            v_coin_amount,
+            v_pc_amount,
+            open_shorts_v_coin: 0,
+            open_longs_v_coin: 0,
+            open_shorts_v_pc: 0,
+            open_longs_v_pc: 0,
+            last_funding_timestamp: 0,
+            last_recording_timestamp: 0,
+            funding_samples_count: 0,
+            funding_samples_sum: 0,
+            funding_history_offset: 0,
+            funding_history: [0; 16],
+            funding_balancing_factors: [0; 16],
+            number_of_instances: 0,
+        }
+    }
+
+    #[test]
+    fn test_quote_open_and_close() {
+        // Mark price of 1000
+        let market_state = get_market_state(1_000_000_000, 1_000_000_000_000);
+        let oracle_price = 1000 << 32;
+        let collateral = 1_000_000_000;
+
+        let open_quote = quote_open_position(
+            &market_state,
+            0,
+            oracle_price,
+            PositionType::Long,
+            collateral,
+            OrderSize::Leverage(5 << 32),
+            0,
+        )
+        .unwrap();
+        assert_eq!(open_quote.v_pc_amount, 5 * collateral);
+        assert_eq!(open_quote.leverage, 5 << 32);
+        assert!(open_quote.fill_price > oracle_price);
+        assert!(open_quote.price_impact > 0);
+        assert!(open_quote.liquidation_price < oracle_price);
+        // The mark price is above the oracle price after buying, longs pay funding
+        assert!(open_quote.funding_ratio > 0);
+        assert!(open_quote.funding_payment > 0);
+
+        let size_quote = quote_open_position(
+            &market_state,
+            0,
+            oracle_price,
+            PositionType::Long,
+            collateral,
+            OrderSize::QuoteSize(5 * collateral),
+            0,
+        )
+        .unwrap();
+        assert_eq!(size_quote.v_coin_amount, open_quote.v_coin_amount);
+
+        let mut traded_market_state = market_state.clone();
+        let v_coin = traded_market_state
+            .compute_add_v_coin(open_quote.v_pc_amount as i64)
+            .unwrap();
+        traded_market_state
+            .add_v_pc(open_quote.v_pc_amount as i64)
+            .unwrap();
+        traded_market_state.add_v_coin(v_coin).unwrap();
+        traded_market_state
+            .add_open_interest(
+                open_quote.v_coin_amount,
+                open_quote.v_pc_amount,
+                PositionType::Long,
+            )
+            .unwrap();
+
+        let position = OpenPosition {
+            last_funding_offset: 0,
+            instance_index: 0,
+            side: PositionType::Long,
+            liquidation_index: open_quote.liquidation_price,
+            collateral,
+            slot_number: 0,
+            v_coin_amount: open_quote.v_coin_amount,
+            v_pc_amount: open_quote.v_pc_amount,
+        };
+        let close_quote = quote_close_position(
+            &traded_market_state,
+            oracle_price,
+            0,
+            &position,
+            collateral,
+            position.v_coin_amount,
+            0,
+        )
+        .unwrap();
+        assert_eq!(close_quote.remaining_collateral, 0);
+        assert_eq!(close_quote.liquidation_price, 0);
+        // Closing right away gives back the collateral minus the closing fees
+        assert!((close_quote.payout - (collateral as i64)).abs() <= close_quote.fees.total);
+
+        // The funding of the current cycle is charged on what is left of the position
+        traded_market_state.funding_samples_count = 1;
+        traded_market_state.funding_samples_sum = 1 << 32;
+        let partial_close_quote = |market_state: &MarketState| {
+            quote_close_position(
+                market_state,
+                oracle_price,
+                10,
+                &position,
+                collateral / 2,
+                position.v_coin_amount / 2,
+                0,
+            )
+            .unwrap()
+        };
+        let quote_with_funding = partial_close_quote(&traded_market_state);
+        assert!(quote_with_funding.funding_debt > 0);
+        assert_eq!(
+            quote_with_funding.funding_debt,
+            traded_market_state.get_funding_debt(
+                PositionType::Long,
+                position.v_coin_amount - quote_with_funding.v_coin_amount,
+                10
+            )
+        );
+        traded_market_state.funding_samples_count = 0;
+        traded_market_state.funding_samples_sum = 0;
+        let quote_without_funding = partial_close_quote(&traded_market_state);
+        assert_eq!(quote_without_funding.funding_debt, 0);
+        assert_eq!(
+            quote_without_funding.payout - quote_with_funding.payout,
+            quote_with_funding.funding_debt as i64
+        );
+    }
+
+    #[test]
+    fn test_quote_reduce_at_a_loss() {
+        // Mark price of 920, below the entry price of the position and the oracle price
+        let mut market_state = get_market_state(4_000_000_000, 3_680_000_000_000);
+        market_state.open_longs_v_coin = 100_000_000;
+        market_state.open_longs_v_pc = 100_000_000_000;
+        let oracle_price = 1050 << 32;
+
+        // Opened at the initial margin of its tier
+        let position = OpenPosition {
+            last_funding_offset: 0,
+            instance_index: 0,
+            side: PositionType::Long,
+            liquidation_index: 0,
+            collateral: 7_500_000_000,
+            slot_number: 0,
+            v_coin_amount: 100_000_000,
+            v_pc_amount: 100_000_000_000,
+        };
+        let trade = market_state
+            .clone()
+            .close_trade(&position, 0, 10_000_000, oracle_price)
+            .unwrap();
+        assert!(trade.payout < 0);
+        assert!(!trade.is_removing_collateral);
+
+        // The loss of the closed part is taken from the collateral, which leaves the rest of the
+        // position under the initial margin. Reducing it only requires the maintenance margin.
+        let quote =
+            quote_close_position(&market_state, oracle_price, 0, &position, 0, 10_000_000, 0)
+                .unwrap();
+        assert_eq!(
+            quote.remaining_collateral,
+            position.collateral - trade.collateral
+        );
+        assert!(check_initial_margin(quote.remaining_collateral, 90_000_000_000).is_err());
+        assert!(quote.liquidation_price < oracle_price);
+    }
+
+    #[test]
+    fn test_quote_solvency() {
+        let mut market_state = get_market_state(1_000_000_000, 1_000_000_000_000);
+        market_state.total_user_balances = 1_000_000;
+        let quote = |market_vault_balance| {
+            quote_open_position(
+                &market_state,
+                market_vault_balance,
+                1000 << 32,
+                PositionType::Long,
+                1_000_000,
+                OrderSize::Leverage(2 << 32),
+                0,
+            )
+        };
+        // The market only accepts position reductions while the insurance fund is depleted
+        assert_eq!(quote(999_999).unwrap_err(), PerpError::MarketInsolvent);
+        assert!(quote(1_000_000).is_ok());
+    }
+
+    #[test]
+    fn test_quote_margin() {
+        let market_state = get_market_state(1_000_000_000, 1_000_000_000_000);
+        let r = quote_open_position(
+            &market_state,
+            0,
+            1000 << 32,
+            PositionType::Short,
+            1_000_000,
+            OrderSize::Leverage(21 << 32),
+            0,
+        );
+        assert_eq!(r.unwrap_err(), PerpError::MarginTooLow);
+    }
+}
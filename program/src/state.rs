@@ -2,6 +2,7 @@ use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::account_info::AccountInfo;
 
 pub mod instance;
+pub mod liquidation_receipts;
 pub mod market;
 pub mod user_account;
 
@@ -10,8 +11,10 @@ pub enum StateObject {
     Uninitialized,
     MarketState,
     UserAccount,
-    MemoryPage,
+    MemoryPage, // Slots of LEGACY_SLOT_SIZE bytes, read in place until the page is migrated
     Instance,
+    LiquidationReceipts,
+    MemoryPageV2,
 }
 pub fn is_initialized(account: &AccountInfo) -> bool {
     account.data.borrow()[0] != (StateObject::Uninitialized as u8)
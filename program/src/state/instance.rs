@@ -1,4 +1,10 @@
-use crate::positions_book::{memory::Pointer, positions_book_tree::PositionsBook};
+use crate::{
+    error::PerpError,
+    positions_book::{
+        memory::{Pointer, MAX_PAGES},
+        positions_book_tree::{LiquidationCursor, PositionsBook},
+    },
+};
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::{
     entrypoint::ProgramResult,
@@ -10,6 +16,10 @@ use solana_program::{
 
 use super::StateObject;
 
+/// Version of the instances created by the program. Instances from version 1 hold the pending
+/// liquidation between their header and their page infos.
+pub const INSTANCE_VERSION: u8 = 1;
+
 #[derive(BorshDeserialize, BorshSerialize, Debug)]
 pub struct Instance {
     pub version: u8,
@@ -24,9 +34,12 @@ impl Instance {
         self.shorts_pointer = book.shorts_root;
         self.longs_pointer = book.longs_root;
         self.garbage_pointer = book.memory.gc_list_hd;
-        for (i, page) in page_infos.iter_mut().enumerate() {
-            page.unitialized_memory_index = book.memory.pages[i].uninitialized_memory;
-            page.free_slot_list_hd = book.memory.pages[i].free_slot_list_hd;
+        // The pages which weren't provided are left untouched
+        for (page_info, page) in page_infos.iter_mut().zip(book.memory.pages.iter()) {
+            if let Some(page) = page {
+                page_info.unitialized_memory_index = page.uninitialized_memory;
+                page_info.free_slot_list_hd = page.free_slot_list_hd;
+            }
         }
     }
 }
@@ -56,6 +69,104 @@ impl Pack for Instance {
     }
 }
 
+/// Liquidation walks left unfinished by a bounded liquidation of the instance
+#[derive(BorshDeserialize, BorshSerialize, Debug, Default, PartialEq)]
+pub struct PendingLiquidation {
+    pub shorts: Option<LiquidationCursor>,
+    pub longs: Option<LiquidationCursor>,
+}
+
+impl PendingLiquidation {
+    pub const LEN: usize = 2 * (1 + LiquidationCursor::LEN);
+
+    pub fn is_empty(&self) -> bool {
+        self.shorts.is_none() && self.longs.is_none()
+    }
+}
+
+/// Offset of the first page info in an instance account of the given version
+pub fn get_page_infos_offset(version: u8) -> usize {
+    match version {
+        0 => Instance::LEN,
+        _ => Instance::LEN + PendingLiquidation::LEN,
+    }
+}
+
+/// Number of pages the instance account can reference
+pub fn get_page_capacity(instance_account_data: &[u8]) -> Result<usize, ProgramError> {
+    let page_infos_offset = get_page_infos_offset(get_version(instance_account_data)?);
+    Ok((instance_account_data
+        .len()
+        .saturating_sub(page_infos_offset)
+        / PageInfo::LEN)
+        .min(MAX_PAGES))
+}
+
+fn get_version(instance_account_data: &[u8]) -> Result<u8, ProgramError> {
+    instance_account_data
+        .get(1)
+        .copied()
+        .ok_or(ProgramError::InvalidAccountData)
+}
+
+pub fn get_pending_liquidation(
+    instance_account_data: &[u8],
+) -> Result<PendingLiquidation, ProgramError> {
+    if get_version(instance_account_data)? == 0 {
+        return Ok(PendingLiquidation::default());
+    }
+    let mut slice = instance_account_data
+        .get(Instance::LEN..Instance::LEN + PendingLiquidation::LEN)
+        .ok_or(ProgramError::InvalidAccountData)?;
+    PendingLiquidation::deserialize(&mut slice).map_err(|_| {
+        msg!("Failed to deserialize the pending liquidation of the instance");
+        ProgramError::InvalidAccountData
+    })
+}
+
+pub fn write_pending_liquidation(
+    instance_account_data: &mut [u8],
+    pending_liquidation: &PendingLiquidation,
+) -> ProgramResult {
+    if get_version(instance_account_data)? == 0 {
+        msg!("The instance has to be upgraded to hold a pending liquidation");
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let mut slice = instance_account_data
+        .get_mut(Instance::LEN..Instance::LEN + PendingLiquidation::LEN)
+        .ok_or(ProgramError::InvalidAccountData)?;
+    pending_liquidation.serialize(&mut slice).unwrap();
+    Ok(())
+}
+
+/// The positions book of an instance is only partially updated while a liquidation is pending,
+/// every other instruction which reads or modifies it has to wait for its completion.
+pub fn check_no_pending_liquidation(instance_account_data: &[u8]) -> ProgramResult {
+    if !get_pending_liquidation(instance_account_data)?.is_empty() {
+        msg!("A liquidation of the instance is pending, it has to be completed first");
+        return Err(PerpError::PendingLiquidation.into());
+    }
+    Ok(())
+}
+
+/// Moves the page infos of a version 0 instance to make room for the pending liquidation
+pub fn upgrade_instance(instance_account_data: &mut [u8]) -> ProgramResult {
+    let mut instance = Instance::unpack_from_slice(instance_account_data)?;
+    if instance.version != 0 {
+        return Ok(());
+    }
+    let page_infos_len = (instance.number_of_pages as usize) * PageInfo::LEN;
+    let new_offset = get_page_infos_offset(INSTANCE_VERSION);
+    if instance_account_data.len() < new_offset + page_infos_len {
+        msg!("The instance account is too small to hold a pending liquidation");
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+    instance_account_data.copy_within(Instance::LEN..Instance::LEN + page_infos_len, new_offset);
+    instance.version = INSTANCE_VERSION;
+    write_instance(instance_account_data, &instance)?;
+    write_pending_liquidation(instance_account_data, &PendingLiquidation::default())
+}
+
 pub fn parse_instance(
     instance_account_data: &[u8],
 ) -> Result<(Instance, Vec<PageInfo>), ProgramError> {
@@ -63,7 +174,7 @@ pub fn parse_instance(
         .get(0..Instance::LEN)
         .ok_or(ProgramError::InvalidAccountData)?;
     let instance = Instance::unpack_from_slice(header_slice)?;
-    let mut offset = Instance::LEN;
+    let mut offset = get_page_infos_offset(instance.version);
     let mut pages = Vec::with_capacity(instance.number_of_pages as usize);
     for _ in 0..instance.number_of_pages {
         let next_offset = offset.checked_add(PageInfo::LEN).unwrap();
@@ -93,9 +204,10 @@ pub fn write_page_info(
     page_index: usize,
     page_info: &PageInfo,
 ) -> Result<(), ProgramError> {
+    let page_infos_offset = get_page_infos_offset(get_version(instance_account_data)?);
     let offset = page_index
         .checked_mul(PageInfo::LEN)
-        .and_then(|s| s.checked_add(Instance::LEN))
+        .and_then(|s| s.checked_add(page_infos_offset))
         .unwrap();
     let offset_end = offset.checked_add(PageInfo::LEN).unwrap();
     let slice = instance_account_data
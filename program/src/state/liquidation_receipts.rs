@@ -0,0 +1,256 @@
+use std::slice::Iter;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{
+    account_info::AccountInfo,
+    clock::Clock,
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    program_pack::{Pack, Sealed},
+    pubkey::Pubkey,
+    sysvar::{self, Sysvar},
+};
+
+use crate::utils::{check_account_key, check_account_owner};
+
+use super::{
+    user_account::{OpenPosition, UserAccountState},
+    PositionType, StateObject,
+};
+
+/// Number of receipts kept by a liquidation receipts account of the default size
+pub const LIQUIDATION_RECEIPTS_CAPACITY: usize = 32;
+
+/// Record of a position which was liquidated, written when it is removed from its user account
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, PartialEq)]
+pub struct LiquidationReceipt {
+    pub instance_index: u8,
+    pub side: PositionType,
+    pub liquidation_index: u64, // 32 bit FP, the liquidation index of the position
+    pub collateral: u64,        // The collateral lost
+    pub v_coin_amount: u64,
+    pub v_pc_amount: u64,
+    pub position_slot: u64, // The slot at which the position was opened
+    pub slot: u64,          // The slot at which the liquidation was recorded
+}
+
+impl Sealed for LiquidationReceipt {}
+
+impl Pack for LiquidationReceipt {
+    const LEN: usize = 50;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let mut p = dst;
+        self.serialize(&mut p).unwrap();
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let mut p = src;
+        LiquidationReceipt::deserialize(&mut p).map_err(|_| {
+            msg!("Failed to deserialize liquidation receipt");
+            ProgramError::InvalidAccountData
+        })
+    }
+}
+
+/// Header of an account holding the last liquidation receipts of a user account in a ring buffer
+#[derive(BorshDeserialize, BorshSerialize, Debug)]
+pub struct LiquidationReceiptsState {
+    pub version: u8,
+    pub user_account: [u8; 32],
+    pub number_of_receipts: u64, // Total number of receipts written, the oldest ones are overwritten
+}
+
+impl Sealed for LiquidationReceiptsState {}
+
+impl Pack for LiquidationReceiptsState {
+    const LEN: usize = 42;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        dst[0] = StateObject::LiquidationReceipts as u8;
+        self.serialize(&mut &mut dst[1..]).unwrap();
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        if src[0] != StateObject::LiquidationReceipts as u8 {
+            if src[0] == 0 {
+                return Err(ProgramError::UninitializedAccount);
+            }
+            return Err(ProgramError::InvalidAccountData);
+        };
+        LiquidationReceiptsState::deserialize(&mut &src[1..]).map_err(|_| {
+            msg!("Failed to deserialize liquidation receipts account");
+            ProgramError::InvalidAccountData
+        })
+    }
+}
+
+impl LiquidationReceiptsState {
+    /// Size of a liquidation receipts account holding the default number of receipts
+    pub const fn account_len() -> usize {
+        Self::LEN + LIQUIDATION_RECEIPTS_CAPACITY * LiquidationReceipt::LEN
+    }
+}
+
+fn get_capacity(receipts_data: &[u8]) -> usize {
+    receipts_data
+        .len()
+        .saturating_sub(LiquidationReceiptsState::LEN)
+        / LiquidationReceipt::LEN
+}
+
+pub fn write_receipt(
+    receipts_data: &mut [u8],
+    receipts_header: &mut LiquidationReceiptsState,
+    receipt: &LiquidationReceipt,
+) -> ProgramResult {
+    let capacity = get_capacity(receipts_data);
+    if capacity == 0 {
+        msg!("The liquidation receipts account is too small");
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+    let offset = ((receipts_header.number_of_receipts % (capacity as u64)) as usize)
+        .checked_mul(LiquidationReceipt::LEN)
+        .and_then(|s| s.checked_add(LiquidationReceiptsState::LEN))
+        .unwrap();
+    receipt.pack_into_slice(&mut receipts_data[offset..offset + LiquidationReceipt::LEN]);
+    receipts_header.number_of_receipts += 1;
+    Ok(())
+}
+
+/// Returns the receipts held by the account, from the oldest to the latest
+pub fn get_receipts(receipts_data: &[u8]) -> Result<Vec<LiquidationReceipt>, ProgramError> {
+    let header = LiquidationReceiptsState::unpack_from_slice(receipts_data)?;
+    let capacity = get_capacity(receipts_data) as u64;
+    let first = header.number_of_receipts.saturating_sub(capacity);
+    (first..header.number_of_receipts)
+        .map(|i| {
+            let offset =
+                LiquidationReceiptsState::LEN + ((i % capacity) as usize) * LiquidationReceipt::LEN;
+            LiquidationReceipt::unpack_from_slice(
+                &receipts_data[offset..offset + LiquidationReceipt::LEN],
+            )
+        })
+        .collect()
+}
+
+/// Liquidation receipts account of a user account, given with the clock sysvar after the page
+/// accounts of the instructions which remove liquidated positions. The accounts are required once
+/// the user account is bound to a receipts account, so that every removed position is recorded.
+pub struct ReceiptsWriter<'a, 'b: 'a> {
+    account: &'a AccountInfo<'b>,
+    header: LiquidationReceiptsState,
+    slot: u64,
+}
+
+impl<'a, 'b: 'a> ReceiptsWriter<'a, 'b> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts_iter: &mut Iter<'a, AccountInfo<'b>>,
+        user_account: &Pubkey,
+        user_account_header: &UserAccountState,
+    ) -> Result<Option<Self>, ProgramError> {
+        if !user_account_header.has_liquidation_receipts() {
+            return Ok(None);
+        }
+        let (clock_sysvar, account) = match (accounts_iter.next(), accounts_iter.next()) {
+            (Some(c), Some(a)) => (c, a),
+            _ => {
+                msg!("The liquidation receipts account of the user account is missing");
+                return Err(ProgramError::NotEnoughAccountKeys);
+            }
+        };
+        check_account_key(clock_sysvar, &sysvar::clock::ID)?;
+        check_account_owner(account, program_id)?;
+        let header = LiquidationReceiptsState::unpack_from_slice(&account.data.borrow())?;
+        if &Pubkey::new(&header.user_account) != user_account {
+            msg!("The liquidation receipts account doesn't match the user account");
+            return Err(ProgramError::InvalidArgument);
+        }
+        Ok(Some(Self {
+            account,
+            header,
+            slot: Clock::from_account_info(clock_sysvar)?.slot,
+        }))
+    }
+
+    pub fn record(&mut self, position: &OpenPosition) -> ProgramResult {
+        let receipt = LiquidationReceipt {
+            instance_index: position.instance_index,
+            side: position.side,
+            liquidation_index: position.liquidation_index,
+            collateral: position.collateral,
+            v_coin_amount: position.v_coin_amount,
+            v_pc_amount: position.v_pc_amount,
+            position_slot: position.slot_number,
+            slot: self.slot,
+        };
+        let mut data = self.account.data.borrow_mut();
+        write_receipt(&mut data, &mut self.header, &receipt)?;
+        self.header.pack_into_slice(&mut data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receipt(liquidation_index: u64) -> LiquidationReceipt {
+        LiquidationReceipt {
+            instance_index: 0,
+            side: PositionType::Long,
+            liquidation_index,
+            collateral: 1_000_000,
+            v_coin_amount: 100,
+            v_pc_amount: 10_000_000,
+            position_slot: 1,
+            slot: liquidation_index,
+        }
+    }
+
+    #[test]
+    fn test_receipts_ring_buffer() {
+        let capacity = 3;
+        let mut data = vec![0; LiquidationReceiptsState::LEN + capacity * LiquidationReceipt::LEN];
+        let mut header = LiquidationReceiptsState {
+            version: 0,
+            user_account: Pubkey::new_unique().to_bytes(),
+            number_of_receipts: 0,
+        };
+        header.pack_into_slice(&mut data);
+        assert_eq!(get_receipts(&data).unwrap(), vec![]);
+
+        for i in 0..2 {
+            write_receipt(&mut data, &mut header, &receipt(i)).unwrap();
+            header.pack_into_slice(&mut data);
+        }
+        assert_eq!(get_receipts(&data).unwrap(), vec![receipt(0), receipt(1)]);
+
+        // Past the capacity, the oldest receipts are overwritten
+        for i in 2..5 {
+            write_receipt(&mut data, &mut header, &receipt(i)).unwrap();
+            header.pack_into_slice(&mut data);
+        }
+        assert_eq!(header.number_of_receipts, 5);
+        assert_eq!(
+            get_receipts(&data).unwrap(),
+            vec![receipt(2), receipt(3), receipt(4)]
+        );
+        let first_receipt =
+            LiquidationReceiptsState::LEN..LiquidationReceiptsState::LEN + LiquidationReceipt::LEN;
+        assert_eq!(
+            LiquidationReceipt::unpack_from_slice(&data[first_receipt]).unwrap(),
+            receipt(3)
+        );
+
+        // An account without room for a receipt is rejected
+        let mut data = vec![0; LiquidationReceiptsState::LEN + LiquidationReceipt::LEN - 1];
+        header.pack_into_slice(&mut data);
+        assert_eq!(
+            write_receipt(&mut data, &mut header, &receipt(5)),
+            Err(ProgramError::AccountDataTooSmall)
+        );
+    }
+}
@@ -2,9 +2,9 @@ use crate::{
     error::{PerpError, PerpResult},
     processor::{
         ALLOCATION_FEE, FEE_BUY_BURN_BONFIDA, FEE_REBALANCING_FUND, FEE_REFERRER,
-        REBALANCING_LEVERAGE, REBALANCING_MARGIN,
+        FUNDING_NORMALIZATION, FUNDING_PERIOD, REBALANCING_LEVERAGE, REBALANCING_MARGIN,
     },
-    state::PositionType,
+    state::{user_account::OpenPosition, PositionType},
     utils::compute_bias,
 };
 use borsh::{BorshDeserialize, BorshSerialize};
@@ -78,6 +78,17 @@ impl Pack for MarketState {
     }
 }
 
+// The part of an open position which is traded back against the vAMM when closing it
+#[derive(Debug, PartialEq)]
+pub struct ClosingTrade {
+    pub collateral: u64,              // Collateral released from the position
+    pub v_coin_amount: u64,           // Traded v_coin amount
+    pub v_pc_amount: u64,             // Traded v_pc amount, at the vAMM price
+    pub v_pc_to_settle: u64, // Share of the position's v_pc which is closed, at its entry price
+    pub payout: i64, // Payout before fees and funding, negative when the loss exceeds the released collateral
+    pub is_removing_collateral: bool, // Whether the margin ratio of the remaining position decreases
+}
+
 impl MarketState {
     pub fn compute_add_v_coin(&self, v_pc_amount: i64) -> Result<i64, PerpError> {
         let final_v_pc = self.v_pc_amount as i64 + v_pc_amount;
@@ -170,7 +181,7 @@ impl MarketState {
         v_pc_to_add: i64,
         v_coin_to_add: i64,
         oracle_price: u64,
-    ) -> Result<(i64, i64), ProgramError> {
+    ) -> Result<(i64, i64), PerpError> {
         let side_sign = -v_coin_to_add.signum();
         let mut balanced_pc_to_add = v_pc_to_add;
         let mut balanced_v_coin_to_add = v_coin_to_add;
@@ -224,6 +235,103 @@ impl MarketState {
         Ok((balanced_pc_to_add, balanced_v_coin_to_add))
     }
 
+    // Trades a new position of the given size against the vAMM and records its open interest.
+    // Returns the v_coin amount of the position.
+    pub fn open_trade(
+        &mut self,
+        side: PositionType,
+        v_pc_amount: u64,
+        oracle_price: u64,
+    ) -> Result<u64, PerpError> {
+        let signed_v_pc_amount = side.get_sign() * (v_pc_amount as i64);
+        let signed_v_coin_amount = self.compute_add_v_coin(signed_v_pc_amount)?;
+
+        let (balanced_v_pc_amount, balanced_v_coin_amount) =
+            self.balance_operation(signed_v_pc_amount, signed_v_coin_amount, oracle_price)?;
+        self.add_v_pc(balanced_v_pc_amount)?;
+        self.add_v_coin(balanced_v_coin_amount)?;
+
+        msg!("Add_v_pc_amount: {:?}", signed_v_pc_amount);
+        msg!("Add_v_coin_amount: {:?}", signed_v_coin_amount);
+
+        let v_coin_amount = signed_v_coin_amount.abs() as u64;
+        if v_coin_amount == 0 {
+            msg!("The given order size is not sufficient!");
+            return Err(PerpError::AmountTooLow);
+        }
+        self.add_open_interest(v_coin_amount, v_pc_amount, side)?;
+        Ok(v_coin_amount)
+    }
+
+    // Trades part of an open position back against the vAMM and removes its open interest.
+    // The amounts are capped by the position.
+    pub fn close_trade(
+        &mut self,
+        open_position: &OpenPosition,
+        closing_collateral: u64,
+        closing_v_coin: u64,
+        oracle_price: u64,
+    ) -> Result<ClosingTrade, PerpError> {
+        let mut closing_collateral_ltd =
+            core::cmp::min(closing_collateral, open_position.collateral);
+        let closing_v_coin_ltd = core::cmp::min(closing_v_coin, open_position.v_coin_amount);
+
+        let signed_closing_v_coin = open_position.side.get_sign() * (closing_v_coin_ltd as i64);
+        let v_pc_closing_amount = self.compute_add_v_pc(signed_closing_v_coin)?;
+
+        msg!(
+            "Transaction info: v_coin_amount {:?}, v_pc_amount {:?}",
+            closing_v_coin_ltd,
+            v_pc_closing_amount.abs()
+        );
+
+        // Keep entry price constant for position
+        let v_pc_to_settle = (((closing_v_coin_ltd as u128) * (open_position.v_pc_amount as u128))
+            / (open_position.v_coin_amount as u128)) as i64;
+
+        // Collateral is being removed when the margin ratio of the remaining position decreases.
+        // This only depends on the collateral which is asked for, the loss of the closed part is
+        // covered by the maintenance margin check of the remaining position.
+        let is_removing_collateral = (closing_collateral_ltd as u128)
+            * (open_position.v_pc_amount as u128)
+            > (v_pc_to_settle as u128) * (open_position.collateral as u128);
+
+        let payout = match open_position.side {
+            PositionType::Long => (((v_pc_closing_amount.abs() as u64) + closing_collateral_ltd)
+                as i64)
+                .checked_sub(v_pc_to_settle),
+            PositionType::Short => (v_pc_to_settle + closing_collateral_ltd as i64)
+                .checked_sub(v_pc_closing_amount.abs() as i64),
+        }
+        .ok_or(PerpError::Overflow)?;
+
+        if payout < 0 {
+            closing_collateral_ltd = core::cmp::min(
+                closing_collateral_ltd + ((-payout) as u64),
+                open_position.collateral,
+            ); // The insurance fund buffers the payout in the second case
+        }
+
+        let (balanced_pc_closing_amount, balanced_closing_v_coin) =
+            self.balance_operation(v_pc_closing_amount, signed_closing_v_coin, oracle_price)?;
+        self.add_v_coin(balanced_closing_v_coin)?;
+        self.add_v_pc(balanced_pc_closing_amount)?;
+        self.sub_open_interest(
+            closing_v_coin_ltd,
+            v_pc_to_settle as u64,
+            open_position.side,
+        )?;
+
+        Ok(ClosingTrade {
+            collateral: closing_collateral_ltd,
+            v_coin_amount: closing_v_coin_ltd,
+            v_pc_amount: v_pc_closing_amount.abs() as u64,
+            v_pc_to_settle: v_pc_to_settle as u64,
+            payout,
+            is_removing_collateral,
+        })
+    }
+
     pub fn apply_fees(
         &mut self,
         fees: &Fees,
@@ -322,6 +430,41 @@ impl MarketState {
             - (self.rebalancing_funds as i64)
     }
 
+    // The side of the net open interest, whose profit is paid by the insurance fund as the vAMM
+    // holds the opposite position. Returns None when the open interest is balanced.
+    pub fn get_deleveraging_side(&self) -> Option<PositionType> {
+        match self.open_longs_v_coin.cmp(&self.open_shorts_v_coin) {
+            core::cmp::Ordering::Greater => Some(PositionType::Long),
+            core::cmp::Ordering::Less => Some(PositionType::Short),
+            core::cmp::Ordering::Equal => None,
+        }
+    }
+
+    // Funding owed for the current, not yet cranked, funding cycle by a position of the given size.
+    // Closing a position doesn't entitle the user to receiving any funding.
+    pub fn get_funding_debt(
+        &self,
+        side: PositionType,
+        v_coin_amount: u64,
+        current_timestamp: i64,
+    ) -> u64 {
+        if (current_timestamp as u64) >= self.last_funding_timestamp + FUNDING_PERIOD {
+            // The position doesn't have to pay funding when it happens before the current cycle's funding crank (unlikely)
+            return 0;
+        }
+        // We calculate the funding ratio for the current funding cycle until now
+        let s = self.funding_samples_sum;
+        let denom = (self.funding_samples_count as u64) * FUNDING_NORMALIZATION;
+        let funding_ratio = s.signum() * ((s.abs() as u64).checked_div(denom).unwrap_or(0)) as i64;
+
+        let position_v_coin = side.get_sign() * (v_coin_amount as i64);
+        let mut funding_ratio = (position_v_coin.signum() * funding_ratio) as i128;
+        if funding_ratio.is_negative() {
+            funding_ratio = 0;
+        }
+        (((v_coin_amount as i128) * funding_ratio) >> 32) as u64
+    }
+
     pub fn slippage_protection(
         &self,
         desired_mark_price: u64,
@@ -401,6 +544,7 @@ pub struct MarketDataPoint {
     pub equilibrium_price: f64,
     pub gc_list_lengths: Vec<u64>,
     pub page_full_ratios: Vec<Vec<f64>>,
+    pub page_fragmentation_ratios: Vec<Vec<f64>>,
     pub longs_depths: Vec<u64>,
     pub shorts_depths: Vec<u64>,
 }
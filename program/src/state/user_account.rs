@@ -11,6 +11,9 @@ use super::StateObject;
 
 // Pubkeys are stored as [u8; 32] for use with borsh
 
+/// Version of the user accounts bound to a liquidation receipts account
+pub const USER_ACCOUNT_RECEIPTS_VERSION: u8 = 1;
+
 #[derive(BorshDeserialize, BorshSerialize, Clone, Debug)]
 pub struct OpenPosition {
     pub last_funding_offset: u8,
@@ -91,6 +94,10 @@ impl UserAccountState {
     pub fn is_initialized(&self) -> bool {
         self.owner != [0u8; 32]
     }
+
+    pub fn has_liquidation_receipts(&self) -> bool {
+        self.version >= USER_ACCOUNT_RECEIPTS_VERSION
+    }
 }
 
 pub fn write_position(
@@ -179,3 +186,27 @@ pub fn get_position(
         .ok_or(ProgramError::InvalidArgument)?;
     OpenPosition::unpack_unchecked(slice)
 }
+
+/// Returns the indices of the positions of a user account held by a leaf of the positions book,
+/// which are the positions with the liquidation index and slot number of the leaf.
+pub fn find_leaf_positions(
+    user_account_data: &mut [u8],
+    user_account_header: &UserAccountState,
+    instance_index: u8,
+    side: PositionType,
+    liquidation_index: u64,
+    slot_number: u64,
+) -> Result<Vec<u16>, ProgramError> {
+    let mut positions = vec![];
+    for i in 0..user_account_header.number_of_open_positions as u16 {
+        let p = get_position(user_account_data, user_account_header, i)?;
+        if p.instance_index == instance_index
+            && p.side == side
+            && p.liquidation_index == liquidation_index
+            && p.slot_number == slot_number
+        {
+            positions.push(i);
+        }
+    }
+    Ok(positions)
+}
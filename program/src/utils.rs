@@ -1,5 +1,5 @@
 use crate::{
-    error::PerpError,
+    error::{PerpError, PerpResult},
     positions_book::{
         memory::{Memory, Pointer, SLOT_SIZE, TAG_SIZE},
         page::{Page, SlotType},
@@ -7,7 +7,8 @@ use crate::{
     },
     processor::{
         ALLOCATION_FEE, FEES_HIGH_LEVERAGE, FEES_LOW_LEVERAGE, FEE_TIERS, FIDA_MINT,
-        HIGH_LEVERAGE_MIN, MARGIN_RATIO,
+        HIGH_LEVERAGE_MIN, INITIAL_MARGIN_BPS, MAINTENANCE_MARGIN_BPS, MARGIN_TIERS, MAX_LEVERAGE,
+        MAX_POSITION_SIZE,
     },
     state::{
         instance::parse_instance,
@@ -50,6 +51,28 @@ pub fn check_signer(account: &AccountInfo) -> ProgramResult {
     Ok(())
 }
 
+// The market enters a reduce-only mode while the insurance fund is negative, until it is auto-deleveraged
+pub fn check_market_solvency(
+    market_state: &MarketState,
+    market_vault: &AccountInfo,
+) -> ProgramResult {
+    if &Pubkey::new(&market_state.vault_address) != market_vault.key {
+        msg!("Provided market vault account is incorrect.");
+        return Err(ProgramError::InvalidArgument);
+    }
+    let market_vault_balance = Account::unpack(&market_vault.data.borrow())?.amount;
+    check_insurance_fund(market_state, market_vault_balance)?;
+    Ok(())
+}
+
+pub fn check_insurance_fund(market_state: &MarketState, market_vault_balance: u64) -> PerpResult {
+    if market_state.get_insurance_fund(market_vault_balance) < 0 {
+        msg!("The insurance fund is depleted, positions cannot be opened or increased.");
+        return Err(PerpError::MarketInsolvent);
+    }
+    Ok(())
+}
+
 ////////////////////////////////////////
 // Numerical computations
 
@@ -132,6 +155,124 @@ pub fn compute_fees(
     Ok(fees)
 }
 
+pub fn get_margin_tier(notional: u64) -> usize {
+    match MARGIN_TIERS.iter().position(|&t| notional < t) {
+        Some(i) => i,
+        None => MARGIN_TIERS.len(),
+    }
+}
+
+// Returns the initial margin ratio for a position of the given notional as fixed point 64
+pub fn compute_initial_margin_ratio(notional: u64) -> u64 {
+    (((INITIAL_MARGIN_BPS[get_margin_tier(notional)] as u128) << 64) / 10_000) as u64
+}
+
+// Returns the maintenance margin ratio for a position of the given notional as fixed point 64
+pub fn compute_maintenance_margin_ratio(notional: u64) -> u64 {
+    (((MAINTENANCE_MARGIN_BPS[get_margin_tier(notional)] as u128) << 64) / 10_000) as u64
+}
+
+pub fn check_initial_margin(collateral: u64, v_pc_amount: u64) -> PerpResult {
+    let required_collateral =
+        ((v_pc_amount as u128) * (compute_initial_margin_ratio(v_pc_amount) as u128)) >> 64;
+    if (collateral as u128) < required_collateral {
+        msg!(
+            "The position does not meet the initial margin requirement. Required collateral: {:?}, found: {:?}",
+            required_collateral,
+            collateral
+        );
+        return Err(PerpError::MarginTooLow);
+    }
+    Ok(())
+}
+
+// Checks the leverage, size and margin of a new position
+pub fn check_open_order(
+    market_state: &MarketState,
+    collateral: u64,
+    v_pc_amount: u64,
+    leverage: u64, // FP 32
+    side: PositionType,
+) -> PerpResult {
+    if leverage > MAX_LEVERAGE {
+        msg!(
+            "Leverage cannot be higher than: {:?}. Found: {:?}",
+            MAX_LEVERAGE >> 32,
+            leverage >> 32
+        );
+        return Err(PerpError::MarginTooLow);
+    }
+    if v_pc_amount >= market_state.v_pc_amount && side == PositionType::Long {
+        msg!("The given order size is too large!");
+        return Err(PerpError::AmountTooLarge);
+    }
+    if v_pc_amount >= MAX_POSITION_SIZE {
+        msg!(
+            "The given order size is too large! The maximum size is: {:?}",
+            MAX_POSITION_SIZE
+        );
+        return Err(PerpError::AmountTooLarge);
+    }
+    check_initial_margin(collateral, v_pc_amount)
+}
+
+// Checks that what is left of a partially closed position can stay in the book, and returns its
+// new liquidation index
+pub fn check_remaining_position(
+    market_state: &MarketState,
+    collateral: u64,
+    v_coin_amount: u64,
+    v_pc_amount: u64,
+    side: PositionType,
+    is_removing_collateral: bool,
+    oracle_price: u64, // FP 32
+) -> Result<u64, PerpError> {
+    msg!("VCoin Amount {:?}", v_coin_amount);
+    if v_coin_amount == 0 {
+        msg!("There is some collateral left on this position. Zero-leverage positions are not supported.");
+        return Err(PerpError::AmountTooLow);
+    }
+    if is_removing_collateral {
+        check_initial_margin(collateral, v_pc_amount)?;
+    }
+    let liquidation_index = compute_liquidation_index(
+        collateral,
+        v_coin_amount,
+        v_pc_amount,
+        side,
+        market_state.get_k(),
+    );
+    msg!(
+        "Liquidation index for this position: {:?}",
+        liquidation_index
+    );
+    let preliquidation = match side {
+        PositionType::Long => liquidation_index >= oracle_price,
+        PositionType::Short => liquidation_index <= oracle_price,
+    };
+    if preliquidation {
+        msg!("Position margin is too low");
+        return Err(PerpError::MarginTooLow);
+    }
+    Ok(liquidation_index)
+}
+
+// Returns the leverage of a position as FP 32, which is 0 once it has no collateral left
+pub fn check_leverage(collateral: u64, v_pc_amount: u64) -> Result<u64, PerpError> {
+    let leverage = ((v_pc_amount as u128) << 32)
+        .checked_div(collateral as u128)
+        .unwrap_or(0) as u64;
+    if leverage > MAX_LEVERAGE {
+        msg!(
+            "New leverage cannot be higher than: {:?}. Found: {:?}",
+            MAX_LEVERAGE >> 32,
+            leverage >> 32
+        );
+        return Err(PerpError::MarginTooLow);
+    }
+    Ok(leverage)
+}
+
 pub fn compute_liquidation_index(
     // Returns the liquidation index as fixed point 32
     collateral: u64,
@@ -140,15 +281,16 @@ pub fn compute_liquidation_index(
     position_type: PositionType,
     k: u128,
 ) -> u64 {
+    let margin_ratio = compute_maintenance_margin_ratio(v_pc_amount);
     let f = match position_type {
         PositionType::Long => {
             if v_pc_amount <= collateral {
                 return 0;
             }
-            (((v_pc_amount - collateral) as u128) << 64) / ((1u128 << 64) - (MARGIN_RATIO as u128))
+            (((v_pc_amount - collateral) as u128) << 64) / ((1u128 << 64) - (margin_ratio as u128))
         }
         PositionType::Short => {
-            (((v_pc_amount + collateral) as u128) << 64) / ((1u128 << 64) + (MARGIN_RATIO as u128))
+            (((v_pc_amount + collateral) as u128) << 64) / ((1u128 << 64) + (margin_ratio as u128))
         }
     };
     // FP32 calculation
@@ -173,14 +315,15 @@ pub fn compute_liquidation_index_old(
     v_pc_amount: u64,
     position_type: PositionType,
 ) -> u64 {
+    let margin_ratio = compute_maintenance_margin_ratio(v_pc_amount);
     let (numerator, denominator) = match position_type {
         PositionType::Short => (
             ((v_pc_amount + collateral) as u128),
-            ((v_coin_amount as u128) * (((MARGIN_RATIO) as u128 + (1 << 64)) as u128)) >> 64, // Optimized
+            ((v_coin_amount as u128) * (((margin_ratio) as u128 + (1 << 64)) as u128)) >> 64, // Optimized
         ),
         PositionType::Long => (
             (v_pc_amount.saturating_sub(collateral) as u128),
-            ((v_coin_amount as u128) * (((1 + !MARGIN_RATIO) as u128) as u128)) >> 64, // Optimized
+            ((v_coin_amount as u128) * (((1 + !margin_ratio) as u128) as u128)) >> 64, // Optimized
         ),
     };
     ((numerator << 32)
@@ -194,16 +337,17 @@ pub fn compute_liquidation_index_inverse(
     v_coin_amount: u64,
     liquidation_index: u64,
     position_type: PositionType,
+    margin_ratio: u64, // 64 fixed point maintenance margin ratio of the position
 ) -> u64 {
     match position_type {
         PositionType::Short => {
             let a =
-                ((v_coin_amount as u128) * (((MARGIN_RATIO) as u128 + (1 << 64)) as u128)) >> 64;
+                ((v_coin_amount as u128) * (((margin_ratio) as u128 + (1 << 64)) as u128)) >> 64;
             ((((liquidation_index as u128) * a) >> 32) - (collateral as u128)) as u64
             // Optimized
         }
         PositionType::Long => {
-            let a = ((v_coin_amount as u128) * (((1 + !MARGIN_RATIO) as u128) as u128)) >> 64;
+            let a = ((v_coin_amount as u128) * (((1 + !margin_ratio) as u128) as u128)) >> 64;
             // Optimized
             ((((liquidation_index as u128) * a) >> 32) + (collateral as u128)) as u64
         }
@@ -236,6 +380,29 @@ pub fn compute_payout(
     }
 }
 
+// Ranks positions for auto-deleveraging by return on collateral, which puts the most profitable,
+// highest leverage positions first. Returns None for positions which are not in profit.
+pub fn compute_deleveraging_score(
+    market_state: &MarketState,
+    collateral: u64,
+    v_coin_amount: u64,
+    v_pc_amount: u64,
+    side: PositionType,
+) -> Result<Option<u128>, PerpError> {
+    if collateral == 0 {
+        return Ok(None);
+    }
+    let v_pc_closing_amount = market_state
+        .compute_add_v_pc(side.get_sign() * (v_coin_amount as i64))?
+        .abs() as u64;
+    let profit =
+        compute_payout(v_pc_closing_amount, v_pc_amount, collateral, &side) - (collateral as i64);
+    if profit <= 0 {
+        return Ok(None);
+    }
+    Ok(Some(((profit as u128) << 32) / (collateral as u128)))
+}
+
 ////////////////////////////////////////
 // Oracle utils
 
@@ -501,42 +668,62 @@ pub fn print_node(pt: Pointer, mem: &Memory, offset: u8) {
     }
 }
 
+/// Ratio of the slots of a page which are allocated or waiting to be garbage collected
+#[cfg(not(target_arch = "bpf"))]
+pub fn get_page_full_ratio(page: &Page) -> Result<f64, PerpError> {
+    Ok(
+        ((page.uninitialized_memory as f64) - (page.get_nb_free_slots()? as f64))
+            / (page.page_size as f64),
+    )
+}
+
+/// Ratio of the initialized slots of a page which are free, i.e. the holes left by freed nodes
+#[cfg(not(target_arch = "bpf"))]
+pub fn get_page_fragmentation_ratio(page: &Page) -> Result<f64, PerpError> {
+    if page.uninitialized_memory == 0 {
+        return Ok(0.);
+    }
+    Ok((page.get_nb_free_slots()? as f64) / (page.uninitialized_memory as f64))
+}
+
 #[cfg(not(target_arch = "bpf"))]
-pub fn get_market_data(
+pub fn get_market_data<E: From<ProgramError>>(
     market_key: Pubkey,
-    get_account_data: &dyn Fn(&Pubkey) -> Vec<u8>,
-) -> Result<MarketDataPoint, ProgramError> {
-    let market_account_data = get_account_data(&market_key);
+    get_account_data: &dyn Fn(&Pubkey) -> Result<Vec<u8>, E>,
+) -> Result<MarketDataPoint, E> {
+    let market_account_data = get_account_data(&market_key)?;
     let market_state = MarketState::unpack_from_slice(&market_account_data)?;
 
-    let market_vault_balance = spl_token::state::Account::unpack(&get_account_data(&Pubkey::new(
-        &market_state.vault_address,
-    )))
-    .unwrap()
+    let market_vault_balance = spl_token::state::Account::unpack(&get_account_data(
+        &Pubkey::new(&market_state.vault_address),
+    )?)?
     .amount;
 
     let mut instances = Vec::with_capacity(market_state.number_of_instances as usize);
     for i in 0..market_state.number_of_instances {
-        let instance_address = get_instance_address(&market_account_data, i).unwrap();
-        let instance_account_data = get_account_data(&instance_address);
-        instances.push(parse_instance(&instance_account_data).unwrap());
+        let instance_address = get_instance_address(&market_account_data, i)?;
+        let instance_account_data = get_account_data(&instance_address)?;
+        instances.push(parse_instance(&instance_account_data)?);
     }
 
     let mut gc_list_lengths = Vec::with_capacity(market_state.number_of_instances as usize);
-    let mut page_full_ratios = vec![vec![]];
+    let mut page_full_ratios = Vec::with_capacity(market_state.number_of_instances as usize);
+    let mut page_fragmentation_ratios =
+        Vec::with_capacity(market_state.number_of_instances as usize);
     for (instance, page_infos) in &instances {
         let mut page_datas = page_infos
             .iter()
             .map(|p| {
-                (
-                    get_account_data(&Pubkey::new(&p.address)),
+                Ok((
+                    get_account_data(&Pubkey::new(&p.address))?,
                     p.unitialized_memory_index,
                     p.free_slot_list_hd,
-                )
+                ))
             })
-            .collect::<Vec<_>>();
+            .collect::<Result<Vec<_>, E>>()?;
         let mut pages = Vec::with_capacity(page_datas.len());
         let mut instance_page_full_ratios = vec![];
+        let mut instance_page_fragmentation_ratios = vec![];
         for (page_data, u_mem_index, free_slot_list_hd) in &mut page_datas {
             let page = Page {
                 page_size: ((page_data.len() - TAG_SIZE) / SLOT_SIZE) as u32,
@@ -544,27 +731,26 @@ pub fn get_market_data(
                 uninitialized_memory: u_mem_index.to_owned(),
                 free_slot_list_hd: free_slot_list_hd.to_owned(),
             };
-            let page_ratio = ((page.uninitialized_memory as f64)
-                - (page.get_nb_free_slots().unwrap() as f64))
-                / (page.page_size as f64);
+            let page_ratio = get_page_full_ratio(&page).unwrap();
 
             instance_page_full_ratios.push(page_ratio);
+            instance_page_fragmentation_ratios.push(get_page_fragmentation_ratio(&page).unwrap());
             pages.push(page);
         }
         page_full_ratios.push(instance_page_full_ratios);
+        page_fragmentation_ratios.push(instance_page_fragmentation_ratios);
         let mem = Memory::new(pages, instance.garbage_pointer);
         gc_list_lengths.push(mem.get_gc_list_len().unwrap());
     }
     let insurance_fund = market_state.get_insurance_fund(market_vault_balance);
 
     // Get the current index price
-    let oracle_account_data = get_account_data(&Pubkey::new(&market_state.oracle_address));
+    let oracle_account_data = get_account_data(&Pubkey::new(&market_state.oracle_address))?;
     let oracle_price = (get_oracle_price(
         &oracle_account_data,
         market_state.coin_decimals,
         market_state.quote_decimals,
-    )
-    .unwrap() as f64)
+    )? as f64)
         / (2u64.pow(32) as f64);
 
     println!("Market vault balance: {}", market_vault_balance);
@@ -597,6 +783,7 @@ pub fn get_market_data(
                 .pow(2) as f64),
         gc_list_lengths,
         page_full_ratios,
+        page_fragmentation_ratios,
         longs_depths: vec![],
         shorts_depths: vec![],
     };
@@ -633,7 +820,70 @@ pub fn get_tree_depth(pt: Option<Pointer>, mem: &Memory) -> usize {
 
 #[cfg(test)]
 mod tests {
-    // use super::*;
+    use super::*;
+
+    #[test]
+    pub fn test_margin_tiers() {
+        assert_eq!(get_margin_tier(0), 0);
+        assert_eq!(get_margin_tier(MARGIN_TIERS[0] - 1), 0);
+        assert_eq!(get_margin_tier(MARGIN_TIERS[0]), 1);
+        assert_eq!(get_margin_tier(u64::MAX), MARGIN_TIERS.len());
+        for notional in [0, MARGIN_TIERS[0], MARGIN_TIERS[1], MARGIN_TIERS[2]].iter() {
+            assert!(
+                compute_maintenance_margin_ratio(*notional)
+                    < compute_initial_margin_ratio(*notional)
+            );
+            assert!(
+                compute_maintenance_margin_ratio(*notional) >= compute_maintenance_margin_ratio(0)
+            );
+        }
+        // A position opened at the maximum leverage satisfies the initial margin
+        let collateral = 1_000_000;
+        assert!(check_initial_margin(collateral, collateral * 20).is_ok());
+        assert!(check_initial_margin(collateral, collateral * 21).is_err());
+    }
+
+    #[test]
+    pub fn test_margin_tier_boundaries() {
+        // Market of 10^10 v_coin at a price of 10_000
+        let k = 10_000_000_000u128 * 100_000_000_000_000u128;
+        let price = 10_000;
+        let mut notionals = vec![];
+        for t in MARGIN_TIERS.iter() {
+            notionals.push(t - 1);
+            notionals.push(*t);
+        }
+        for v_pc_amount in notionals {
+            let tier = get_margin_tier(v_pc_amount);
+            let collateral = v_pc_amount / 10;
+            let v_coin_amount = v_pc_amount / price;
+            for side in [PositionType::Long, PositionType::Short].iter() {
+                let liquidation_index =
+                    compute_liquidation_index(collateral, v_coin_amount, v_pc_amount, *side, k);
+                let inverse = |margin_ratio| {
+                    compute_liquidation_index_inverse(
+                        collateral,
+                        v_coin_amount,
+                        liquidation_index,
+                        *side,
+                        margin_ratio,
+                    )
+                };
+                let error = |v_pc: u64| ((v_pc as i64) - (v_pc_amount as i64)).abs() as u64;
+                // The maintenance margin of the tier of the position gives back its notional, up
+                // to the price impact of the AMM
+                let predicted_v_pc_amount = inverse(compute_maintenance_margin_ratio(v_pc_amount));
+                assert!(error(predicted_v_pc_amount) * 200 < v_pc_amount);
+                // The margin of the neighbouring tiers does not
+                for other_tier in [tier.wrapping_sub(1), tier + 1].iter() {
+                    if let Some(bps) = MAINTENANCE_MARGIN_BPS.get(*other_tier) {
+                        let margin_ratio = (((*bps as u128) << 64) / 10_000) as u64;
+                        assert!(error(inverse(margin_ratio)) * 200 > v_pc_amount);
+                    }
+                }
+            }
+        }
+    }
 
     #[test]
     pub fn test_liq_index_inverse() {
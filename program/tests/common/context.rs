@@ -13,12 +13,14 @@ use audaces_protocol::{
         instance::parse_instance,
         instance::Instance,
         instance::PageInfo,
+        instance::{get_pending_liquidation, PendingLiquidation},
+        liquidation_receipts::{get_receipts, LiquidationReceipt},
         market::get_instance_address,
         market::{MarketDataPoint, MarketState},
         user_account::OpenPosition,
         user_account::UserAccountState,
     },
-    utils::{get_oracle_price, get_tree_depth, print_tree},
+    utils::{get_oracle_price, get_page_fragmentation_ratio, get_tree_depth, print_tree},
 };
 use mock_oracle::instruction::change_price;
 use solana_program::{
@@ -320,6 +322,20 @@ impl Context {
         UserAccountState::unpack_from_slice(&user_account.data)
     }
 
+    pub async fn get_liquidation_receipts(
+        &mut self,
+        receipts_account: Pubkey,
+    ) -> Result<Vec<LiquidationReceipt>, ProgramError> {
+        let receipts_account = self
+            .prg_test_ctx
+            .banks_client
+            .get_account(receipts_account)
+            .await
+            .unwrap()
+            .unwrap();
+        get_receipts(&receipts_account.data)
+    }
+
     pub async fn get_market_state(&mut self) -> Result<MarketState, ProgramError> {
         let market_account = self
             .prg_test_ctx
@@ -369,12 +385,15 @@ impl Context {
 
         let mut gc_list_lengths = Vec::with_capacity(market_state.number_of_instances as usize);
         let mut page_full_ratios = Vec::with_capacity(market_state.number_of_instances as usize);
+        let mut page_fragmentation_ratios =
+            Vec::with_capacity(market_state.number_of_instances as usize);
         let mut longs_depths = Vec::with_capacity(market_state.number_of_instances as usize);
         let mut shorts_depths = Vec::with_capacity(market_state.number_of_instances as usize);
         for (instance, page_infos) in &instances {
             let mut page_datas = self.get_page_datas(&page_infos).await?;
             let mut pages = Vec::with_capacity(page_datas.len());
             let mut instance_page_full_ratios = vec![];
+            let mut instance_page_fragmentation_ratios = vec![];
             for (page_data, u_mem_index, free_slot_list_hd) in &mut page_datas {
                 let page = Page {
                     page_size: ((page_data.data.len() - TAG_SIZE) / SLOT_SIZE) as u32,
@@ -386,9 +405,12 @@ impl Context {
                     - (page.get_nb_free_slots().unwrap() as f64))
                     / (page.page_size as f64);
                 instance_page_full_ratios.push(page_ratio);
+                instance_page_fragmentation_ratios
+                    .push(get_page_fragmentation_ratio(&page).unwrap());
                 pages.push(page);
             }
             page_full_ratios.push(instance_page_full_ratios);
+            page_fragmentation_ratios.push(instance_page_fragmentation_ratios);
             let mem = Memory::new(pages, instance.garbage_pointer);
             let (longs_depth, shorts_depth) = self.get_tree_depth(instance, &mem).await;
             longs_depths.push(longs_depth as u64);
@@ -425,6 +447,7 @@ impl Context {
                     .pow(2) as f64),
             gc_list_lengths,
             page_full_ratios,
+            page_fragmentation_ratios,
             longs_depths,
             shorts_depths,
         };
@@ -444,6 +467,14 @@ impl Context {
             .amount)
     }
 
+    pub async fn get_insurance_fund(&mut self) -> i64 {
+        let market_vault_balance = self.get_market_vault_balance().await.unwrap();
+        self.get_market_state()
+            .await
+            .unwrap()
+            .get_insurance_fund(market_vault_balance)
+    }
+
     pub async fn get_instance_address(
         &mut self,
         instance_index: u32,
@@ -477,24 +508,22 @@ impl Context {
             .await
             .unwrap()
             .unwrap();
-        let header_slice = instance_account
-            .data
-            .get(0..Instance::LEN)
-            .ok_or(ProgramError::InvalidAccountData)?;
-        let instance = Instance::unpack_from_slice(header_slice)?;
-        let mut offset = Instance::LEN;
-        let mut pages = Vec::with_capacity(instance.number_of_pages as usize);
-        for _ in 0..instance.number_of_pages {
-            let next_offset = offset.checked_add(PageInfo::LEN).unwrap();
-            let slice = instance_account
-                .data
-                .get(offset..next_offset)
-                .ok_or(ProgramError::InvalidAccountData)?;
-            let page = PageInfo::unpack_from_slice(slice)?;
-            pages.push(page);
-            offset = next_offset;
-        }
-        Ok((instance, pages))
+        parse_instance(&instance_account.data)
+    }
+
+    pub async fn get_pending_liquidation(
+        &mut self,
+        instance_index: u32,
+    ) -> Result<PendingLiquidation, ProgramError> {
+        let instance_address = self.get_instance_address(instance_index).await?;
+        let instance_account = self
+            .prg_test_ctx
+            .banks_client
+            .get_account(instance_address)
+            .await
+            .unwrap()
+            .unwrap();
+        get_pending_liquidation(&instance_account.data)
     }
 
     pub async fn update_blockhash(&mut self) -> ProgramResult {
@@ -2,14 +2,16 @@ use super::utils::sign_send_instructions;
 use crate::common::context::Context;
 use audaces_protocol::{
     instruction::{
-        add_budget, add_instance, add_page, close_account, close_position, collect_garbage,
-        crank_funding, crank_liquidation, create_market, extract_funding, increase_position,
-        open_position, rebalance, transfer_position, transfer_user_account, withdraw_budget,
+        add_budget, add_instance, add_page, auto_deleverage, close_account, close_position,
+        collect_garbage, crank_bounded_liquidation, crank_funding, crank_liquidation,
+        create_market, defragment_page, extract_funding, increase_position,
+        init_liquidation_receipts, migrate_instance, open_position, purge_liquidated_positions,
+        rebalance, transfer_position, transfer_user_account, withdraw_budget,
     },
     instruction::{InstanceContext, PositionInfo},
-    state::PositionType,
+    state::{liquidation_receipts::LiquidationReceiptsState, PositionType},
 };
-use solana_program::{pubkey::Pubkey, system_instruction::create_account};
+use solana_program::{pubkey::Pubkey, rent::Rent, system_instruction::create_account};
 use solana_sdk::{signature::Keypair, signer::Signer, transport::TransportError};
 
 impl Context {
@@ -224,6 +226,20 @@ impl Context {
         sign_send_instructions(&mut self.prg_test_ctx, vec![liquidate_instruction], vec![]).await
     }
 
+    pub async fn liquidate_with_budget(
+        &mut self,
+        instance_index: u8,
+        max_nodes: u16,
+    ) -> Result<(), TransportError> {
+        let liquidate_instruction = crank_bounded_liquidation(
+            &self.market_ctx,
+            instance_index,
+            max_nodes,
+            self.user_ctx.usdc_account,
+        );
+        sign_send_instructions(&mut self.prg_test_ctx, vec![liquidate_instruction], vec![]).await
+    }
+
     pub async fn collect_garbage(
         &mut self,
         instance_index: u8,
@@ -243,6 +259,28 @@ impl Context {
         .await
     }
 
+    pub async fn defragment_page(
+        &mut self,
+        instance_index: u8,
+        page_index: u32,
+        first_slot: u32,
+        max_moves: u16,
+    ) -> Result<(), TransportError> {
+        let defragment_page_instruction = defragment_page(
+            &self.market_ctx,
+            instance_index,
+            page_index,
+            first_slot,
+            max_moves,
+        );
+        sign_send_instructions(
+            &mut self.prg_test_ctx,
+            vec![defragment_page_instruction],
+            vec![],
+        )
+        .await
+    }
+
     pub async fn crank_funding(&mut self) -> Result<(), TransportError> {
         let crank_funding_instruction = crank_funding(&self.market_ctx);
         sign_send_instructions(
@@ -262,6 +300,7 @@ impl Context {
             &self.market_ctx,
             instance_index,
             self.user_ctx.user_accounts[user_account_index],
+            None,
         );
         sign_send_instructions(
             &mut self.prg_test_ctx,
@@ -271,6 +310,71 @@ impl Context {
         .await
     }
 
+    pub async fn auto_deleverage(
+        &mut self,
+        instance_index: u8,
+        position_index: u16,
+        user_account_index: usize,
+    ) -> Result<(), TransportError> {
+        let auto_deleverage_instruction = auto_deleverage(
+            &self.market_ctx,
+            instance_index,
+            self.user_ctx.user_accounts[user_account_index],
+            position_index,
+        );
+        sign_send_instructions(
+            &mut self.prg_test_ctx,
+            vec![auto_deleverage_instruction],
+            vec![&self.test_ctx.market_admin_keypair],
+        )
+        .await
+    }
+
+    pub async fn purge_liquidated_positions(
+        &mut self,
+        instance_index: u8,
+        user_account_index: usize,
+        receipts_account: Option<Pubkey>,
+    ) -> Result<(), TransportError> {
+        let purge_instruction = purge_liquidated_positions(
+            &self.market_ctx,
+            instance_index,
+            self.user_ctx.user_accounts[user_account_index],
+            self.user_ctx.usdc_account,
+            receipts_account,
+        );
+        sign_send_instructions(&mut self.prg_test_ctx, vec![purge_instruction], vec![]).await
+    }
+
+    pub async fn init_liquidation_receipts(
+        &mut self,
+        user_account_index: usize,
+    ) -> Result<Pubkey, TransportError> {
+        let receipts_keypair = Keypair::new();
+        let instructions = vec![
+            create_account(
+                &self.prg_test_ctx.payer.pubkey(),
+                &receipts_keypair.pubkey(),
+                1_000_000,
+                LiquidationReceiptsState::account_len() as u64,
+                &self.market_ctx.audaces_protocol_program_id,
+            ),
+            init_liquidation_receipts(
+                &self.market_ctx,
+                self.user_ctx.user_accounts[user_account_index],
+                self.user_ctx.owner_account.pubkey(),
+                receipts_keypair.pubkey(),
+            ),
+        ];
+        sign_send_instructions(
+            &mut self.prg_test_ctx,
+            instructions,
+            vec![&receipts_keypair, &self.user_ctx.owner_account],
+        )
+        .await?;
+        Ok(receipts_keypair.pubkey())
+    }
+
     pub async fn close_account(
         &mut self,
         lamports_target: Pubkey,
@@ -313,6 +417,48 @@ impl Context {
         sign_send_instructions(&mut self.prg_test_ctx, instructions, signers).await
     }
 
+    pub async fn migrate_instance(
+        &mut self,
+        instance_index: u8,
+        space: u64,
+    ) -> Result<(), TransportError> {
+        let lamports = Rent::default().minimum_balance(space as usize);
+        self.migrate_instance_with_lamports(instance_index, space, lamports)
+            .await
+    }
+
+    pub async fn migrate_instance_with_lamports(
+        &mut self,
+        instance_index: u8,
+        space: u64,
+        lamports: u64,
+    ) -> Result<(), TransportError> {
+        let instance_keypair = Keypair::new();
+
+        let instructions = vec![
+            create_account(
+                &self.prg_test_ctx.payer.pubkey(),
+                &instance_keypair.pubkey(),
+                lamports,
+                space,
+                &self.market_ctx.audaces_protocol_program_id,
+            ),
+            migrate_instance(
+                &self.market_ctx,
+                instance_index,
+                instance_keypair.pubkey(),
+                self.prg_test_ctx.payer.pubkey(),
+            ),
+        ];
+        let signers = vec![&instance_keypair, &self.test_ctx.market_admin_keypair];
+
+        sign_send_instructions(&mut self.prg_test_ctx, instructions, signers).await?;
+
+        self.market_ctx.instances[instance_index as usize].instance_account =
+            instance_keypair.pubkey();
+        Ok(())
+    }
+
     pub async fn rebalance(
         &mut self,
         instance_index: u8,
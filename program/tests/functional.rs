@@ -1,8 +1,21 @@
-use audaces_protocol::state::PositionType;
-use solana_program::pubkey::Pubkey;
-use solana_sdk::signer::keypair::Keypair;
+use audaces_protocol::{
+    error::PerpError,
+    instruction::{add_budget, close_position, select_pages, PositionInfo},
+    positions_book::memory::{MAX_PAGES, SLOT_SIZE, TAG_SIZE},
+    processor::{ALLOCATION_FEE, PURGE_BOUNTY},
+    state::{
+        instance::{get_page_infos_offset, PageInfo, INSTANCE_VERSION},
+        PositionType,
+    },
+    utils::compute_payout,
+};
+use solana_program::{instruction::InstructionError, pubkey::Pubkey, rent::Rent};
+use solana_sdk::signer::{keypair::Keypair, Signer};
 pub mod common;
-use crate::common::{context::Context, utils::catch_noop};
+use crate::common::{
+    context::Context,
+    utils::{catch_noop, sign_send_instructions},
+};
 
 #[tokio::test]
 async fn test_audaces_protocol() {
@@ -204,3 +217,463 @@ async fn test_overflow_0() {
     let state = context.get_market_state().await.unwrap();
     println!("market_state : {:#?}", state);
 }
+
+#[tokio::test]
+async fn test_purge_liquidated_positions() {
+    let mut context = Context::init(0, 6, 6).await;
+    context.change_oracle_price(10_000 << 32u64).await.unwrap();
+    context
+        .create_market("BTC/USD".to_string(), 1e10f64 as u64, 6, 6)
+        .await
+        .unwrap();
+    context.add_instance(1, 1_000_000).await.unwrap();
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+
+    // Only the owner can close a position
+    let position = context.get_position(0, 0).await.unwrap();
+    let impostor = Keypair::new();
+    let instruction = close_position(
+        &context.market_ctx,
+        &PositionInfo {
+            user_account: context.user_ctx.user_accounts[0],
+            user_account_owner: impostor.pubkey(),
+            instance_index: position.instance_index,
+            side: position.side,
+        },
+        u64::MAX,
+        u64::MAX,
+        0,
+        0,
+        u64::MAX,
+        None,
+        None,
+    );
+    assert!(sign_send_instructions(
+        &mut context.prg_test_ctx,
+        vec![instruction],
+        vec![&impostor]
+    )
+    .await
+    .is_err());
+
+    // Nothing to purge while the position is open
+    catch_noop(
+        context
+            .purge_liquidated_positions(0, 0, None)
+            .await
+            .unwrap_err(),
+    )
+    .unwrap();
+
+    let receipts_account = context.init_liquidation_receipts(0).await.unwrap();
+    assert!(context
+        .get_liquidation_receipts(receipts_account)
+        .await
+        .unwrap()
+        .is_empty());
+
+    context.change_oracle_price(1 << 32u64).await.unwrap();
+    context.liquidate(0).await.unwrap();
+    // The receipts account is required once the user account is bound to it
+    let err = context
+        .purge_liquidated_positions(0, 0, None)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        catch_noop(err).unwrap_err(),
+        InstructionError::NotEnoughAccountKeys
+    );
+    // A user account is bound to a single receipts account
+    assert!(context.init_liquidation_receipts(0).await.is_err());
+
+    let vault_balance = context.get_market_vault_balance().await.unwrap();
+    context
+        .purge_liquidated_positions(0, 0, Some(receipts_account))
+        .await
+        .unwrap();
+    assert_eq!(
+        context
+            .get_user_account(0)
+            .await
+            .unwrap()
+            .number_of_open_positions,
+        0
+    );
+    assert_eq!(
+        vault_balance - context.get_market_vault_balance().await.unwrap(),
+        PURGE_BOUNTY
+    );
+
+    // The purged position is recorded in the receipts account
+    let receipts = context
+        .get_liquidation_receipts(receipts_account)
+        .await
+        .unwrap();
+    assert_eq!(receipts.len(), 1);
+    assert_eq!(receipts[0].instance_index, position.instance_index);
+    assert_eq!(receipts[0].side, position.side);
+    assert_eq!(receipts[0].collateral, position.collateral);
+    assert_eq!(receipts[0].liquidation_index, position.liquidation_index);
+    assert_eq!(receipts[0].position_slot, position.slot_number);
+}
+
+#[tokio::test]
+async fn test_bounded_liquidation() {
+    let mut context = Context::init(0, 6, 6).await;
+    context.change_oracle_price(10_000 << 32u64).await.unwrap();
+    context
+        .create_market("BTC/USD".to_string(), 1e10f64 as u64, 6, 6)
+        .await
+        .unwrap();
+    context.add_instance(1, 1_000_000).await.unwrap();
+    context.add_budget(10_000_000, 0).await.unwrap();
+    for leverage in [2u64, 10, 15].iter() {
+        context
+            .open_position(PositionType::Long, 1_000_000, leverage << 32u64, 0, 0)
+            .await
+            .unwrap();
+    }
+    let safe_position = context.get_position(0, 0).await.unwrap();
+
+    // Only the most leveraged positions are liquidated, which takes more than one node
+    context.change_oracle_price(7_000 << 32u64).await.unwrap();
+    context.liquidate_with_budget(0, 1).await.unwrap();
+    assert!(!context.get_pending_liquidation(0).await.unwrap().is_empty());
+
+    // The instance is locked until the liquidation completes
+    assert!(context
+        .open_position(PositionType::Long, 1_000_000, 2 << 32u64, 0, 0)
+        .await
+        .is_err());
+    assert!(context.liquidate(0).await.is_err());
+
+    // Each call has a different budget so that the transactions differ
+    let mut max_nodes = 2;
+    while !context.get_pending_liquidation(0).await.unwrap().is_empty() {
+        context.liquidate_with_budget(0, max_nodes).await.unwrap();
+        max_nodes += 1;
+    }
+    assert_eq!(
+        context.get_market_state().await.unwrap().total_collateral,
+        safe_position.collateral
+    );
+    context
+        .open_position(PositionType::Long, 1_000_000, 2 << 32u64, 0, 0)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_many_pages() {
+    let mut context = Context::init(0, 6, 6).await;
+    context.change_oracle_price(10_000 << 32u64).await.unwrap();
+    context
+        .create_market("BTC/USD".to_string(), 1e10f64 as u64, 6, 6)
+        .await
+        .unwrap();
+    // Pages of a single slot, so that the positions are spread over more than 16 pages
+    let page_space = (TAG_SIZE + SLOT_SIZE) as u64;
+    context.add_instance(1, page_space).await.unwrap();
+    context.add_budget(100_000_000, 0).await.unwrap();
+
+    let previous_instance = context.get_instance_address(0).await.unwrap();
+    let instance_space = get_page_infos_offset(INSTANCE_VERSION) + MAX_PAGES * PageInfo::LEN;
+    // The new instance account has to be rent exempt
+    let lamports = Rent::default().minimum_balance(instance_space) - 1;
+    let err = context
+        .migrate_instance_with_lamports(0, instance_space as u64, lamports)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        catch_noop(err).unwrap_err(),
+        InstructionError::AccountNotRentExempt
+    );
+    context
+        .migrate_instance(0, instance_space as u64)
+        .await
+        .unwrap();
+    let instance_address = context.get_instance_address(0).await.unwrap();
+    assert_ne!(instance_address, previous_instance);
+    let (_, page_infos) = context.parse_instance(instance_address).await.unwrap();
+    assert_eq!(page_infos.len(), 1);
+
+    for _ in 1..17 {
+        context.add_page(0, page_space).await.unwrap();
+    }
+    for leverage in 2..11u64 {
+        context
+            .open_position(PositionType::Long, 1_000_000, leverage << 32u64, 0, 0)
+            .await
+            .unwrap();
+    }
+    let (_, page_infos) = context.parse_instance(instance_address).await.unwrap();
+    assert_eq!(page_infos.len(), 17);
+    assert_eq!(page_infos[16].unitialized_memory_index, 1);
+
+    // The page accounts are checked against the page selection of the instruction
+    let position = context.get_position(0, 0).await.unwrap();
+    let instruction = close_position(
+        &context.market_ctx,
+        &PositionInfo {
+            user_account: context.user_ctx.user_accounts[0],
+            user_account_owner: context.user_ctx.owner_account.pubkey(),
+            instance_index: position.instance_index,
+            side: position.side,
+        },
+        position.collateral,
+        position.v_coin_amount,
+        0,
+        0,
+        u64::MAX,
+        None,
+        None,
+    );
+    let instance_ctx = &context.market_ctx.instances[0];
+    let all_pages = (0..17).collect::<Vec<_>>();
+    let mut swapped_pages = select_pages(instruction.clone(), instance_ctx, &all_pages);
+    let len = swapped_pages.accounts.len();
+    swapped_pages.accounts.swap(len - 2, len - 1);
+    let mut out_of_range = select_pages(instruction.clone(), instance_ctx, &all_pages);
+    *out_of_range.data.last_mut().unwrap() |= 0b10;
+    // The first position is in page 0, which doesn't hold the root
+    let partial = select_pages(instruction, instance_ctx, &[0]);
+    // Only the instructions using the positions book can be followed by a page selection
+    let mut trailing_bytes = add_budget(
+        &context.market_ctx,
+        1,
+        context.user_ctx.owner_account.pubkey(),
+        context.user_ctx.usdc_account,
+        context.user_ctx.user_accounts[0],
+    );
+    trailing_bytes.data.push(1);
+    for (instruction, error) in vec![
+        (swapped_pages, InstructionError::InvalidArgument),
+        (out_of_range, InstructionError::InvalidInstructionData),
+        (
+            partial,
+            InstructionError::Custom(PerpError::MissingPage as u32),
+        ),
+        (trailing_bytes, InstructionError::InvalidInstructionData),
+    ] {
+        let err = sign_send_instructions(
+            &mut context.prg_test_ctx,
+            vec![instruction],
+            vec![&context.user_ctx.owner_account],
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(catch_noop(err).unwrap_err(), error);
+    }
+
+    for _ in 2..11 {
+        let position = context.get_position(0, 0).await.unwrap();
+        context
+            .close_position(position.collateral, position.v_coin_amount, 0, 0)
+            .await
+            .unwrap();
+    }
+    let (instance, _) = context.parse_instance(instance_address).await.unwrap();
+    assert_eq!(instance.longs_pointer, None);
+}
+
+#[tokio::test]
+async fn test_defragment_page() {
+    let mut context = Context::init(0, 6, 6).await;
+    context.change_oracle_price(10_000 << 32u64).await.unwrap();
+    context
+        .create_market("BTC/USD".to_string(), 1e10f64 as u64, 6, 6)
+        .await
+        .unwrap();
+    let page_space = (TAG_SIZE + 16 * SLOT_SIZE) as u64;
+    context.add_instance(1, page_space).await.unwrap();
+    context.add_page(0, page_space).await.unwrap();
+    context.add_page(0, page_space).await.unwrap();
+    context.add_budget(100_000_000, 0).await.unwrap();
+
+    for leverage in 2..8u64 {
+        context
+            .open_position(PositionType::Long, 1_000_000, leverage << 32u64, 0, 0)
+            .await
+            .unwrap();
+    }
+    for _ in 0..3 {
+        let position = context.get_position(0, 0).await.unwrap();
+        context
+            .close_position(position.collateral, position.v_coin_amount, 0, 0)
+            .await
+            .unwrap();
+    }
+
+    // The sparsest page which still holds nodes is emptied into the other ones
+    let market_data = context.get_market_data().await.unwrap();
+    let page_index = (0..3)
+        .filter(|i| market_data.page_full_ratios[0][*i] > 0.)
+        .min_by(|a, b| {
+            market_data.page_full_ratios[0][*a]
+                .partial_cmp(&market_data.page_full_ratios[0][*b])
+                .unwrap()
+        })
+        .unwrap();
+    let total_collateral = market_data.total_collateral;
+    context
+        .defragment_page(0, page_index as u32, 0, u16::MAX)
+        .await
+        .unwrap();
+    let market_data = context.get_market_data().await.unwrap();
+    assert_eq!(market_data.page_full_ratios[0][page_index], 0.);
+    assert_eq!(market_data.page_fragmentation_ratios[0][page_index], 0.);
+    assert_eq!(market_data.total_collateral, total_collateral);
+
+    // There is nothing left to move
+    if let Err(err) = context
+        .defragment_page(0, page_index as u32, 0, u16::MAX)
+        .await
+    {
+        catch_noop(err).unwrap();
+    }
+
+    for _ in 0..3 {
+        let position = context.get_position(0, 0).await.unwrap();
+        context
+            .close_position(position.collateral, position.v_coin_amount, 0, 0)
+            .await
+            .unwrap();
+    }
+    let instance_address = context.get_instance_address(0).await.unwrap();
+    let (instance, _) = context.parse_instance(instance_address).await.unwrap();
+    assert_eq!(instance.longs_pointer, None);
+}
+
+#[tokio::test]
+async fn test_auto_deleverage() {
+    let mut context = Context::init(0, 6, 6).await;
+    context.change_oracle_price(10_000 << 32u64).await.unwrap();
+    context
+        .create_market("BTC/USD".to_string(), 1e10f64 as u64, 6, 6)
+        .await
+        .unwrap();
+    context.add_instance(1, 1_000_000).await.unwrap();
+    context.add_budget(200_000_000, 0).await.unwrap();
+
+    // The shorts push the mark price about 15% below the entry of the long, which then owes more
+    // than its collateral
+    context
+        .open_position(PositionType::Long, 1_000_000, 15 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    context
+        .open_position(PositionType::Short, 1_000_000, 2 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    context
+        .open_position(PositionType::Short, 80_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+
+    // Liquidating the long leaves its loss to the insurance fund
+    context.change_oracle_price(8_000 << 32u64).await.unwrap();
+    context.liquidate(0).await.unwrap();
+    // Back at the initial price, the market bias is null and the closes are not rebalanced
+    context.change_oracle_price(10_000 << 32u64).await.unwrap();
+    let insurance_fund = context.get_insurance_fund().await;
+    assert!(insurance_fund < 0);
+
+    // The longs owe nothing to the insurance fund once the long is liquidated
+    let err = context.auto_deleverage(0, 0, 0).await.unwrap_err();
+    assert_eq!(
+        catch_noop(err).unwrap_err(),
+        InstructionError::Custom(PerpError::DeleveragingSide as u32)
+    );
+
+    // The small short which rode the large one has the highest return on collateral and goes
+    // first. Its profit doesn't cover the shortfall, it is closed entirely. The large short then
+    // restores the insurance fund by closing only part of its v_coin.
+    let mut insurance_fund = insurance_fund;
+    for (slot, (position_index, is_haircut_capped)) in [(1, true), (1, false)].iter().enumerate() {
+        let market_state = context.get_market_state().await.unwrap();
+        // No funding was sampled, the positions don't owe any
+        assert_eq!(market_state.funding_samples_count, 0);
+        let position = context.get_position(*position_index, 0).await.unwrap();
+        let v_pc_closing_amount = market_state
+            .compute_add_v_pc(position.side.get_sign() * (position.v_coin_amount as i64))
+            .unwrap();
+        let payout = compute_payout(
+            v_pc_closing_amount.abs() as u64,
+            position.v_pc_amount,
+            position.collateral,
+            &position.side,
+        );
+        let profit = payout - (position.collateral as i64);
+        assert!(profit > 0);
+        let haircut = std::cmp::min(-insurance_fund, profit);
+        assert_eq!(haircut == profit, *is_haircut_capped);
+
+        let closing_v_coin =
+            ((position.v_coin_amount as i128) * (haircut as i128) + (profit as i128) - 1)
+                / (profit as i128);
+        let closing_collateral =
+            (position.collateral as i128) * closing_v_coin / (position.v_coin_amount as i128);
+        let trade = market_state
+            .clone()
+            .close_trade(
+                &position,
+                closing_collateral as u64,
+                closing_v_coin as u64,
+                10_000 << 32,
+            )
+            .unwrap();
+        assert!(trade.payout - (trade.collateral as i64) >= haircut);
+        let allocation_refund = if *is_haircut_capped {
+            ALLOCATION_FEE
+        } else {
+            0
+        };
+
+        let balance = context.get_user_account(0).await.unwrap().balance;
+        // Both instructions are identical, they have to land in different slots
+        context.prg_test_ctx.warp_to_slot(3 + slot as u64).unwrap();
+        context
+            .auto_deleverage(0, *position_index, 0)
+            .await
+            .unwrap();
+        assert_eq!(
+            context.get_user_account(0).await.unwrap().balance - balance,
+            (trade.payout - haircut) as u64 + allocation_refund
+        );
+        if !*is_haircut_capped {
+            let reduced_position = context.get_position(*position_index, 0).await.unwrap();
+            assert!(reduced_position.v_coin_amount > 0);
+            assert_eq!(
+                reduced_position.v_coin_amount,
+                position.v_coin_amount - trade.v_coin_amount
+            );
+            assert_eq!(
+                reduced_position.collateral,
+                position.collateral - trade.collateral
+            );
+        }
+
+        // The haircut stays in the vault, up to the rounding of the aggregate payout
+        let new_insurance_fund = context.get_insurance_fund().await;
+        assert!((new_insurance_fund - insurance_fund - haircut).abs() <= 1);
+        insurance_fund = new_insurance_fund;
+    }
+    assert!(insurance_fund >= -1);
+
+    // The liquidated long and what is left of the large short are in the user account
+    assert_eq!(
+        context
+            .get_user_account(0)
+            .await
+            .unwrap()
+            .number_of_open_positions,
+        2
+    );
+    if let Err(err) = context.auto_deleverage(0, 1, 0).await {
+        catch_noop(err).unwrap();
+    }
+}
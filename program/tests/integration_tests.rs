@@ -167,7 +167,8 @@ fn simulation() {
                     | InstructionError::Custom(2)
                     | InstructionError::Custom(4)
                     | InstructionError::Custom(6)
-                    | InstructionError::Custom(11) => {
+                    | InstructionError::Custom(11)
+                    | InstructionError::Custom(14) => {
                         log::error!("{:?}", ie)
                     }
                     _ => {